@@ -1,9 +1,12 @@
 use tokio::task;
 use std::{error::Error, sync::Arc};
 
+// the overflow policies and dot product kernel are shared with the other matmults
+use matmult_overflow::{OverflowPolicy, dot_product};
+
 type Matrix = Vec<Vec<i32>>;
 
-pub async fn async_matmult(left: Matrix, right: Matrix) -> Result<Matrix, Box<dyn Error>> {
+pub async fn async_matmult(left: Matrix, right: Matrix, policy: OverflowPolicy) -> Result<Matrix, Box<dyn Error>> {
    // ensure matrices are populated:
     if left.is_empty() || right.is_empty() {
         return Err("At least one of the given matrices was empty".into());
@@ -34,8 +37,9 @@ pub async fn async_matmult(left: Matrix, right: Matrix) -> Result<Matrix, Box<dy
     let right: Arc<Matrix> = Arc::new(right);
     
     // Create tasks for each dot product (each element of the result matrix)
-    // Each task will return a tuple: (row_index, col_index, calculated_value)
-    let mut tasks: Vec<task::JoinHandle<(usize, usize, i32)>> = Vec::new();
+    // Each task will return a tuple: (row_index, col_index, calculated_value), the value
+    // being None if it overflowed under the policy
+    let mut tasks: Vec<task::JoinHandle<(usize, usize, Option<i32>)>> = Vec::new();
     
     // Iterate over each cell of the target matrix
     (0..num_rows_left).for_each(|i: usize| { // i is the row index for the result matrix
@@ -45,17 +49,12 @@ pub async fn async_matmult(left: Matrix, right: Matrix) -> Result<Matrix, Box<dy
             let right_clone: Arc<Matrix> = Arc::clone(&right
     );
             
-            // num_cols_left captured from outer scope, 
-            // variable determines the length of dot product sum
-
             // spawn new task to calculate dot product for result[i][j]:
-            let task: task::JoinHandle<(usize, usize, i32)> = task::spawn(async move {
-                // accumulator for the sot product:
-                let mut sum: i32 = 0;
-                // dot product: sum(left[i][k] * right[k][j]) for k:
-                (0..num_cols_left).for_each(|k: usize| {
-                    sum += left_clone[i][k] * right_clone[k][j];
-                });
+            let task: task::JoinHandle<(usize, usize, Option<i32>)> = task::spawn(async move {
+                // column j of the right matrix, to pair up with row i of the left:
+                let col: Vec<i32> = right_clone.iter().map(|row| row[j]).collect();
+                // dot product: sum(left[i][k] * right[k][j]) for k, under the overflow policy:
+                let sum: Option<i32> = dot_product(&left_clone[i], &col, policy);
                 // return calculated value with its coordinates in result:
                 (i, j, sum)
             });
@@ -70,7 +69,9 @@ pub async fn async_matmult(left: Matrix, right: Matrix) -> Result<Matrix, Box<dy
     for task_handle in tasks {
         // task_handle.await returns Result<(usize, usize, i32), JoinError>
         let (i, j, value) = task_handle.await.map_err(|e: task::JoinError| format!("Task failed: {}", e))?;
-        result[i][j] = value;
+        result[i][j] = value.ok_or_else(|| {
+            format!("Arithmetic overflow computing result[{}][{}] under the {:?} overflow policy", i, j, policy)
+        })?;
     }
     
     Ok(result)
@@ -83,7 +84,7 @@ async fn main() {
 
     // unwrap returns the value of a Result if it is a value, 
     // otherwise panics with the error
-    let result_matrix: Matrix = async_matmult(left_matrix, right_matrix).await.unwrap();
+    let result_matrix: Matrix = async_matmult(left_matrix, right_matrix, OverflowPolicy::Saturating).await.unwrap();
     let expected_matrix: Matrix = vec![
         vec![19, 22], // [1*5 + 2*7, 1*6 + 2*8]
         vec![43, 50], // [3*5 + 4*7, 3*6 + 4*8]
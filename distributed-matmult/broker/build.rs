@@ -0,0 +1,9 @@
+// generates the broker's gRPC service, and the client for the workers' one,
+// from the proto file shared with the workers
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use the vendored protoc so building doesn't need one installed
+    // SAFETY: build scripts are single threaded, nothing else reads the environment
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    tonic_prost_build::compile_protos("../proto/matmult.proto")?;
+    Ok(())
+}
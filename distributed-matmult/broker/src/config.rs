@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use matmult_common::config::{ConfigError, env_option_override, env_override};
+use matmult_common::trace::{LogFormat, SpanExport};
+use serde::Deserialize;
+
+use crate::dispatch::Strategy;
+use crate::scheduler::SchedulerKind;
+use crate::wire::WireFormat;
+
+// config file read when BROKER_CONFIG is not set, skipped if it does not exist
+const DEFAULT_CONFIG_PATH: &str = "broker.toml";
+
+/// Broker settings. Read from a TOML file (`BROKER_CONFIG`, or `broker.toml`
+/// if present), then overridden by `BROKER_*` environment variables, which may
+/// also come from a `.env` file. Anything left unset keeps its default.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// Address the HTTP server binds to.
+    pub listen_addr: SocketAddr,
+    /// Address the gRPC server binds to, gRPC is not served when unset.
+    pub grpc_listen_addr: Option<SocketAddr>,
+    /// Base URLs of workers which are always live, on top of those that register.
+    pub workers: Vec<String>,
+    /// Capacity of the workers listed in `workers` for the weighted scheduler,
+    /// 1 for any not listed. Registering workers report their own.
+    pub worker_capacity: HashMap<String, usize>,
+    /// gRPC URLs of the workers listed in `workers` which should be sent
+    /// tiles over gRPC, the rest are sent them over HTTP. Registering workers
+    /// give their own.
+    pub worker_grpc_urls: HashMap<String, String>,
+    /// How each tile's worker is chosen.
+    pub scheduler: SchedulerKind,
+    /// How long a registered worker stays live without a heartbeat.
+    pub worker_ttl_ms: u64,
+    /// Timeout for a whole tile request to a worker, including the response body.
+    pub request_timeout_ms: u64,
+    /// Timeout for establishing a connection to a worker.
+    pub connect_timeout_ms: u64,
+    /// Most tile requests in flight at once, across every job.
+    pub max_in_flight_tiles: usize,
+    /// Most tile requests in flight to any one worker.
+    pub max_in_flight_per_worker: usize,
+    /// Most tiles the broker accepts work for at once, across every job that
+    /// hasn't finished. Requests beyond it are turned away with a 503.
+    pub max_pending_tiles: usize,
+    /// Most jobs waiting to run, further `POST /jobs` requests get a 429.
+    pub max_queued_jobs: usize,
+    /// What the `Retry-After` header tells turned away clients.
+    pub retry_after_secs: u64,
+    /// Most jobs submitted through `/jobs` running at once, the rest stay queued.
+    pub max_running_jobs: usize,
+    /// How long a finished job's result is kept for `GET /jobs/{id}`.
+    pub job_retention_ms: u64,
+    /// Most values kept across every matrix stored with `POST /matrices`.
+    pub max_stored_cells: usize,
+    /// Most cells the product of a request may have, which is assembled dense,
+    /// and most rows or columns either matrix may have.
+    pub max_result_cells: usize,
+    /// Largest request body accepted, larger ones get a 413.
+    pub max_body_bytes: u64,
+    /// Tile size used when a request does not give one.
+    pub tile_rows: usize,
+    pub tile_cols: usize,
+    /// Encoding used for broker to worker traffic unless a request asks otherwise.
+    pub wire_format: WireFormat,
+    /// How tiles are handed to workers unless a request asks otherwise.
+    pub strategy: Strategy,
+    /// Dot products sent to a worker per request under the `dot_products` strategy.
+    pub dot_product_batch_size: usize,
+    /// Rounds of Freivalds' check each result gets unless a request asks
+    /// otherwise, 0 trusts the workers.
+    pub verify_rounds: usize,
+    /// Distinct workers each tile is computed on unless a request asks
+    /// otherwise, the block most of them agree on is kept.
+    pub replicas: usize,
+    pub retry: RetryConfig,
+    pub quarantine: QuarantineConfig,
+    pub hedge: HedgeConfig,
+    /// Origins allowed by CORS, `*` allows any origin.
+    pub cors_origins: Vec<String>,
+    pub tracing: TracingConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Failed tile attempts a job may retry unless the request gives its own budget.
+    pub budget: usize,
+    /// Retries back off exponentially from the base delay up to the max.
+    pub base_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+/// Workers outvoted on a tile again and again are handed no tiles for a while.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineConfig {
+    /// Tiles a worker may disagree with the majority on before it is
+    /// quarantined, 0 never quarantines.
+    pub after: usize,
+    /// How long a quarantine lasts.
+    pub duration_ms: u64,
+}
+
+/// A tile outstanding for longer than most is sent to a second worker too,
+/// and whichever answers first is kept.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HedgeConfig {
+    /// Percentile of recent tile round trips a tile may be outstanding for
+    /// before it is hedged, unless a request asks otherwise. 0 never hedges.
+    pub percentile: f64,
+    /// Round trips to see before hedging anything.
+    pub min_samples: usize,
+    /// Most recent round trips the percentile is taken over.
+    pub window: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub log_format: LogFormat,
+    /// Which log lines are written, in `RUST_LOG` syntax.
+    pub log_filter: String,
+    /// Base URL of an OTLP/HTTP collector to send spans to, such as
+    /// `http://localhost:4318`.
+    pub otlp_endpoint: Option<String>,
+    /// File to append spans to as OTLP/JSON, one export request per line.
+    pub otlp_file: Option<PathBuf>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8000)),
+            grpc_listen_addr: None,
+            workers: Vec::new(),
+            worker_capacity: HashMap::new(),
+            worker_grpc_urls: HashMap::new(),
+            scheduler: SchedulerKind::default(),
+            worker_ttl_ms: 15_000,
+            request_timeout_ms: 30_000,
+            connect_timeout_ms: 2_000,
+            max_in_flight_tiles: 256,
+            max_in_flight_per_worker: 16,
+            max_pending_tiles: 100_000,
+            max_queued_jobs: 64,
+            retry_after_secs: 5,
+            max_running_jobs: 4,
+            job_retention_ms: 3_600_000,
+            max_stored_cells: 10_000_000,
+            max_result_cells: 100_000_000,
+            max_body_bytes: 256 * 1024 * 1024,
+            tile_rows: 64,
+            tile_cols: 64,
+            wire_format: WireFormat::Bincode,
+            strategy: Strategy::default(),
+            dot_product_batch_size: 256,
+            verify_rounds: 0,
+            replicas: 1,
+            retry: RetryConfig::default(),
+            quarantine: QuarantineConfig::default(),
+            hedge: HedgeConfig::default(),
+            cors_origins: vec!["*".to_string()],
+            tracing: TracingConfig::default(),
+        }
+    }
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        QuarantineConfig { after: 3, duration_ms: 300_000 }
+    }
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        HedgeConfig { percentile: 0.0, min_samples: 20, window: 1_000 }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            log_format: LogFormat::Text,
+            log_filter: "info,warp=warn".to_string(),
+            otlp_endpoint: None,
+            otlp_file: None,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig { budget: 16, base_backoff_ms: 50, max_backoff_ms: 2_000 }
+    }
+}
+
+impl BrokerConfig {
+    /// Loads the file, applies environment overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        // a missing .env file is fine, the real environment still applies
+        dotenvy::dotenv().ok();
+
+        let mut config = match env::var("BROKER_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            Err(_) => BrokerConfig::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError(format!("cannot read config file {}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| ConfigError(format!("invalid config file {}: {}", path.display(), e)))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override("BROKER_LISTEN_ADDR", &mut self.listen_addr)?;
+        env_option_override("BROKER_GRPC_LISTEN_ADDR", &mut self.grpc_listen_addr)?;
+        env_list_override("BROKER_WORKERS", &mut self.workers);
+        env_override("BROKER_SCHEDULER", &mut self.scheduler)?;
+        env_override("BROKER_WORKER_TTL_MS", &mut self.worker_ttl_ms)?;
+        env_override("BROKER_REQUEST_TIMEOUT_MS", &mut self.request_timeout_ms)?;
+        env_override("BROKER_CONNECT_TIMEOUT_MS", &mut self.connect_timeout_ms)?;
+        env_override("BROKER_MAX_IN_FLIGHT_TILES", &mut self.max_in_flight_tiles)?;
+        env_override("BROKER_MAX_IN_FLIGHT_PER_WORKER", &mut self.max_in_flight_per_worker)?;
+        env_override("BROKER_MAX_PENDING_TILES", &mut self.max_pending_tiles)?;
+        env_override("BROKER_MAX_QUEUED_JOBS", &mut self.max_queued_jobs)?;
+        env_override("BROKER_RETRY_AFTER_SECS", &mut self.retry_after_secs)?;
+        env_override("BROKER_MAX_RUNNING_JOBS", &mut self.max_running_jobs)?;
+        env_override("BROKER_JOB_RETENTION_MS", &mut self.job_retention_ms)?;
+        env_override("BROKER_MAX_STORED_CELLS", &mut self.max_stored_cells)?;
+        env_override("BROKER_MAX_RESULT_CELLS", &mut self.max_result_cells)?;
+        env_override("BROKER_MAX_BODY_BYTES", &mut self.max_body_bytes)?;
+        env_override("BROKER_TILE_ROWS", &mut self.tile_rows)?;
+        env_override("BROKER_TILE_COLS", &mut self.tile_cols)?;
+        if let Ok(value) = env::var("BROKER_WIRE_FORMAT") {
+            self.wire_format = match value.trim().to_ascii_lowercase().as_str() {
+                "json" => WireFormat::Json,
+                "bincode" => WireFormat::Bincode,
+                _ => return Err(ConfigError(format!("BROKER_WIRE_FORMAT must be json or bincode, got {:?}", value))),
+            };
+        }
+        env_override("BROKER_STRATEGY", &mut self.strategy)?;
+        env_override("BROKER_DOT_PRODUCT_BATCH_SIZE", &mut self.dot_product_batch_size)?;
+        env_override("BROKER_VERIFY_ROUNDS", &mut self.verify_rounds)?;
+        env_override("BROKER_REPLICAS", &mut self.replicas)?;
+        env_override("BROKER_RETRY_BUDGET", &mut self.retry.budget)?;
+        env_override("BROKER_RETRY_BASE_BACKOFF_MS", &mut self.retry.base_backoff_ms)?;
+        env_override("BROKER_RETRY_MAX_BACKOFF_MS", &mut self.retry.max_backoff_ms)?;
+        env_override("BROKER_QUARANTINE_AFTER", &mut self.quarantine.after)?;
+        env_override("BROKER_QUARANTINE_MS", &mut self.quarantine.duration_ms)?;
+        env_override("BROKER_HEDGE_PERCENTILE", &mut self.hedge.percentile)?;
+        env_override("BROKER_HEDGE_MIN_SAMPLES", &mut self.hedge.min_samples)?;
+        env_override("BROKER_HEDGE_WINDOW", &mut self.hedge.window)?;
+        env_list_override("BROKER_CORS_ORIGINS", &mut self.cors_origins);
+        env_override("BROKER_LOG_FORMAT", &mut self.tracing.log_format)?;
+        env_override("BROKER_LOG_FILTER", &mut self.tracing.log_filter)?;
+        env_option_override("BROKER_OTLP_ENDPOINT", &mut self.tracing.otlp_endpoint)?;
+        env_option_override("BROKER_OTLP_FILE", &mut self.tracing.otlp_file)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for url in &self.workers {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                return Err(ConfigError(format!("workers: {:?} is not an absolute http(s) URL", url)));
+            }
+        }
+        for (url, capacity) in &self.worker_capacity {
+            if *capacity == 0 {
+                return Err(ConfigError(format!("worker_capacity: {} must be greater than 0", url)));
+            }
+        }
+        for (url, grpc_url) in &self.worker_grpc_urls {
+            if !(grpc_url.starts_with("http://") || grpc_url.starts_with("https://")) {
+                return Err(ConfigError(format!("worker_grpc_urls: {} is not an absolute http(s) URL", url)));
+            }
+        }
+        let positive = [
+            ("worker_ttl_ms", self.worker_ttl_ms as usize),
+            ("request_timeout_ms", self.request_timeout_ms as usize),
+            ("connect_timeout_ms", self.connect_timeout_ms as usize),
+            ("max_in_flight_tiles", self.max_in_flight_tiles),
+            ("max_in_flight_per_worker", self.max_in_flight_per_worker),
+            ("max_pending_tiles", self.max_pending_tiles),
+            ("max_running_jobs", self.max_running_jobs),
+            ("max_result_cells", self.max_result_cells),
+            ("max_body_bytes", self.max_body_bytes as usize),
+            ("tile_rows", self.tile_rows),
+            ("tile_cols", self.tile_cols),
+            ("dot_product_batch_size", self.dot_product_batch_size),
+            ("replicas", self.replicas),
+            ("hedge.window", self.hedge.window),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(ConfigError(format!("{} must be greater than 0", name)));
+            }
+        }
+        if !(0.0..100.0).contains(&self.hedge.percentile) {
+            return Err(ConfigError(format!(
+                "hedge.percentile must be at least 0 and below 100, got {}",
+                self.hedge.percentile
+            )));
+        }
+        if self.retry.base_backoff_ms > self.retry.max_backoff_ms {
+            return Err(ConfigError(format!(
+                "retry.base_backoff_ms ({}) must not exceed retry.max_backoff_ms ({})",
+                self.retry.base_backoff_ms, self.retry.max_backoff_ms
+            )));
+        }
+        if let Some(endpoint) = &self.tracing.otlp_endpoint
+            && !(endpoint.starts_with("http://") || endpoint.starts_with("https://"))
+        {
+            return Err(ConfigError(format!("tracing.otlp_endpoint: {:?} is not an absolute http(s) URL", endpoint)));
+        }
+        if self.tracing.otlp_endpoint.is_some() && self.tracing.otlp_file.is_some() {
+            return Err(ConfigError("tracing: set otlp_endpoint or otlp_file, not both".to_string()));
+        }
+        if self.cors_origins.is_empty() {
+            return Err(ConfigError("cors_origins must list at least one origin, or \"*\"".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn worker_ttl(&self) -> Duration {
+        Duration::from_millis(self.worker_ttl_ms)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn job_retention(&self) -> Duration {
+        Duration::from_millis(self.job_retention_ms)
+    }
+
+    pub fn quarantine_duration(&self) -> Duration {
+        Duration::from_millis(self.quarantine.duration_ms)
+    }
+
+    /// Where finished spans are exported to, if anywhere.
+    pub fn span_export(&self) -> Option<SpanExport> {
+        match (&self.tracing.otlp_endpoint, &self.tracing.otlp_file) {
+            (Some(endpoint), _) => Some(SpanExport::Collector(endpoint.clone())),
+            (None, Some(path)) => Some(SpanExport::File(path.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+// replaces `target` with the comma separated entries of the variable, if it is set
+fn env_list_override(name: &str, target: &mut Vec<String>) {
+    if let Ok(value) = env::var(name) {
+        *target = value
+            .split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect();
+    }
+}
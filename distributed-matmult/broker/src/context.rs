@@ -0,0 +1,136 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use matmult_common::config::ConfigError;
+use reqwest::Client;
+use tokio::sync::Semaphore;
+
+use crate::config::BrokerConfig;
+use crate::grpc::WorkerChannels;
+use crate::hedge::LatencyWindow;
+use crate::jobs::JobTable;
+use crate::metrics::Metrics;
+use crate::registry::WorkerRegistry;
+use crate::scheduler::Scheduler;
+use crate::store::MatrixStore;
+use crate::error::BrokerError;
+
+/// State shared by every request the broker handles.
+pub struct BrokerContext {
+    pub config: BrokerConfig,
+    /// Single client shared by every request, so connections to workers are reused.
+    pub http_client: Client,
+    /// Channels to the workers reached over gRPC, likewise reused.
+    pub grpc_channels: WorkerChannels,
+    pub registry: WorkerRegistry,
+    /// Chooses the worker for each tile, using the strategy in the config.
+    pub scheduler: Box<dyn Scheduler>,
+    /// Caps the tile requests in flight across all jobs at `max_in_flight_tiles`.
+    pub tile_slots: Arc<Semaphore>,
+    /// Jobs submitted through `/jobs`, and the slots that let them start running.
+    pub jobs: JobTable,
+    pub job_slots: Arc<Semaphore>,
+    /// Matrices uploaded through `/matrices` for requests to name by ID.
+    pub matrices: MatrixStore,
+    /// Counters and histograms exported on `GET /metrics`.
+    pub metrics: Metrics,
+    /// Recent tile round trips, which decide when a tile is hedged.
+    pub latencies: LatencyWindow,
+    // tiles of every admitted job that hasn't finished, see `admit`
+    pending_tiles: Arc<AtomicUsize>,
+}
+
+/// Tiles a job has been admitted with, given back to the broker when dropped.
+pub struct TileReservation {
+    tiles: usize,
+    pending_tiles: Arc<AtomicUsize>,
+}
+
+impl Drop for TileReservation {
+    fn drop(&mut self) {
+        self.pending_tiles.fetch_sub(self.tiles, Ordering::Relaxed);
+    }
+}
+
+impl BrokerContext {
+    pub fn new(config: BrokerConfig) -> Result<Self, ConfigError> {
+        let http_client = Client::builder()
+            .timeout(config.request_timeout())
+            .connect_timeout(config.connect_timeout())
+            .build()
+            .map_err(|e| ConfigError(format!("cannot build HTTP client: {}", e)))?;
+
+        // workers listed in the config are always live, others join by registering
+        let registry = WorkerRegistry::new(
+            config.worker_ttl(),
+            config.max_in_flight_per_worker,
+            config.quarantine.after,
+            config.quarantine_duration(),
+        );
+        for url in &config.workers {
+            let url = url.trim_end_matches('/');
+            let capacity = config.worker_capacity.get(url).copied().unwrap_or(1);
+            let grpc_url = config.worker_grpc_urls.get(url).map(|grpc_url| grpc_url.trim_end_matches('/').to_string());
+            registry.pin(url, capacity, grpc_url);
+        }
+
+        let metrics = Metrics::new().map_err(|e| ConfigError(format!("cannot register metrics: {}", e)))?;
+
+        Ok(BrokerContext {
+            http_client,
+            grpc_channels: WorkerChannels::new(config.connect_timeout(), config.request_timeout()),
+            registry,
+            scheduler: config.scheduler.build(),
+            tile_slots: Arc::new(Semaphore::new(config.max_in_flight_tiles)),
+            jobs: JobTable::new(config.job_retention()),
+            job_slots: Arc::new(Semaphore::new(config.max_running_jobs)),
+            matrices: MatrixStore::new(config.max_stored_cells),
+            metrics,
+            latencies: LatencyWindow::new(config.hedge.window),
+            pending_tiles: Arc::new(AtomicUsize::new(0)),
+            config,
+        })
+    }
+
+    /// Admits a job of `tiles` tiles if the broker has room for it, which it
+    /// holds until the reservation is dropped. A job bigger than the whole
+    /// budget is still let in once nothing else is pending, so it can't be
+    /// turned away forever.
+    pub fn admit(&self, tiles: usize) -> Result<TileReservation, BrokerError> {
+        let limit = self.config.max_pending_tiles;
+        self.pending_tiles
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                (pending == 0 || pending + tiles <= limit).then_some(pending + tiles)
+            })
+            .map(|_| TileReservation { tiles, pending_tiles: Arc::clone(&self.pending_tiles) })
+            .map_err(|pending| BrokerError::Overloaded {
+                reason: format!(
+                    "The broker is busy with {} pending tiles, which leaves no room for {} more (limit {})",
+                    pending, tiles, limit
+                ),
+                retry_after: self.retry_after(),
+            })
+    }
+
+    /// Turns a job away if too many are already waiting to run.
+    pub fn admit_queued_job(&self) -> Result<(), BrokerError> {
+        let queued = self.jobs.queued();
+        if queued >= self.config.max_queued_jobs {
+            return Err(BrokerError::TooManyRequests {
+                reason: format!("{} jobs are already queued, try again later", queued),
+                retry_after: self.retry_after(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Tiles of every admitted job that hasn't finished yet.
+    pub fn pending_tiles(&self) -> usize {
+        self.pending_tiles.load(Ordering::Relaxed)
+    }
+
+    fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.config.retry_after_secs)
+    }
+}
@@ -0,0 +1,764 @@
+use std::cmp::Reverse;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use matmult_common::trace;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::context::BrokerContext;
+use crate::element::{Element, OverflowPolicy};
+use crate::error::{BrokerError, ErrorBody};
+use crate::grpc::{self, TileCallError, TileMessages};
+use crate::jobs::{JobEvent, JobProgress};
+use crate::registry::{Candidate, OutstandingGuard};
+use crate::types::{
+    BlockResponse, DotProductBatchResponse, SparseBlockResponse, TypedBlockPayload, TypedDotProductBatch,
+    TypedSparseBlockPayload,
+};
+use crate::wire::WireFormat;
+
+// path on each worker which multiplies a block of rows by a block of columns:
+const MULTIPLY_BLOCK_PATH: &str = "/multiply_block";
+// path on each worker which computes a batch of dot products:
+const DOT_PRODUCTS_PATH: &str = "/calculate_dot_products";
+// path on each worker which multiplies sparse blocks:
+const MULTIPLY_SPARSE_BLOCK_PATH: &str = "/multiply_sparse_block";
+
+/// How the cells of a tile are handed to a worker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// The tile's block of rows and block of columns, multiplied by the worker.
+    #[default]
+    Blocks,
+    /// One dot product per cell, a tile's worth sent in a single request.
+    DotProducts,
+}
+
+impl Strategy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Strategy::Blocks => "blocks",
+            Strategy::DotProducts => "dot_products",
+        }
+    }
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(Strategy::Blocks),
+            "dot_products" => Ok(Strategy::DotProducts),
+            _ => Err("expected blocks or dot_products".to_string()),
+        }
+    }
+}
+
+/// The work for one tile, in the shape its strategy sends it, or as sparse
+/// blocks when the matrices are sparse.
+pub enum TilePayload {
+    Block(TypedBlockPayload),
+    DotProducts(TypedDotProductBatch),
+    SparseBlock(TypedSparseBlockPayload),
+}
+
+impl TilePayload {
+    // the worker route which computes the payload over HTTP
+    fn path(&self) -> &'static str {
+        match self {
+            TilePayload::Block(_) => MULTIPLY_BLOCK_PATH,
+            TilePayload::DotProducts(_) => DOT_PRODUCTS_PATH,
+            TilePayload::SparseBlock(_) => MULTIPLY_SPARSE_BLOCK_PATH,
+        }
+    }
+
+    fn encode(&self, format: WireFormat) -> Result<Vec<u8>, String> {
+        match self {
+            TilePayload::Block(payload) => format.encode(payload),
+            TilePayload::DotProducts(batch) => format.encode(batch),
+            TilePayload::SparseBlock(payload) => format.encode(payload),
+        }
+    }
+}
+
+/// How failed tiles are retried. `budget` is shared by every tile of a job, so
+/// a job only fails once its workers have failed more than `budget` times in total.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub budget: usize,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1), doubling each time.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Whether a failed attempt is worth repeating on another worker.
+enum AttemptError {
+    /// The worker could not be reached, timed out, answered 5xx or sent back garbage.
+    Retryable(BrokerError),
+    /// The worker rejected the tile itself (4xx), so any other worker would too.
+    Fatal(BrokerError),
+}
+
+/// A replica of a tile which failed for good, with the worker it was last sent
+/// to if it got as far as one.
+struct ReplicaFailure {
+    worker: Option<String>,
+    error: BrokerError,
+}
+
+/// A worker one replica of a tile is using, which the tile's other replicas
+/// skip. It is given back when dropped, when the replica fails on the worker
+/// or is cancelled, unless `keep` records that the replica's answer came from it.
+struct Claim<'a> {
+    claimed: &'a Mutex<Vec<String>>,
+    url: String,
+    kept: bool,
+}
+
+impl Claim<'_> {
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.claimed.lock().unwrap().retain(|claim| *claim != self.url);
+        }
+    }
+}
+
+/// Sends the tiles of one job to workers, retrying failed tiles on other
+/// workers until the job's retry budget runs out. With more than one replica
+/// each tile is computed on that many distinct workers, which vote on it. A
+/// tile outstanding past `hedge_percentile` of recent round trips is sent to
+/// another worker as well.
+pub struct Dispatcher {
+    context: Arc<BrokerContext>,
+    progress: Arc<JobProgress>,
+    wire_format: WireFormat,
+    retry: RetryPolicy,
+    replicas: usize,
+    hedge_percentile: f64,
+    retries_left: AtomicUsize,
+    hedged: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    bytes_received: AtomicUsize,
+}
+
+impl Dispatcher {
+    pub fn new(
+        context: Arc<BrokerContext>,
+        progress: Arc<JobProgress>,
+        wire_format: WireFormat,
+        retry: RetryPolicy,
+        replicas: usize,
+        hedge_percentile: f64,
+    ) -> Self {
+        Dispatcher {
+            context,
+            progress,
+            wire_format,
+            retry,
+            replicas,
+            hedge_percentile,
+            retries_left: AtomicUsize::new(retry.budget),
+            hedged: AtomicUsize::new(0),
+            bytes_sent: AtomicUsize::new(0),
+            bytes_received: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Retries this job has used so far.
+    pub fn retries_used(&self) -> usize {
+        self.retry.budget - self.retries_left.load(Ordering::Relaxed)
+    }
+
+    /// Tiles of this job sent to a second worker for being slow.
+    pub fn hedged(&self) -> usize {
+        self.hedged.load(Ordering::Relaxed)
+    }
+
+    // takes one retry from the job's budget, false if there are none left
+    fn take_retry(&self) -> bool {
+        self.retries_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1))
+            .is_ok()
+    }
+
+    // asks the scheduler for a live worker, skipping those in `avoid` unless every
+    // live worker is in it and always skipping those other replicas of the tile have
+    // `claimed`, then claims it, waits for a slot on it and counts the tile as outstanding
+    async fn pick_worker<'a>(
+        &self,
+        avoid: &[String],
+        claimed: &'a Mutex<Vec<String>>,
+    ) -> Option<(Candidate, Claim<'a>, OutstandingGuard)> {
+        let (worker, claim) = {
+            let mut taken = claimed.lock().unwrap();
+            let live: Vec<_> =
+                self.context.registry.candidates().into_iter().filter(|c| !taken.contains(&c.url)).collect();
+            let fresh: Vec<_> = live.iter().filter(|c| !avoid.contains(&c.url)).cloned().collect();
+            let candidates = if fresh.is_empty() { live } else { fresh };
+            // prefer workers below their in-flight limit, only queue on a full one when all are full
+            let free: Vec<_> = candidates.iter().filter(|c| c.has_free_slot).cloned().collect();
+            let candidates = if free.is_empty() { candidates } else { free };
+            if candidates.is_empty() {
+                return None;
+            }
+            let worker = candidates[self.context.scheduler.pick(&candidates)].clone();
+            taken.push(worker.url.clone());
+            let claim = Claim { claimed, url: worker.url.clone(), kept: false };
+            (worker, claim)
+        };
+        // the worker may have expired since the lookup, in which case it isn't tracked any more
+        let guard = self.context.registry.begin_request(&worker.url).await?;
+        Some((worker, claim, guard))
+    }
+
+    /// Gets the block for the tile covering `rows` x `cols` computed by some worker,
+    /// or by as many distinct workers as the job has replicas, reporting each
+    /// assignment, retry and the outcome to the job's progress. Workers in `avoid`
+    /// are only used if no other is live. Returns the block and the workers which
+    /// computed it.
+    pub async fn run_tile<T: Element>(
+        &self,
+        rows: Range<usize>,
+        cols: Range<usize>,
+        payload: TilePayload,
+        avoid: Vec<String>,
+    ) -> Result<(DenseMatrix<T>, Vec<String>), BrokerError> {
+        let outcome = if self.replicas > 1 {
+            self.vote::<T>(&rows, &cols, &payload, &avoid).await
+        } else {
+            let claimed = Mutex::new(Vec::new());
+            let replica = self.run_hedged_replica::<T>(&rows, &cols, &payload, avoid, &claimed).await;
+            replica.map(|(block, worker)| (block, vec![worker]))
+        };
+        match outcome {
+            Ok((block, workers)) => {
+                self.progress.tile_done();
+                // only pay for converting the block to JSON if someone is watching
+                if self.progress.has_subscribers() {
+                    self.progress.emit(JobEvent::TileDone {
+                        rows,
+                        cols,
+                        worker: workers[0].clone(),
+                        block: serde_json::to_value(block.to_rows()).unwrap_or_default(),
+                    });
+                }
+                Ok((block, workers))
+            }
+            Err(ReplicaFailure { worker: Some(worker), error }) => Err(self.tile_failed(rows, cols, worker, error)),
+            Err(ReplicaFailure { worker: None, error }) => Err(error),
+        }
+    }
+
+    // computes the tile on `replicas` distinct workers at once and keeps the block a
+    // majority of them answered with, recording which workers agreed with it and which
+    // didn't. Replicas that fail for good just don't get a say.
+    async fn vote<T: Element>(
+        &self,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+        payload: &TilePayload,
+        avoid: &[String],
+    ) -> Result<(DenseMatrix<T>, Vec<String>), ReplicaFailure> {
+        let claimed = Mutex::new(Vec::new());
+        let replicas =
+            (0..self.replicas).map(|_| self.run_hedged_replica::<T>(rows, cols, payload, avoid.to_vec(), &claimed));
+        let outcomes = futures::future::join_all(replicas).await;
+
+        // the workers behind each different block, those with the most first
+        let mut answers: Vec<(DenseMatrix<T>, Vec<String>)> = Vec::new();
+        let mut failures = Vec::new();
+        for outcome in outcomes {
+            match outcome {
+                Ok((block, worker)) => match answers.iter_mut().find(|(answer, _)| same_block(answer, &block)) {
+                    Some((_, workers)) => workers.push(worker),
+                    None => answers.push((block, vec![worker])),
+                },
+                Err(failure) => failures.push(failure),
+            }
+        }
+        answers.sort_by_key(|(_, workers)| Reverse(workers.len()));
+
+        let majority = self.replicas / 2 + 1;
+        if answers.first().is_none_or(|(_, workers)| workers.len() < majority) {
+            self.context.metrics.observe_vote("no_majority");
+            // a replica which failed is the likelier reason, otherwise nobody can be blamed
+            if let Some(failure) = failures.into_iter().next() {
+                return Err(failure);
+            }
+            let error = BrokerError::NoMajority {
+                rows: rows.clone(),
+                cols: cols.clone(),
+                replicas: self.replicas,
+                answers: answers.iter().map(|(_, workers)| workers.len()).collect(),
+            };
+            return Err(ReplicaFailure { worker: None, error });
+        }
+
+        let (block, agreed) = answers.remove(0);
+        self.context.metrics.observe_vote(if agreed.len() == self.replicas { "unanimous" } else { "majority" });
+        for worker in &agreed {
+            self.context.registry.record_vote(worker, true);
+            self.context.metrics.observe_worker_vote(worker, true);
+        }
+        for worker in answers.iter().flat_map(|(_, workers)| workers) {
+            tracing::warn!(worker = %worker, "Worker was outvoted on tile rows {:?}, cols {:?}", rows, cols);
+            self.context.metrics.observe_worker_vote(worker, false);
+            if self.context.registry.record_vote(worker, false) {
+                tracing::warn!(
+                    worker = %worker,
+                    "Worker disagreed with the majority too often, quarantined for {:?}",
+                    self.context.config.quarantine_duration()
+                );
+                self.context.metrics.observe_quarantine(worker);
+            }
+        }
+        Ok((block, agreed))
+    }
+
+    // runs a replica and, if it is still outstanding once most tiles would have been
+    // answered, a duplicate of it on another worker, keeping whichever answers first.
+    // The other is dropped, which cancels its request. A duplicate that fails leaves
+    // the original to finish, and the other way round.
+    async fn run_hedged_replica<T: Element>(
+        &self,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+        payload: &TilePayload,
+        avoid: Vec<String>,
+        claimed: &Mutex<Vec<String>>,
+    ) -> Result<(DenseMatrix<T>, String), ReplicaFailure> {
+        let original = self.run_replica::<T>(rows, cols, payload, avoid.clone(), claimed);
+        let delay = match self.hedge_percentile {
+            0.0 => None,
+            percentile => self.context.latencies.percentile(percentile, self.context.config.hedge.min_samples),
+        };
+        let Some(delay) = delay else {
+            return original.await;
+        };
+        tokio::pin!(original);
+        tokio::select! {
+            outcome = &mut original => return outcome,
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        self.hedged.fetch_add(1, Ordering::Relaxed);
+        self.progress.emit(JobEvent::TileHedged { rows: rows.clone(), cols: cols.clone(), after_ms: delay.as_millis() });
+        tracing::info!("Tile rows {:?}, cols {:?} is still outstanding after {:?}, hedging it", rows, cols, delay);
+        let hedge = self.run_replica::<T>(rows, cols, payload, avoid, claimed);
+        tokio::pin!(hedge);
+        let (outcome, hedge_won) = tokio::select! {
+            outcome = &mut original => match outcome {
+                Ok(answer) => (Ok(answer), false),
+                Err(_) => (hedge.await, true),
+            },
+            outcome = &mut hedge => match outcome {
+                Ok(answer) => (Ok(answer), true),
+                Err(_) => (original.await, false),
+            },
+        };
+        if outcome.is_ok() {
+            self.context.metrics.observe_hedge(if hedge_won { "hedge" } else { "original" });
+        }
+        outcome
+    }
+
+    // gets the block for the tile from one worker, retrying on others as the job's
+    // budget allows, and never on a worker another replica of the tile is using or
+    // got its answer from
+    async fn run_replica<T: Element>(
+        &self,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+        payload: &TilePayload,
+        avoid: Vec<String>,
+        claimed: &Mutex<Vec<String>>,
+    ) -> Result<(DenseMatrix<T>, String), ReplicaFailure> {
+        let (rows, cols) = (rows.clone(), cols.clone());
+        // encode the payload at most once per transport, the first time a worker reached
+        // over it is picked: in the job's wire format for HTTP workers and as protobuf
+        // messages for gRPC ones, every attempt over the same transport sends the same thing
+        let mut body: Option<Bytes> = None;
+        let mut messages: Option<TileMessages> = None;
+
+        let mut failed_workers = avoid;
+        let mut attempt: u32 = 0;
+        loop {
+            // wait for one of the broker wide in-flight slots before choosing a worker, so
+            // the outstanding counts the scheduler sees are tiles actually sent to workers
+            let slot = self
+                .context
+                .tile_slots
+                .acquire()
+                .await
+                .map_err(|e| ReplicaFailure {
+                    worker: None,
+                    error: BrokerError::Internal(format!("in-flight limiter closed: {}", e)),
+                })?;
+            let Some((worker, claim, outstanding)) = self.pick_worker(&failed_workers, claimed).await else {
+                let reason = if self.replicas > 1 {
+                    format!("Fewer than the {} live workers needed to compute each tile on are left", self.replicas)
+                } else {
+                    "No live workers are registered with the broker".to_string()
+                };
+                return Err(ReplicaFailure { worker: None, error: BrokerError::WorkerUnavailable { worker: None, reason } });
+            };
+            let worker_url = worker.url;
+
+            self.progress.emit(JobEvent::TileAssigned {
+                rows: rows.clone(),
+                cols: cols.clone(),
+                worker: worker_url.clone(),
+                attempt: attempt + 1,
+            });
+
+            let started = Instant::now();
+            // each attempt is its own span, which the worker's spans hang off through `traceparent`
+            let attempt_span = tracing::info_span!(
+                "tile_attempt",
+                otel.kind = "client",
+                worker = %worker_url,
+                transport = if worker.grpc_url.is_some() { "grpc" } else { "http" },
+                attempt = attempt + 1,
+            );
+            let attempt_result = match &worker.grpc_url {
+                Some(grpc_url) => {
+                    let messages = messages.get_or_insert_with(|| TileMessages::new(payload)).clone();
+                    self.attempt_grpc::<T>(&worker_url, grpc_url, messages, &rows, &cols)
+                        .instrument(attempt_span)
+                        .await
+                }
+                None => {
+                    let body = match body.clone() {
+                        Some(body) => body,
+                        None => {
+                            let encoded = Bytes::from(payload.encode(self.wire_format).map_err(|e| ReplicaFailure {
+                                worker: None,
+                                error: BrokerError::Internal(format!(
+                                    "Failed to encode tile rows {:?}, cols {:?}: {}",
+                                    rows, cols, e
+                                )),
+                            })?);
+                            body = Some(encoded.clone());
+                            encoded
+                        }
+                    };
+                    self.attempt::<T>(&worker_url, payload, body, &rows, &cols)
+                        .instrument(attempt_span)
+                        .await
+                }
+            };
+            let error = match attempt_result {
+                // make sure the worker sent back a block of the shape that was asked for
+                Ok(block) if block.shape() == (rows.len(), cols.len()) => {
+                    self.context.metrics.observe_tile(&worker_url, "ok", started.elapsed());
+                    self.context.latencies.record(started.elapsed());
+                    // the worker answered for this replica, so no other may use it
+                    claim.keep();
+                    return Ok((block, worker_url));
+                }
+                Ok(block) => BrokerError::BadWorkerResponse {
+                    worker: worker_url.clone(),
+                    reason: format!("returned a {}x{} block", block.rows(), block.cols()),
+                },
+                Err(AttemptError::Fatal(e)) => {
+                    self.context.metrics.observe_tile(&worker_url, "failed", started.elapsed());
+                    return Err(ReplicaFailure { worker: Some(worker_url), error: e });
+                }
+                Err(AttemptError::Retryable(e)) => e,
+            };
+
+            // the worker (or the path to it) failed, try again elsewhere if the job can afford it
+            let can_retry = self.take_retry();
+            let outcome = if can_retry { "retry" } else { "failed" };
+            self.context.metrics.observe_tile(&worker_url, outcome, started.elapsed());
+            if !can_retry {
+                let budget = self.retry.budget;
+                let error = error.map_reason(|reason| format!("{} (the job's retry budget of {} is exhausted)", reason, budget));
+                return Err(ReplicaFailure { worker: Some(worker_url), error });
+            }
+            self.progress.emit(JobEvent::TileRetry {
+                rows: rows.clone(),
+                cols: cols.clone(),
+                worker: worker_url.clone(),
+                error: error.body(),
+            });
+            attempt += 1;
+            let delay = self.retry.backoff(attempt);
+            tracing::warn!(
+                worker = %worker_url,
+                code = error.code(),
+                retry = attempt,
+                "Tile rows {:?}, cols {:?} failed ({}), retrying in {:?}",
+                rows, cols, error, delay
+            );
+            // the worker is only avoided from here on, other replicas may still pick it
+            drop(claim);
+            failed_workers.push(worker_url);
+            // don't hold up other tiles, or count against the failed worker, while backing off
+            drop(outstanding);
+            drop(slot);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // announces that a tile failed for good straight away, rather than once every
+    // other tile has finished, and builds the error the job fails with
+    fn tile_failed(&self, rows: Range<usize>, cols: Range<usize>, worker_url: String, error: BrokerError) -> BrokerError {
+        let error = error.in_tile(&rows, &cols);
+        self.progress.emit(JobEvent::TileFailed { rows, cols, worker: worker_url, error: error.body() });
+        error
+    }
+
+    // one request to one worker
+    async fn attempt<T: Element>(
+        &self,
+        worker_url: &str,
+        payload: &TilePayload,
+        body: Bytes,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+    ) -> Result<DenseMatrix<T>, AttemptError> {
+        let path = payload.path();
+
+        self.bytes_sent.fetch_add(body.len(), Ordering::Relaxed);
+        self.context.metrics.add_bytes_sent(self.wire_format.as_str(), body.len());
+
+        // use the client to send the request to the chosen worker:
+        let mut request = self
+            .context
+            .http_client
+            .post(format!("{}{}", worker_url, path)) // POST request
+            .header(CONTENT_TYPE, self.wire_format.content_type()) // tell the worker how the body is encoded
+            .header(ACCEPT, self.wire_format.content_type()) // and ask for the reply in the same format
+            .body(body);
+        // so the worker's spans for this tile join the job's trace
+        if let Some(traceparent) = trace::current_traceparent() {
+            request = request.header("traceparent", traceparent);
+        }
+        let response = request
+            .send() // request is sent
+            .await // task waits for the response
+            .map_err(|e| AttemptError::Retryable(request_error(worker_url, e)))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_body = response.bytes().await.unwrap_or_default();
+            return Err(worker_error(worker_url, status, &error_body));
+        }
+
+        // the worker says which format it replied in, JSON if it doesn't say
+        let reply_format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Some(WireFormat::Json), WireFormat::from_media_type)
+            .ok_or_else(|| AttemptError::Retryable(bad_response(worker_url, "unsupported response content type")))?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| AttemptError::Retryable(request_error(worker_url, e)))?;
+        self.bytes_received.fetch_add(bytes.len(), Ordering::Relaxed);
+        self.context.metrics.add_bytes_received(reply_format.as_str(), bytes.len());
+
+        let unreadable = |e: String| AttemptError::Retryable(bad_response(worker_url, format!("unreadable response: {}", e)));
+        match payload {
+            TilePayload::Block(_) => {
+                reply_format.decode::<BlockResponse<T>>(&bytes).map(|data| data.result).map_err(unreadable)
+            }
+            TilePayload::DotProducts(_) => {
+                let data = reply_format.decode::<DotProductBatchResponse<T>>(&bytes).map_err(unreadable)?;
+                assemble_dot_products(data, rows, cols).map_err(|reason| AttemptError::Retryable(bad_response(worker_url, reason)))
+            }
+            // only the tile's non-zero cells come back, the zeros are filled in here
+            TilePayload::SparseBlock(_) => {
+                let data = reply_format.decode::<SparseBlockResponse<T>>(&bytes).map_err(unreadable)?;
+                sparse_tile(data.result, rows, cols).map_err(|reason| AttemptError::Retryable(bad_response(worker_url, reason)))
+            }
+        }
+    }
+
+    // one request to one worker over gRPC
+    async fn attempt_grpc<T: Element>(
+        &self,
+        worker_url: &str,
+        grpc_url: &str,
+        messages: TileMessages,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+    ) -> Result<DenseMatrix<T>, AttemptError> {
+        let channel = self.context.grpc_channels.get(grpc_url).map_err(|reason| {
+            AttemptError::Retryable(BrokerError::WorkerUnavailable { worker: Some(worker_url.to_string()), reason })
+        })?;
+
+        let sent = messages.encoded_len();
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.context.metrics.add_bytes_sent(grpc::PROTOBUF, sent);
+
+        match grpc::compute_tile::<T>(channel, messages, trace::current_traceparent(), rows, cols).await {
+            Ok((block, received)) => {
+                self.bytes_received.fetch_add(received, Ordering::Relaxed);
+                self.context.metrics.add_bytes_received(grpc::PROTOBUF, received);
+                Ok(block)
+            }
+            Err(TileCallError::Status(status)) => Err(grpc_worker_error(worker_url, status)),
+            Err(TileCallError::BadReply(reason)) => Err(AttemptError::Retryable(bad_response(worker_url, reason))),
+        }
+    }
+}
+
+// whether two workers answered a tile with the same block, where NaN in one
+// cell matches NaN in the other
+fn same_block<T: Element>(a: &DenseMatrix<T>, b: &DenseMatrix<T>) -> bool {
+    a.shape() == b.shape()
+        && a.as_slice().iter().zip(b.as_slice()).all(|(&x, &y)| x == y || (x.to_f64().is_nan() && y.to_f64().is_nan()))
+}
+
+// a sparse tile with its zeros filled in, checked to be the shape of the tile
+// first so a worker can't have a block of any size allocated
+pub fn sparse_tile<T: Element>(block: CsrMatrix<T>, rows: &Range<usize>, cols: &Range<usize>) -> Result<DenseMatrix<T>, String> {
+    if block.shape() != (rows.len(), cols.len()) {
+        return Err(format!("returned a {}x{} block", block.rows(), block.cols()));
+    }
+    Ok(block.to_dense())
+}
+
+// places each dot product of a batch at its cell of the tile, every cell of the
+// tile must be answered exactly once and nothing outside it
+pub fn assemble_dot_products<T: Element>(
+    response: DotProductBatchResponse<T>,
+    rows: &Range<usize>,
+    cols: &Range<usize>,
+) -> Result<DenseMatrix<T>, String> {
+    let mut block = DenseMatrix::zeros(rows.len(), cols.len());
+    let mut filled = vec![false; rows.len() * cols.len()];
+    for item in response.results {
+        if !rows.contains(&item.row_id) || !cols.contains(&item.col_id) {
+            return Err(format!("returned cell ({}, {}), which is outside the tile", item.row_id, item.col_id));
+        }
+        let (i, j) = (item.row_id - rows.start, item.col_id - cols.start);
+        if std::mem::replace(&mut filled[i * cols.len() + j], true) {
+            return Err(format!("returned cell ({}, {}) more than once", item.row_id, item.col_id));
+        }
+        block.row_mut(i)[j] = item.result;
+    }
+    match filled.iter().position(|done| !done) {
+        Some(missing) => Err(format!(
+            "left out cell ({}, {})",
+            rows.start + missing / cols.len(),
+            cols.start + missing % cols.len()
+        )),
+        None => Ok(block),
+    }
+}
+
+fn bad_response(worker_url: &str, reason: impl Into<String>) -> BrokerError {
+    BrokerError::BadWorkerResponse { worker: worker_url.to_string(), reason: reason.into() }
+}
+
+// a request that got no response at all, either because it timed out or the worker couldn't be reached
+fn request_error(worker_url: &str, error: reqwest::Error) -> BrokerError {
+    let worker = worker_url.to_string();
+    if error.is_timeout() {
+        BrokerError::WorkerTimeout { worker, reason: error.to_string() }
+    } else if error.is_connect() {
+        BrokerError::WorkerUnavailable { worker: Some(worker), reason: error.to_string() }
+    } else {
+        BrokerError::BadWorkerResponse { worker, reason: error.to_string() }
+    }
+}
+
+// turns a worker's error response into the broker's own error: server errors may be
+// specific to that worker, client errors are about the tile itself
+fn worker_error(worker_url: &str, status: reqwest::StatusCode, body: &[u8]) -> AttemptError {
+    let worker = worker_url.to_string();
+    let body: Option<ErrorBody> = serde_json::from_slice(body).ok();
+    let message = body.as_ref().map_or_else(|| status.to_string(), |body| body.message.clone());
+
+    if status.is_server_error() {
+        let reason = format!("answered {}: {}", status, message);
+        return AttemptError::Retryable(if status == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            BrokerError::WorkerUnavailable { worker: Some(worker), reason }
+        } else {
+            BrokerError::BadWorkerResponse { worker, reason }
+        });
+    }
+    AttemptError::Fatal(match body {
+        // the worker found a result cell that overflowed, which is the client's to deal with
+        Some(body) if body.code == "overflow" => BrokerError::Overflow {
+            policy: serde_json::from_value::<OverflowPolicy>(body.details["policy"].clone()).unwrap_or_default(),
+            cell: serde_json::from_value(body.details["cell"].clone()).unwrap_or(None),
+        },
+        _ if status == reqwest::StatusCode::PAYLOAD_TOO_LARGE => BrokerError::PayloadTooLarge(format!(
+            "Worker {} refused a tile as too large, try smaller tiles: {}",
+            worker, message
+        )),
+        _ => BrokerError::BadWorkerResponse { worker, reason: format!("rejected the tile with {}: {}", status, message) },
+    })
+}
+
+// the gRPC counterpart of `worker_error`: codes a worker answers for a bad tile are
+// fatal, the rest mean the worker or the connection to it failed
+fn grpc_worker_error(worker_url: &str, status: tonic::Status) -> AttemptError {
+    let worker = worker_url.to_string();
+    let reason = format!("answered {:?}: {}", status.code(), status.message());
+    match status.code() {
+        tonic::Code::Unavailable => AttemptError::Retryable(BrokerError::WorkerUnavailable { worker: Some(worker), reason }),
+        // the channel's request timeout cancels the call
+        tonic::Code::DeadlineExceeded | tonic::Code::Cancelled => {
+            AttemptError::Retryable(BrokerError::WorkerTimeout { worker, reason })
+        }
+        tonic::Code::InvalidArgument
+        | tonic::Code::OutOfRange
+        | tonic::Code::FailedPrecondition
+        | tonic::Code::ResourceExhausted
+        | tonic::Code::Unimplemented
+        | tonic::Code::NotFound => {
+            let metadata = |key| status.metadata().get(key).and_then(|value| value.to_str().ok());
+            let details: serde_json::Value =
+                metadata("error-details").and_then(|details| serde_json::from_str(details).ok()).unwrap_or_default();
+            AttemptError::Fatal(match metadata("error-code") {
+                Some("overflow") => BrokerError::Overflow {
+                    policy: serde_json::from_value::<OverflowPolicy>(details["policy"].clone()).unwrap_or_default(),
+                    cell: serde_json::from_value(details["cell"].clone()).unwrap_or(None),
+                },
+                Some("payload_too_large") => BrokerError::PayloadTooLarge(format!(
+                    "Worker {} refused a tile as too large, try smaller tiles: {}",
+                    worker,
+                    status.message()
+                )),
+                _ => BrokerError::BadWorkerResponse { worker, reason: format!("rejected the tile, {}", reason) },
+            })
+        }
+        _ => AttemptError::Retryable(BrokerError::BadWorkerResponse { worker, reason }),
+    }
+}
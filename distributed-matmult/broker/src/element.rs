@@ -0,0 +1,343 @@
+use std::fmt::Debug;
+
+pub use matmult_common::element::{DType, OverflowPolicy};
+use matmult_common::matrix::{DenseMatrix, ShapeError};
+use matmult_common::sparse::{self, CsrMatrix, SparseError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::BrokerError;
+use crate::grpc::pb;
+use crate::types::{
+    BlockPayload, DotProductBatch, SparseBlockPayload, SparseParts, TypedBlockPayload, TypedDotProductBatch,
+    TypedSparseBlockPayload,
+};
+
+/// A value type the broker can multiply. Implemented for each `DType`.
+pub trait Element:
+    Copy + Default + PartialEq + Debug + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+    const DTYPE: DType;
+
+    /// Converts a JSON number from a client request, `None` if it does not fit.
+    fn from_json(value: &serde_json::Number) -> Option<Self>;
+
+    /// Tags a block payload with this type so the worker knows how to decode it.
+    fn tag_payload(payload: BlockPayload<Self>) -> TypedBlockPayload;
+
+    /// Tags a batch of dot products with this type, as `tag_payload` does for blocks.
+    fn tag_dot_products(batch: DotProductBatch<Self>) -> TypedDotProductBatch;
+
+    /// Tags a sparse block payload with this type, as `tag_payload` does for dense ones.
+    fn tag_sparse_payload(payload: SparseBlockPayload<Self>) -> TypedSparseBlockPayload;
+
+    /// Unpacks gRPC values, `None` if they are of another type.
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>>;
+
+    /// Packs values for a gRPC message.
+    fn into_values(values: Vec<Self>) -> pb::values::Kind;
+
+    /// Unpacks a worker's gRPC dot product, `None` if it is of another type.
+    fn from_scalar(value: pb::scalar::Kind) -> Option<Self>;
+
+    // the arithmetic results are verified in, see `verify`
+
+    /// Relative rounding error of one operation, 0 for the exact integer types.
+    const EPSILON: f64;
+    /// Largest magnitude the type holds.
+    const LIMIT: f64;
+
+    fn to_f64(self) -> f64;
+
+    /// Addition modulo 2^bits for integers, in which every overflow policy but
+    /// `saturating` is exact, and plain addition for floats.
+    fn wrapping_add(self, other: Self) -> Self;
+
+    /// Multiplication in the same arithmetic as `wrapping_add`.
+    fn wrapping_mul(self, other: Self) -> Self;
+}
+
+impl Element for i32 {
+    const DTYPE: DType = DType::I32;
+
+    fn from_json(value: &serde_json::Number) -> Option<Self> {
+        value.as_i64().and_then(|v| i32::try_from(v).ok())
+    }
+
+    fn tag_payload(payload: BlockPayload<Self>) -> TypedBlockPayload {
+        TypedBlockPayload::I32(payload)
+    }
+
+    fn tag_dot_products(batch: DotProductBatch<Self>) -> TypedDotProductBatch {
+        TypedDotProductBatch::I32(batch)
+    }
+
+    fn tag_sparse_payload(payload: SparseBlockPayload<Self>) -> TypedSparseBlockPayload {
+        TypedSparseBlockPayload::I32(payload)
+    }
+
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>> {
+        match values {
+            pb::values::Kind::I32(array) => Some(array.values),
+            _ => None,
+        }
+    }
+
+    fn into_values(values: Vec<Self>) -> pb::values::Kind {
+        pb::values::Kind::I32(pb::Int32Array { values })
+    }
+
+    fn from_scalar(value: pb::scalar::Kind) -> Option<Self> {
+        match value {
+            pb::scalar::Kind::I32(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    const EPSILON: f64 = 0.0;
+    const LIMIT: f64 = i32::MAX as f64;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        i32::wrapping_add(self, other)
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        i32::wrapping_mul(self, other)
+    }
+}
+
+impl Element for i64 {
+    const DTYPE: DType = DType::I64;
+
+    fn from_json(value: &serde_json::Number) -> Option<Self> {
+        value.as_i64()
+    }
+
+    fn tag_payload(payload: BlockPayload<Self>) -> TypedBlockPayload {
+        TypedBlockPayload::I64(payload)
+    }
+
+    fn tag_dot_products(batch: DotProductBatch<Self>) -> TypedDotProductBatch {
+        TypedDotProductBatch::I64(batch)
+    }
+
+    fn tag_sparse_payload(payload: SparseBlockPayload<Self>) -> TypedSparseBlockPayload {
+        TypedSparseBlockPayload::I64(payload)
+    }
+
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>> {
+        match values {
+            pb::values::Kind::I64(array) => Some(array.values),
+            _ => None,
+        }
+    }
+
+    fn into_values(values: Vec<Self>) -> pb::values::Kind {
+        pb::values::Kind::I64(pb::Int64Array { values })
+    }
+
+    fn from_scalar(value: pb::scalar::Kind) -> Option<Self> {
+        match value {
+            pb::scalar::Kind::I64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    const EPSILON: f64 = 0.0;
+    const LIMIT: f64 = i64::MAX as f64;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        i64::wrapping_add(self, other)
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        i64::wrapping_mul(self, other)
+    }
+}
+
+impl Element for f32 {
+    const DTYPE: DType = DType::F32;
+
+    fn from_json(value: &serde_json::Number) -> Option<Self> {
+        // JSON numbers are finite, so an infinite one is a value beyond f32's range
+        value.as_f64().map(|v| v as f32).filter(|v| v.is_finite())
+    }
+
+    fn tag_payload(payload: BlockPayload<Self>) -> TypedBlockPayload {
+        TypedBlockPayload::F32(payload)
+    }
+
+    fn tag_dot_products(batch: DotProductBatch<Self>) -> TypedDotProductBatch {
+        TypedDotProductBatch::F32(batch)
+    }
+
+    fn tag_sparse_payload(payload: SparseBlockPayload<Self>) -> TypedSparseBlockPayload {
+        TypedSparseBlockPayload::F32(payload)
+    }
+
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>> {
+        match values {
+            pb::values::Kind::F32(array) => Some(array.values),
+            _ => None,
+        }
+    }
+
+    fn into_values(values: Vec<Self>) -> pb::values::Kind {
+        pb::values::Kind::F32(pb::FloatArray { values })
+    }
+
+    fn from_scalar(value: pb::scalar::Kind) -> Option<Self> {
+        match value {
+            pb::scalar::Kind::F32(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    const EPSILON: f64 = f32::EPSILON as f64;
+    const LIMIT: f64 = f32::MAX as f64;
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        self * other
+    }
+}
+
+impl Element for f64 {
+    const DTYPE: DType = DType::F64;
+
+    fn from_json(value: &serde_json::Number) -> Option<Self> {
+        value.as_f64()
+    }
+
+    fn tag_payload(payload: BlockPayload<Self>) -> TypedBlockPayload {
+        TypedBlockPayload::F64(payload)
+    }
+
+    fn tag_dot_products(batch: DotProductBatch<Self>) -> TypedDotProductBatch {
+        TypedDotProductBatch::F64(batch)
+    }
+
+    fn tag_sparse_payload(payload: SparseBlockPayload<Self>) -> TypedSparseBlockPayload {
+        TypedSparseBlockPayload::F64(payload)
+    }
+
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>> {
+        match values {
+            pb::values::Kind::F64(array) => Some(array.values),
+            _ => None,
+        }
+    }
+
+    fn into_values(values: Vec<Self>) -> pb::values::Kind {
+        pb::values::Kind::F64(pb::DoubleArray { values })
+    }
+
+    fn from_scalar(value: pb::scalar::Kind) -> Option<Self> {
+        match value {
+            pb::scalar::Kind::F64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    const EPSILON: f64 = f64::EPSILON;
+    const LIMIT: f64 = f64::MAX;
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn wrapping_add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn wrapping_mul(self, other: Self) -> Self {
+        self * other
+    }
+}
+
+/// Converts a client's nested JSON rows into a dense matrix of `T`, naming the
+/// first value which does not fit the type or the first ragged row.
+pub fn dense_from_json<T: Element>(
+    matrix: &'static str,
+    nested: &[Vec<serde_json::Number>],
+) -> Result<DenseMatrix<T>, BrokerError> {
+    let mut rows = Vec::with_capacity(nested.len());
+    for (i, row) in nested.iter().enumerate() {
+        let converted = row
+            .iter()
+            .enumerate()
+            .map(|(j, value)| {
+                T::from_json(value).ok_or_else(|| BrokerError::InvalidValue {
+                    matrix,
+                    row: i,
+                    col: j,
+                    value: value.to_string(),
+                    dtype: T::DTYPE,
+                })
+            })
+            .collect::<Result<Vec<T>, BrokerError>>()?;
+        rows.push(converted);
+    }
+    DenseMatrix::from_rows(&rows).map_err(|e| match e {
+        ShapeError::Ragged { row, len, expected } => BrokerError::RaggedMatrix { matrix, row, len, expected },
+        other => BrokerError::Internal(other.to_string()),
+    })
+}
+
+/// Converts a client's sparse matrix into CSR form with values of `T`, naming
+/// the first value which does not fit the type or what is wrong with its parts.
+/// Cells given as zero are left out, like those not given at all.
+pub fn sparse_from_json<T: Element>(
+    matrix: &'static str,
+    parts: &SparseParts<serde_json::Number>,
+) -> Result<CsrMatrix<T>, BrokerError> {
+    let malformed = |e: SparseError| BrokerError::InvalidSparse { matrix, reason: e.to_string() };
+    let same_length = |indices: &'static str, len: usize, values: usize| {
+        if len == values { Ok(()) } else { Err(malformed(SparseError::IndexLength { indices, len, values })) }
+    };
+    let (rows, cols) = parts.shape();
+    let cells: Vec<(usize, usize, &serde_json::Number)> = match parts {
+        SparseParts::Csr { row_ptr, col_indices, values, .. } => {
+            sparse::check_row_ptr(rows, row_ptr, values.len()).map_err(malformed)?;
+            same_length("col_indices", col_indices.len(), values.len())?;
+            row_ptr
+                .windows(2)
+                .enumerate()
+                .flat_map(|(i, bounds)| (bounds[0]..bounds[1]).map(move |n| (i, col_indices[n], &values[n])))
+                .collect()
+        }
+        SparseParts::Coo { row_indices, col_indices, values, .. } => {
+            same_length("row_indices", row_indices.len(), values.len())?;
+            same_length("col_indices", col_indices.len(), values.len())?;
+            row_indices.iter().zip(col_indices).zip(values).map(|((&i, &j), value)| (i, j, value)).collect()
+        }
+    };
+    let mut triplets = Vec::with_capacity(cells.len());
+    for (row, col, value) in cells {
+        let converted = T::from_json(value).ok_or_else(|| BrokerError::InvalidValue {
+            matrix,
+            row,
+            col,
+            value: value.to_string(),
+            dtype: T::DTYPE,
+        })?;
+        triplets.push((row, col, converted));
+    }
+    // the zeros go after the checks, so a zero out of place is still caught
+    Ok(CsrMatrix::from_triplets(rows, cols, triplets).map_err(malformed)?.without_zeros())
+}
@@ -0,0 +1,287 @@
+use std::fmt;
+use std::ops::Range;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tonic::metadata::MetadataValue;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+
+use crate::element::{DType, OverflowPolicy};
+
+/// Everything that can go wrong handling a broker request. Each variant has a
+/// stable `code` and HTTP status, so clients can tell "fix your input" (4xx)
+/// from "retry later" (5xx) without parsing messages.
+#[derive(Debug, Clone)]
+pub enum BrokerError {
+    /// The request is malformed, or a setting in it makes no sense.
+    InvalidRequest(String),
+    /// A matrix has no rows.
+    EmptyInput { matrix: &'static str },
+    /// A matrix has rows, but they have no columns.
+    NoColumns { matrix: &'static str, rows: usize },
+    /// A row of a matrix is a different length from the first row.
+    RaggedMatrix { matrix: &'static str, row: usize, len: usize, expected: usize },
+    /// A value does not fit the request's element type.
+    InvalidValue { matrix: &'static str, row: usize, col: usize, value: String, dtype: DType },
+    /// The parts of a matrix sent in CSR or COO form don't fit together.
+    InvalidSparse { matrix: &'static str, reason: String },
+    /// The left matrix's columns don't match the right matrix's rows.
+    InvalidShape { left: (usize, usize), right: (usize, usize) },
+    /// A result cell overflowed under the `checked` policy.
+    Overflow { policy: OverflowPolicy, cell: Option<(usize, usize)> },
+    /// A request body, or a tile built from it, is over the size limit.
+    PayloadTooLarge(String),
+    NotFound(String),
+    MethodNotAllowed,
+    /// No worker could be reached to do the work.
+    WorkerUnavailable { worker: Option<String>, reason: String },
+    /// A worker took too long to answer.
+    WorkerTimeout { worker: String, reason: String },
+    /// A worker answered with an error or with something the broker can't use.
+    BadWorkerResponse { worker: String, reason: String },
+    /// The result failed verification in these rows and columns, even after
+    /// the tiles holding them were recomputed on other workers.
+    VerificationFailed { rows: Vec<usize>, cols: Vec<usize> },
+    /// The workers computing a tile redundantly came back with answers none
+    /// of which a majority of them agree on. `answers` counts the workers
+    /// behind each different block.
+    NoMajority { rows: Range<usize>, cols: Range<usize>, replicas: usize, answers: Vec<usize> },
+    /// The broker as a whole has as much work as it can take.
+    Overloaded { reason: String, retry_after: Duration },
+    /// This client should slow down.
+    TooManyRequests { reason: String, retry_after: Duration },
+    /// Storing a matrix would take the broker over its limit.
+    StoreFull(String),
+    Internal(String),
+}
+
+impl warp::reject::Reject for BrokerError {}
+
+/// The JSON body of every error response, also kept with failed jobs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(default)]
+    pub details: serde_json::Value,
+}
+
+impl BrokerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BrokerError::InvalidRequest(_) => "invalid_request",
+            BrokerError::EmptyInput { .. } => "empty_input",
+            BrokerError::NoColumns { .. } => "no_columns",
+            BrokerError::RaggedMatrix { .. } => "ragged_matrix",
+            BrokerError::InvalidValue { .. } => "invalid_value",
+            BrokerError::InvalidSparse { .. } => "invalid_sparse_matrix",
+            BrokerError::InvalidShape { .. } => "invalid_shape",
+            BrokerError::Overflow { .. } => "overflow",
+            BrokerError::PayloadTooLarge(_) => "payload_too_large",
+            BrokerError::NotFound(_) => "not_found",
+            BrokerError::MethodNotAllowed => "method_not_allowed",
+            BrokerError::WorkerUnavailable { .. } => "worker_unavailable",
+            BrokerError::WorkerTimeout { .. } => "worker_timeout",
+            BrokerError::BadWorkerResponse { .. } => "bad_worker_response",
+            BrokerError::VerificationFailed { .. } => "verification_failed",
+            BrokerError::NoMajority { .. } => "no_majority",
+            BrokerError::Overloaded { .. } => "overloaded",
+            BrokerError::TooManyRequests { .. } => "too_many_requests",
+            BrokerError::StoreFull(_) => "store_full",
+            BrokerError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BrokerError::InvalidRequest(_)
+            | BrokerError::EmptyInput { .. }
+            | BrokerError::NoColumns { .. }
+            | BrokerError::RaggedMatrix { .. }
+            | BrokerError::InvalidValue { .. }
+            | BrokerError::InvalidSparse { .. }
+            | BrokerError::InvalidShape { .. } => StatusCode::BAD_REQUEST,
+            BrokerError::Overflow { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            // a matrix the store has no room left for is too large for it, whatever its size
+            BrokerError::PayloadTooLarge(_) | BrokerError::StoreFull(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            BrokerError::NotFound(_) => StatusCode::NOT_FOUND,
+            BrokerError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            BrokerError::WorkerUnavailable { .. } | BrokerError::Overloaded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            BrokerError::WorkerTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            BrokerError::BadWorkerResponse { .. }
+            | BrokerError::VerificationFailed { .. }
+            | BrokerError::NoMajority { .. } => StatusCode::BAD_GATEWAY,
+            BrokerError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            BrokerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The fields of the error worth acting on, as JSON.
+    pub fn details(&self) -> serde_json::Value {
+        match self {
+            BrokerError::EmptyInput { matrix } => json!({ "matrix": matrix }),
+            BrokerError::NoColumns { matrix, rows } => json!({ "matrix": matrix, "rows": rows }),
+            BrokerError::RaggedMatrix { matrix, row, len, expected } => {
+                json!({ "matrix": matrix, "row": row, "len": len, "expected": expected })
+            }
+            BrokerError::InvalidValue { matrix, row, col, value, dtype } => {
+                json!({ "matrix": matrix, "row": row, "col": col, "value": value, "dtype": dtype })
+            }
+            BrokerError::InvalidSparse { matrix, .. } => json!({ "matrix": matrix }),
+            BrokerError::InvalidShape { left, right } => json!({ "left": left, "right": right }),
+            BrokerError::Overflow { policy, cell } => json!({ "policy": policy, "cell": cell }),
+            BrokerError::WorkerUnavailable { worker, .. } => json!({ "worker": worker }),
+            BrokerError::WorkerTimeout { worker, .. } | BrokerError::BadWorkerResponse { worker, .. } => {
+                json!({ "worker": worker })
+            }
+            BrokerError::VerificationFailed { rows, cols } => json!({ "rows": rows, "cols": cols }),
+            BrokerError::NoMajority { rows, cols, replicas, answers } => {
+                json!({ "rows": rows, "cols": cols, "replicas": replicas, "answers": answers })
+            }
+            BrokerError::Overloaded { retry_after, .. } | BrokerError::TooManyRequests { retry_after, .. } => {
+                json!({ "retry_after_secs": retry_after.as_secs() })
+            }
+            BrokerError::InvalidRequest(_)
+            | BrokerError::PayloadTooLarge(_)
+            | BrokerError::NotFound(_)
+            | BrokerError::MethodNotAllowed
+            | BrokerError::StoreFull(_)
+            | BrokerError::Internal(_) => json!({}),
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody { code: self.code().to_string(), message: self.to_string(), details: self.details() }
+    }
+
+    /// Rewrites the reason of a worker error, leaving other errors alone.
+    pub fn map_reason(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            BrokerError::WorkerUnavailable { worker, reason } => BrokerError::WorkerUnavailable { worker, reason: f(reason) },
+            BrokerError::WorkerTimeout { worker, reason } => BrokerError::WorkerTimeout { worker, reason: f(reason) },
+            BrokerError::BadWorkerResponse { worker, reason } => BrokerError::BadWorkerResponse { worker, reason: f(reason) },
+            other => other,
+        }
+    }
+
+    /// Prefixes the reason of a worker error with the tile it happened on.
+    pub fn in_tile(self, rows: &Range<usize>, cols: &Range<usize>) -> Self {
+        self.map_reason(|reason| format!("tile rows {:?}, cols {:?}: {}", rows, cols, reason))
+    }
+
+    /// The error as an HTTP response, with `Retry-After` when the client is
+    /// being asked to come back later.
+    pub fn into_response(self) -> Response {
+        let retry_after = match &self {
+            BrokerError::Overloaded { retry_after, .. } | BrokerError::TooManyRequests { retry_after, .. } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        };
+        let reply = warp::reply::with_status(warp::reply::json(&self.body()), self.status());
+        match retry_after {
+            Some(after) => warp::reply::with_header(reply, "Retry-After", after.as_secs().to_string()).into_response(),
+            None => reply.into_response(),
+        }
+    }
+
+    /// The error as a gRPC status: the code closest to the HTTP status, with
+    /// `code`, `details` and any `Retry-After` in the `error-code`,
+    /// `error-details` and `retry-after` metadata.
+    pub fn into_status(self) -> tonic::Status {
+        let mut status = tonic::Status::new(grpc_code(self.status()), self.to_string());
+        let metadata = status.metadata_mut();
+        metadata.insert("error-code", MetadataValue::from_static(self.code()));
+        if let Ok(details) = MetadataValue::try_from(self.details().to_string()) {
+            metadata.insert("error-details", details);
+        }
+        if let BrokerError::Overloaded { retry_after, .. } | BrokerError::TooManyRequests { retry_after, .. } = &self {
+            metadata.insert("retry-after", MetadataValue::from(retry_after.as_secs()));
+        }
+        status
+    }
+}
+
+// the gRPC status code closest to an HTTP status
+fn grpc_code(status: StatusCode) -> tonic::Code {
+    match status {
+        StatusCode::BAD_REQUEST => tonic::Code::InvalidArgument,
+        StatusCode::NOT_FOUND => tonic::Code::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => tonic::Code::Unimplemented,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => tonic::Code::ResourceExhausted,
+        StatusCode::UNPROCESSABLE_ENTITY => tonic::Code::OutOfRange,
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::BAD_GATEWAY => tonic::Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => tonic::Code::DeadlineExceeded,
+        _ => tonic::Code::Internal,
+    }
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrokerError::InvalidRequest(reason) => f.write_str(reason),
+            BrokerError::EmptyInput { matrix } => write!(f, "The {} matrix is empty", matrix),
+            BrokerError::NoColumns { matrix, rows } => {
+                write!(f, "The {} matrix has {} rows but no columns", matrix, rows)
+            }
+            BrokerError::RaggedMatrix { matrix, row, len, expected } => write!(
+                f,
+                "Row {} of the {} matrix has {} columns but row 0 has {}",
+                row, matrix, len, expected
+            ),
+            BrokerError::InvalidValue { matrix, row, col, value, dtype } => write!(
+                f,
+                "Value {} at ({}, {}) of the {} matrix is not a valid {}",
+                value, row, col, matrix, dtype.as_str()
+            ),
+            BrokerError::InvalidSparse { matrix, reason } => write!(f, "The sparse {} matrix is malformed: {}", matrix, reason),
+            BrokerError::InvalidShape { left, right } => write!(
+                f,
+                "Unable to multiply a {}x{} matrix by a {}x{} matrix, the number of columns on the left \
+                 should equal the number of rows on the right",
+                left.0, left.1, right.0, right.1
+            ),
+            BrokerError::Overflow { policy, cell: Some((row, col)) } => write!(
+                f,
+                "Arithmetic overflow computing result cell ({}, {}) under the {} overflow policy",
+                row, col, policy.as_str()
+            ),
+            BrokerError::Overflow { policy, cell: None } => {
+                write!(f, "Arithmetic overflow under the {} overflow policy", policy.as_str())
+            }
+            BrokerError::PayloadTooLarge(reason) | BrokerError::StoreFull(reason) => f.write_str(reason),
+            BrokerError::NotFound(reason) => f.write_str(reason),
+            BrokerError::MethodNotAllowed => f.write_str("Method not allowed"),
+            BrokerError::WorkerUnavailable { worker: Some(worker), reason } => {
+                write!(f, "Worker {} is unavailable: {}", worker, reason)
+            }
+            BrokerError::WorkerUnavailable { worker: None, reason } => f.write_str(reason),
+            BrokerError::WorkerTimeout { worker, reason } => write!(f, "Worker {} timed out: {}", worker, reason),
+            BrokerError::BadWorkerResponse { worker, reason } => {
+                write!(f, "Worker {} failed: {}", worker, reason)
+            }
+            BrokerError::VerificationFailed { rows, cols } => write!(
+                f,
+                "The result failed verification in {} rows and {} columns, even after recomputing them on other workers",
+                rows.len(),
+                cols.len()
+            ),
+            BrokerError::NoMajority { rows, cols, replicas, answers } => write!(
+                f,
+                "The {} workers computing tile rows {:?}, cols {:?} gave {} different answers, none of them from a majority",
+                replicas,
+                rows,
+                cols,
+                answers.len()
+            ),
+            BrokerError::Overloaded { reason, .. } | BrokerError::TooManyRequests { reason, .. } => {
+                f.write_str(reason)
+            }
+            BrokerError::Internal(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BrokerError {}
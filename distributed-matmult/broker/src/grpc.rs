@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Stream, stream};
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use matmult_common::trace;
+use prost::Message;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::Instrument;
+
+use crate::context::BrokerContext;
+use crate::dispatch::{Strategy, TilePayload};
+use crate::element::{DType, Element, OverflowPolicy};
+use crate::error::BrokerError;
+use crate::types::{
+    BlockPayload, DotProductBatch, DotProductBatchResponse, DotProductResult, RequestedOptions, SparseBlockPayload,
+    TypedBlockPayload, TypedDotProductBatch, TypedSparseBlockPayload, check_size,
+};
+
+pub mod pb {
+    tonic::include_proto!("matmult");
+}
+
+// the most matrix data put in one message, blocks bigger than this are streamed
+// to workers and results bigger than this are streamed back in parts, which
+// keeps every message well under gRPC's default 4 MiB limit
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+/// The `format` label of the byte counters for tiles sent over gRPC.
+pub const PROTOBUF: &str = "protobuf";
+
+type ReplyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The `Broker` gRPC service, multiplying matrices across the workers as
+/// `POST /multiply_matrices_distributed` does.
+pub struct BrokerService {
+    context: Arc<BrokerContext>,
+}
+
+impl BrokerService {
+    pub fn new(context: Arc<BrokerContext>) -> Self {
+        BrokerService { context }
+    }
+
+    // runs one call under a request span, continuing the client's trace if it
+    // sent a traceparent, and counts it as the HTTP routes are
+    async fn serve<R, F>(&self, method: &str, traceparent: Option<String>, call: F) -> Result<R, Status>
+    where
+        F: Future<Output = Result<R, BrokerError>>,
+    {
+        let route = format!("/matmult.Broker/{}", method);
+        let span = trace::request_span("POST", &route, traceparent.as_deref());
+        let started = Instant::now();
+        let outcome = call.instrument(span.clone()).await;
+        let _entered = span.enter();
+        let metrics = &self.context.metrics;
+        match outcome {
+            Ok(reply) => {
+                metrics.observe_request(&route, 200, started.elapsed());
+                Ok(reply)
+            }
+            Err(error) => {
+                metrics.observe_request(&route, error.status().as_u16(), started.elapsed());
+                metrics.observe_error(error.code());
+                if error.status().is_server_error() {
+                    tracing::error!(code = error.code(), "{}", error);
+                }
+                Err(error.into_status())
+            }
+        }
+    }
+
+    async fn multiply<T: Element>(
+        &self,
+        left: DenseMatrix<T>,
+        right: DenseMatrix<T>,
+        overflow: OverflowPolicy,
+        requested: RequestedOptions,
+    ) -> Result<DenseMatrix<T>, BrokerError> {
+        if left.cols() != right.rows() {
+            return Err(BrokerError::InvalidShape { left: left.shape(), right: right.shape() });
+        }
+        check_size(left.shape(), right.shape(), self.context.config.max_result_cells)?;
+        let options = crate::job_options(requested, overflow, right.cols(), false, &self.context.config)?;
+        crate::multiply_now(&crate::Factors::Dense(left, right), options, Arc::clone(&self.context)).await
+    }
+
+    async fn multiply_matrices<T: Element>(&self, request: pb::MultiplyRequest) -> Result<pb::MultiplyReply, BrokerError> {
+        let overflow = overflow_policy(request.overflow());
+        let left = dense::<T>("left", request.left)?;
+        let right = dense::<T>("right", request.right)?;
+        let result = self.multiply(left, right, overflow, requested_options(request.options)).await?;
+        Ok(pb::MultiplyReply { result: Some(to_proto(result)) })
+    }
+
+    async fn multiply_parts<T: Element>(
+        &self,
+        header: pb::MultiplyHeader,
+        left: Vec<pb::Matrix>,
+        right: Vec<pb::Matrix>,
+    ) -> Result<Vec<pb::Matrix>, BrokerError> {
+        let overflow = overflow_policy(header.overflow());
+        let left = concat_rows::<T>("left", left)?;
+        let right = concat_rows::<T>("right", right)?;
+        let result = self.multiply(left, right, overflow, requested_options(header.options)).await?;
+        Ok(row_chunks(&result).into_iter().map(to_proto).collect())
+    }
+}
+
+#[tonic::async_trait]
+impl pb::broker_server::Broker for BrokerService {
+    async fn multiply(&self, request: Request<pb::MultiplyRequest>) -> Result<Response<pb::MultiplyReply>, Status> {
+        let traceparent = traceparent(&request);
+        let request = request.into_inner();
+        let reply = self
+            .serve("Multiply", traceparent, async {
+                // the left matrix's values decide the element type of the whole request
+                match dtype("left", request.left.as_ref().and_then(|left| left.values.as_ref()))? {
+                    DType::I32 => self.multiply_matrices::<i32>(request).await,
+                    DType::I64 => self.multiply_matrices::<i64>(request).await,
+                    DType::F32 => self.multiply_matrices::<f32>(request).await,
+                    DType::F64 => self.multiply_matrices::<f64>(request).await,
+                }
+            })
+            .await?;
+        Ok(Response::new(reply))
+    }
+
+    type MultiplyStreamStream = ReplyStream<pb::Matrix>;
+
+    async fn multiply_stream(
+        &self,
+        request: Request<Streaming<pb::MultiplyPart>>,
+    ) -> Result<Response<Self::MultiplyStreamStream>, Status> {
+        let traceparent = traceparent(&request);
+        let mut parts = request.into_inner();
+        let chunks = self
+            .serve("MultiplyStream", traceparent, async {
+                let mut header = None;
+                let (mut left, mut right) = (Vec::new(), Vec::new());
+                // neither matrix may hold more cells than the product may, checked as
+                // they arrive so a stream without end is cut off rather than kept
+                let max_cells = self.context.config.max_result_cells;
+                let (mut left_cells, mut right_cells) = (0, 0);
+                while let Some(part) = parts.message().await.map_err(stream_error)? {
+                    match part.part {
+                        Some(pb::multiply_part::Part::Header(part)) if header.is_none() => header = Some(part),
+                        Some(pb::multiply_part::Part::Header(_)) => {
+                            return Err(BrokerError::InvalidRequest("The stream has more than one header".to_string()));
+                        }
+                        Some(pb::multiply_part::Part::Left(rows)) => {
+                            left_cells = count_cells("left", left_cells, &rows, max_cells)?;
+                            left.push(rows);
+                        }
+                        Some(pb::multiply_part::Part::Right(rows)) => {
+                            right_cells = count_cells("right", right_cells, &rows, max_cells)?;
+                            right.push(rows);
+                        }
+                        None => {}
+                    }
+                }
+                let header = header.ok_or_else(|| {
+                    BrokerError::InvalidRequest("The stream must start with a header".to_string())
+                })?;
+                match dtype("left", left.first().and_then(|rows| rows.values.as_ref()))? {
+                    DType::I32 => self.multiply_parts::<i32>(header, left, right).await,
+                    DType::I64 => self.multiply_parts::<i64>(header, left, right).await,
+                    DType::F32 => self.multiply_parts::<f32>(header, left, right).await,
+                    DType::F64 => self.multiply_parts::<f64>(header, left, right).await,
+                }
+            })
+            .await?;
+        Ok(Response::new(Box::pin(stream::iter(chunks.into_iter().map(Ok)))))
+    }
+}
+
+/// gRPC channels to the workers that take their tiles over gRPC, one per
+/// worker and reused by every tile, as the HTTP client's connections are.
+pub struct WorkerChannels {
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+impl WorkerChannels {
+    pub fn new(connect_timeout: Duration, request_timeout: Duration) -> Self {
+        WorkerChannels { connect_timeout, request_timeout, channels: Mutex::new(HashMap::new()) }
+    }
+
+    /// The channel to `grpc_url`, which connects on first use.
+    pub fn get(&self, grpc_url: &str) -> Result<Channel, String> {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(channel) = channels.get(grpc_url) {
+            return Ok(channel.clone());
+        }
+        let channel = Endpoint::from_shared(grpc_url.to_string())
+            .map_err(|e| format!("invalid gRPC URL {:?}: {}", grpc_url, e))?
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .connect_lazy();
+        channels.insert(grpc_url.to_string(), channel.clone());
+        Ok(channel)
+    }
+}
+
+/// A tile as the messages of the worker RPC which computes it.
+#[derive(Clone)]
+pub enum TileMessages {
+    Block(pb::BlockRequest),
+    /// A block too big for one message, for `ComputeBlockStream`.
+    BlockParts(Vec<pb::BlockPart>),
+    DotProducts(Vec<pb::DotProductRequest>),
+    SparseBlock(pb::SparseBlockRequest),
+}
+
+impl TileMessages {
+    pub fn new(payload: &TilePayload) -> Self {
+        match payload {
+            TilePayload::Block(TypedBlockPayload::I32(payload)) => block_messages(payload),
+            TilePayload::Block(TypedBlockPayload::I64(payload)) => block_messages(payload),
+            TilePayload::Block(TypedBlockPayload::F32(payload)) => block_messages(payload),
+            TilePayload::Block(TypedBlockPayload::F64(payload)) => block_messages(payload),
+            TilePayload::DotProducts(TypedDotProductBatch::I32(batch)) => dot_product_messages(batch),
+            TilePayload::DotProducts(TypedDotProductBatch::I64(batch)) => dot_product_messages(batch),
+            TilePayload::DotProducts(TypedDotProductBatch::F32(batch)) => dot_product_messages(batch),
+            TilePayload::DotProducts(TypedDotProductBatch::F64(batch)) => dot_product_messages(batch),
+            TilePayload::SparseBlock(TypedSparseBlockPayload::I32(payload)) => sparse_block_messages(payload),
+            TilePayload::SparseBlock(TypedSparseBlockPayload::I64(payload)) => sparse_block_messages(payload),
+            TilePayload::SparseBlock(TypedSparseBlockPayload::F32(payload)) => sparse_block_messages(payload),
+            TilePayload::SparseBlock(TypedSparseBlockPayload::F64(payload)) => sparse_block_messages(payload),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            TileMessages::Block(request) => request.encoded_len(),
+            TileMessages::BlockParts(parts) => parts.iter().map(Message::encoded_len).sum(),
+            TileMessages::DotProducts(requests) => requests.iter().map(Message::encoded_len).sum(),
+            TileMessages::SparseBlock(request) => request.encoded_len(),
+        }
+    }
+
+}
+
+/// Why a gRPC tile request failed.
+pub enum TileCallError {
+    /// The call itself failed, or the worker answered with an error.
+    Status(Status),
+    /// The worker answered with something that isn't the tile.
+    BadReply(String),
+}
+
+/// Has the worker behind `channel` compute the tile covering `rows` x `cols`,
+/// returning the block and the encoded bytes of the reply.
+pub async fn compute_tile<T: Element>(
+    channel: Channel,
+    messages: TileMessages,
+    traceparent: Option<String>,
+    rows: &Range<usize>,
+    cols: &Range<usize>,
+) -> Result<(DenseMatrix<T>, usize), TileCallError> {
+    // worker replies are sized by the broker's own tiles, so don't cap them
+    let mut client = pb::worker_client::WorkerClient::new(channel).max_decoding_message_size(usize::MAX);
+    let traceparent = traceparent.as_deref();
+
+    let reply = match messages {
+        TileMessages::Block(message) => client.compute_block(traced(message, traceparent)).await,
+        TileMessages::BlockParts(parts) => client.compute_block_stream(traced(stream::iter(parts), traceparent)).await,
+        TileMessages::DotProducts(requests) => {
+            let mut replies = client
+                .dot_product_stream(traced(stream::iter(requests), traceparent))
+                .await
+                .map_err(TileCallError::Status)?
+                .into_inner();
+            let mut received = 0;
+            let mut results = Vec::new();
+            while let Some(reply) = replies.message().await.map_err(TileCallError::Status)? {
+                received += reply.encoded_len();
+                let result = reply.result.and_then(|result| result.kind).and_then(T::from_scalar).ok_or_else(|| {
+                    TileCallError::BadReply(format!("no {} result for cell ({}, {})", T::DTYPE.as_str(), reply.row_id, reply.col_id))
+                })?;
+                results.push(DotProductResult { row_id: reply.row_id as usize, col_id: reply.col_id as usize, result });
+            }
+            let block = crate::dispatch::assemble_dot_products(DotProductBatchResponse { results }, rows, cols)
+                .map_err(TileCallError::BadReply)?;
+            return Ok((block, received));
+        }
+        // a sparse tile comes back with just its non-zero cells
+        TileMessages::SparseBlock(message) => {
+            let reply = client.compute_sparse_block(traced(message, traceparent)).await;
+            let reply = reply.map_err(TileCallError::Status)?.into_inner();
+            let received = reply.encoded_len();
+            let block = sparse::<T>("result", reply.result).map_err(|e| TileCallError::BadReply(e.to_string()))?;
+            let block = crate::dispatch::sparse_tile(block, rows, cols).map_err(TileCallError::BadReply)?;
+            return Ok((block, received));
+        }
+    };
+    let reply = reply.map_err(TileCallError::Status)?.into_inner();
+    let received = reply.encoded_len();
+    let block = dense::<T>("result", reply.result).map_err(|e| TileCallError::BadReply(e.to_string()))?;
+    Ok((block, received))
+}
+
+// a request carrying the job's trace context, so the worker's spans for the tile
+// join the job's trace
+fn traced<M>(message: M, traceparent: Option<&str>) -> Request<M> {
+    let mut request = Request::new(message);
+    if let Some(value) = traceparent.and_then(|value| value.parse().ok()) {
+        request.metadata_mut().insert("traceparent", value);
+    }
+    request
+}
+
+// a tile's block payload as one `ComputeBlock` request, or as the parts of a
+// `ComputeBlockStream` if it is too big for one message
+fn block_messages<T: Element>(payload: &BlockPayload<T>) -> TileMessages {
+    let (row_offset, col_offset) = (payload.row_offset as u64, payload.col_offset as u64);
+    let overflow = proto_overflow_policy(payload.overflow);
+    let values = payload.left.as_slice().len() + payload.right.as_slice().len();
+    if values * size_of::<T>() <= CHUNK_BYTES {
+        return TileMessages::Block(pb::BlockRequest {
+            row_offset,
+            col_offset,
+            overflow: overflow as i32,
+            left: Some(to_proto(DenseMatrix::clone(&payload.left))),
+            right: Some(to_proto(DenseMatrix::clone(&payload.right))),
+        });
+    }
+    let header = pb::BlockHeader { row_offset, col_offset, overflow: overflow as i32 };
+    let mut parts = vec![pb::BlockPart { part: Some(pb::block_part::Part::Header(header)) }];
+    parts.extend(row_chunks(&payload.left).into_iter().map(|rows| pb::BlockPart {
+        part: Some(pb::block_part::Part::Left(to_proto(rows))),
+    }));
+    parts.extend(row_chunks(&payload.right).into_iter().map(|rows| pb::BlockPart {
+        part: Some(pb::block_part::Part::Right(to_proto(rows))),
+    }));
+    TileMessages::BlockParts(parts)
+}
+
+fn dot_product_messages<T: Element>(batch: &DotProductBatch<T>) -> TileMessages {
+    let overflow = proto_overflow_policy(batch.overflow) as i32;
+    TileMessages::DotProducts(
+        batch
+            .items
+            .iter()
+            .map(|item| pb::DotProductRequest {
+                row_id: item.row_id as u64,
+                col_id: item.col_id as u64,
+                overflow,
+                row: Some(pb::Values { kind: Some(T::into_values(item.row.clone())) }),
+                col: Some(pb::Values { kind: Some(T::into_values(item.col.clone())) }),
+            })
+            .collect(),
+    )
+}
+
+// a tile's sparse block payload as one `ComputeSparseBlock` request, which only
+// holds the cells that aren't zero so is never split up
+fn sparse_block_messages<T: Element>(payload: &SparseBlockPayload<T>) -> TileMessages {
+    TileMessages::SparseBlock(pb::SparseBlockRequest {
+        row_offset: payload.row_offset as u64,
+        col_offset: payload.col_offset as u64,
+        overflow: proto_overflow_policy(payload.overflow) as i32,
+        left: Some(sparse_to_proto(&payload.left)),
+        right: Some(sparse_to_proto(&payload.right)),
+    })
+}
+
+// a matrix split into runs of whole rows of at most `CHUNK_BYTES` each, and at
+// least one row
+fn row_chunks<T: Element>(matrix: &DenseMatrix<T>) -> Vec<DenseMatrix<T>> {
+    let rows_per_chunk = (CHUNK_BYTES / (matrix.cols() * size_of::<T>()).max(1)).max(1);
+    (0..matrix.rows())
+        .step_by(rows_per_chunk)
+        .map(|start| matrix.block(start..(start + rows_per_chunk).min(matrix.rows()), 0..matrix.cols()).to_matrix())
+        .collect()
+}
+
+// the caller's trace context, from the metadata as the HTTP routes read the header
+fn traceparent<M>(request: &Request<M>) -> Option<String> {
+    request.metadata().get("traceparent").and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+// a stream which broke off part way, the client went away or sent something unreadable
+fn stream_error(status: Status) -> BrokerError {
+    BrokerError::InvalidRequest(format!("Request stream failed: {}", status.message()))
+}
+
+fn requested_options(options: Option<pb::JobOptions>) -> RequestedOptions {
+    let Some(options) = options else {
+        return RequestedOptions::default();
+    };
+    RequestedOptions {
+        tile_rows: options.tile_rows.map(|rows| rows as usize),
+        tile_cols: options.tile_cols.map(|cols| cols as usize),
+        wire_format: None,
+        retry_budget: options.retry_budget.map(|budget| budget as usize),
+        strategy: match options.strategy() {
+            pb::Strategy::Unspecified => None,
+            pb::Strategy::Blocks => Some(Strategy::Blocks),
+            pb::Strategy::DotProducts => Some(Strategy::DotProducts),
+        },
+        batch_size: options.batch_size.map(|size| size as usize),
+        verify_rounds: options.verify_rounds.map(|rounds| rounds as usize),
+        replicas: options.replicas.map(|replicas| replicas as usize),
+        hedge_percentile: options.hedge_percentile,
+        result_format: None,
+    }
+}
+
+/// The overflow policy of a gRPC message.
+pub fn overflow_policy(policy: pb::OverflowPolicy) -> OverflowPolicy {
+    match policy {
+        pb::OverflowPolicy::Saturating => OverflowPolicy::Saturating,
+        pb::OverflowPolicy::Wrapping => OverflowPolicy::Wrapping,
+        pb::OverflowPolicy::Checked => OverflowPolicy::Checked,
+        pb::OverflowPolicy::Widen => OverflowPolicy::Widen,
+    }
+}
+
+fn proto_overflow_policy(policy: OverflowPolicy) -> pb::OverflowPolicy {
+    match policy {
+        OverflowPolicy::Saturating => pb::OverflowPolicy::Saturating,
+        OverflowPolicy::Wrapping => pb::OverflowPolicy::Wrapping,
+        OverflowPolicy::Checked => pb::OverflowPolicy::Checked,
+        OverflowPolicy::Widen => pb::OverflowPolicy::Widen,
+    }
+}
+
+/// The element type of a request, from the values which decide it.
+pub fn dtype(matrix: &'static str, values: Option<&pb::Values>) -> Result<DType, BrokerError> {
+    match values.and_then(|values| values.kind.as_ref()) {
+        Some(pb::values::Kind::I32(_)) => Ok(DType::I32),
+        Some(pb::values::Kind::I64(_)) => Ok(DType::I64),
+        Some(pb::values::Kind::F32(_)) => Ok(DType::F32),
+        Some(pb::values::Kind::F64(_)) => Ok(DType::F64),
+        None => Err(BrokerError::EmptyInput { matrix }),
+    }
+}
+
+/// Unpacks a matrix of a request, which must be of type `T` and have values
+/// filling its rows and columns.
+pub fn dense<T: Element>(matrix: &'static str, message: Option<pb::Matrix>) -> Result<DenseMatrix<T>, BrokerError> {
+    let message = message.ok_or(BrokerError::EmptyInput { matrix })?;
+    let (rows, cols) = (message.rows as usize, message.cols as usize);
+    if rows == 0 {
+        return Err(BrokerError::EmptyInput { matrix });
+    }
+    if cols == 0 {
+        return Err(BrokerError::NoColumns { matrix, rows });
+    }
+    let data = match message.values.and_then(|values| values.kind) {
+        Some(kind) => T::from_values(kind).ok_or_else(|| {
+            BrokerError::InvalidRequest(format!(
+                "The {} matrix does not hold {} values like the rest of the request",
+                matrix,
+                T::DTYPE.as_str()
+            ))
+        })?,
+        None => Vec::new(),
+    };
+    DenseMatrix::new(rows, cols, data)
+        .map_err(|e| BrokerError::InvalidRequest(format!("The {} matrix is malformed: {}", matrix, e)))
+}
+
+/// Unpacks a sparse block a worker sent back, which must be of type `T` and well formed.
+pub fn sparse<T: Element>(matrix: &'static str, message: Option<pb::SparseMatrix>) -> Result<CsrMatrix<T>, BrokerError> {
+    let message = message.ok_or(BrokerError::EmptyInput { matrix })?;
+    let values = match message.values.and_then(|values| values.kind) {
+        Some(kind) => T::from_values(kind).ok_or_else(|| {
+            BrokerError::InvalidRequest(format!("The {} matrix does not hold {} values", matrix, T::DTYPE.as_str()))
+        })?,
+        None => Vec::new(),
+    };
+    CsrMatrix::new(
+        message.rows as usize,
+        message.cols as usize,
+        message.row_ptr.into_iter().map(|ptr| ptr as usize).collect(),
+        message.col_indices.into_iter().map(|col| col as usize).collect(),
+        values,
+    )
+    .map_err(|e| BrokerError::InvalidSparse { matrix, reason: e.to_string() })
+}
+
+// adds the values of a streamed run of rows to the cells of its matrix so far,
+// refusing the stream once they pass `max_cells`
+fn count_cells(matrix: &'static str, cells: usize, part: &pb::Matrix, max_cells: usize) -> Result<usize, BrokerError> {
+    let len = match part.values.as_ref().and_then(|values| values.kind.as_ref()) {
+        Some(pb::values::Kind::I32(array)) => array.values.len(),
+        Some(pb::values::Kind::I64(array)) => array.values.len(),
+        Some(pb::values::Kind::F32(array)) => array.values.len(),
+        Some(pb::values::Kind::F64(array)) => array.values.len(),
+        None => 0,
+    };
+    match cells.checked_add(len) {
+        Some(cells) if cells <= max_cells => Ok(cells),
+        _ => Err(BrokerError::PayloadTooLarge(format!(
+            "The streamed {} matrix holds more than the {} cells the broker computes",
+            matrix, max_cells
+        ))),
+    }
+}
+
+/// Stacks the runs of rows a matrix was streamed in.
+pub fn concat_rows<T: Element>(matrix: &'static str, parts: Vec<pb::Matrix>) -> Result<DenseMatrix<T>, BrokerError> {
+    let cols = parts.first().map_or(0, |part| part.cols as usize);
+    let mut rows = 0;
+    let mut data = Vec::new();
+    for part in parts {
+        if part.cols as usize != cols {
+            return Err(BrokerError::RaggedMatrix { matrix, row: rows, len: part.cols as usize, expected: cols });
+        }
+        let part = dense::<T>(matrix, Some(part))?;
+        rows += part.rows();
+        data.extend(part.into_data());
+    }
+    if rows == 0 {
+        return Err(BrokerError::EmptyInput { matrix });
+    }
+    DenseMatrix::new(rows, cols, data)
+        .map_err(|e| BrokerError::InvalidRequest(format!("The {} matrix is malformed: {}", matrix, e)))
+}
+
+/// Packs a matrix for a gRPC message.
+pub fn to_proto<T: Element>(matrix: DenseMatrix<T>) -> pb::Matrix {
+    let (rows, cols) = matrix.shape();
+    pb::Matrix {
+        rows: rows as u64,
+        cols: cols as u64,
+        values: Some(pb::Values { kind: Some(T::into_values(matrix.into_data())) }),
+    }
+}
+
+/// Packs a sparse matrix for a gRPC message.
+pub fn sparse_to_proto<T: Element>(matrix: &CsrMatrix<T>) -> pb::SparseMatrix {
+    pb::SparseMatrix {
+        rows: matrix.rows() as u64,
+        cols: matrix.cols() as u64,
+        row_ptr: matrix.row_ptr().iter().map(|&ptr| ptr as u64).collect(),
+        col_indices: matrix.col_indices().iter().map(|&col| col as u64).collect(),
+        values: Some(pb::Values { kind: Some(T::into_values(matrix.values().to_vec())) }),
+    }
+}
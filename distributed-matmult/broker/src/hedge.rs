@@ -0,0 +1,38 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Round trips of the most recent tiles workers answered, across every job,
+/// which tell how long a tile may be outstanding before it is hedged: sent to
+/// a second worker as well, whichever answers first being kept.
+pub struct LatencyWindow {
+    capacity: usize,
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl LatencyWindow {
+    /// A window of the last `capacity` round trips.
+    pub fn new(capacity: usize) -> Self {
+        LatencyWindow { capacity, samples: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(elapsed);
+    }
+
+    /// The round trip `percentile` percent of the recorded ones were at most,
+    /// `None` until there are `min_samples` of them to go by.
+    pub fn percentile(&self, percentile: f64, min_samples: usize) -> Option<Duration> {
+        let mut samples: Vec<Duration> = self.samples.lock().unwrap().iter().copied().collect();
+        if samples.is_empty() || samples.len() < min_samples {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = (percentile / 100.0 * samples.len() as f64).ceil() as usize;
+        Some(samples[rank.clamp(1, samples.len()) - 1])
+    }
+}
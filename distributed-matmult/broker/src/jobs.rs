@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::element::{DType, OverflowPolicy};
+use crate::error::{BrokerError, ErrorBody};
+
+// events buffered for each subscriber before a slow one starts missing them
+const EVENT_BUFFER: usize = 1024;
+
+/// Something that happened to a job, as streamed by `GET /jobs/{id}/events`.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// A tile was sent to a worker, `attempt` counts from 1.
+    TileAssigned { rows: Range<usize>, cols: Range<usize>, worker: String, attempt: u32 },
+    /// A tile has been outstanding for longer than most, so it was sent to
+    /// another worker as well.
+    TileHedged { rows: Range<usize>, cols: Range<usize>, after_ms: u128 },
+    /// A worker failed a tile, which will be retried elsewhere.
+    TileRetry { rows: Range<usize>, cols: Range<usize>, worker: String, error: ErrorBody },
+    /// A tile is finished, `block` holds its cells.
+    TileDone { rows: Range<usize>, cols: Range<usize>, worker: String, block: serde_json::Value },
+    /// A tile failed for good, so the job will fail too.
+    TileFailed { rows: Range<usize>, cols: Range<usize>, worker: String, error: ErrorBody },
+    Done { result: Arc<serde_json::Value> },
+    Failed { error: ErrorBody },
+}
+
+impl JobEvent {
+    /// The SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobEvent::TileAssigned { .. } => "tile_assigned",
+            JobEvent::TileHedged { .. } => "tile_hedged",
+            JobEvent::TileRetry { .. } => "tile_retry",
+            JobEvent::TileDone { .. } => "tile_done",
+            JobEvent::TileFailed { .. } => "tile_failed",
+            JobEvent::Done { .. } => "done",
+            JobEvent::Failed { .. } => "failed",
+        }
+    }
+
+    /// Whether this is the last event the job sends.
+    pub fn is_final(&self) -> bool {
+        matches!(self, JobEvent::Done { .. } | JobEvent::Failed { .. })
+    }
+}
+
+/// How far through its tiles a job is, and the events announcing each step.
+/// Shared with `distribute_mat_mult` and the dispatcher, which fill it in as
+/// tiles are assigned and complete.
+pub struct JobProgress {
+    tiles_total: AtomicUsize,
+    tiles_done: AtomicUsize,
+    events: broadcast::Sender<JobEvent>,
+}
+
+impl Default for JobProgress {
+    fn default() -> Self {
+        JobProgress {
+            tiles_total: AtomicUsize::new(0),
+            tiles_done: AtomicUsize::new(0),
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct ProgressCounts {
+    pub tiles_total: usize,
+    pub tiles_done: usize,
+}
+
+impl JobProgress {
+    pub fn set_total(&self, tiles: usize) {
+        self.tiles_total.store(tiles, Ordering::Relaxed);
+    }
+
+    /// Adds tiles to run on top of the total, for tiles computed again.
+    pub fn add_tiles(&self, tiles: usize) {
+        self.tiles_total.fetch_add(tiles, Ordering::Relaxed);
+    }
+
+    pub fn tile_done(&self) {
+        self.tiles_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sends an event to whoever is streaming this job, if anyone is.
+    pub fn emit(&self, event: JobEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Whether anyone is streaming this job's events, so callers can skip
+    /// building events that are expensive to make.
+    pub fn has_subscribers(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn counts(&self) -> ProgressCounts {
+        ProgressCounts {
+            tiles_total: self.tiles_total.load(Ordering::Relaxed),
+            tiles_done: self.tiles_done.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+enum JobState {
+    Queued,
+    Running,
+    // the result rows, already converted to JSON so the table doesn't need to be generic
+    Done(Arc<serde_json::Value>),
+    Failed(ErrorBody),
+}
+
+/// A multiplication submitted through `POST /jobs`.
+pub struct Job {
+    pub id: String,
+    pub dtype: DType,
+    pub overflow: OverflowPolicy,
+    pub progress: Arc<JobProgress>,
+    submitted_at: Instant,
+    state: RwLock<JobState>,
+    finished_at: RwLock<Option<Instant>>,
+}
+
+/// What `GET /jobs/{id}` reports about a job.
+#[derive(Serialize, Clone)]
+pub struct JobReport {
+    pub id: String,
+    pub status: JobStatus,
+    pub dtype: DType,
+    pub overflow: OverflowPolicy,
+    pub progress: ProgressCounts,
+    pub elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Arc<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl Job {
+    pub fn start(&self) {
+        *self.state.write().unwrap() = JobState::Running;
+    }
+
+    pub fn finish(&self, outcome: Result<serde_json::Value, BrokerError>) {
+        let (state, event) = match outcome {
+            Ok(result) => {
+                let result = Arc::new(result);
+                (JobState::Done(Arc::clone(&result)), JobEvent::Done { result })
+            }
+            Err(error) => {
+                let error = error.body();
+                (JobState::Failed(error.clone()), JobEvent::Failed { error })
+            }
+        };
+        *self.state.write().unwrap() = state;
+        *self.finished_at.write().unwrap() = Some(Instant::now());
+        self.progress.emit(event);
+    }
+
+    /// The event a finished job ended with, `None` while it is still queued or running.
+    pub fn final_event(&self) -> Option<JobEvent> {
+        match &*self.state.read().unwrap() {
+            JobState::Done(result) => Some(JobEvent::Done { result: Arc::clone(result) }),
+            JobState::Failed(error) => Some(JobEvent::Failed { error: error.clone() }),
+            JobState::Queued | JobState::Running => None,
+        }
+    }
+
+    pub fn report(&self) -> JobReport {
+        let state = self.state.read().unwrap();
+        let (status, result, error) = match &*state {
+            JobState::Queued => (JobStatus::Queued, None, None),
+            JobState::Running => (JobStatus::Running, None, None),
+            JobState::Done(result) => (JobStatus::Done, Some(Arc::clone(result)), None),
+            JobState::Failed(error) => (JobStatus::Failed, None, Some(error.clone())),
+        };
+        // a finished job's elapsed time stops at the moment it finished
+        let elapsed = match *self.finished_at.read().unwrap() {
+            Some(finished_at) => finished_at - self.submitted_at,
+            None => self.submitted_at.elapsed(),
+        };
+        JobReport {
+            id: self.id.clone(),
+            status,
+            dtype: self.dtype,
+            overflow: self.overflow,
+            progress: self.progress.counts(),
+            elapsed_ms: elapsed.as_millis(),
+            result,
+            error,
+        }
+    }
+}
+
+/// Every job the broker knows about, keyed by ID. Finished jobs are kept for
+/// `retention` so clients have time to come back for the result.
+pub struct JobTable {
+    retention: Duration,
+    jobs: RwLock<HashMap<String, Arc<Job>>>,
+}
+
+impl JobTable {
+    pub fn new(retention: Duration) -> Self {
+        JobTable { retention, jobs: RwLock::new(HashMap::new()) }
+    }
+
+    /// Adds a queued job with a fresh ID.
+    pub fn create(&self, dtype: DType, overflow: OverflowPolicy) -> Arc<Job> {
+        let job = Arc::new(Job {
+            id: Uuid::new_v4().to_string(),
+            dtype,
+            overflow,
+            progress: Arc::new(JobProgress::default()),
+            submitted_at: Instant::now(),
+            state: RwLock::new(JobState::Queued),
+            finished_at: RwLock::new(None),
+        });
+        self.jobs.write().unwrap().insert(job.id.clone(), Arc::clone(&job));
+        job
+    }
+
+    /// Jobs waiting for a running job slot.
+    pub fn queued(&self) -> usize {
+        let jobs = self.jobs.read().unwrap();
+        jobs.values()
+            .filter(|job| matches!(*job.state.read().unwrap(), JobState::Queued))
+            .count()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.read().unwrap().get(id).cloned()
+    }
+
+    /// Drops jobs that finished longer ago than the retention period, returning their IDs.
+    pub fn expire(&self) -> Vec<String> {
+        let mut jobs = self.jobs.write().unwrap();
+        let retention = self.retention;
+        let expired: Vec<String> = jobs
+            .values()
+            .filter(|job| job.finished_at.read().unwrap().is_some_and(|at| at.elapsed() > retention))
+            .map(|job| job.id.clone())
+            .collect();
+        for id in &expired {
+            jobs.remove(id);
+        }
+        expired
+    }
+}
@@ -1,242 +1,1017 @@
-use std::error::Error;
-use std::sync::Arc; // For sharing worker list and client across threads
-use std::sync::atomic::{AtomicUsize, Ordering}; // For round-robin load balancing
-
-use futures::future::join_all;
-use reqwest::Client;
+use std::convert::Infallible;
+use std::ops::Range;
+use std::sync::Arc; // For sharing the broker's state across threads
+use std::time::Duration;
+
+use futures::StreamExt;
+use futures::stream::{self, FuturesUnordered};
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use matmult_common::trace;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::task;
+use tracing::Instrument;
+use warp::sse::Event;
 use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
 
+mod config;
+mod context;
+mod dispatch;
+mod element;
+mod error;
+mod grpc;
+mod hedge;
+mod jobs;
+mod metrics;
+mod registry;
+mod scheduler;
+mod store;
 mod types;
+mod verify;
+mod wire;
+#[cfg(test)]
+mod tests;
+use config::BrokerConfig;
+use context::BrokerContext;
+use dispatch::{Dispatcher, RetryPolicy, Strategy, TilePayload};
+use element::{DType, Element, OverflowPolicy, dense_from_json, sparse_from_json};
+use error::BrokerError;
+use jobs::{Job, JobEvent, JobProgress};
+use store::UploadRequest;
 use types::{
-    AppError, DotProductPayload, DotProductResponse, MatMultRequest, Matrix,
+    BlockPayload, DotProductBatch, DotProductItem, JobOptions, MatMultRequest, MatMultResponse, MatrixInput, Operands,
+    RequestedOptions, SparseBlockPayload, WorkerAddress,
 };
 
-// local endpoints to send matrix multiplication dot products to:
-const WORKER_NODES: &[&str] = &[
-    "http://worker1:9001/calculate_dot_product",
-    "http://worker2:9002/calculate_dot_product",
-];
+// times a result which fails verification has its suspect tiles recomputed
+// before the job gives up on it
+const MAX_VERIFY_REPAIRS: usize = 2;
 
+/// Splits `0..len` into consecutive ranges of at most `tile_len` elements.
+fn tile_ranges(len: usize, tile_len: usize) -> Vec<Range<usize>> {
+    (0..len)
+        .step_by(tile_len)
+        .map(|start| start..(start + tile_len).min(len))
+        .collect()
+}
 
-async fn distribute_mat_mult(
-    left: &Matrix,
-    right: &Matrix,
-    http_client: Arc<Client>,
-    worker_urls: Arc<Vec<String>>,
-    next_worker_index: Arc<AtomicUsize>,
-) -> Result<Matrix, Box<dyn Error + Send + Sync>> { // Send and Sync traits are what allow this to be multithreaded
-    // ensure matrices are populated:
-    if left.is_empty() || right.is_empty() {
-        return Err("At least one of the given matrices was empty".into());
+/// The two matrices of a multiplication as `T`. When either was sent sparse
+/// both are multiplied as sparse ones, and only the tiles whose rows of the
+/// left matrix and columns of the right one hold values at a common index are
+/// computed, as every other tile of the product is zero.
+enum Factors<T> {
+    Dense(DenseMatrix<T>, DenseMatrix<T>),
+    Sparse(CsrMatrix<T>, CsrMatrix<T>),
+}
+
+impl<T: Element> Factors<T> {
+    /// Converts the request's matrices, rejecting ragged, malformed or out of range input.
+    fn from_operands(operands: &Operands) -> Result<Self, BrokerError> {
+        match (&*operands.left, &*operands.right) {
+            (MatrixInput::Dense(left), MatrixInput::Dense(right)) => {
+                Ok(Factors::Dense(dense_from_json("left", left)?, dense_from_json("right", right)?))
+            }
+            (left, right) => Ok(Factors::Sparse(sparse_factor("left", left)?, sparse_factor("right", right)?)),
+        }
+    }
+
+    fn shapes(&self) -> ((usize, usize), (usize, usize)) {
+        match self {
+            Factors::Dense(left, right) => (left.shape(), right.shape()),
+            Factors::Sparse(left, right) => (left.shape(), right.shape()),
+        }
+    }
+
+    fn verify(&self, product: &DenseMatrix<T>, overflow: OverflowPolicy, rounds: usize) -> verify::Verdict {
+        match self {
+            Factors::Dense(left, right) => verify::check(left, right, product, overflow, rounds),
+            Factors::Sparse(left, right) => verify::check(left, right, product, overflow, rounds),
+        }
+    }
+
+    fn verify_tile(
+        &self,
+        product: &DenseMatrix<T>,
+        rows: &Range<usize>,
+        cols: &Range<usize>,
+        overflow: OverflowPolicy,
+        rounds: usize,
+    ) -> bool {
+        match self {
+            Factors::Dense(left, right) => verify::check_tile(left, right, product, rows, cols, overflow, rounds),
+            Factors::Sparse(left, right) => verify::check_tile(left, right, product, rows, cols, overflow, rounds),
+        }
+    }
+}
+
+// a matrix of a sparse multiplication, dense input keeps just its non-zero cells
+fn sparse_factor<T: Element>(matrix: &'static str, input: &MatrixInput) -> Result<CsrMatrix<T>, BrokerError> {
+    match input {
+        MatrixInput::Dense(nested) => Ok(CsrMatrix::from_dense(&dense_from_json(matrix, nested)?)),
+        MatrixInput::Sparse(parts) => sparse_from_json(matrix, parts),
     }
+}
 
+// the blocks of rows of the left matrix and blocks of columns of the right one
+// which tiles are made of, copied once and shared by every tile using them
+enum SharedBlocks<T> {
+    Dense(Vec<Arc<DenseMatrix<T>>>, Vec<Arc<DenseMatrix<T>>>),
+    // the right matrix's columns are kept as rows of its transpose
+    Sparse(Vec<Arc<CsrMatrix<T>>>, Vec<Arc<CsrMatrix<T>>>),
+}
+
+/// The tiles `distribute_mat_mult` computes, each a block of rows by a block of
+/// columns of the product. Tiles of a sparse product with no term that isn't
+/// zero are left out.
+fn planned_tiles<T: Element>(factors: &Factors<T>, options: &JobOptions) -> Vec<(Range<usize>, Range<usize>)> {
+    let ((num_rows_left, _), (_, num_cols_right)) = factors.shapes();
+    let (tile_rows, tile_cols) = (options.tile_rows.max(1), options.tile_cols.max(1));
+    let row_tiles = tile_ranges(num_rows_left, tile_rows);
+    let col_tiles = tile_ranges(num_cols_right, tile_cols);
+    let Factors::Sparse(left, right) = factors else {
+        return row_tiles.iter().flat_map(|rows| col_tiles.iter().map(|cols| (rows.clone(), cols.clone()))).collect();
+    };
+
+    // the ascending inner indices each block of rows of the left matrix holds values at
+    let row_inner: Vec<Vec<usize>> = row_tiles
+        .iter()
+        .map(|rows| {
+            let mut inner: Vec<usize> = rows.clone().flat_map(|i| left.row(i).0.iter().copied()).collect();
+            inner.sort_unstable();
+            inner.dedup();
+            inner
+        })
+        .collect();
+    // and each block of columns of the right one, walking its rows in order keeps them ascending
+    let mut col_inner = vec![Vec::new(); col_tiles.len()];
+    for (k, j, _) in right.triplets() {
+        let inner: &mut Vec<usize> = &mut col_inner[j / tile_cols];
+        if inner.last() != Some(&k) {
+            inner.push(k);
+        }
+    }
+
+    let mut tiles = Vec::new();
+    for (rows, row_inner) in row_tiles.iter().zip(&row_inner) {
+        for (cols, col_inner) in col_tiles.iter().zip(&col_inner) {
+            if share_index(row_inner, col_inner) {
+                tiles.push((rows.clone(), cols.clone()));
+            }
+        }
+    }
+    tiles
+}
+
+// whether two ascending lists have a value in common
+fn share_index(a: &[usize], b: &[usize]) -> bool {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => return true,
+        }
+    }
+    false
+}
+
+async fn distribute_mat_mult<T: Element>(
+    factors: &Factors<T>,
+    options: JobOptions,
+    context: Arc<BrokerContext>,
+    progress: Arc<JobProgress>,
+) -> Result<DenseMatrix<T>, BrokerError> {
     // extract the dimensions of the matrices to ensure they are mathematically compatible:
-    let num_rows_left = left.len();
-    let num_cols_left = left[0].len();
-    let num_rows_right = right.len();
-    let num_cols_right = right[0].len();
+    let ((num_rows_left, num_cols_left), (num_rows_right, num_cols_right)) = factors.shapes();
+
+    // ensure matrices are populated:
+    if num_rows_left == 0 {
+        return Err(BrokerError::EmptyInput { matrix: "left" });
+    }
+    if num_rows_right == 0 {
+        return Err(BrokerError::EmptyInput { matrix: "right" });
+    }
 
     // check matrices are mathematically compatitible:
     if num_cols_left != num_rows_right {
-        return Err(format!(
-            "Unable multiply {}x{} matrix by a {}x{} matrix, number of columns on left should
-            equal the number of rows on the right",
-            num_rows_left, num_cols_left, num_rows_right, num_cols_right
-        )
-        .into());
+        return Err(BrokerError::InvalidShape {
+            left: (num_rows_left, num_cols_left),
+            right: (num_rows_right, num_cols_right),
+        });
+    }
+
+    let JobOptions {
+        tile_rows,
+        tile_cols,
+        wire_format,
+        overflow,
+        retry_budget,
+        strategy,
+        verify_rounds,
+        replicas,
+        hedge_percentile,
+        result_format: _,
+    } = options;
+
+    // a tile must cover at least one row and one column of the result:
+    if tile_rows == 0 || tile_cols == 0 {
+        return Err(BrokerError::InvalidRequest(format!(
+            "Tile dimensions must be positive, got {}x{}",
+            tile_rows, tile_cols
+        )));
     }
 
     // init result matrix and populate w all 0s:
-    let mut result = vec![vec![0; num_cols_right]; num_rows_left];
+    let mut result = DenseMatrix::zeros(num_rows_left, num_cols_right);
 
     // init an empty vector of http request tasks:
     let mut http_call_tasks = Vec::new();
-
-    // check there is at one worker
-    if worker_urls.is_empty() {
-        return Err("Need worker node URLs".into());
+    // each finished tile, with the workers which have computed it
+    let mut tiles: Vec<(Range<usize>, Range<usize>, Vec<String>)> = Vec::new();
+
+    // check there is at least one live worker before splitting up the work, and
+    // enough of them to compute each tile on as many as the job asks for
+    let live_workers = context.registry.live_workers().len();
+    if live_workers == 0 {
+        return Err(BrokerError::WorkerUnavailable {
+            worker: None,
+            reason: "No live workers are registered with the broker".to_string(),
+        });
+    }
+    if live_workers < replicas {
+        return Err(BrokerError::WorkerUnavailable {
+            worker: None,
+            reason: format!("Each tile is to be computed on {} workers, but only {} are live", replicas, live_workers),
+        });
     }
 
-    (0..num_rows_left).for_each(|i: usize| {
-        (0..num_cols_right).for_each(|j: usize| {
-            let row_of_left: Vec<i32> = left[i].clone();
-            let col_of_right: Vec<i32> = (0..num_rows_right).map(|k| right[k][j]).collect();
-
-            // round robin selection of worker node:
-            let chosen_worker_id = next_worker_index.fetch_add(1, Ordering::Relaxed) % worker_urls.len();
-            let chosen_worker_url = worker_urls[chosen_worker_id].clone();
+    // the dispatcher picks workers for tiles, retries failed tiles elsewhere and keeps
+    // count of the encoded bytes exchanged, so the cost of each wire format can be compared
+    let retry = RetryPolicy {
+        budget: retry_budget,
+        base_backoff: Duration::from_millis(context.config.retry.base_backoff_ms),
+        max_backoff: Duration::from_millis(context.config.retry.max_backoff_ms),
+    };
+    let dispatcher = Arc::new(Dispatcher::new(
+        Arc::clone(&context),
+        Arc::clone(&progress),
+        wire_format,
+        retry,
+        replicas,
+        hedge_percentile,
+    ));
+
+    // split the result into tiles, each tile is a block of rows of the left matrix
+    // multiplied by a block of columns of the right matrix:
+    let row_tiles = tile_ranges(num_rows_left, tile_rows);
+    let col_tiles = tile_ranges(num_cols_right, tile_cols);
+    let planned = planned_tiles(factors, &options);
+    let empty_tiles = row_tiles.len() * col_tiles.len() - planned.len();
+    progress.set_total(planned.len());
+    context.metrics.observe_job(planned.len(), num_rows_left * num_cols_right);
+    context.metrics.add_empty_tiles(empty_tiles);
+
+    // copy each block of rows of the left matrix and each block of columns of the right
+    // matrix once, every tile then shares them rather than cloning its own:
+    let blocks = match factors {
+        Factors::Dense(left, right) => SharedBlocks::Dense(
+            row_tiles.iter().map(|rows| Arc::new(left.block(rows.clone(), 0..num_cols_left).to_matrix())).collect(),
+            col_tiles.iter().map(|cols| Arc::new(right.block(0..num_rows_right, cols.clone()).to_matrix())).collect(),
+        ),
+        Factors::Sparse(left, right) => {
+            let right_t = right.transpose();
+            SharedBlocks::Sparse(
+                row_tiles.iter().map(|rows| Arc::new(left.block(rows.clone(), 0..num_cols_left))).collect(),
+                col_tiles.iter().map(|cols| Arc::new(right_t.block(cols.clone(), 0..num_rows_right))).collect(),
+            )
+        }
+    };
+
+    // create the request payload out of the row block and column block, along
+    // with where the tile sits in the result so errors can name the cells,
+    // tagged with the element type so the worker knows how to decode it
+    let tile_payload = |rows: &Range<usize>, cols: &Range<usize>| match (factors, &blocks) {
+        // as one dot product per cell, each naming the cell it is for, which
+        // `job_options` never picks for sparse matrices
+        (Factors::Dense(left, right), _) if strategy == Strategy::DotProducts => {
+            TilePayload::DotProducts(T::tag_dot_products(DotProductBatch {
+                overflow,
+                items: dot_product_items(left, right, rows, cols),
+            }))
+        }
+        // or as the tile's blocks of rows and columns
+        (_, SharedBlocks::Dense(left_blocks, right_blocks)) => TilePayload::Block(T::tag_payload(BlockPayload {
+            row_offset: rows.start,
+            col_offset: cols.start,
+            overflow,
+            left: Arc::clone(&left_blocks[rows.start / tile_rows]),
+            right: Arc::clone(&right_blocks[cols.start / tile_cols]),
+        })),
+        (_, SharedBlocks::Sparse(left_blocks, right_blocks)) => {
+            TilePayload::SparseBlock(T::tag_sparse_payload(SparseBlockPayload {
+                row_offset: rows.start,
+                col_offset: cols.start,
+                overflow,
+                left: Arc::clone(&left_blocks[rows.start / tile_rows]),
+                right: Arc::clone(&right_blocks[cols.start / tile_cols]),
+            }))
+        }
+    };
+
+    for (rows, cols) in &planned {
+        let payload = tile_payload(rows, cols);
+        let (rows, cols) = (rows.clone(), cols.clone());
+        let dispatcher = Arc::clone(&dispatcher);
+
+        // spawning a task creates a new thread, the dispatcher sends the tile to a
+        // worker and retries it on another one if that fails
+        let tile_span = tracing::info_span!("tile", rows = ?rows, cols = ?cols);
+        let task_handle = task::spawn(
+            async move {
+                let (block, workers) = dispatcher.run_tile::<T>(rows.clone(), cols.clone(), payload, Vec::new()).await?;
+                Ok::<_, BrokerError>((rows, cols, block, workers))
+            }
+            .instrument(tile_span),
+        );
+        // all the http requests can be completed concurrently, and are held in 
+        // a vector of tasks 
+        http_call_tasks.push(task_handle);
+    }
 
-            // clone the reference to the client which is used to send the dot product task
-            let client_clone = Arc::clone(&http_client);
+    // keep a handle on each task so the rest can be cancelled once one fails
+    let abort_handles: Vec<task::AbortHandle> = http_call_tasks.iter().map(|t| t.abort_handle()).collect();
+    let cancel_remaining = || abort_handles.iter().for_each(|handle| handle.abort());
 
-            // spawning a task creates a new thread
-            let task_handle = task::spawn(async move {
-                // create the request payload out of the given row and colum 
-                // of the left and righ matrices
-                let payload = DotProductPayload {
-                    row: row_of_left,
-                    col: col_of_right,
-                };
+    // take the http request results in whatever order they finish, so the first
+    // failure is reported straight away rather than after every other tile
+    let mut task_outcomes: FuturesUnordered<_> = http_call_tasks.into_iter().collect();
 
-                // use the client to send the request to the chosen worker:
-                let response_result = client_clone
-                    .post(&chosen_worker_url) // POST request
-                    .json(&payload) // data serialized to JSON before being sent
-                    .send() // request is sent
-                    .await; // task waits for the response
-
-                // Example of error propogation in rust, the server responds with a result type
-                // if the result is ok, and the response stantus is success, we extract the reposnse
-                // values by deserializing the response from JSON, again checking this is successful, 
-                // and then finally packing the unpacked values into an Ok to be used in the matrix
-                // If any of those checks fail, we report the error, adding on a little context and
-                // propogating the original error back to the caller so they can make an informed
-                // decision on how to handle it
-                match response_result {
-                    Ok(response) => { // first result is ok, so a value is unpacked
-                        if response.status().is_success() { // check the response stat
-                            match response.json::<DotProductResponse>().await {
-                                // check if parsing of respsponse JSON payload worked
-                                Ok(data) => Ok((i, j, data.result)), // it worked, upack the value
-                                Err(e) => Err(format!( // didnt work, propogate back to caller
-                                    "Failed to parse worker response for ({}, {}) from {}: {}",
-                                    i, j, chosen_worker_url, e
-                                )),
-                            }
-                        } else { // status is not success
-                            let status = response.status();
-                            let error_body =
-                                response.text().await.unwrap_or_else(|_| "N/A".to_string());
-                            Err(format!( // propogate the error back to the caller, informed by the 
-                                // response of the server
-                                "Worker error for ({}, {}) from {} - Status: {}, Body: {}",
-                                i, j, chosen_worker_url, status, error_body
-                            ))
-                        }
-                    }
-                    Err(e) => Err(format!( // first result is error, propogate back to caller
-                        "HTTP request to worker {} failed for ({}, {}): {}",
-                        chosen_worker_url, i, j, e
-                    )),
-                }
-            });
-            // all the http requests can be completed concurrently, and are held in 
-            // a vector of tasks 
-            http_call_tasks.push(task_handle);
-        });
-    });
-
-    // wait for all the http request results, and create a new vector out of those results
-    let task_outcomes = join_all(http_call_tasks).await;
-
-    // for each of the tasks, make sure the result found is the value and not an error,
+    // for each of the tasks, make sure the result found is the block and not an error,
     // otherwise proposgate it back up to the caller with more information
-    for task_result in task_outcomes {
+    while let Some(task_result) = task_outcomes.next().await {
         match task_result { // match the result of the task itself
             Ok(http_call_outcome) => match http_call_outcome { // it worked, unpack the values from the work inside the task
-                Ok((row_ind, col_ind, value)) => { // unpacks to another result value
-                    result[row_ind][col_ind] = value; // set the value for the result matrix in the given coordinates
+                Ok((rows, cols, block, workers)) => { // unpacks to another result value
+                    // stitch the block into the result matrix at the tile's coordinates
+                    result.set_block(rows.start, cols.start, &block);
+                    tiles.push((rows, cols, workers));
+                }
+                Err(err) => { // unpacks to an error
+                    // propogate it to the caller, it already names the tile and worker
+                    cancel_remaining();
+                    return Err(err);
                 }
-                Err(err) => return Err(format!("worker sub-task failed: {}", err).into()), // unpacks to an error
-                // propogate it to the caller with more info
             }, // result of the task itself is an error
-            Err(err) => return Err(format!("spawned task failed: {}", err).into()),
+            Err(err) => {
+                cancel_remaining();
+                return Err(BrokerError::Internal(format!("spawned task failed: {}", err)));
+            }
+        }
+    }
+
+    // check the result before handing it back if asked to, recomputing the tiles
+    // which could hold a wrong cell on other workers until it passes
+    let mut repairs = 0;
+    if verify_rounds > 0 {
+        loop {
+            let verdict = factors.verify(&result, overflow, verify_rounds);
+            if verdict.passed() {
+                context.metrics.observe_verification(if repairs == 0 { "passed" } else { "repaired" });
+                break;
+            }
+            let error = BrokerError::VerificationFailed { rows: verdict.rows.clone(), cols: verdict.cols.clone() };
+            if repairs == MAX_VERIFY_REPAIRS {
+                context.metrics.observe_verification("failed");
+                return Err(error);
+            }
+            repairs += 1;
+
+            let suspects: Vec<usize> = (0..tiles.len())
+                .filter(|&t| {
+                    let (rows, cols, _) = &tiles[t];
+                    verdict.suspects(rows, cols)
+                        && !factors.verify_tile(&result, rows, cols, overflow, verify_rounds)
+                })
+                .collect();
+            tracing::warn!(
+                rows = ?verdict.rows,
+                cols = ?verdict.cols,
+                unchecked_rows = verdict.unchecked_rows,
+                "Result failed verification, recomputing {} tiles on other workers",
+                suspects.len()
+            );
+            progress.add_tiles(suspects.len());
+            let recomputed: Vec<_> = suspects
+                .iter()
+                .map(|&t| {
+                    let (rows, cols, workers) = &tiles[t];
+                    let worker = workers.last().cloned().unwrap_or_default();
+                    context.metrics.observe_suspect_tile(&worker);
+                    progress.emit(JobEvent::TileRetry {
+                        rows: rows.clone(),
+                        cols: cols.clone(),
+                        worker,
+                        error: error.body(),
+                    });
+                    dispatcher.run_tile::<T>(rows.clone(), cols.clone(), tile_payload(rows, cols), workers.clone())
+                })
+                .collect();
+            let outcomes = futures::future::join_all(recomputed).await;
+            for (&t, outcome) in suspects.iter().zip(outcomes) {
+                let (block, recomputed_by) = outcome?;
+                let (rows, cols, workers) = &mut tiles[t];
+                result.set_block(rows.start, cols.start, &block);
+                workers.extend(recomputed_by);
+            }
         }
     }
 
-    
+    tracing::info!(
+        tiles = planned.len(),
+        empty_tiles,
+        retries = dispatcher.retries_used(),
+        bytes_sent = dispatcher.bytes_sent(),
+        bytes_received = dispatcher.bytes_received(),
+        wire_format = wire_format.as_str(),
+        strategy = strategy.as_str(),
+        verify_rounds,
+        repairs,
+        replicas,
+        hedged = dispatcher.hedged(),
+        "Multiplied {}x{} by {}x{} {} matrices ({} overflow)",
+        num_rows_left, num_cols_left, num_rows_right, num_cols_right, T::DTYPE.as_str(), overflow.as_str(),
+    );
+
     Ok(result) // warp the result in an Ok to tell the caller the thing was successful
 }
 
+// the row and column behind every cell of the tile, as the dot products a worker computes
+fn dot_product_items<T: Element>(
+    left: &DenseMatrix<T>,
+    right: &DenseMatrix<T>,
+    rows: &Range<usize>,
+    cols: &Range<usize>,
+) -> Vec<DotProductItem<T>> {
+    let columns: Vec<Vec<T>> = cols.clone().map(|j| right.col(j).collect()).collect();
+    rows.clone()
+        .flat_map(|i| {
+            cols.clone().zip(&columns).map(move |(j, col)| DotProductItem {
+                row_id: i,
+                col_id: j,
+                row: left.row(i).to_vec(),
+                col: col.clone(),
+            })
+        })
+        .collect()
+}
+
+// the span every tile of a multiplication is traced under
+fn job_span<T: Element>(job_id: &str, factors: &Factors<T>) -> tracing::Span {
+    let (left, right) = factors.shapes();
+    tracing::info_span!(
+        "job",
+        job_id = %job_id,
+        dtype = T::DTYPE.as_str(),
+        left = ?left,
+        right = ?right,
+        sparse = matches!(factors, Factors::Sparse(..)),
+    )
+}
+
+/// Number of tiles `distribute_mat_mult` will compute, each counted once for
+/// every worker it is computed on.
+fn tile_count<T: Element>(factors: &Factors<T>, options: &JobOptions) -> usize {
+    planned_tiles(factors, options).len() * options.replicas
+}
+
+/// Multiplies two matrices across the workers while the client waits, as long
+/// as the broker has room for the job.
+async fn multiply_now<T: Element>(
+    factors: &Factors<T>,
+    options: JobOptions,
+    context: Arc<BrokerContext>,
+) -> Result<DenseMatrix<T>, BrokerError> {
+    // turn the request away if the broker already has as much work as it can take
+    let _reservation = context.admit(tile_count(factors, &options))?;
+
+    // sends the given matrices, split into tiles of the requested size, traced
+    // under an ID of its own as a job submitted through /jobs would be
+    let progress = Arc::new(JobProgress::default());
+    let span = job_span(&uuid::Uuid::new_v4().to_string(), factors);
+    async {
+        distribute_mat_mult(factors, options, context, progress).await.inspect_err(|e| {
+            tracing::error!(code = e.code(), "Distributed multiplication failed: {}", e);
+        })
+    }
+    .instrument(span)
+    .await
+}
+
+/// Converts the request's matrices to `T`, multiplies them across the workers and
+/// builds the JSON reply.
+async fn multiply_as<T: Element>(
+    operands: &Operands,
+    options: JobOptions,
+    context: Arc<BrokerContext>,
+) -> Result<warp::reply::Json, Rejection> {
+    // pack the matrices into dense or sparse ones, rejecting ragged, malformed or out of range input up front
+    let factors = Factors::<T>::from_operands(operands)?;
+    let result = multiply_now(&factors, options, context).await?;
+
+    Ok(warp::reply::json(&MatMultResponse {
+        dtype: T::DTYPE,
+        overflow: options.overflow,
+        result: options.result_format.render(&result),
+    }))
+}
+
+// anything the request leaves out comes from the broker's config, `result_cols`
+// is the width of the product and `sparse` whether it is multiplied as sparse matrices
+fn job_options(
+    requested: RequestedOptions,
+    overflow: OverflowPolicy,
+    result_cols: usize,
+    sparse: bool,
+    config: &BrokerConfig,
+) -> Result<JobOptions, BrokerError> {
+    // sparse matrices are only ever sent as blocks, spelling out every cell's
+    // row and column as dot products would send their zeros too
+    let strategy = match requested.strategy {
+        Some(Strategy::DotProducts) if sparse => {
+            return Err(BrokerError::InvalidRequest(
+                "The dot_products strategy can't multiply sparse matrices, use blocks".to_string(),
+            ));
+        }
+        _ if sparse => Strategy::Blocks,
+        strategy => strategy.unwrap_or(config.strategy),
+    };
+    let (tile_rows, tile_cols) = match strategy {
+        Strategy::Blocks => (
+            requested.tile_rows.unwrap_or(config.tile_rows),
+            requested.tile_cols.unwrap_or(config.tile_cols),
+        ),
+        // each batch is a tile of at most `batch_size` cells, as many whole rows of
+        // the result as fit. Every item still carries its own copy of its row and
+        // column, so a row of the left matrix is sent once per cell it is used for
+        Strategy::DotProducts => {
+            let batch_size = requested.batch_size.unwrap_or(config.dot_product_batch_size);
+            if batch_size == 0 {
+                return Err(BrokerError::InvalidRequest("Batch size must be positive, got 0".to_string()));
+            }
+            let width = batch_size.min(result_cols.max(1));
+            ((batch_size / width).max(1), width)
+        }
+    };
+    let replicas = requested.replicas.unwrap_or(config.replicas);
+    if replicas == 0 {
+        return Err(BrokerError::InvalidRequest("Replicas must be positive, got 0".to_string()));
+    }
+    let hedge_percentile = requested.hedge_percentile.unwrap_or(config.hedge.percentile);
+    if !(0.0..100.0).contains(&hedge_percentile) {
+        return Err(BrokerError::InvalidRequest(format!(
+            "Hedge percentile must be at least 0 and below 100, got {}",
+            hedge_percentile
+        )));
+    }
+    Ok(JobOptions {
+        tile_rows,
+        tile_cols,
+        wire_format: requested.wire_format.unwrap_or(config.wire_format),
+        overflow,
+        retry_budget: requested.retry_budget.unwrap_or(config.retry.budget),
+        strategy,
+        verify_rounds: requested.verify_rounds.unwrap_or(config.verify_rounds),
+        replicas,
+        hedge_percentile,
+        result_format: requested.result_format.unwrap_or_default(),
+    })
+}
+
 /// Warp handler for the /multiply_matrices_distributed endpoint.
 async fn matmult_handler(
-    body: MatMultRequest,
-    http_client: Arc<Client>,
-    worker_urls: Arc<Vec<String>>,
-    next_worker_index: Arc<AtomicUsize>,
+    mut body: MatMultRequest,
+    context: Arc<BrokerContext>,
 ) -> Result<impl Reply, Rejection> {
+    // a malformed or unknown matrix is turned away before any of it is converted or sent out
+    let operands = body.take_operands(&context.matrices, context.config.max_result_cells)?;
+    let sparse = operands.is_sparse();
+    let options = job_options(body.requested_options(), body.overflow, operands.result_cols, sparse, &context.config)?;
+
+    // the element type decides which instantiation of the distribution logic runs
+    match body.dtype {
+        DType::I32 => multiply_as::<i32>(&operands, options, context).await,
+        DType::I64 => multiply_as::<i64>(&operands, options, context).await,
+        DType::F32 => multiply_as::<f32>(&operands, options, context).await,
+        DType::F64 => multiply_as::<f64>(&operands, options, context).await,
+    }
+}
 
-    // sends the given matrices 
-    let answer = distribute_mat_mult(
-        &body.left,
-        &body.right,
-        http_client, // reqwest client
-        worker_urls,
-        next_worker_index,
-    )
-    .await;
-
-    match answer {
-        Ok(result_matrix) => Ok(warp::reply::json(&result_matrix)),
-        Err(e) => {
-            eprintln!("Error during distributed multiplication: {}", e);
-            // Convert Box<dyn Error> to our AppError for Warp's rejection system
-            Err(warp::reject::custom(AppError(e.to_string())))
+/// Converts the request's matrices to `T` and queues a job to multiply them,
+/// returning as soon as the job is in the job table.
+fn submit_as<T: Element>(
+    operands: &Operands,
+    options: JobOptions,
+    context: Arc<BrokerContext>,
+) -> Result<Arc<Job>, Rejection> {
+    // bad input is rejected now, rather than turning into a failed job later
+    let factors = Factors::<T>::from_operands(operands)?;
+
+    // and so is a job the broker has no room for, queued jobs count against the
+    // pending tiles too so a backlog can't grow without bound
+    context.admit_queued_job()?;
+    let reservation = context.admit(tile_count(&factors, &options))?;
+
+    let job = context.jobs.create(T::DTYPE, options.overflow);
+    let background_job = Arc::clone(&job);
+    let span = job_span(&job.id, &factors);
+    task::spawn(async move {
+        let job = background_job;
+        let _reservation = reservation;
+        // the job stays queued until one of the running job slots frees up
+        let _slot = match Arc::clone(&context.job_slots).acquire_owned().await {
+            Ok(slot) => slot,
+            Err(e) => return job.finish(Err(BrokerError::Internal(format!("job limiter closed: {}", e)))),
+        };
+        job.start();
+
+        let outcome = distribute_mat_mult(&factors, options, Arc::clone(&context), Arc::clone(&job.progress))
+            .await
+            .and_then(|result| {
+                serde_json::to_value(options.result_format.render(&result))
+                    .map_err(|e| BrokerError::Internal(e.to_string()))
+            });
+        if let Err(e) = &outcome {
+            tracing::error!(code = e.code(), "Job failed: {}", e);
+            context.metrics.observe_error(e.code());
         }
+        job.finish(outcome);
+    }.instrument(span));
+    Ok(job)
+}
+
+/// Warp handler for POST /jobs, queues the multiplication and replies straight
+/// away with the job's ID for polling GET /jobs/{id}.
+async fn submit_job_handler(mut body: MatMultRequest, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let operands = body.take_operands(&context.matrices, context.config.max_result_cells)?;
+    let sparse = operands.is_sparse();
+    let options = job_options(body.requested_options(), body.overflow, operands.result_cols, sparse, &context.config)?;
+    let job = match body.dtype {
+        DType::I32 => submit_as::<i32>(&operands, options, context),
+        DType::I64 => submit_as::<i64>(&operands, options, context),
+        DType::F32 => submit_as::<f32>(&operands, options, context),
+        DType::F64 => submit_as::<f64>(&operands, options, context),
+    }?;
+
+    let reply = warp::reply::with_status(warp::reply::json(&job.report()), StatusCode::ACCEPTED);
+    Ok(warp::reply::with_header(reply, "Location", format!("/jobs/{}", job.id)))
+}
+
+/// Warp handler for GET /jobs/{id}, reports the job's status, progress and,
+/// once it is done, its result.
+async fn get_job_handler(id: String, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let job = context
+        .jobs
+        .get(&id)
+        .ok_or_else(|| BrokerError::NotFound(format!("No job with ID {}", id)))?;
+    Ok(warp::reply::json(&job.report()))
+}
+
+// builds a named SSE event with a JSON body
+fn sse_event<D: Serialize>(name: &str, data: &D) -> Result<Event, Infallible> {
+    Ok(Event::default()
+        .event(name)
+        .json_data(data)
+        .unwrap_or_else(|e| Event::default().event("error").data(e.to_string())))
+}
+
+/// Warp handler for GET /jobs/{id}/events, streams the job's progress as
+/// Server-Sent Events: a `status` snapshot first, then `tile_assigned`,
+/// `tile_retry`, `tile_done` and `tile_failed` as they happen, and finally
+/// `done` with the result or `failed` with the error, after which the stream ends.
+async fn job_events_handler(id: String, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let job = context
+        .jobs
+        .get(&id)
+        .ok_or_else(|| BrokerError::NotFound(format!("No job with ID {}", id)))?;
+
+    // subscribe before taking the snapshot so no event falls in between the two
+    let receiver = job.progress.subscribe();
+    let status = stream::iter([sse_event("status", &job.report())]);
+
+    let events = match job.final_event() {
+        // a finished job has nothing left to stream but how it ended
+        Some(event) => status.chain(stream::iter([sse_event(event.name(), &event)])).boxed(),
+        None => status
+            .chain(stream::unfold(Some(receiver), |receiver| async move {
+                let mut receiver = receiver?;
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    // this client fell behind and missed some tile events, say so and carry on
+                    Err(RecvError::Lagged(skipped)) => {
+                        let event = sse_event("lagged", &serde_json::json!({ "skipped": skipped }));
+                        return Some((event, Some(receiver)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                // stop streaming once the job has ended
+                let next = (!event.is_final()).then_some(receiver);
+                Some((sse_event(event.name(), &event), next))
+            }))
+            .boxed(),
+    };
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+// strips trailing slashes and checks the URL a worker registered with is usable
+fn normalize_worker_url(url: &str) -> Result<String, BrokerError> {
+    let url = url.trim().trim_end_matches('/');
+    if !(url.starts_with("http://") || url.starts_with("https://")) || url.contains(char::is_whitespace) {
+        return Err(BrokerError::InvalidRequest(format!("Worker URL must be an absolute http(s) URL, got {:?}", url)));
     }
+    Ok(url.to_string())
 }
 
-// Custom rejection handler to convert AppError into a proper HTTP response.
-async fn rejection_handler(err: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(app_err) = err.find::<AppError>() {
-        let json = warp::reply::json(&serde_json::json!({
-            "error": app_err.0,
-        }));
-        Ok(warp::reply::with_status(json, StatusCode::BAD_REQUEST)) // Or INTERNAL_SERVER_ERROR
-    } else {
-        // For other rejections, like missing headers or method not allowed
-        eprintln!("Unhandled rejection: {:?}", err);
-        let json = warp::reply::json(&serde_json::json!({
-            "error": format!("Unhandled error: {:?}", err),
-        }));
-        Ok(warp::reply::with_status(
-            json,
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+/// Warp handler for POST /matrices, stores the matrix and replies with the ID
+/// requests can name it by in `left_id` or `right_id`, along with its shape.
+async fn upload_matrix_handler(body: UploadRequest, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let stored = context.matrices.insert(body.matrix)?;
+    tracing::info!(id = %stored.id, rows = stored.rows, cols = stored.cols, "Stored matrix");
+    let reply = warp::reply::with_status(warp::reply::json(&stored.report(false)), StatusCode::CREATED);
+    Ok(warp::reply::with_header(reply, "Location", format!("/matrices/{}", stored.id)))
+}
+
+/// Warp handler for GET /matrices/{id}, replies with the stored matrix and its shape.
+async fn get_matrix_handler(id: String, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let stored = context.matrices.get(&id)?;
+    Ok(warp::reply::json(&stored.report(true)))
+}
+
+/// Warp handler for DELETE /matrices/{id}.
+async fn delete_matrix_handler(id: String, context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    context.matrices.remove(&id)?;
+    tracing::info!(id = %id, "Deleted matrix");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Warp handler for POST /workers/register, called by workers on startup.
+async fn register_worker_handler(
+    body: WorkerAddress,
+    context: Arc<BrokerContext>,
+) -> Result<impl Reply, Rejection> {
+    let url = normalize_worker_url(&body.url)?;
+    let capacity = body.capacity.unwrap_or(1).max(1);
+    let grpc_url = body.grpc_url.as_deref().map(normalize_worker_url).transpose()?;
+    let transport = if grpc_url.is_some() { "grpc" } else { "http" };
+    if context.registry.register(&url, capacity, grpc_url) {
+        tracing::info!(worker = %url, capacity, transport, "Worker registered");
     }
+    // tell the worker how long it has between heartbeats
+    Ok(warp::reply::json(&serde_json::json!({
+        "url": url,
+        "heartbeat_ttl_ms": context.registry.ttl().as_millis() as u64,
+    })))
 }
 
-#[tokio::main]
-async fn main() {
-    // init single reqwest client to be shared:
-    let http_client = Arc::new(Client::new());
+/// Warp handler for POST /workers/heartbeat. Unknown workers get a 404 so they register again.
+async fn heartbeat_handler(
+    body: WorkerAddress,
+    context: Arc<BrokerContext>,
+) -> Result<impl Reply, Rejection> {
+    let url = normalize_worker_url(&body.url)?;
+    if !context.registry.heartbeat(&url) {
+        return Err(BrokerError::NotFound(format!("Worker {} is not registered", url)).into());
+    }
+    Ok(warp::reply::json(&serde_json::json!({ "url": url })))
+}
+
+/// Warp handler for GET /metrics, in the Prometheus text format.
+async fn metrics_handler(context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    let body = context
+        .metrics
+        .render(&context.registry, context.pending_tiles())
+        .map_err(BrokerError::Internal)?;
+    Ok(warp::reply::with_header(body, "Content-Type", "text/plain; version=0.0.4"))
+}
 
-    // prepare worker URLs:
-    let worker_urls_vec: Vec<String> = WORKER_NODES.iter().map(|s| s.to_string()).collect();
-    let worker_urls_arc = Arc::new(worker_urls_vec);
+/// Warp handler for GET /workers, lists the live workers.
+async fn list_workers_handler(context: Arc<BrokerContext>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&context.registry.statuses()))
+}
 
-    // Atomic counter for simple round-robin load balancing
-    let next_worker_index = Arc::new(AtomicUsize::new(0));
+// Custom rejection handler, every error goes back to the client as a
+// `{code, message, details}` body with the status its code calls for.
+async fn rejection_handler(err: Rejection, context: Arc<BrokerContext>) -> Result<warp::reply::Response, Rejection> {
+    let error = if let Some(broker_err) = err.find::<BrokerError>() {
+        broker_err.clone()
+    } else if err.is_not_found() {
+        BrokerError::NotFound("No such route".to_string())
+    } else if let Some(too_large) = err.find::<warp::reject::PayloadTooLarge>() {
+        BrokerError::PayloadTooLarge(too_large.to_string())
+    } else if let Some(bad_body) = err.find::<warp::body::BodyDeserializeError>() {
+        BrokerError::InvalidRequest(bad_body.to_string())
+    } else if let Some(bad_type) = err.find::<warp::reject::UnsupportedMediaType>() {
+        BrokerError::InvalidRequest(bad_type.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        BrokerError::MethodNotAllowed
+    } else {
+        // anything else is a rejection this server doesn't expect to produce
+        tracing::error!("Unhandled rejection: {:?}", err);
+        BrokerError::Internal(format!("Unhandled rejection: {:?}", err))
+    };
+    context.metrics.observe_error(error.code());
+    if error.status().is_server_error() {
+        tracing::error!(code = error.code(), "{}", error);
+    }
+    Ok(error.into_response())
+}
 
+/// The broker's HTTP API, with CORS, error replies, tracing and request metrics.
+fn routes(
+    context: Arc<BrokerContext>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static {
     // CORS support needed to allow different origins to access the server
-    // CORS configuration allows POST or OPTIONS from any origin with specified headers
+    // CORS configuration allows POST or OPTIONS from the configured origins with specified headers
     let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec!["Content-Type"])
-        .allow_methods(vec!["POST", "OPTIONS"]); // OPTIONS allows for preflight requests
+        .allow_headers(vec!["Content-Type", "traceparent"])
+        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"]); // OPTIONS allows for preflight requests
+    let cors = if context.config.cors_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(context.config.cors_origins.iter().map(String::as_str))
+    };
 
-    // have to clone Arcs for the filter closure
-    let http_client_filter = warp::any().map(move || Arc::clone(&http_client));
-    let worker_urls_filter = warp::any().map(move || Arc::clone(&worker_urls_arc));
-    let next_worker_filter = warp::any().map(move || Arc::clone(&next_worker_index));
+    // have to clone the Arc for the filter closure
+    let filter_context = Arc::clone(&context);
+    let context_filter = warp::any().map(move || Arc::clone(&filter_context));
+    // every route with a body refuses one over the limit with a 413 before reading it
+    let body_limit = warp::body::content_length_limit(context.config.max_body_bytes);
 
     // Define the route for matrix multiplication
     // POST /multiply_matrices_distributed
     let multiply_route = warp::post() // limit requests to POST
        .and(warp::path("multiply_matrices_distributed")) // matches URL path "/multiply_matrices_distributed"
+       .and(body_limit) // refuse bodies over max_body_bytes
        .and(warp::body::json()) // deserialize request body from JSON into expected type
-       .and(http_client_filter) // inject reqwest client
-       .and(worker_urls_filter) // inject worker node URLs
-       .and(next_worker_filter) // inject next worker index
+       .and(context_filter.clone()) // inject the shared client, registry and config
        .and_then(matmult_handler); // call handler function with all injected values
 
+    // POST /jobs queues a multiplication, GET /jobs/{id} polls it
+    let submit_job_route = warp::post()
+       .and(warp::path!("jobs"))
+       .and(body_limit)
+       .and(warp::body::json())
+       .and(context_filter.clone())
+       .and_then(submit_job_handler);
+
+    let get_job_route = warp::get()
+       .and(warp::path!("jobs" / String))
+       .and(context_filter.clone())
+       .and_then(get_job_handler);
+
+    // GET /jobs/{id}/events streams the job's progress as Server-Sent Events
+    let job_events_route = warp::get()
+       .and(warp::path!("jobs" / String / "events"))
+       .and(context_filter.clone())
+       .and_then(job_events_handler);
+
+    // POST /matrices stores a matrix for requests to name by ID, GET and DELETE
+    // /matrices/{id} fetch and remove it
+    let upload_matrix_route = warp::post()
+       .and(warp::path!("matrices"))
+       .and(body_limit)
+       .and(warp::body::json())
+       .and(context_filter.clone())
+       .and_then(upload_matrix_handler);
+
+    let get_matrix_route = warp::get()
+       .and(warp::path!("matrices" / String))
+       .and(context_filter.clone())
+       .and_then(get_matrix_handler);
+
+    let delete_matrix_route = warp::delete()
+       .and(warp::path!("matrices" / String))
+       .and(context_filter.clone())
+       .and_then(delete_matrix_handler);
+
+    // POST /workers/register and POST /workers/heartbeat, used by workers to join and stay live
+    let register_route = warp::post()
+       .and(warp::path!("workers" / "register"))
+       .and(body_limit)
+       .and(warp::body::json())
+       .and(context_filter.clone())
+       .and_then(register_worker_handler);
+
+    let heartbeat_route = warp::post()
+       .and(warp::path!("workers" / "heartbeat"))
+       .and(body_limit)
+       .and(warp::body::json())
+       .and(context_filter.clone())
+       .and_then(heartbeat_handler);
+
+    // GET /workers lists the live workers
+    let list_workers_route = warp::get()
+       .and(warp::path!("workers"))
+       .and(context_filter.clone())
+       .and_then(list_workers_handler);
+
+    // GET /metrics exports request, tile, worker and job metrics for Prometheus
+    let metrics_route = warp::get()
+       .and(warp::path!("metrics"))
+       .and(context_filter)
+       .and_then(metrics_handler);
+
+    // every response, errors included, is counted and timed by route and status
+    let metrics_context = Arc::clone(&context);
+    let request_metrics = warp::log::custom(move |info| {
+        let route = metrics::route_label(info.path());
+        metrics_context.metrics.observe_request(route, info.status().as_u16(), info.elapsed());
+    });
+    let recover_context = Arc::clone(&context);
+
     // combine routes with CORS support and rejection handler
-    let routes = multiply_route.with(cors).recover(rejection_handler);
+    multiply_route
+        .or(submit_job_route)
+        .or(get_job_route)
+        .or(job_events_route)
+        .or(upload_matrix_route)
+        .or(get_matrix_route)
+        .or(delete_matrix_route)
+        .or(register_route)
+        .or(heartbeat_route)
+        .or(list_workers_route)
+        .or(metrics_route)
+        .with(cors)
+        .recover(move |err| rejection_handler(err, Arc::clone(&recover_context)))
+        // each request is traced, continuing the client's trace if it sent a traceparent
+        .with(warp::trace(|info| {
+            let traceparent = info.request_headers().get("traceparent").and_then(|value| value.to_str().ok());
+            trace::request_span(info.method().as_str(), info.path(), traceparent)
+        }))
+        .with(request_metrics)
+}
+
+#[tokio::main]
+async fn main() {
+    // settings come from the config file and BROKER_* environment variables,
+    // refuse to start on anything that doesn't make sense
+    let config = match BrokerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid broker configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tracing_config = &config.tracing;
+    if let Err(e) = trace::init("broker", tracing_config.log_format, &tracing_config.log_filter, config.span_export()) {
+        eprintln!("Invalid broker configuration: {}", e);
+        std::process::exit(1);
+    }
+    let context = match BrokerContext::new(config) {
+        Ok(context) => Arc::new(context),
+        Err(e) => {
+            tracing::error!("Invalid broker configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // periodically drop workers that have stopped heartbeating, and finished
+    // jobs nobody came back for
+    let expiry_context = Arc::clone(&context);
+    task::spawn(async move {
+        let mut ticker = tokio::time::interval(expiry_context.registry.ttl() / 3);
+        loop {
+            ticker.tick().await;
+            for url in expiry_context.registry.expire() {
+                tracing::warn!(worker = %url, "Worker expired after missing heartbeats");
+            }
+            for id in expiry_context.jobs.expire() {
+                tracing::info!(job_id = %id, "Job expired from the job table");
+            }
+        }
+    });
+
+    // the Broker gRPC service, next to the HTTP routes on its own port
+    if let Some(grpc_addr) = context.config.grpc_listen_addr {
+        let server = tonic::transport::Server::builder()
+            .add_service(grpc::pb::broker_server::BrokerServer::new(grpc::BrokerService::new(Arc::clone(&context))));
+        task::spawn(async move {
+            if let Err(e) = server.serve(grpc_addr).await {
+                tracing::error!("gRPC server on {} failed: {}", grpc_addr, e);
+            }
+        });
+        tracing::info!("Broker gRPC server listening on http://{} (matmult.Broker)", grpc_addr);
+    }
 
-    let port = 8000; // port for this server
+    let listen_addr = context.config.listen_addr;
+    tracing::info!(
+        "Broker listening on http://{} ({} scheduler)",
+        listen_addr,
+        context.config.scheduler.as_str()
+    );
 
     // init the server and wait for requests
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    warp::serve(routes(context)).run(listen_addr).await;
 }
@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use matmult_common::trace;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder, exponential_buckets,
+};
+
+use crate::registry::WorkerRegistry;
+
+/// Everything the broker exports on `GET /metrics`, in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_seconds: HistogramVec,
+    errors: IntCounterVec,
+    tile_attempts: IntCounterVec,
+    tile_attempt_seconds: HistogramVec,
+    bytes_sent: IntCounterVec,
+    bytes_received: IntCounterVec,
+    job_tiles: Histogram,
+    job_cells: Histogram,
+    empty_tiles: IntCounter,
+    verifications: IntCounterVec,
+    suspect_tiles: IntCounterVec,
+    tile_votes: IntCounterVec,
+    worker_votes: IntCounterVec,
+    quarantines: IntCounterVec,
+    hedges: IntCounterVec,
+    // the gauges below are filled in from the registry when scraped
+    worker_in_flight: IntGaugeVec,
+    worker_capacity: IntGaugeVec,
+    live_workers: IntGauge,
+    quarantined_workers: IntGauge,
+    pending_tiles: IntGauge,
+    // counted by the span exporter, copied over when scraped
+    dropped_spans: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("broker".to_string()), None)?;
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered, by route and status"),
+                &["route", "status"],
+            )?,
+            http_request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time to answer an HTTP request, by route")
+                    .buckets(exponential_buckets(0.001, 4.0, 10)?),
+                &["route"],
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors returned to clients, by error code"),
+                &["code"],
+            )?,
+            tile_attempts: IntCounterVec::new(
+                Opts::new("tile_attempts_total", "Tiles sent to workers, by worker and outcome (ok, retry or failed)"),
+                &["worker", "outcome"],
+            )?,
+            tile_attempt_seconds: HistogramVec::new(
+                HistogramOpts::new("tile_attempt_duration_seconds", "Round trip of one tile to a worker, by worker")
+                    .buckets(exponential_buckets(0.001, 4.0, 10)?),
+                &["worker"],
+            )?,
+            bytes_sent: IntCounterVec::new(
+                Opts::new("bytes_sent_total", "Encoded tile bytes sent to workers, by wire format"),
+                &["format"],
+            )?,
+            bytes_received: IntCounterVec::new(
+                Opts::new("bytes_received_total", "Encoded block bytes received from workers, by wire format"),
+                &["format"],
+            )?,
+            job_tiles: Histogram::with_opts(
+                HistogramOpts::new("job_tiles", "Tiles each multiplication was split into")
+                    .buckets(exponential_buckets(1.0, 4.0, 10)?),
+            )?,
+            job_cells: Histogram::with_opts(
+                HistogramOpts::new("job_result_cells", "Cells in the result of each multiplication")
+                    .buckets(exponential_buckets(1.0, 10.0, 10)?),
+            )?,
+            empty_tiles: IntCounter::new(
+                "empty_tiles_total",
+                "Tiles of sparse multiplications left out for having no term that isn't zero",
+            )?,
+            verifications: IntCounterVec::new(
+                Opts::new("verifications_total", "Results checked with Freivalds' algorithm, by outcome (passed, repaired or failed)"),
+                &["outcome"],
+            )?,
+            suspect_tiles: IntCounterVec::new(
+                Opts::new("suspect_tiles_total", "Tiles recomputed after failing verification, by the worker which computed them"),
+                &["worker"],
+            )?,
+            tile_votes: IntCounterVec::new(
+                Opts::new("tile_votes_total", "Tiles computed on several workers, by outcome (unanimous, majority or no_majority)"),
+                &["outcome"],
+            )?,
+            worker_votes: IntCounterVec::new(
+                Opts::new("worker_votes_total", "Answers to tiles computed on several workers, by worker and vote (agreed or disagreed)"),
+                &["worker", "vote"],
+            )?,
+            quarantines: IntCounterVec::new(
+                Opts::new("worker_quarantines_total", "Times a worker was quarantined for disagreeing with the majority"),
+                &["worker"],
+            )?,
+            hedges: IntCounterVec::new(
+                Opts::new("hedged_tiles_total", "Tiles also sent to a second worker for being slow, by which answered first (original or hedge)"),
+                &["winner"],
+            )?,
+            worker_in_flight: IntGaugeVec::new(
+                Opts::new("worker_in_flight_tiles", "Tiles sent to a worker and not yet answered"),
+                &["worker"],
+            )?,
+            worker_capacity: IntGaugeVec::new(
+                Opts::new("worker_capacity", "Capacity each live worker registered with"),
+                &["worker"],
+            )?,
+            live_workers: IntGauge::new("live_workers", "Workers currently handed tiles")?,
+            quarantined_workers: IntGauge::new("quarantined_workers", "Workers handed no tiles while quarantined")?,
+            pending_tiles: IntGauge::new("pending_tiles", "Tiles of admitted jobs that haven't finished")?,
+            dropped_spans: IntCounter::new(
+                "dropped_spans_total",
+                "Closed spans dropped rather than exported, for finding the export queue full",
+            )?,
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.http_requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.http_request_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.tile_attempts.clone()))?;
+        metrics.registry.register(Box::new(metrics.tile_attempt_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.bytes_sent.clone()))?;
+        metrics.registry.register(Box::new(metrics.bytes_received.clone()))?;
+        metrics.registry.register(Box::new(metrics.job_tiles.clone()))?;
+        metrics.registry.register(Box::new(metrics.job_cells.clone()))?;
+        metrics.registry.register(Box::new(metrics.empty_tiles.clone()))?;
+        metrics.registry.register(Box::new(metrics.verifications.clone()))?;
+        metrics.registry.register(Box::new(metrics.suspect_tiles.clone()))?;
+        metrics.registry.register(Box::new(metrics.tile_votes.clone()))?;
+        metrics.registry.register(Box::new(metrics.worker_votes.clone()))?;
+        metrics.registry.register(Box::new(metrics.quarantines.clone()))?;
+        metrics.registry.register(Box::new(metrics.hedges.clone()))?;
+        metrics.registry.register(Box::new(metrics.worker_in_flight.clone()))?;
+        metrics.registry.register(Box::new(metrics.worker_capacity.clone()))?;
+        metrics.registry.register(Box::new(metrics.live_workers.clone()))?;
+        metrics.registry.register(Box::new(metrics.quarantined_workers.clone()))?;
+        metrics.registry.register(Box::new(metrics.pending_tiles.clone()))?;
+        metrics.registry.register(Box::new(metrics.dropped_spans.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn observe_request(&self, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests.with_label_values(&[route, &status.to_string()]).inc();
+        self.http_request_seconds.with_label_values(&[route]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_error(&self, code: &str) {
+        self.errors.with_label_values(&[code]).inc();
+    }
+
+    pub fn observe_tile(&self, worker: &str, outcome: &str, elapsed: Duration) {
+        self.tile_attempts.with_label_values(&[worker, outcome]).inc();
+        self.tile_attempt_seconds.with_label_values(&[worker]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn add_bytes_sent(&self, format: &str, bytes: usize) {
+        self.bytes_sent.with_label_values(&[format]).inc_by(bytes as u64);
+    }
+
+    pub fn add_bytes_received(&self, format: &str, bytes: usize) {
+        self.bytes_received.with_label_values(&[format]).inc_by(bytes as u64);
+    }
+
+    pub fn observe_job(&self, tiles: usize, cells: usize) {
+        self.job_tiles.observe(tiles as f64);
+        self.job_cells.observe(cells as f64);
+    }
+
+    pub fn add_empty_tiles(&self, tiles: usize) {
+        self.empty_tiles.inc_by(tiles as u64);
+    }
+
+    pub fn observe_verification(&self, outcome: &str) {
+        self.verifications.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn observe_suspect_tile(&self, worker: &str) {
+        self.suspect_tiles.with_label_values(&[worker]).inc();
+    }
+
+    pub fn observe_vote(&self, outcome: &str) {
+        self.tile_votes.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn observe_worker_vote(&self, worker: &str, agreed: bool) {
+        let vote = if agreed { "agreed" } else { "disagreed" };
+        self.worker_votes.with_label_values(&[worker, vote]).inc();
+    }
+
+    pub fn observe_quarantine(&self, worker: &str) {
+        self.quarantines.with_label_values(&[worker]).inc();
+    }
+
+    pub fn observe_hedge(&self, winner: &str) {
+        self.hedges.with_label_values(&[winner]).inc();
+    }
+
+    /// Renders every metric, first refreshing the gauges that mirror the
+    /// worker registry and the admission counter.
+    pub fn render(&self, workers: &WorkerRegistry, pending_tiles: usize) -> Result<String, String> {
+        // start from scratch so expired workers drop out of the output
+        self.worker_in_flight.reset();
+        self.worker_capacity.reset();
+        let candidates = workers.candidates();
+        for candidate in &candidates {
+            self.worker_in_flight.with_label_values(&[&candidate.url]).set(candidate.outstanding as i64);
+            self.worker_capacity.with_label_values(&[&candidate.url]).set(candidate.capacity as i64);
+        }
+        self.live_workers.set(candidates.len() as i64);
+        self.quarantined_workers.set(workers.quarantined() as i64);
+        self.pending_tiles.set(pending_tiles as i64);
+
+        self.dropped_spans.inc_by(trace::dropped_spans().saturating_sub(self.dropped_spans.get()));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+/// The route a request path was served by, with IDs replaced so every job
+/// shares one label value.
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["multiply_matrices_distributed"] => "/multiply_matrices_distributed",
+        ["jobs"] => "/jobs",
+        ["jobs", _] => "/jobs/{id}",
+        ["jobs", _, "events"] => "/jobs/{id}/events",
+        ["matrices"] => "/matrices",
+        ["matrices", _] => "/matrices/{id}",
+        ["workers"] => "/workers",
+        ["workers", "register"] => "/workers/register",
+        ["workers", "heartbeat"] => "/workers/heartbeat",
+        ["metrics"] => "/metrics",
+        _ => "other",
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Live set of workers, keyed by base URL. Workers register themselves on
+/// startup and heartbeat periodically; any that stop heartbeating for longer
+/// than `ttl` are expired and no longer handed work. Pinned workers come from
+/// the broker's config and stay live whether or not they heartbeat. No worker
+/// is sent more than `max_in_flight` tiles at once. Workers that gave a gRPC
+/// URL are sent their tiles over gRPC, the rest over HTTP. A worker outvoted
+/// on `quarantine_after` tiles computed redundantly is quarantined, handed no
+/// tiles for `quarantine_for`, then given a clean slate.
+pub struct WorkerRegistry {
+    ttl: Duration,
+    max_in_flight: usize,
+    quarantine_after: usize,
+    quarantine_for: Duration,
+    workers: RwLock<HashMap<String, WorkerEntry>>,
+}
+
+struct WorkerEntry {
+    last_seen: Instant,
+    pinned: bool,
+    // relative throughput of the worker, used by the weighted scheduler
+    capacity: usize,
+    // where to send the worker tiles over gRPC instead of HTTP
+    grpc_url: Option<String>,
+    // tile requests sent to the worker which haven't been answered yet
+    outstanding: Arc<AtomicUsize>,
+    // one permit per tile the worker may be sent at once
+    slots: Arc<Semaphore>,
+    // tiles computed redundantly on which the worker sided with the majority, or didn't
+    agreements: u64,
+    disagreements: u64,
+    // disagreements since the worker was last let out of quarantine
+    strikes: usize,
+    quarantined_until: Option<Instant>,
+}
+
+impl WorkerEntry {
+    fn is_live(&self, ttl: Duration) -> bool {
+        self.pinned || self.last_seen.elapsed() <= ttl
+    }
+
+    fn is_quarantined(&self) -> bool {
+        self.quarantined_until.is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// A worker as reported by `GET /workers`.
+#[derive(Serialize)]
+pub struct WorkerStatus {
+    pub url: String,
+    pub last_seen_ms: u128,
+    pub pinned: bool,
+    pub capacity: usize,
+    pub outstanding: usize,
+    /// `http` or `grpc`, whichever tiles are sent to the worker over.
+    pub transport: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grpc_url: Option<String>,
+    /// Tiles computed redundantly on which the worker agreed with the majority.
+    pub agreements: u64,
+    /// Tiles computed redundantly on which the majority outvoted the worker.
+    pub disagreements: u64,
+    /// Time left until the worker is let out of quarantine, if it is in one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantined_ms: Option<u128>,
+}
+
+/// A live worker a tile could be sent to, with its load when it was looked up.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub url: String,
+    pub capacity: usize,
+    pub outstanding: usize,
+    pub grpc_url: Option<String>,
+    /// Whether the worker is below its in-flight limit.
+    pub has_free_slot: bool,
+}
+
+/// Counts a request as outstanding on a worker, and holds one of its in-flight
+/// slots, until it is dropped.
+pub struct OutstandingGuard {
+    outstanding: Arc<AtomicUsize>,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WorkerRegistry {
+    pub fn new(ttl: Duration, max_in_flight: usize, quarantine_after: usize, quarantine_for: Duration) -> Self {
+        WorkerRegistry { ttl, max_in_flight, quarantine_after, quarantine_for, workers: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Adds a worker, or refreshes it if it is already known. Returns true if it is new.
+    pub fn register(&self, url: &str, capacity: usize, grpc_url: Option<String>) -> bool {
+        self.insert(url, capacity, grpc_url, false)
+    }
+
+    /// Adds a worker which never expires.
+    pub fn pin(&self, url: &str, capacity: usize, grpc_url: Option<String>) {
+        self.insert(url, capacity, grpc_url, true);
+    }
+
+    fn insert(&self, url: &str, capacity: usize, grpc_url: Option<String>, pinned: bool) -> bool {
+        let mut workers = self.workers.write().unwrap();
+        match workers.get_mut(url) {
+            Some(entry) => {
+                // keep the outstanding count, tiles may still be in flight to it
+                entry.last_seen = Instant::now();
+                entry.capacity = capacity;
+                entry.grpc_url = grpc_url;
+                entry.pinned |= pinned;
+                false
+            }
+            None => {
+                let entry = WorkerEntry {
+                    last_seen: Instant::now(),
+                    pinned,
+                    capacity,
+                    grpc_url,
+                    outstanding: Arc::new(AtomicUsize::new(0)),
+                    slots: Arc::new(Semaphore::new(self.max_in_flight)),
+                    agreements: 0,
+                    disagreements: 0,
+                    strikes: 0,
+                    quarantined_until: None,
+                };
+                workers.insert(url.to_string(), entry);
+                true
+            }
+        }
+    }
+
+    /// Refreshes a known worker. Returns false if the worker is unknown (for
+    /// example because it already expired) and needs to register again.
+    pub fn heartbeat(&self, url: &str) -> bool {
+        let mut workers = self.workers.write().unwrap();
+        match workers.get_mut(url) {
+            Some(entry) => {
+                entry.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every worker whose last heartbeat is older than the ttl, returning their URLs.
+    pub fn expire(&self) -> Vec<String> {
+        let mut workers = self.workers.write().unwrap();
+        let ttl = self.ttl;
+        let expired: Vec<String> = workers
+            .iter()
+            .filter(|(_, entry)| !entry.is_live(ttl))
+            .map(|(url, _)| url.clone())
+            .collect();
+        for url in &expired {
+            workers.remove(url);
+        }
+        expired
+    }
+
+    /// Base URLs of the workers that are currently live and not quarantined, in a stable order.
+    pub fn live_workers(&self) -> Vec<String> {
+        self.candidates().into_iter().map(|candidate| candidate.url).collect()
+    }
+
+    /// Records whether `url` agreed with the majority on a tile computed
+    /// redundantly. Returns true if that disagreement got it quarantined.
+    pub fn record_vote(&self, url: &str, agreed: bool) -> bool {
+        let mut workers = self.workers.write().unwrap();
+        let Some(entry) = workers.get_mut(url) else {
+            return false;
+        };
+        // a quarantine that has run out wipes the slate clean
+        if entry.quarantined_until.is_some() && !entry.is_quarantined() {
+            entry.quarantined_until = None;
+            entry.strikes = 0;
+        }
+        if agreed {
+            entry.agreements += 1;
+            return false;
+        }
+        entry.disagreements += 1;
+        entry.strikes += 1;
+        if self.quarantine_after == 0 || entry.strikes < self.quarantine_after || entry.is_quarantined() {
+            return false;
+        }
+        entry.quarantined_until = Some(Instant::now() + self.quarantine_for);
+        true
+    }
+
+    /// Workers currently in quarantine.
+    pub fn quarantined(&self) -> usize {
+        self.workers.read().unwrap().values().filter(|entry| entry.is_quarantined()).count()
+    }
+
+    /// The live workers with their current load, in a stable order. Workers
+    /// in quarantine are left out.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let workers = self.workers.read().unwrap();
+        let mut candidates: Vec<Candidate> = workers
+            .iter()
+            .filter(|(_, entry)| entry.is_live(self.ttl) && !entry.is_quarantined())
+            .map(|(url, entry)| Candidate {
+                url: url.clone(),
+                capacity: entry.capacity,
+                outstanding: entry.outstanding.load(Ordering::Relaxed),
+                grpc_url: entry.grpc_url.clone(),
+                has_free_slot: entry.slots.available_permits() > 0,
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.url.cmp(&b.url));
+        candidates
+    }
+
+    /// Waits for one of `url`'s in-flight slots, then counts a request to it as
+    /// outstanding until the guard is dropped. `None` if the worker is no longer registered.
+    pub async fn begin_request(&self, url: &str) -> Option<OutstandingGuard> {
+        let (outstanding, slots) = {
+            let workers = self.workers.read().unwrap();
+            let entry = workers.get(url)?;
+            (Arc::clone(&entry.outstanding), Arc::clone(&entry.slots))
+        };
+        let slot = slots.acquire_owned().await.ok()?;
+        outstanding.fetch_add(1, Ordering::Relaxed);
+        Some(OutstandingGuard { outstanding, _slot: slot })
+    }
+
+    pub fn statuses(&self) -> Vec<WorkerStatus> {
+        let workers = self.workers.read().unwrap();
+        let mut statuses: Vec<WorkerStatus> = workers
+            .iter()
+            .map(|(url, entry)| WorkerStatus {
+                url: url.clone(),
+                last_seen_ms: entry.last_seen.elapsed().as_millis(),
+                pinned: entry.pinned,
+                capacity: entry.capacity,
+                outstanding: entry.outstanding.load(Ordering::Relaxed),
+                transport: if entry.grpc_url.is_some() { "grpc" } else { "http" },
+                grpc_url: entry.grpc_url.clone(),
+                agreements: entry.agreements,
+                disagreements: entry.disagreements,
+                quarantined_ms: entry
+                    .quarantined_until
+                    .filter(|_| entry.is_quarantined())
+                    .map(|until| until.saturating_duration_since(Instant::now()).as_millis()),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.url.cmp(&b.url));
+        statuses
+    }
+}
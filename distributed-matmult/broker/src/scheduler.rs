@@ -0,0 +1,204 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+use crate::registry::Candidate;
+
+/// Which strategy the broker uses to choose a worker for each tile.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulerKind {
+    /// Every live worker in turn, regardless of load.
+    #[default]
+    RoundRobin,
+    /// The worker with the fewest tiles in flight.
+    LeastOutstanding,
+    /// The worker with the fewest tiles in flight relative to its capacity,
+    /// so a worker with twice the capacity is given twice the tiles.
+    WeightedCapacity,
+}
+
+impl SchedulerKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SchedulerKind::RoundRobin => "round_robin",
+            SchedulerKind::LeastOutstanding => "least_outstanding",
+            SchedulerKind::WeightedCapacity => "weighted_capacity",
+        }
+    }
+
+    pub fn build(self) -> Box<dyn Scheduler> {
+        match self {
+            SchedulerKind::RoundRobin => Box::new(RoundRobin::default()),
+            SchedulerKind::LeastOutstanding => Box::new(LeastOutstanding::default()),
+            SchedulerKind::WeightedCapacity => Box::new(WeightedCapacity::default()),
+        }
+    }
+}
+
+impl std::str::FromStr for SchedulerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(SchedulerKind::RoundRobin),
+            "least_outstanding" => Ok(SchedulerKind::LeastOutstanding),
+            "weighted_capacity" => Ok(SchedulerKind::WeightedCapacity),
+            _ => Err("expected round_robin, least_outstanding or weighted_capacity".to_string()),
+        }
+    }
+}
+
+/// Chooses which worker the next tile goes to.
+pub trait Scheduler: Send + Sync {
+    /// Index into `candidates`, which is never empty, of the chosen worker.
+    fn pick(&self, candidates: &[Candidate]) -> usize;
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Scheduler for RoundRobin {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+    }
+}
+
+#[derive(Default)]
+pub struct LeastOutstanding {
+    // rotates where the search starts, so ties don't all land on the first worker
+    next: AtomicUsize,
+}
+
+impl Scheduler for LeastOutstanding {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        least_by(candidates, start, |candidate| candidate.outstanding as f64)
+    }
+}
+
+#[derive(Default)]
+pub struct WeightedCapacity {
+    next: AtomicUsize,
+}
+
+impl Scheduler for WeightedCapacity {
+    fn pick(&self, candidates: &[Candidate]) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        // the load the worker would have with one more tile, per unit of capacity
+        least_by(candidates, start, |candidate| {
+            (candidate.outstanding + 1) as f64 / candidate.capacity.max(1) as f64
+        })
+    }
+}
+
+// index of the candidate with the lowest load, checking them in order from `start`
+fn least_by(candidates: &[Candidate], start: usize, load: impl Fn(&Candidate) -> f64) -> usize {
+    let len = candidates.len();
+    (0..len)
+        .map(|offset| (start + offset) % len)
+        .min_by(|&a, &b| load(&candidates[a]).total_cmp(&load(&candidates[b])))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(loads: &[(usize, usize)]) -> Vec<Candidate> {
+        loads
+            .iter()
+            .enumerate()
+            .map(|(i, &(outstanding, capacity))| Candidate {
+                url: format!("http://worker-{}", i),
+                capacity,
+                outstanding,
+                grpc_url: None,
+                has_free_slot: true,
+            })
+            .collect()
+    }
+
+    // sends `tiles` tiles, none of which finish, returning how many each worker got
+    fn dispatch(scheduler: &dyn Scheduler, mut candidates: Vec<Candidate>, tiles: usize) -> Vec<usize> {
+        let mut counts = vec![0; candidates.len()];
+        for _ in 0..tiles {
+            let picked = scheduler.pick(&candidates);
+            candidates[picked].outstanding += 1;
+            counts[picked] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin_takes_every_worker_in_turn_regardless_of_load() {
+        let scheduler = RoundRobin::default();
+        let candidates = candidates(&[(5, 1), (0, 1), (9, 1)]);
+
+        let picks: Vec<usize> = (0..7).map(|_| scheduler.pick(&candidates)).collect();
+
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn least_outstanding_picks_the_least_loaded_worker() {
+        let scheduler = LeastOutstanding::default();
+        let candidates = candidates(&[(3, 1), (1, 1), (2, 1)]);
+
+        // wherever the search starts
+        for _ in 0..3 {
+            assert_eq!(scheduler.pick(&candidates), 1);
+        }
+    }
+
+    #[test]
+    fn least_outstanding_rotates_between_tied_workers() {
+        let scheduler = LeastOutstanding::default();
+        let candidates = candidates(&[(0, 1), (4, 1), (0, 1), (0, 1)]);
+
+        let picks: Vec<usize> = (0..4).map(|_| scheduler.pick(&candidates)).collect();
+
+        // each search starts one worker further on, and passes over the busy one
+        assert_eq!(picks, [0, 2, 2, 3]);
+    }
+
+    #[test]
+    fn least_outstanding_evens_out_the_tiles() {
+        let counts = dispatch(&LeastOutstanding::default(), candidates(&[(0, 1), (0, 4), (0, 1)]), 30);
+
+        // capacity makes no difference to it
+        assert_eq!(counts, [10, 10, 10]);
+    }
+
+    #[test]
+    fn weighted_capacity_gives_twice_the_tiles_to_twice_the_capacity() {
+        let counts = dispatch(&WeightedCapacity::default(), candidates(&[(0, 1), (0, 2)]), 30);
+
+        assert_eq!(counts, [10, 20]);
+    }
+
+    #[test]
+    fn weighted_capacity_weighs_tiles_already_in_flight() {
+        let scheduler = WeightedCapacity::default();
+
+        // 5 of 4 slots against 2 of 1 and 3 of 1
+        assert_eq!(scheduler.pick(&candidates(&[(2, 1), (5, 4), (3, 1)])), 1);
+        // a worker with no capacity counts as having one
+        assert_eq!(scheduler.pick(&candidates(&[(0, 0), (2, 1)])), 0);
+    }
+
+    #[test]
+    fn least_by_searches_from_the_start_and_wraps_around() {
+        let candidates = candidates(&[(1, 1), (0, 1), (0, 1)]);
+        let load = |candidate: &Candidate| candidate.outstanding as f64;
+
+        assert_eq!(least_by(&candidates, 0, load), 1);
+        assert_eq!(least_by(&candidates, 2, load), 2);
+        // starts past the end wrap around to the front
+        assert_eq!(least_by(&candidates, 4, load), 1);
+        assert_eq!(least_by(&candidates, 3, load), 1);
+        assert_eq!(least_by(&[], 0, load), 0);
+    }
+}
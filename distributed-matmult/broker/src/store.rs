@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::BrokerError;
+use crate::types::{MatrixInput, input_shape};
+
+/// Matrices uploaded with `POST /matrices`, kept until deleted so requests can
+/// name them by ID instead of sending them again. Together they hold at most
+/// `max_cells` values, of which a sparse matrix only holds those it lists.
+pub struct MatrixStore {
+    max_cells: usize,
+    matrices: RwLock<HashMap<String, Arc<StoredMatrix>>>,
+}
+
+/// A stored matrix, which is checked to be rectangular and non-empty on the way
+/// in. A sparse one is kept as it was sent, its parts are checked whenever a
+/// request uses it, as they would be if it were sent inline.
+pub struct StoredMatrix {
+    pub id: String,
+    pub rows: usize,
+    pub cols: usize,
+    pub matrix: Arc<MatrixInput>,
+}
+
+/// Body of `POST /matrices`.
+#[derive(Deserialize, Debug)]
+pub struct UploadRequest {
+    pub matrix: MatrixInput,
+}
+
+/// What `POST /matrices` answers with, and `GET /matrices/{id}` too along with the matrix.
+#[derive(Serialize)]
+pub struct MatrixReport<'a> {
+    pub id: &'a str,
+    pub rows: usize,
+    pub cols: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<&'a MatrixInput>,
+}
+
+impl StoredMatrix {
+    /// The matrix's ID and shape, and its values if `with_values`.
+    pub fn report(&self, with_values: bool) -> MatrixReport<'_> {
+        MatrixReport { id: &self.id, rows: self.rows, cols: self.cols, matrix: with_values.then_some(&*self.matrix) }
+    }
+}
+
+impl MatrixStore {
+    pub fn new(max_cells: usize) -> Self {
+        MatrixStore { max_cells, matrices: RwLock::new(HashMap::new()) }
+    }
+
+    /// Checks the shape of `matrix` and stores it under a new ID, unless that
+    /// would take the store over its limit.
+    pub fn insert(&self, matrix: MatrixInput) -> Result<Arc<StoredMatrix>, BrokerError> {
+        let (rows, cols) = input_shape("matrix", &matrix)?;
+        let mut matrices = self.matrices.write().unwrap();
+        let stored: usize = matrices.values().map(|stored| stored.matrix.cells()).sum();
+        if stored + matrix.cells() > self.max_cells {
+            return Err(BrokerError::StoreFull(format!(
+                "Storing a {}x{} matrix of {} cells would take the broker over its limit of {} stored cells, {} of \
+                 which are in use; delete some matrices first",
+                rows, cols, matrix.cells(), self.max_cells, stored
+            )));
+        }
+        let stored = Arc::new(StoredMatrix { id: Uuid::new_v4().to_string(), rows, cols, matrix: Arc::new(matrix) });
+        matrices.insert(stored.id.clone(), Arc::clone(&stored));
+        Ok(stored)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<StoredMatrix>, BrokerError> {
+        self.matrices.read().unwrap().get(id).cloned().ok_or_else(|| not_found(id))
+    }
+
+    /// Removes a matrix. Requests already using it keep it until they finish.
+    pub fn remove(&self, id: &str) -> Result<(), BrokerError> {
+        self.matrices.write().unwrap().remove(id).map(|_| ()).ok_or_else(|| not_found(id))
+    }
+}
+
+fn not_found(id: &str) -> BrokerError {
+    BrokerError::NotFound(format!("No stored matrix with ID {}", id))
+}
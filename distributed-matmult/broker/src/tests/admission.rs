@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use super::harness::{
+    Behaviour, MockWorker, broker, matrix, multiply, over_grpc, reference, result, send, send_with_headers,
+    wait_for_job,
+};
+
+#[tokio::test]
+async fn jobs_beyond_the_pending_tile_budget_are_turned_away_until_it_frees_up() {
+    let worker = MockWorker::start(Behaviour::Slow(Duration::from_millis(200)));
+    let broker = broker(&[&worker.url], |config| {
+        config.max_pending_tiles = 4;
+        config.retry_after_secs = 7;
+    });
+    let (left, right) = (matrix(4, 3, 1), matrix(3, 4, 2));
+
+    // 2x2 tiles of a 4x4 product take up the whole budget while the worker is slow
+    let running = tokio::spawn({
+        let (broker, body) = (Arc::clone(&broker), json!({ "left": left, "right": right }));
+        async move { multiply(&broker, body).await }
+    });
+    while broker.pending_tiles() == 0 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert_eq!(broker.pending_tiles(), 4);
+
+    for path in ["/multiply_matrices_distributed", "/jobs"] {
+        let body = json!({ "left": [[1]], "right": [[1]] });
+        let (status, headers, reply) = send_with_headers(&broker, "POST", path, Some(body)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}: {}", path, reply);
+        assert_eq!(reply["code"], "overloaded");
+        assert_eq!(reply["details"]["retry_after_secs"], 7);
+        assert_eq!(headers["Retry-After"], "7", "{}", path);
+    }
+
+    let (status, reply) = running.await.unwrap();
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    // neither refused request sent the worker anything
+    assert_eq!(worker.requests(), 4);
+    assert_eq!(broker.pending_tiles(), 0);
+
+    // a job bigger than the whole budget still gets in once nothing else is pending
+    let (left, right) = (matrix(6, 2, 3), matrix(2, 6, 4));
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(broker.pending_tiles(), 0);
+}
+
+#[tokio::test]
+async fn jobs_beyond_the_queue_limit_are_turned_away() {
+    let worker = MockWorker::start(Behaviour::Slow(Duration::from_millis(300)));
+    let broker = broker(&[&worker.url], |config| {
+        config.max_running_jobs = 1;
+        config.max_queued_jobs = 1;
+        config.retry_after_secs = 3;
+    });
+    let body = json!({ "left": [[2]], "right": [[3]] });
+
+    let (status, running) = send(&broker, "POST", "/jobs", Some(body.clone())).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", running);
+    let running = running["id"].as_str().unwrap().to_string();
+    wait_for_job(&broker, &running, "running").await;
+
+    // the one running slot is taken, so the next job waits in the queue
+    let (status, queued) = send(&broker, "POST", "/jobs", Some(body.clone())).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", queued);
+    assert_eq!(queued["status"], "queued");
+    let queued = queued["id"].as_str().unwrap().to_string();
+
+    // and with the queue full the one after that is refused
+    let (status, headers, reply) = send_with_headers(&broker, "POST", "/jobs", Some(body.clone())).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", reply);
+    assert_eq!(reply["code"], "too_many_requests");
+    assert_eq!(reply["details"]["retry_after_secs"], 3);
+    assert_eq!(headers["Retry-After"], "3");
+
+    // both admitted jobs still finish, one after the other
+    for id in [&running, &queued] {
+        let report = wait_for_job(&broker, id, "done").await;
+        assert_eq!(report["result"], json!([[6]]));
+    }
+    assert_eq!(worker.requests(), 2);
+    assert_eq!(broker.pending_tiles(), 0);
+
+    // once the queue has drained there is room again
+    let (status, reply) = send(&broker, "POST", "/jobs", Some(body)).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", reply);
+}
+
+#[tokio::test]
+async fn no_worker_is_sent_more_tiles_at_once_than_its_in_flight_limit() {
+    for grpc in [false, true] {
+        let worker = MockWorker::start_grpc(Behaviour::Slow(Duration::from_millis(50)));
+        let broker = broker(&[&worker.url], |config| {
+            config.max_in_flight_per_worker = 2;
+            if grpc {
+                over_grpc(config, &worker);
+            }
+        });
+        let (left, right) = (matrix(8, 3, 5), matrix(3, 8, 6));
+
+        let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+        assert_eq!(status, StatusCode::OK, "grpc {}: {}", grpc, reply);
+        assert_eq!(result(&reply), reference(&left, &right));
+        // 16 tiles, never more than two of them at the worker at once
+        assert_eq!(worker.requests(), 16);
+        assert_eq!(worker.max_concurrent(), 2, "grpc {}", grpc);
+    }
+}
+
+#[tokio::test]
+async fn no_more_tiles_are_in_flight_than_the_broker_wide_limit() {
+    let worker = MockWorker::start(Behaviour::Slow(Duration::from_millis(50)));
+    let broker = broker(&[&worker.url], |config| {
+        config.max_in_flight_tiles = 3;
+        config.max_in_flight_per_worker = 16;
+    });
+    let (left, right) = (matrix(8, 3, 7), matrix(3, 8, 8));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(worker.requests(), 16);
+    assert_eq!(worker.max_concurrent(), 3);
+}
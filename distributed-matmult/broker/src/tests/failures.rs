@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, matrix, multiply, reference, result, unreachable_url};
+
+// every tile of a job whose workers fail is retried on a healthy one, and the
+// job still comes out right
+async fn assert_recovers_from(failure: Behaviour) {
+    let failing = MockWorker::start(failure.clone());
+    let healthy = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&failing.url, &healthy.url], |config| config.request_timeout_ms = 200);
+    let (left, right) = (matrix(4, 3, 11), matrix(3, 4, 12));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{:?}: {}", failure, reply);
+    assert_eq!(result(&reply), reference(&left, &right), "{:?}", failure);
+    assert!(failing.requests() > 0, "{:?}", failure);
+    // each of the 4 tiles is answered once by the healthy worker
+    assert_eq!(healthy.requests(), 4, "{:?}", failure);
+}
+
+#[tokio::test]
+async fn server_errors_are_retried_on_another_worker() {
+    assert_recovers_from(Behaviour::Fail(StatusCode::INTERNAL_SERVER_ERROR)).await;
+    assert_recovers_from(Behaviour::Fail(StatusCode::SERVICE_UNAVAILABLE)).await;
+}
+
+#[tokio::test]
+async fn malformed_responses_are_retried_on_another_worker() {
+    assert_recovers_from(Behaviour::Malformed).await;
+}
+
+#[tokio::test]
+async fn blocks_of_the_wrong_shape_are_retried_on_another_worker() {
+    assert_recovers_from(Behaviour::WrongShape).await;
+}
+
+#[tokio::test]
+async fn slow_workers_time_out_and_are_retried_on_another_worker() {
+    assert_recovers_from(Behaviour::Slow(Duration::from_secs(5))).await;
+}
+
+#[tokio::test]
+async fn unreachable_workers_are_skipped() {
+    let healthy = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&unreachable_url(), &healthy.url], |_| {});
+    let (left, right) = (matrix(4, 3, 13), matrix(3, 4, 14));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+}
+
+#[tokio::test]
+async fn a_lone_worker_gets_its_failed_tile_back() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    worker.script([Behaviour::Fail(StatusCode::BAD_GATEWAY), Behaviour::Malformed]);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(2, 2, 15), matrix(2, 2, 16));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(worker.requests(), 3);
+}
+
+#[tokio::test]
+async fn a_lone_worker_which_fails_once_is_retried() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    worker.script([Behaviour::Fail(StatusCode::INTERNAL_SERVER_ERROR)]);
+    let broker = broker(&[&worker.url], |_| {});
+
+    let (status, reply) = multiply(&broker, json!({ "left": [[2]], "right": [[3]] })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(reply["result"], json!([[6]]));
+    assert_eq!(worker.requests(), 2);
+}
+
+#[tokio::test]
+async fn jobs_fail_once_the_retry_budget_is_spent() {
+    let worker = MockWorker::start(Behaviour::Fail(StatusCode::INTERNAL_SERVER_ERROR));
+    let broker = broker(&[&worker.url], |_| {});
+
+    let body = json!({ "left": [[1]], "right": [[1]], "retry_budget": 2 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(reply["code"], "bad_worker_response");
+    assert_eq!(reply["details"]["worker"], worker.url);
+    assert!(reply["message"].as_str().unwrap().contains("retry budget of 2 is exhausted"), "{}", reply);
+    // the first attempt and both retries
+    assert_eq!(worker.requests(), 3);
+}
+
+#[tokio::test]
+async fn tiles_a_worker_rejects_are_not_retried() {
+    let workers = [
+        MockWorker::start(Behaviour::Fail(StatusCode::BAD_REQUEST)),
+        MockWorker::start(Behaviour::Fail(StatusCode::BAD_REQUEST)),
+    ];
+    let broker = broker(&[&workers[0].url, &workers[1].url], |_| {});
+
+    let (status, reply) = multiply(&broker, json!({ "left": [[1]], "right": [[1]] })).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(reply["code"], "bad_worker_response");
+    assert_eq!(workers[0].requests() + workers[1].requests(), 1);
+}
+
+#[tokio::test]
+async fn worker_overflow_reaches_the_client() {
+    let worker = MockWorker::start(Behaviour::Overflow);
+    let broker = broker(&[&worker.url], |_| {});
+
+    let body = json!({ "left": matrix(4, 2, 17), "right": matrix(2, 4, 18), "overflow": "checked", "tile_rows": 4 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(reply["code"], "overflow");
+    assert_eq!(reply["details"]["policy"], "checked");
+    // the worker names the first cell of the tile it was sent
+    let cell = &reply["details"]["cell"];
+    assert_eq!(cell[0], 0);
+    assert!(cell[1] == 0 || cell[1] == 2, "{}", reply);
+}
+
+#[tokio::test]
+async fn no_workers_means_service_unavailable() {
+    let broker = broker(&[], |_| {});
+
+    let (status, reply) = multiply(&broker, json!({ "left": [[1]], "right": [[1]] })).await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(reply["code"], "worker_unavailable");
+}
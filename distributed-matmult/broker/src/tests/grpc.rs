@@ -0,0 +1,163 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use matmult_common::matrix::DenseMatrix;
+use serde_json::json;
+use tokio::sync::oneshot;
+use tonic::transport::server::TcpIncoming;
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, get, matrix, multiply, over_grpc, reference, result};
+use crate::context::BrokerContext;
+use crate::grpc::{self, BrokerService, pb};
+
+// the broker's gRPC service on an ephemeral localhost port, and a client of it;
+// the server shuts down when the sender is dropped
+async fn serve(broker: &Arc<BrokerContext>) -> (pb::broker_client::BrokerClient<tonic::transport::Channel>, oneshot::Sender<()>) {
+    let incoming = TcpIncoming::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let url = format!("http://{}", incoming.local_addr().unwrap());
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let service = pb::broker_server::BrokerServer::new(BrokerService::new(Arc::clone(broker)));
+    let server = tonic::transport::Server::builder().add_service(service).serve_with_incoming_shutdown(incoming, async {
+        stopped.await.ok();
+    });
+    tokio::spawn(server);
+    (pb::broker_client::BrokerClient::connect(url).await.unwrap(), shutdown)
+}
+
+// rows of a test matrix as a gRPC matrix of i32s
+fn proto(rows: &[Vec<i64>]) -> pb::Matrix {
+    let data = rows.iter().flatten().map(|&value| value as i32).collect();
+    grpc::to_proto(DenseMatrix::new(rows.len(), rows[0].len(), data).unwrap())
+}
+
+// a gRPC result as rows, as `result` gives them for a JSON reply
+fn rows(result: DenseMatrix<i32>) -> Vec<Vec<i64>> {
+    (0..result.rows()).map(|i| result.row(i).iter().map(|&value| value as i64).collect()).collect()
+}
+
+#[tokio::test]
+async fn tiles_sent_over_grpc_match_the_reference() {
+    let worker = MockWorker::start_grpc(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| over_grpc(config, &worker));
+    let (left, right) = (matrix(6, 4, 91), matrix(4, 5, 92));
+
+    let (_, workers) = get(&broker, "/workers").await;
+    assert_eq!(workers[0]["transport"], "grpc", "{}", workers);
+    for strategy in ["blocks", "dot_products"] {
+        for (tile_rows, tile_cols) in [(1, 1), (2, 3), (64, 64)] {
+            let body = json!({
+                "left": left, "right": right, "strategy": strategy, "tile_rows": tile_rows, "tile_cols": tile_cols,
+            });
+            let (status, reply) = multiply(&broker, body).await;
+            assert_eq!(status, StatusCode::OK, "{} in {}x{} tiles: {}", strategy, tile_rows, tile_cols, reply);
+            assert_eq!(result(&reply), reference(&left, &right), "{} in {}x{} tiles", strategy, tile_rows, tile_cols);
+        }
+    }
+    // nothing was streamed, every block fit in one message
+    assert_eq!(worker.streamed_parts(), 0);
+}
+
+#[tokio::test]
+async fn blocks_too_big_for_one_message_are_streamed() {
+    let worker = MockWorker::start_grpc(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| over_grpc(config, &worker));
+    // 40 rows of 16384 i32s is 2.5 MiB of left block, over the 1 MiB a message holds
+    let (left, right) = (matrix(40, 16384, 93), matrix(16384, 4, 94));
+
+    let body = json!({ "left": left, "right": right, "tile_rows": 40, "tile_cols": 4 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply["message"]);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(worker.requests(), 1);
+    // a header, the left block in three runs of rows and the right one in one
+    assert_eq!(worker.streamed_parts(), 5);
+}
+
+#[tokio::test]
+async fn grpc_worker_failures_are_retried_or_reported() {
+    let worker = MockWorker::start_grpc(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| over_grpc(config, &worker));
+    let (left, right) = (matrix(2, 2, 95), matrix(2, 2, 96));
+
+    // an unavailable worker and a reply without its result are both tried again
+    worker.script([Behaviour::Fail(StatusCode::SERVICE_UNAVAILABLE), Behaviour::Malformed]);
+    let body = json!({ "left": left, "right": right, "tile_rows": 2 });
+    let (status, reply) = multiply(&broker, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(worker.requests(), 3);
+
+    worker.script([Behaviour::Overflow]);
+    let body = json!({ "left": left, "right": right, "tile_rows": 2, "overflow": "checked" });
+    let (status, reply) = multiply(&broker, body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", reply);
+    assert_eq!(reply["code"], "overflow");
+    assert_eq!(reply["details"]["policy"], "checked");
+    assert_eq!(reply["details"]["cell"], json!([0, 0]));
+}
+
+#[tokio::test]
+async fn the_broker_service_multiplies() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (mut client, _shutdown) = serve(&broker).await;
+    let (left, right) = (matrix(5, 3, 97), matrix(3, 4, 98));
+
+    let request = pb::MultiplyRequest { left: Some(proto(&left)), right: Some(proto(&right)), ..Default::default() };
+    let reply = client.multiply(request).await.unwrap().into_inner();
+
+    let product = grpc::dense::<i32>("result", reply.result).unwrap();
+    assert_eq!(rows(product), reference(&left, &right));
+
+    let request = pb::MultiplyRequest { left: Some(proto(&left)), right: Some(proto(&[vec![1]])), ..Default::default() };
+    let status = client.multiply(request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status.message());
+}
+
+#[tokio::test]
+async fn the_broker_service_streams_both_ways() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| {
+        config.tile_rows = 300;
+        config.tile_cols = 300;
+    });
+    let (mut client, _shutdown) = serve(&broker).await;
+    // a 600x600 product of i32s is 1.4 MiB, so comes back in two runs of rows
+    let (left, right) = (matrix(600, 1, 99), matrix(1, 600, 100));
+
+    let part = |part| pb::MultiplyPart { part: Some(part) };
+    let parts = vec![
+        part(pb::multiply_part::Part::Header(pb::MultiplyHeader::default())),
+        part(pb::multiply_part::Part::Left(proto(&left[..200]))),
+        part(pb::multiply_part::Part::Left(proto(&left[200..]))),
+        part(pb::multiply_part::Part::Right(proto(&right))),
+    ];
+    let mut replies = client.multiply_stream(futures::stream::iter(parts)).await.unwrap().into_inner();
+    let mut chunks = Vec::new();
+    while let Some(chunk) = replies.message().await.unwrap() {
+        chunks.push(chunk);
+    }
+
+    assert_eq!(chunks.len(), 2);
+    let product = grpc::concat_rows::<i32>("result", chunks).unwrap();
+    assert_eq!(rows(product), reference(&left, &right));
+}
+
+#[tokio::test]
+async fn streams_over_the_result_limit_are_cut_off() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| config.max_result_cells = 100);
+    let (mut client, _shutdown) = serve(&broker).await;
+
+    // ten runs of 20 cells, which pass the limit half way
+    let mut parts = vec![pb::MultiplyPart { part: Some(pb::multiply_part::Part::Header(pb::MultiplyHeader::default())) }];
+    parts.extend((0..10).map(|seed| pb::MultiplyPart {
+        part: Some(pb::multiply_part::Part::Left(proto(&matrix(2, 10, seed)))),
+    }));
+    let status = client.multiply_stream(futures::stream::iter(parts)).await.unwrap_err();
+
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted, "{}", status.message());
+    assert_eq!(worker.requests(), 0);
+}
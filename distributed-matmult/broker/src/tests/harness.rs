@@ -0,0 +1,690 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::ops::{Add, Mul};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{Stream, stream};
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::sync::oneshot;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Status, Streaming};
+use warp::http::{HeaderMap, StatusCode};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::config::BrokerConfig;
+use crate::context::BrokerContext;
+use crate::element::{DType, Element, OverflowPolicy};
+use crate::error::BrokerError;
+use crate::grpc::{self, overflow_policy, pb};
+use crate::types::{
+    BlockPayload, BlockResponse, DotProductBatch, DotProductBatchResponse, DotProductItem, DotProductResult,
+    SparseBlockPayload, SparseBlockResponse,
+};
+use crate::wire::WireFormat;
+
+/// How a mock worker answers a tile.
+#[derive(Clone, Debug)]
+pub enum Behaviour {
+    /// Computes the tile, as a real worker would.
+    Correct,
+    /// Computes the tile, but only answers after the delay.
+    Slow(Duration),
+    /// Answers with this status and an error body.
+    Fail(StatusCode),
+    /// Answers 422 `overflow` for the tile's first cell, as a worker does under
+    /// the `checked` policy.
+    Overflow,
+    /// Answers 200 with a body that can't be decoded.
+    Malformed,
+    /// Answers with a block one row short, or one dot product short.
+    WrongShape,
+    /// Answers a sparse tile claiming far more columns than could be filled in
+    /// dense, and any other tile as `WrongShape` does.
+    HugeShape,
+    /// Answers 200 with the tile's first cell off by one, which only
+    /// verification catches.
+    Corrupt,
+}
+
+// what the tests can see and script of a mock worker, shared with its routes
+struct MockState {
+    default: Behaviour,
+    script: Mutex<VecDeque<Behaviour>>,
+    requests: AtomicUsize,
+    streamed_parts: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl MockState {
+    // the scripted behaviour for the next request, if any is left, otherwise the default
+    fn next(&self) -> Behaviour {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.script.lock().unwrap().pop_front().unwrap_or_else(|| self.default.clone())
+    }
+
+    // counts a request in flight until the guard is dropped, keeping the most seen at once
+    fn enter(self: &Arc<Self>) -> InFlight {
+        let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+        InFlight(Arc::clone(self))
+    }
+}
+
+struct InFlight(Arc<MockState>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A worker on an ephemeral localhost port which serves `/multiply_block`,
+/// `/multiply_sparse_block` and `/calculate_dot_products` as the real worker
+/// does, unless told otherwise, and the `Worker` gRPC service too if started
+/// with `start_grpc`.
+/// It shuts down when dropped.
+pub struct MockWorker {
+    pub url: String,
+    /// Where the gRPC service listens, for `worker_grpc_urls`.
+    pub grpc_url: Option<String>,
+    state: Arc<MockState>,
+    _shutdown: oneshot::Sender<()>,
+    _grpc_shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockWorker {
+    /// Starts a worker which answers every tile with `behaviour`, unless scripted otherwise.
+    pub fn start(behaviour: Behaviour) -> MockWorker {
+        let state = Arc::new(MockState {
+            default: behaviour,
+            script: Mutex::new(VecDeque::new()),
+            requests: AtomicUsize::new(0),
+            streamed_parts: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        });
+        let route_state = Arc::clone(&state);
+        let routes = warp::post()
+            .and(warp::path::full())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and_then(move |path, content_type, body| answer(path, content_type, body, Arc::clone(&route_state)));
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let (addr, server) = warp::serve(routes).bind_with_graceful_shutdown(([127, 0, 0, 1], 0), async {
+            stopped.await.ok();
+        });
+        tokio::spawn(server);
+        MockWorker { url: format!("http://{}", addr), grpc_url: None, state, _shutdown: shutdown, _grpc_shutdown: None }
+    }
+
+    /// Starts a worker as `start` does, which also serves its tiles over gRPC.
+    pub fn start_grpc(behaviour: Behaviour) -> MockWorker {
+        let mut worker = MockWorker::start(behaviour);
+        let incoming = TcpIncoming::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = incoming.local_addr().unwrap();
+        let service = pb::worker_server::WorkerServer::new(MockGrpcWorker { state: Arc::clone(&worker.state) });
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let server = tonic::transport::Server::builder().add_service(service).serve_with_incoming_shutdown(incoming, async {
+            stopped.await.ok();
+        });
+        tokio::spawn(server);
+        worker.grpc_url = Some(format!("http://{}", addr));
+        worker._grpc_shutdown = Some(shutdown);
+        worker
+    }
+
+    /// Answers the next requests with `behaviours`, one each in order, before
+    /// going back to the default.
+    pub fn script(&self, behaviours: impl IntoIterator<Item = Behaviour>) {
+        self.state.script.lock().unwrap().extend(behaviours);
+    }
+
+    /// Tile requests this worker has been sent.
+    pub fn requests(&self) -> usize {
+        self.state.requests.load(Ordering::Relaxed)
+    }
+
+    /// Parts of the `ComputeBlockStream` calls this worker has been sent.
+    pub fn streamed_parts(&self) -> usize {
+        self.state.streamed_parts.load(Ordering::Relaxed)
+    }
+
+    /// The most tile requests this worker has been answering at once.
+    pub fn max_concurrent(&self) -> usize {
+        self.state.max_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// The URL of a port nothing listens on, for a worker that can't be reached.
+pub fn unreachable_url() -> String {
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+/// A broker with `workers` as its static workers, with short timeouts and
+/// backoffs so failures are quick to test. `configure` can change anything else.
+pub fn broker(workers: &[&str], configure: impl FnOnce(&mut BrokerConfig)) -> Arc<BrokerContext> {
+    let mut config = BrokerConfig {
+        workers: workers.iter().map(|url| url.to_string()).collect(),
+        request_timeout_ms: 1_000,
+        connect_timeout_ms: 200,
+        tile_rows: 2,
+        tile_cols: 2,
+        ..BrokerConfig::default()
+    };
+    config.retry.base_backoff_ms = 1;
+    config.retry.max_backoff_ms = 5;
+    configure(&mut config);
+    Arc::new(BrokerContext::new(config).unwrap())
+}
+
+/// Has the broker send `worker` its tiles over gRPC, for the `configure` of `broker`.
+pub fn over_grpc(config: &mut BrokerConfig, worker: &MockWorker) {
+    let grpc_url = worker.grpc_url.clone().expect("the worker was started with start_grpc");
+    config.worker_grpc_urls.insert(worker.url.clone(), grpc_url);
+}
+
+/// Posts `body` to `/multiply_matrices_distributed` on `broker`, returning the
+/// status and JSON body of the reply.
+pub async fn multiply(broker: &Arc<BrokerContext>, body: Value) -> (StatusCode, Value) {
+    send(broker, "POST", "/multiply_matrices_distributed", Some(body)).await
+}
+
+/// Gets `path` from `broker`, returning the status and JSON body of the reply.
+pub async fn get(broker: &Arc<BrokerContext>, path: &str) -> (StatusCode, Value) {
+    send(broker, "GET", path, None).await
+}
+
+/// Sends a request to `broker`, with `body` as JSON if there is one, returning
+/// the status and JSON body of the reply, `null` if it has none.
+pub async fn send(broker: &Arc<BrokerContext>, method: &str, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let (status, _, reply) = send_with_headers(broker, method, path, body).await;
+    (status, reply)
+}
+
+/// Sends a request to `broker` as `send` does, returning the reply's headers too.
+pub async fn send_with_headers(
+    broker: &Arc<BrokerContext>,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+) -> (StatusCode, HeaderMap, Value) {
+    let mut request = warp::test::request().method(method).path(path);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.reply(&crate::routes(Arc::clone(broker))).await;
+    let reply = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+    (response.status(), response.headers().clone(), reply)
+}
+
+/// Polls `GET /jobs/{id}` on `broker` until the job's status is `status`,
+/// returning its report. Panics if it takes longer than five seconds.
+pub async fn wait_for_job(broker: &Arc<BrokerContext>, id: &str, status: &str) -> Value {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let (_, report) = get(broker, &format!("/jobs/{}", id)).await;
+        if report["status"] == status {
+            return report;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "job {} never became {}: {}", id, status, report);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+/// Streams `GET /jobs/{id}/events` from `broker` until it ends, returning the
+/// name and JSON data of each event.
+pub async fn events(broker: &Arc<BrokerContext>, id: &str) -> Vec<(String, Value)> {
+    let path = format!("/jobs/{}/events", id);
+    let response = warp::test::request().path(&path).reply(&crate::routes(Arc::clone(broker))).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", String::from_utf8_lossy(response.body()));
+    sse_events(response.body())
+}
+
+/// The name and JSON data of each event in a Server-Sent Events body.
+pub fn sse_events(body: &[u8]) -> Vec<(String, Value)> {
+    let body = std::str::from_utf8(body).unwrap();
+    body.split("\n\n")
+        .filter_map(|event| {
+            let name = event.lines().find_map(|line| line.strip_prefix("event:"))?;
+            let data = event.lines().find_map(|line| line.strip_prefix("data:")).unwrap_or("null");
+            Some((name.to_string(), serde_json::from_str(data).unwrap()))
+        })
+        .collect()
+}
+
+/// A `rows` x `cols` matrix of small integers, different for each `seed`.
+pub fn matrix(rows: usize, cols: usize, seed: u64) -> Vec<Vec<i64>> {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (0..rows)
+        .map(|_| {
+            (0..cols)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    (state >> 33) as i64 % 19 - 9
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The product of `left` and `right` worked out sequentially, the answer every
+/// distributed multiplication must match.
+pub fn reference(left: &[Vec<i64>], right: &[Vec<i64>]) -> Vec<Vec<i64>> {
+    left.iter()
+        .map(|row| (0..right[0].len()).map(|j| row.iter().zip(right).map(|(a, b)| a * b[j]).sum()).collect())
+        .collect()
+}
+
+/// The result matrix of a successful reply, as integers.
+pub fn result(reply: &Value) -> Vec<Vec<i64>> {
+    serde_json::from_value(reply["result"].clone()).unwrap()
+}
+
+// the arithmetic a mock worker needs, which every element type has
+trait Arith: Element + Add<Output = Self> + Mul<Output = Self> {}
+
+impl<T: Element + Add<Output = T> + Mul<Output = T>> Arith for T {}
+
+// the tiles the broker sends, by element type, as the worker decodes them
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Block {
+    I32(BlockPayload<i32>),
+    I64(BlockPayload<i64>),
+    F32(BlockPayload<f32>),
+    F64(BlockPayload<f64>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SparseBlock {
+    I32(SparseBlockPayload<i32>),
+    I64(SparseBlockPayload<i64>),
+    F32(SparseBlockPayload<f32>),
+    F64(SparseBlockPayload<f64>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DotProducts {
+    I32(DotProductBatch<i32>),
+    I64(DotProductBatch<i64>),
+    F32(DotProductBatch<f32>),
+    F64(DotProductBatch<f64>),
+}
+
+async fn answer(
+    path: FullPath,
+    content_type: Option<String>,
+    body: Bytes,
+    state: Arc<MockState>,
+) -> Result<Response, Infallible> {
+    let _in_flight = state.enter();
+    let behaviour = state.next();
+    let format = content_type.as_deref().map_or(Some(WireFormat::Json), WireFormat::from_media_type);
+    let Some(format) = format else {
+        return Ok(error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", json!({})));
+    };
+    if let Behaviour::Slow(delay) = behaviour {
+        tokio::time::sleep(delay).await;
+    }
+
+    let reply = match path.as_str() {
+        "/multiply_block" => match format.decode::<Block>(&body) {
+            Ok(Block::I32(payload)) => block(payload, &behaviour, format),
+            Ok(Block::I64(payload)) => block(payload, &behaviour, format),
+            Ok(Block::F32(payload)) => block(payload, &behaviour, format),
+            Ok(Block::F64(payload)) => block(payload, &behaviour, format),
+            Err(e) => error(StatusCode::BAD_REQUEST, "invalid_request", json!({ "reason": e })),
+        },
+        "/multiply_sparse_block" => match format.decode::<SparseBlock>(&body) {
+            Ok(SparseBlock::I32(payload)) => sparse_block(payload, &behaviour, format),
+            Ok(SparseBlock::I64(payload)) => sparse_block(payload, &behaviour, format),
+            Ok(SparseBlock::F32(payload)) => sparse_block(payload, &behaviour, format),
+            Ok(SparseBlock::F64(payload)) => sparse_block(payload, &behaviour, format),
+            Err(e) => error(StatusCode::BAD_REQUEST, "invalid_request", json!({ "reason": e })),
+        },
+        "/calculate_dot_products" => match format.decode::<DotProducts>(&body) {
+            Ok(DotProducts::I32(batch)) => dot_products(batch, &behaviour, format),
+            Ok(DotProducts::I64(batch)) => dot_products(batch, &behaviour, format),
+            Ok(DotProducts::F32(batch)) => dot_products(batch, &behaviour, format),
+            Ok(DotProducts::F64(batch)) => dot_products(batch, &behaviour, format),
+            Err(e) => error(StatusCode::BAD_REQUEST, "invalid_request", json!({ "reason": e })),
+        },
+        _ => error(StatusCode::NOT_FOUND, "not_found", json!({})),
+    };
+    Ok(reply)
+}
+
+fn block<T: Arith>(payload: BlockPayload<T>, behaviour: &Behaviour, format: WireFormat) -> Response {
+    let cell = (payload.row_offset, payload.col_offset);
+    let result = block_product(&payload.left, &payload.right, behaviour);
+    tile_reply(&BlockResponse { result }, behaviour, format, payload.overflow, cell)
+}
+
+fn sparse_block<T: Arith>(payload: SparseBlockPayload<T>, behaviour: &Behaviour, format: WireFormat) -> Response {
+    let cell = (payload.row_offset, payload.col_offset);
+    let result = sparse_block_product(&payload.left, &payload.right, behaviour);
+    tile_reply(&SparseBlockResponse { result }, behaviour, format, payload.overflow, cell)
+}
+
+fn dot_products<T: Arith>(batch: DotProductBatch<T>, behaviour: &Behaviour, format: WireFormat) -> Response {
+    let cell = batch.items.first().map_or((0, 0), |item| (item.row_id, item.col_id));
+    let results = dot_product_results(&batch, behaviour);
+    tile_reply(&DotProductBatchResponse { results }, behaviour, format, batch.overflow, cell)
+}
+
+// the product of two blocks, spoilt as the behaviour says
+fn block_product<T: Arith>(left: &DenseMatrix<T>, right: &DenseMatrix<T>, behaviour: &Behaviour) -> DenseMatrix<T> {
+    let mut result = DenseMatrix::zeros(left.rows(), right.cols());
+    for i in 0..left.rows() {
+        for j in 0..right.cols() {
+            result.row_mut(i)[j] = (0..left.cols()).fold(T::default(), |sum, k| sum + left.get(i, k) * right.get(k, j));
+        }
+    }
+    spoil(result, behaviour)
+}
+
+// the right block comes transposed, each of its rows is a column of the tile
+fn sparse_block_product<T: Arith>(left: &CsrMatrix<T>, right: &CsrMatrix<T>, behaviour: &Behaviour) -> CsrMatrix<T> {
+    let (left, right) = (left.to_dense(), right.to_dense());
+    let mut result = DenseMatrix::zeros(left.rows(), right.rows());
+    for i in 0..left.rows() {
+        for j in 0..right.rows() {
+            result.row_mut(i)[j] = (0..left.cols()).fold(T::default(), |sum, k| sum + left.get(i, k) * right.get(j, k));
+        }
+    }
+    let result = CsrMatrix::from_dense(&spoil(result, behaviour));
+    match behaviour {
+        Behaviour::HugeShape => {
+            let (row_ptr, col_indices) = (result.row_ptr().to_vec(), result.col_indices().to_vec());
+            CsrMatrix::new(result.rows(), 1 << 40, row_ptr, col_indices, result.values().to_vec()).unwrap()
+        }
+        _ => result,
+    }
+}
+
+fn spoil<T: Arith>(mut result: DenseMatrix<T>, behaviour: &Behaviour) -> DenseMatrix<T> {
+    match behaviour {
+        Behaviour::WrongShape | Behaviour::HugeShape => {
+            result.block(0..result.rows() - 1, 0..result.cols()).to_matrix()
+        }
+        Behaviour::Corrupt => {
+            result.row_mut(0)[0] = result.get(0, 0) + one();
+            result
+        }
+        _ => result,
+    }
+}
+
+fn dot_product_results<T: Arith>(batch: &DotProductBatch<T>, behaviour: &Behaviour) -> Vec<DotProductResult<T>> {
+    let mut results: Vec<_> = batch
+        .items
+        .iter()
+        .map(|item| DotProductResult {
+            row_id: item.row_id,
+            col_id: item.col_id,
+            result: item.row.iter().zip(&item.col).fold(T::default(), |sum, (&a, &b)| sum + a * b),
+        })
+        .collect();
+    match behaviour {
+        Behaviour::WrongShape | Behaviour::HugeShape => drop(results.pop()),
+        Behaviour::Corrupt => results[0].result = results[0].result + one(),
+        _ => {}
+    }
+    results
+}
+
+fn one<T: Element>() -> T {
+    T::from_json(&1.into()).unwrap()
+}
+
+// the computed tile, or whatever the behaviour says to answer instead
+fn tile_reply<R: serde::Serialize>(
+    reply: &R,
+    behaviour: &Behaviour,
+    format: WireFormat,
+    overflow: OverflowPolicy,
+    cell: (usize, usize),
+) -> Response {
+    match behaviour {
+        Behaviour::Fail(status) => error(*status, "mock_failure", json!({})),
+        Behaviour::Overflow => {
+            error(StatusCode::UNPROCESSABLE_ENTITY, "overflow", json!({ "policy": overflow, "cell": cell }))
+        }
+        Behaviour::Malformed => {
+            warp::reply::with_header(b"not a tile".to_vec(), "content-type", format.content_type()).into_response()
+        }
+        Behaviour::Correct | Behaviour::Slow(_) | Behaviour::WrongShape | Behaviour::HugeShape | Behaviour::Corrupt => {
+            warp::reply::with_header(format.encode(reply).unwrap(), "content-type", format.content_type())
+                .into_response()
+        }
+    }
+}
+
+fn error(status: StatusCode, code: &str, details: Value) -> Response {
+    let body = json!({ "code": code, "message": format!("mock worker answered {}", status), "details": details });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+// the mock worker's gRPC service, which answers as its HTTP routes do
+struct MockGrpcWorker {
+    state: Arc<MockState>,
+}
+
+impl MockGrpcWorker {
+    // the behaviour for the next call, once a slow one has waited, and the call
+    // counted in flight until the guard is dropped
+    async fn next(&self) -> (Behaviour, InFlight) {
+        let in_flight = self.state.enter();
+        let behaviour = self.state.next();
+        if let Behaviour::Slow(delay) = behaviour {
+            tokio::time::sleep(delay).await;
+        }
+        (behaviour, in_flight)
+    }
+}
+
+type ReplyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl pb::worker_server::Worker for MockGrpcWorker {
+    async fn compute_block(&self, request: Request<pb::BlockRequest>) -> Result<tonic::Response<pb::BlockReply>, Status> {
+        let (behaviour, _in_flight) = self.next().await;
+        let request = request.into_inner();
+        let cell = (request.row_offset as usize, request.col_offset as usize);
+        let overflow = overflow_policy(request.overflow());
+        let reply = match grpc::dtype("left", request.left.as_ref().and_then(|left| left.values.as_ref())) {
+            Ok(DType::I32) => grpc_block::<i32>(request.left, request.right, &behaviour),
+            Ok(DType::I64) => grpc_block::<i64>(request.left, request.right, &behaviour),
+            Ok(DType::F32) => grpc_block::<f32>(request.left, request.right, &behaviour),
+            Ok(DType::F64) => grpc_block::<f64>(request.left, request.right, &behaviour),
+            Err(e) => Err(e),
+        };
+        grpc_reply(reply, &behaviour, overflow, cell)
+    }
+
+    async fn compute_sparse_block(
+        &self,
+        request: Request<pb::SparseBlockRequest>,
+    ) -> Result<tonic::Response<pb::SparseBlockReply>, Status> {
+        let (behaviour, _in_flight) = self.next().await;
+        let request = request.into_inner();
+        let cell = (request.row_offset as usize, request.col_offset as usize);
+        let overflow = overflow_policy(request.overflow());
+        let reply = match grpc::dtype("left", request.left.as_ref().and_then(|left| left.values.as_ref())) {
+            Ok(DType::I32) => grpc_sparse_block::<i32>(request.left, request.right, &behaviour),
+            Ok(DType::I64) => grpc_sparse_block::<i64>(request.left, request.right, &behaviour),
+            Ok(DType::F32) => grpc_sparse_block::<f32>(request.left, request.right, &behaviour),
+            Ok(DType::F64) => grpc_sparse_block::<f64>(request.left, request.right, &behaviour),
+            Err(e) => Err(e),
+        };
+        grpc_reply(reply, &behaviour, overflow, cell)
+    }
+
+    async fn compute_block_stream(
+        &self,
+        request: Request<Streaming<pb::BlockPart>>,
+    ) -> Result<tonic::Response<pb::BlockReply>, Status> {
+        let (behaviour, _in_flight) = self.next().await;
+        let mut parts = request.into_inner();
+        let (mut header, mut left, mut right) = (pb::BlockHeader::default(), Vec::new(), Vec::new());
+        while let Some(part) = parts.message().await? {
+            self.state.streamed_parts.fetch_add(1, Ordering::Relaxed);
+            match part.part {
+                Some(pb::block_part::Part::Header(part)) => header = part,
+                Some(pb::block_part::Part::Left(rows)) => left.push(rows),
+                Some(pb::block_part::Part::Right(rows)) => right.push(rows),
+                None => {}
+            }
+        }
+        let cell = (header.row_offset as usize, header.col_offset as usize);
+        let overflow = overflow_policy(header.overflow());
+        let reply = match grpc::dtype("left", left.first().and_then(|rows| rows.values.as_ref())) {
+            Ok(DType::I32) => grpc_block_parts::<i32>(left, right, &behaviour),
+            Ok(DType::I64) => grpc_block_parts::<i64>(left, right, &behaviour),
+            Ok(DType::F32) => grpc_block_parts::<f32>(left, right, &behaviour),
+            Ok(DType::F64) => grpc_block_parts::<f64>(left, right, &behaviour),
+            Err(e) => Err(e),
+        };
+        grpc_reply(reply, &behaviour, overflow, cell)
+    }
+
+    async fn dot_product(
+        &self,
+        _request: Request<pb::DotProductRequest>,
+    ) -> Result<tonic::Response<pb::DotProductReply>, Status> {
+        // the broker always sends its dot products as a stream
+        Err(Status::unimplemented("the broker streams its dot products"))
+    }
+
+    type DotProductStreamStream = ReplyStream<pb::DotProductReply>;
+
+    async fn dot_product_stream(
+        &self,
+        request: Request<Streaming<pb::DotProductRequest>>,
+    ) -> Result<tonic::Response<Self::DotProductStreamStream>, Status> {
+        let (behaviour, _in_flight) = self.next().await;
+        let mut requests = request.into_inner();
+        let mut received = Vec::new();
+        while let Some(request) = requests.message().await? {
+            received.push(request);
+        }
+        let first = received.first();
+        let cell = first.map_or((0, 0), |request| (request.row_id as usize, request.col_id as usize));
+        let overflow = first.map_or(OverflowPolicy::default(), |request| overflow_policy(request.overflow()));
+        let replies = match grpc::dtype("row", first.and_then(|request| request.row.as_ref())) {
+            Ok(DType::I32) => grpc_dot_products::<i32>(received, overflow, &behaviour),
+            Ok(DType::I64) => grpc_dot_products::<i64>(received, overflow, &behaviour),
+            Ok(DType::F32) => grpc_dot_products::<f32>(received, overflow, &behaviour),
+            Ok(DType::F64) => grpc_dot_products::<f64>(received, overflow, &behaviour),
+            Err(e) => Err(e),
+        };
+        let replies = grpc_reply(replies, &behaviour, overflow, cell)?.into_inner();
+        let stream: Self::DotProductStreamStream = Box::pin(stream::iter(replies.into_iter().map(Ok)));
+        Ok(tonic::Response::new(stream))
+    }
+}
+
+fn grpc_block<T: Arith>(
+    left: Option<pb::Matrix>,
+    right: Option<pb::Matrix>,
+    behaviour: &Behaviour,
+) -> Result<pb::BlockReply, BrokerError> {
+    let (left, right) = (grpc::dense::<T>("left", left)?, grpc::dense::<T>("right", right)?);
+    Ok(pb::BlockReply { result: Some(grpc::to_proto(block_product(&left, &right, behaviour))) })
+}
+
+fn grpc_block_parts<T: Arith>(
+    left: Vec<pb::Matrix>,
+    right: Vec<pb::Matrix>,
+    behaviour: &Behaviour,
+) -> Result<pb::BlockReply, BrokerError> {
+    let (left, right) = (grpc::concat_rows::<T>("left", left)?, grpc::concat_rows::<T>("right", right)?);
+    Ok(pb::BlockReply { result: Some(grpc::to_proto(block_product(&left, &right, behaviour))) })
+}
+
+fn grpc_sparse_block<T: Arith>(
+    left: Option<pb::SparseMatrix>,
+    right: Option<pb::SparseMatrix>,
+    behaviour: &Behaviour,
+) -> Result<pb::SparseBlockReply, BrokerError> {
+    let (left, right) = (grpc::sparse::<T>("left", left)?, grpc::sparse::<T>("right", right)?);
+    Ok(pb::SparseBlockReply { result: Some(grpc::sparse_to_proto(&sparse_block_product(&left, &right, behaviour))) })
+}
+
+fn grpc_dot_products<T: Arith>(
+    requests: Vec<pb::DotProductRequest>,
+    overflow: OverflowPolicy,
+    behaviour: &Behaviour,
+) -> Result<Vec<pb::DotProductReply>, BrokerError> {
+    let values = |values: Option<pb::Values>| values.and_then(|values| values.kind).and_then(T::from_values);
+    let items = requests
+        .into_iter()
+        .map(|request| DotProductItem {
+            row_id: request.row_id as usize,
+            col_id: request.col_id as usize,
+            row: values(request.row).unwrap_or_default(),
+            col: values(request.col).unwrap_or_default(),
+        })
+        .collect();
+    let results = dot_product_results(&DotProductBatch { overflow, items }, behaviour);
+    Ok(results
+        .into_iter()
+        .map(|result| pb::DotProductReply {
+            row_id: result.row_id as u64,
+            col_id: result.col_id as u64,
+            result: Some(pb::Scalar { kind: Some(scalar(result.result)) }),
+        })
+        .collect())
+}
+
+// one value as a gRPC scalar, by way of the values the element type packs into
+fn scalar<T: Element>(value: T) -> pb::scalar::Kind {
+    match T::into_values(vec![value]) {
+        pb::values::Kind::I32(array) => pb::scalar::Kind::I32(array.values[0]),
+        pb::values::Kind::I64(array) => pb::scalar::Kind::I64(array.values[0]),
+        pb::values::Kind::F32(array) => pb::scalar::Kind::F32(array.values[0]),
+        pb::values::Kind::F64(array) => pb::scalar::Kind::F64(array.values[0]),
+    }
+}
+
+// the computed reply, or whatever the behaviour says to answer instead, with the
+// status codes and metadata a real worker fails a call with
+fn grpc_reply<R: Default>(
+    reply: Result<R, BrokerError>,
+    behaviour: &Behaviour,
+    overflow: OverflowPolicy,
+    cell: (usize, usize),
+) -> Result<tonic::Response<R>, Status> {
+    let reply = reply.map_err(|e| Status::invalid_argument(e.to_string()))?;
+    match behaviour {
+        Behaviour::Fail(status) if status.is_server_error() => Err(Status::unavailable("mock_failure")),
+        Behaviour::Fail(_) => Err(Status::invalid_argument("mock_failure")),
+        Behaviour::Overflow => {
+            let mut status = Status::out_of_range("overflow");
+            let details = json!({ "policy": overflow, "cell": cell }).to_string();
+            status.metadata_mut().insert("error-code", "overflow".parse().unwrap());
+            status.metadata_mut().insert("error-details", details.parse().unwrap());
+            Err(status)
+        }
+        // a reply without its result
+        Behaviour::Malformed => Ok(tonic::Response::new(R::default())),
+        Behaviour::Correct | Behaviour::Slow(_) | Behaviour::WrongShape | Behaviour::HugeShape | Behaviour::Corrupt => {
+            Ok(tonic::Response::new(reply))
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, matrix, multiply, reference, result};
+use crate::hedge::LatencyWindow;
+
+// gives the broker a history of quick tiles, so anything slower gets hedged
+fn warm_up(latencies: &LatencyWindow) {
+    for _ in 0..20 {
+        latencies.record(Duration::from_millis(5));
+    }
+}
+
+#[tokio::test]
+async fn slow_tiles_are_hedged_on_another_worker() {
+    let slow = MockWorker::start(Behaviour::Slow(Duration::from_secs(5)));
+    let fast = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&slow.url, &fast.url], |config| {
+        config.request_timeout_ms = 10_000;
+        config.hedge.percentile = 95.0;
+    });
+    warm_up(&broker.latencies);
+    let (left, right) = (matrix(4, 3, 51), matrix(3, 4, 52));
+
+    let started = Instant::now();
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert!(slow.requests() > 0);
+    // the slow worker's tiles came from the fast one long before it would have answered
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn a_failed_hedge_leaves_the_original_to_answer() {
+    let slow = MockWorker::start(Behaviour::Slow(Duration::from_millis(200)));
+    let failing = MockWorker::start(Behaviour::Fail(StatusCode::INTERNAL_SERVER_ERROR));
+    let broker = broker(&[&slow.url, &failing.url], |config| config.hedge.percentile = 50.0);
+    warm_up(&broker.latencies);
+    let (left, right) = (matrix(2, 2, 53), matrix(2, 2, 54));
+
+    // tiles which land on the failing worker first are retried on the slow one
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "retry_budget": 8 })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert!(failing.requests() > 0);
+}
+
+#[tokio::test]
+async fn nothing_is_hedged_until_enough_tiles_have_been_timed() {
+    let slow = MockWorker::start(Behaviour::Slow(Duration::from_millis(200)));
+    let other = MockWorker::start(Behaviour::Slow(Duration::from_millis(200)));
+    let broker = broker(&[&slow.url, &other.url], |config| config.hedge.percentile = 50.0);
+    let (left, right) = (matrix(2, 2, 55), matrix(2, 2, 56));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(slow.requests() + other.requests(), 1);
+}
+
+#[test]
+fn latency_percentiles() {
+    let latencies = LatencyWindow::new(4);
+    assert_eq!(latencies.percentile(50.0, 1), None);
+    for ms in [40, 10, 30, 20, 50] {
+        latencies.record(Duration::from_millis(ms));
+    }
+    // the oldest sample has dropped out of the window
+    assert_eq!(latencies.percentile(50.0, 1), Some(Duration::from_millis(20)));
+    assert_eq!(latencies.percentile(99.0, 1), Some(Duration::from_millis(50)));
+    assert_eq!(latencies.percentile(0.0, 1), Some(Duration::from_millis(10)));
+    assert_eq!(latencies.percentile(50.0, 5), None);
+}
@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use warp::Reply;
+use warp::http::StatusCode;
+
+use super::harness::{
+    Behaviour, MockWorker, broker, events, get, matrix, reference, result, send, send_with_headers, sse_events,
+    wait_for_job,
+};
+use crate::context::BrokerContext;
+use crate::element::{DType, OverflowPolicy};
+use crate::jobs::JobEvent;
+
+// submits a job to `broker`, returning the report it was accepted with
+async fn submit(broker: &Arc<BrokerContext>, body: Value) -> Value {
+    let (status, report) = send(broker, "POST", "/jobs", Some(body)).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", report);
+    report
+}
+
+fn id(report: &Value) -> String {
+    report["id"].as_str().unwrap().to_string()
+}
+
+// submits a job to `broker` which stays queued until a one tile job ahead of it
+// is done, so a test can subscribe to its events before any are sent, with
+// `max_running_jobs` at 1 and the worker scripted to take its time over that tile
+async fn submit_behind_another(broker: &Arc<BrokerContext>, body: Value) -> Value {
+    submit(broker, json!({ "left": [[1]], "right": [[1]] })).await;
+    let report = submit(broker, body).await;
+    assert_eq!(report["status"], "queued");
+    report
+}
+
+fn names(events: &[(String, Value)]) -> Vec<&str> {
+    events.iter().map(|(name, _)| name.as_str()).collect()
+}
+
+#[tokio::test]
+async fn jobs_go_from_queued_through_running_to_done() {
+    let worker = MockWorker::start(Behaviour::Slow(Duration::from_millis(200)));
+    let broker = broker(&[&worker.url], |config| config.max_running_jobs = 1);
+    let (left, right) = (matrix(4, 3, 1), matrix(3, 4, 2));
+    let body = json!({ "left": left, "right": right });
+
+    let (status, headers, first) = send_with_headers(&broker, "POST", "/jobs", Some(body.clone())).await;
+    assert_eq!(status, StatusCode::ACCEPTED, "{}", first);
+    assert_eq!(headers["Location"], format!("/jobs/{}", id(&first)));
+    assert_eq!(first["dtype"], "i32");
+    let running = wait_for_job(&broker, &id(&first), "running").await;
+    assert!(running.get("result").is_none(), "{}", running);
+
+    // only one job runs at a time, so the second waits its turn
+    let second = submit(&broker, body).await;
+    assert_eq!(second["status"], "queued");
+    let (_, queued) = get(&broker, &format!("/jobs/{}", id(&second))).await;
+    assert_eq!(queued["status"], "queued");
+    assert_eq!(queued["progress"], json!({ "tiles_total": 0, "tiles_done": 0 }));
+
+    for report in [&first, &second] {
+        let done = wait_for_job(&broker, &id(report), "done").await;
+        assert_eq!(result(&done), reference(&left, &right));
+        assert_eq!(done["progress"], json!({ "tiles_total": 4, "tiles_done": 4 }));
+        assert!(done.get("error").is_none(), "{}", done);
+
+        // a finished job's elapsed time no longer moves
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (_, again) = get(&broker, &format!("/jobs/{}", id(report))).await;
+        assert_eq!(again["elapsed_ms"], done["elapsed_ms"]);
+    }
+}
+
+#[tokio::test]
+async fn jobs_whose_tiles_fail_end_failed_with_the_error() {
+    let worker = MockWorker::start(Behaviour::Fail(StatusCode::BAD_REQUEST));
+    let broker = broker(&[&worker.url], |_| {});
+
+    let report = submit(&broker, json!({ "left": [[1]], "right": [[1]] })).await;
+    let failed = wait_for_job(&broker, &id(&report), "failed").await;
+
+    assert_eq!(failed["error"]["code"], "bad_worker_response");
+    assert_eq!(failed["error"]["details"]["worker"], worker.url);
+    assert!(failed.get("result").is_none(), "{}", failed);
+}
+
+#[tokio::test]
+async fn unknown_jobs_are_not_found() {
+    let broker = broker(&[], |_| {});
+
+    for path in ["/jobs/no-such-job", "/jobs/no-such-job/events"] {
+        let (status, reply) = get(&broker, path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(reply["code"], "not_found");
+        assert_eq!(reply["message"], "No job with ID no-such-job");
+    }
+}
+
+#[tokio::test]
+async fn finished_jobs_expire_after_the_retention_period() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| config.job_retention_ms = 100);
+    let finished = id(&submit(&broker, json!({ "left": [[2]], "right": [[3]] })).await);
+    wait_for_job(&broker, &finished, "done").await;
+
+    worker.script([Behaviour::Slow(Duration::from_millis(600))]);
+    let running = id(&submit(&broker, json!({ "left": [[2]], "right": [[3]] })).await);
+    wait_for_job(&broker, &running, "running").await;
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(broker.jobs.expire(), [finished.as_str()]);
+
+    let (status, reply) = get(&broker, &format!("/jobs/{}", finished)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", reply);
+    // a job that hasn't finished is kept however long it runs
+    let (status, reply) = get(&broker, &format!("/jobs/{}", running)).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(reply["status"], "running");
+}
+
+#[tokio::test]
+async fn events_stream_the_status_every_tile_and_the_result() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    worker.script([Behaviour::Slow(Duration::from_millis(100))]);
+    let broker = broker(&[&worker.url], |config| config.max_running_jobs = 1);
+    let (left, right) = (matrix(4, 3, 3), matrix(3, 4, 4));
+    let report = submit_behind_another(&broker, json!({ "left": left, "right": right })).await;
+
+    let events = events(&broker, &id(&report)).await;
+
+    let (name, status) = &events[0];
+    assert_eq!(name, "status");
+    assert_eq!(status["id"], report["id"]);
+    assert_eq!(status["status"], "queued");
+
+    let names = names(&events);
+    assert_eq!(names.iter().filter(|&&name| name == "tile_assigned").count(), 4, "{:?}", names);
+    let tiles: Vec<&Value> = events.iter().filter(|(name, _)| name == "tile_done").map(|(_, tile)| tile).collect();
+    assert_eq!(tiles.len(), 4, "{:?}", names);
+    // each finished tile carries its cells, which are those of the product
+    let expected = reference(&left, &right);
+    for tile in tiles {
+        assert_eq!(tile["worker"], worker.url);
+        let start = |range: &Value| range["start"].as_u64().unwrap() as usize;
+        let (rows, cols) = (start(&tile["rows"]), start(&tile["cols"]));
+        let block: Vec<Vec<i64>> = serde_json::from_value(tile["block"].clone()).unwrap();
+        let cells: Vec<Vec<i64>> = expected[rows..rows + 2].iter().map(|row| row[cols..cols + 2].to_vec()).collect();
+        assert_eq!(block, cells, "{}", tile);
+    }
+
+    // and the stream ends with the job
+    let (name, done) = events.last().unwrap();
+    assert_eq!(name, "done");
+    assert_eq!(result(done), expected);
+}
+
+#[tokio::test]
+async fn events_of_a_failed_job_end_with_the_error() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    worker.script([Behaviour::Slow(Duration::from_millis(100)), Behaviour::Fail(StatusCode::BAD_REQUEST)]);
+    let broker = broker(&[&worker.url], |config| config.max_running_jobs = 1);
+    let report = submit_behind_another(&broker, json!({ "left": [[1]], "right": [[1]] })).await;
+
+    let events = events(&broker, &id(&report)).await;
+
+    assert_eq!(names(&events), ["status", "tile_assigned", "tile_failed", "failed"]);
+    let (_, failed) = &events[3];
+    assert_eq!(failed["error"]["code"], "bad_worker_response");
+}
+
+#[tokio::test]
+async fn events_of_a_finished_job_are_its_status_and_how_it_ended() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let report = submit(&broker, json!({ "left": [[2]], "right": [[3]] })).await;
+    wait_for_job(&broker, &id(&report), "done").await;
+
+    let events = events(&broker, &id(&report)).await;
+
+    assert_eq!(names(&events), ["status", "done"]);
+    assert_eq!(events[0].1["status"], "done");
+    assert_eq!(events[1].1["result"], json!([[6]]));
+}
+
+#[tokio::test]
+async fn subscribers_that_fall_behind_are_told_how_many_events_they_missed() {
+    let broker = broker(&[], |_| {});
+    // a job nothing runs, so the test decides every event it sends
+    let job = broker.jobs.create(DType::I32, OverflowPolicy::default());
+
+    // the stream subscribes as soon as it is built, but only reads once the body is
+    let reply = warp::test::request()
+        .path(&format!("/jobs/{}/events", job.id))
+        .filter(&crate::routes(Arc::clone(&broker)))
+        .await
+        .unwrap();
+    for row in 0..1100 {
+        let worker = "http://worker".to_string();
+        job.progress.emit(JobEvent::TileAssigned { rows: row..row + 1, cols: 0..1, worker, attempt: 1 });
+    }
+    job.finish(Ok(json!([[1]])));
+    let body = warp::hyper::body::to_bytes(reply.into_response().into_body()).await.unwrap();
+    let events = sse_events(&body);
+
+    // the buffer keeps the last 1024 of the 1101 events, the rest are skipped
+    assert_eq!(names(&events[..3]), ["status", "lagged", "tile_assigned"]);
+    assert_eq!(events[1].1, json!({ "skipped": 77 }));
+    assert_eq!(events[2].1["rows"], json!({ "start": 77, "end": 78 }));
+    assert_eq!(events.len(), 2 + 1024);
+    assert_eq!(events.last().unwrap().0, "done");
+}
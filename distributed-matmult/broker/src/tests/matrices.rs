@@ -0,0 +1,111 @@
+use serde_json::{Value, json};
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, get, matrix, multiply, reference, result, send};
+
+// stores `matrix` on the broker, returning its ID
+async fn upload(broker: &std::sync::Arc<crate::context::BrokerContext>, matrix: &[Vec<i64>]) -> String {
+    let (status, reply) = send(broker, "POST", "/matrices", Some(json!({ "matrix": matrix }))).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", reply);
+    reply["id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn stored_matrices_can_be_fetched_and_deleted() {
+    let broker = broker(&[], |_| {});
+    let stored = matrix(3, 2, 61);
+
+    let (status, reply) = send(&broker, "POST", "/matrices", Some(json!({ "matrix": stored }))).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", reply);
+    assert_eq!((&reply["rows"], &reply["cols"]), (&json!(3), &json!(2)));
+    assert_eq!(reply["matrix"], Value::Null);
+    let path = format!("/matrices/{}", reply["id"].as_str().unwrap());
+
+    let (status, reply) = get(&broker, &path).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(reply["matrix"], json!(stored));
+
+    let (status, _) = send(&broker, "DELETE", &path, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, reply) = get(&broker, &path).await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", reply);
+    let (status, _) = send(&broker, "DELETE", &path, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn requests_can_name_stored_matrices() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let right = matrix(3, 4, 62);
+    let right_id = upload(&broker, &right).await;
+
+    // many left matrices by the same right one, which is only uploaded once
+    for seed in 63..66 {
+        let left = matrix(4, 3, seed);
+        let (status, reply) = multiply(&broker, json!({ "left": left, "right_id": right_id })).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        assert_eq!(result(&reply), reference(&left, &right));
+    }
+
+    let left = matrix(2, 3, 66);
+    let left_id = upload(&broker, &left).await;
+    let (status, reply) = multiply(&broker, json!({ "left_id": left_id, "right_id": right_id })).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+}
+
+#[tokio::test]
+async fn matrices_must_be_given_once_and_exist() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(2, 2, 67), matrix(2, 2, 68));
+    let right_id = upload(&broker, &right).await;
+
+    let cases = [
+        (json!({ "left": left }), StatusCode::BAD_REQUEST, "invalid_request"),
+        (json!({ "left": left, "right": right, "right_id": right_id }), StatusCode::BAD_REQUEST, "invalid_request"),
+        (json!({ "left": left, "right_id": "no-such-matrix" }), StatusCode::NOT_FOUND, "not_found"),
+        // shapes are checked against stored matrices as well
+        (json!({ "left": matrix(2, 3, 69), "right_id": right_id }), StatusCode::BAD_REQUEST, "invalid_shape"),
+    ];
+    for (body, expected_status, expected_code) in cases {
+        let (status, reply) = multiply(&broker, body.clone()).await;
+        assert_eq!(status, expected_status, "{}: {}", body, reply);
+        assert_eq!(reply["code"], expected_code, "{}", body);
+    }
+    assert_eq!(worker.requests(), 0);
+}
+
+#[tokio::test]
+async fn uploads_are_checked_and_limited() {
+    let broker = broker(&[], |config| config.max_stored_cells = 10);
+
+    let (status, reply) = send(&broker, "POST", "/matrices", Some(json!({ "matrix": [[1, 2], [3]] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", reply);
+    assert_eq!(reply["code"], "ragged_matrix");
+
+    upload(&broker, &matrix(2, 3, 70)).await;
+    let (status, reply) = send(&broker, "POST", "/matrices", Some(json!({ "matrix": matrix(2, 3, 71) }))).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", reply);
+    assert_eq!(reply["code"], "store_full");
+}
+
+#[tokio::test]
+async fn bodies_over_the_limit_are_refused_on_every_route() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| config.max_body_bytes = 1024);
+    let body = json!({ "left": matrix(20, 20, 72), "right": matrix(20, 20, 73) });
+    assert!(body.to_string().len() > 1024);
+
+    for path in ["/multiply_matrices_distributed", "/jobs", "/matrices", "/workers/register", "/workers/heartbeat"] {
+        let (status, reply) = send(&broker, "POST", path, Some(body.clone())).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}: {}", path, reply);
+        assert_eq!(reply["code"], "payload_too_large", "{}", path);
+    }
+    assert_eq!(worker.requests(), 0);
+
+    // a small enough body still goes through
+    let (status, reply) = multiply(&broker, json!({ "left": matrix(2, 2, 74), "right": matrix(2, 2, 75) })).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+}
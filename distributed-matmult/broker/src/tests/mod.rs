@@ -0,0 +1,12 @@
+// tests of the broker against mock workers, see `harness`
+mod admission;
+mod failures;
+mod grpc;
+mod harness;
+mod hedging;
+mod jobs;
+mod matrices;
+mod multiply;
+mod sparse;
+mod verify;
+mod voting;
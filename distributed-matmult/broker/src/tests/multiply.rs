@@ -0,0 +1,143 @@
+use serde_json::json;
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, matrix, multiply, reference, result};
+
+#[tokio::test]
+async fn splits_the_product_across_workers() {
+    let workers = [MockWorker::start(Behaviour::Correct), MockWorker::start(Behaviour::Correct)];
+    let broker = broker(&[&workers[0].url, &workers[1].url], |_| {});
+    let (left, right) = (matrix(5, 7, 1), matrix(7, 3, 2));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(reply["dtype"], "i32");
+    assert_eq!(result(&reply), reference(&left, &right));
+    // 3 row tiles by 2 column tiles, shared between both workers
+    assert_eq!(workers[0].requests() + workers[1].requests(), 6);
+    assert!(workers.iter().all(|worker| worker.requests() > 0));
+}
+
+#[tokio::test]
+async fn matches_the_reference_for_every_tile_size() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(6, 4, 3), matrix(4, 5, 4));
+
+    for (tile_rows, tile_cols) in [(1, 1), (2, 3), (4, 5), (6, 5), (64, 64)] {
+        let body = json!({ "left": left, "right": right, "tile_rows": tile_rows, "tile_cols": tile_cols });
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::OK, "{}x{} tiles: {}", tile_rows, tile_cols, reply);
+        assert_eq!(result(&reply), reference(&left, &right), "{}x{} tiles", tile_rows, tile_cols);
+    }
+}
+
+#[tokio::test]
+async fn dot_products_strategy_matches_the_reference() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(4, 6, 5), matrix(6, 5, 6));
+
+    let body = json!({ "left": left, "right": right, "strategy": "dot_products", "batch_size": 3 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    // batches of 3 cells, one row of the result each
+    assert_eq!(worker.requests(), 4 * 2);
+}
+
+#[tokio::test]
+async fn json_wire_format_matches_the_reference() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(3, 3, 7), matrix(3, 3, 8));
+
+    for strategy in ["blocks", "dot_products"] {
+        let body = json!({ "left": left, "right": right, "wire_format": "json", "strategy": strategy });
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", strategy, reply);
+        assert_eq!(result(&reply), reference(&left, &right), "{}", strategy);
+    }
+}
+
+#[tokio::test]
+async fn multiplies_every_element_type() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(3, 4, 9), matrix(4, 2, 10));
+    let expected = reference(&left, &right);
+
+    for dtype in ["i32", "i64", "f32", "f64"] {
+        let (status, reply) = multiply(&broker, json!({ "dtype": dtype, "left": left, "right": right })).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", dtype, reply);
+        assert_eq!(reply["dtype"], dtype);
+        // small integers are exact in every type
+        let product: Vec<Vec<f64>> = serde_json::from_value(reply["result"].clone()).unwrap();
+        let expected: Vec<Vec<f64>> = expected.iter().map(|row| row.iter().map(|&v| v as f64).collect()).collect();
+        assert_eq!(product, expected, "{}", dtype);
+    }
+}
+
+#[tokio::test]
+async fn mismatched_shapes_are_rejected_before_any_tile_is_sent() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+
+    let (status, reply) = multiply(&broker, json!({ "left": matrix(2, 3, 1), "right": matrix(2, 2, 2) })).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(reply["code"], "invalid_shape");
+    assert_eq!(reply["details"], json!({ "left": [2, 3], "right": [2, 2] }));
+    assert_eq!(worker.requests(), 0);
+}
+
+#[tokio::test]
+async fn malformed_matrices_are_rejected() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+
+    let cases = [
+        (json!({ "left": [], "right": [[1]] }), "empty_input", json!({ "matrix": "left" })),
+        (json!({ "left": [[1]], "right": [[], []] }), "no_columns", json!({ "matrix": "right", "rows": 2 })),
+        (
+            json!({ "left": [[1, 2], [3]], "right": [[1], [2]] }),
+            "ragged_matrix",
+            json!({ "matrix": "left", "row": 1, "len": 1, "expected": 2 }),
+        ),
+        (
+            json!({ "left": [[1, 2]], "right": [[1], [4294967296_i64]] }),
+            "invalid_value",
+            json!({ "matrix": "right", "row": 1, "col": 0, "value": "4294967296", "dtype": "i32" }),
+        ),
+        (
+            json!({ "dtype": "f32", "left": [[1.5, -1e39]], "right": [[1], [2]] }),
+            "invalid_value",
+            json!({ "matrix": "left", "row": 0, "col": 1, "value": "-1e+39", "dtype": "f32" }),
+        ),
+    ];
+    for (body, code, details) in cases {
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", code);
+        assert_eq!(reply["code"], code);
+        assert_eq!(reply["details"], details, "{}", code);
+    }
+    assert_eq!(worker.requests(), 0);
+}
+
+#[tokio::test]
+async fn non_positive_tile_sizes_are_rejected() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+
+    for body in [
+        json!({ "left": [[1]], "right": [[1]], "tile_rows": 0 }),
+        json!({ "left": [[1]], "right": [[1]], "strategy": "dot_products", "batch_size": 0 }),
+    ] {
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(reply["code"], "invalid_request");
+    }
+    assert_eq!(worker.requests(), 0);
+}
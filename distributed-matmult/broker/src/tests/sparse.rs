@@ -0,0 +1,210 @@
+use serde_json::{Value, json};
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, matrix, multiply, over_grpc, reference, result};
+
+// a matrix of small integers with about two thirds of its cells zero
+fn sparse_matrix(rows: usize, cols: usize, seed: u64) -> Vec<Vec<i64>> {
+    let mut nested = matrix(rows, cols, seed);
+    nested.iter_mut().flatten().filter(|value| value.abs() < 6).for_each(|value| *value = 0);
+    nested
+}
+
+// the non-zero cells of `nested`, row by row, as (row, column, value)
+fn cells(nested: &[Vec<i64>]) -> Vec<(usize, usize, i64)> {
+    let mut cells = Vec::new();
+    for (i, row) in nested.iter().enumerate() {
+        cells.extend(row.iter().enumerate().filter(|(_, value)| **value != 0).map(|(j, &value)| (i, j, value)));
+    }
+    cells
+}
+
+fn csr(nested: &[Vec<i64>]) -> Value {
+    let mut row_ptr = vec![0];
+    row_ptr.extend(nested.iter().scan(0, |count, row| {
+        *count += row.iter().filter(|value| **value != 0).count();
+        Some(*count)
+    }));
+    let cells = cells(nested);
+    json!({
+        "format": "csr",
+        "rows": nested.len(),
+        "cols": nested[0].len(),
+        "row_ptr": row_ptr,
+        "col_indices": cells.iter().map(|cell| cell.1).collect::<Vec<_>>(),
+        "values": cells.iter().map(|cell| cell.2).collect::<Vec<_>>(),
+    })
+}
+
+fn coo(nested: &[Vec<i64>]) -> Value {
+    let cells = cells(nested);
+    json!({
+        "format": "coo",
+        "rows": nested.len(),
+        "cols": nested[0].len(),
+        "row_indices": cells.iter().map(|cell| cell.0).collect::<Vec<_>>(),
+        "col_indices": cells.iter().map(|cell| cell.1).collect::<Vec<_>>(),
+        "values": cells.iter().map(|cell| cell.2).collect::<Vec<_>>(),
+    })
+}
+
+#[tokio::test]
+async fn sparse_inputs_match_the_reference() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (sparse_matrix(7, 5, 81), sparse_matrix(5, 6, 82));
+    let expected = reference(&left, &right);
+
+    for (left_input, right_input) in [
+        (csr(&left), csr(&right)),
+        (coo(&left), coo(&right)),
+        (csr(&left), coo(&right)),
+        // a dense matrix by a sparse one is multiplied as two sparse ones
+        (json!(left), csr(&right)),
+        (coo(&left), json!(right)),
+    ] {
+        let body = json!({ "left": left_input, "right": right_input, "tile_rows": 3, "tile_cols": 2 });
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        assert_eq!(result(&reply), expected);
+    }
+}
+
+#[tokio::test]
+async fn sparse_tiles_sent_over_grpc_match_the_reference() {
+    let worker = MockWorker::start_grpc(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| over_grpc(config, &worker));
+    let (left, right) = (sparse_matrix(7, 5, 83), sparse_matrix(5, 6, 84));
+
+    let body = json!({ "left": csr(&left), "right": coo(&right), "tile_rows": 3, "tile_cols": 2 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert!(worker.requests() > 0);
+}
+
+#[tokio::test]
+async fn sparse_tiles_of_the_wrong_shape_are_refused_before_being_filled_in() {
+    let (left, right) = (sparse_matrix(4, 5, 85), sparse_matrix(5, 4, 86));
+    for worker in [MockWorker::start(Behaviour::Correct), MockWorker::start_grpc(Behaviour::Correct)] {
+        let grpc = worker.grpc_url.is_some();
+        let broker = broker(&[&worker.url], |config| {
+            if grpc {
+                over_grpc(config, &worker);
+            }
+        });
+        // a tile 2^40 columns wide would not fit in memory dense, so is only
+        // survived if it is refused as it is
+        worker.script([Behaviour::HugeShape]);
+
+        let body = json!({ "left": csr(&left), "right": csr(&right), "tile_rows": 4, "tile_cols": 4 });
+        let (status, reply) = multiply(&broker, body).await;
+
+        assert_eq!(status, StatusCode::OK, "grpc {}: {}", grpc, reply);
+        assert_eq!(result(&reply), reference(&left, &right));
+        assert_eq!(worker.requests(), 2, "grpc {}", grpc);
+    }
+}
+
+#[tokio::test]
+async fn tiles_without_a_non_zero_term_are_not_sent() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    // block diagonal, so of the 2x2 tiles only those on the diagonal have terms
+    let left = vec![vec![1, 2, 0, 0], vec![3, 4, 0, 0], vec![0, 0, 5, 6], vec![0, 0, 7, 8]];
+    let right = vec![vec![2, 0, 0, 0], vec![0, 2, 0, 0], vec![0, 0, 3, 1], vec![0, 0, 1, 3]];
+
+    let body = json!({ "left": csr(&left), "right": csr(&right), "tile_rows": 2, "tile_cols": 2 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(worker.requests(), 2);
+}
+
+#[tokio::test]
+async fn results_can_be_sent_back_sparse() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (sparse_matrix(4, 6, 83), sparse_matrix(6, 5, 84));
+    let expected = reference(&left, &right);
+
+    for (format, sparse) in [("csr", csr(&expected)), ("coo", coo(&expected))] {
+        let body = json!({ "left": coo(&left), "right": json!(right), "result_format": format });
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        assert_eq!(reply["result"], sparse, "{}", format);
+    }
+    // and dense matrices can have a sparse result too
+    let body = json!({ "left": left, "right": right, "result_format": "csr" });
+    let (status, reply) = multiply(&broker, body).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(reply["result"], csr(&expected));
+}
+
+#[tokio::test]
+async fn malformed_sparse_matrices_are_rejected() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let right = csr(&[vec![1, 0], vec![0, 1]]);
+
+    for left in [
+        // row_ptr too short, decreasing and not ending at the number of values
+        json!({ "format": "csr", "rows": 2, "cols": 2, "row_ptr": [0, 1], "col_indices": [0], "values": [1] }),
+        json!({ "format": "csr", "rows": 2, "cols": 2, "row_ptr": [0, 2, 1], "col_indices": [0, 1], "values": [1, 2] }),
+        json!({ "format": "csr", "rows": 2, "cols": 2, "row_ptr": [0, 1, 1], "col_indices": [0, 1], "values": [1, 2] }),
+        // a column outside the matrix, and a cell given twice
+        json!({ "format": "csr", "rows": 2, "cols": 2, "row_ptr": [0, 1, 1], "col_indices": [2], "values": [1] }),
+        json!({ "format": "coo", "rows": 2, "cols": 2, "row_indices": [1, 1], "col_indices": [0, 0], "values": [1, 2] }),
+        json!({ "format": "coo", "rows": 2, "cols": 2, "row_indices": [0], "col_indices": [0, 1], "values": [1, 2] }),
+    ] {
+        let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", left);
+        assert_eq!(reply["code"], "invalid_sparse_matrix", "{}", left);
+        assert_eq!(reply["details"], json!({ "matrix": "left" }));
+    }
+
+    // dot products would spell out every zero
+    let body = json!({ "left": right, "right": right, "strategy": "dot_products" });
+    let (status, reply) = multiply(&broker, body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", reply);
+    assert_eq!(reply["code"], "invalid_request");
+    assert_eq!(worker.requests(), 0);
+}
+
+#[tokio::test]
+async fn corrupt_sparse_tiles_are_recomputed_on_another_worker() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let honest = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&corrupt.url, &honest.url], |_| {});
+    let (left, right) = (sparse_matrix(6, 8, 85), sparse_matrix(8, 6, 86));
+
+    let body = json!({ "left": csr(&left), "right": coo(&right), "tile_rows": 2, "tile_cols": 2, "verify_rounds": 16 });
+    let (status, reply) = multiply(&broker, body).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert!(corrupt.requests() > 0);
+}
+
+#[tokio::test]
+async fn oversized_matrices_are_rejected_before_anything_is_allocated() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |config| config.max_result_cells = 100);
+    let one = csr(&[vec![1]]);
+
+    for (left, right) in [
+        // a tiny body declaring a huge matrix
+        (json!({ "format": "coo", "rows": 10_000_000_000u64, "cols": 1, "row_indices": [0], "col_indices": [0], "values": [1] }), one.clone()),
+        (one.clone(), json!({ "format": "csr", "rows": 1, "cols": usize::MAX, "row_ptr": [0, 0], "col_indices": [], "values": [] })),
+        (json!({ "format": "csr", "rows": usize::MAX, "cols": 1, "row_ptr": [0], "col_indices": [], "values": [] }), one.clone()),
+        // small matrices with a big product
+        (json!(matrix(11, 1, 87)), json!(matrix(1, 10, 88))),
+    ] {
+        let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "{}", reply);
+        assert_eq!(reply["code"], "payload_too_large");
+    }
+    assert_eq!(worker.requests(), 0);
+}
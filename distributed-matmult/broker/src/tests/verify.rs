@@ -0,0 +1,92 @@
+use matmult_common::matrix::DenseMatrix;
+use serde_json::json;
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, matrix, multiply, reference, result};
+use crate::element::OverflowPolicy;
+use crate::verify;
+
+#[tokio::test]
+async fn corrupt_tiles_are_recomputed_on_another_worker() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let honest = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&corrupt.url, &honest.url], |_| {});
+    let (left, right) = (matrix(6, 5, 31), matrix(5, 6, 32));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "verify_rounds": 16 })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert!(corrupt.requests() > 0);
+}
+
+#[tokio::test]
+async fn corrupt_tiles_get_through_without_verification() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let broker = broker(&[&corrupt.url], |_| {});
+    let (left, right) = (matrix(4, 3, 33), matrix(3, 4, 34));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_ne!(result(&reply), reference(&left, &right));
+}
+
+#[tokio::test]
+async fn a_result_no_worker_gets_right_fails_verification() {
+    let first = MockWorker::start(Behaviour::Corrupt);
+    let second = MockWorker::start(Behaviour::Corrupt);
+    // verification is on for every job by the broker's config
+    let broker = broker(&[&first.url, &second.url], |config| config.verify_rounds = 16);
+    let (left, right) = (matrix(4, 3, 35), matrix(3, 4, 36));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right })).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", reply);
+    assert_eq!(reply["code"], "verification_failed");
+    assert!(!reply["details"]["rows"].as_array().unwrap().is_empty(), "{}", reply);
+}
+
+#[tokio::test]
+async fn floats_pass_verification_despite_rounding() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let fractions = |rows: usize, cols: usize, seed: u64| -> Vec<Vec<f64>> {
+        matrix(rows, cols, seed).iter().map(|row| row.iter().map(|&v| v as f64 / 7.0 + 0.1).collect()).collect()
+    };
+    let (left, right) = (fractions(8, 40, 37), fractions(40, 8, 38));
+
+    for dtype in ["f32", "f64"] {
+        let body = json!({ "dtype": dtype, "left": left, "right": right, "verify_rounds": 32 });
+        let (status, reply) = multiply(&broker, body).await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", dtype, reply);
+    }
+    // each of the 16 tiles is computed once per type, none of them again
+    assert_eq!(worker.requests(), 32);
+}
+
+#[test]
+fn check_localizes_a_wrong_cell() {
+    let left = DenseMatrix::from_rows(&matrix(5, 4, 39)).unwrap();
+    let right = DenseMatrix::from_rows(&matrix(4, 6, 40)).unwrap();
+    let mut product = DenseMatrix::from_rows(&reference(&left.to_rows(), &right.to_rows())).unwrap();
+    assert!(verify::check(&left, &right, &product, OverflowPolicy::Wrapping, 32).passed());
+
+    product.row_mut(3)[2] += 1;
+    let verdict = verify::check(&left, &right, &product, OverflowPolicy::Wrapping, 32);
+
+    assert_eq!((verdict.rows, verdict.cols), (vec![3], vec![2]));
+}
+
+#[test]
+fn check_skips_rows_that_may_have_saturated() {
+    let left = DenseMatrix::from_rows(&[vec![i32::MAX, 1], vec![1, 1]]).unwrap();
+    let right = DenseMatrix::from_rows(&[vec![2, 0], vec![0, 1]]).unwrap();
+    // the first row is clamped, which isn't linear in the inputs
+    let product = DenseMatrix::from_rows(&[vec![i32::MAX, 1], vec![2, 1]]).unwrap();
+
+    let verdict = verify::check(&left, &right, &product, OverflowPolicy::Saturating, 16);
+
+    assert!(verdict.passed(), "{:?}", verdict);
+    assert_eq!(verdict.unchecked_rows, 1);
+}
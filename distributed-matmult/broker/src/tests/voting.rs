@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use warp::http::StatusCode;
+
+use super::harness::{Behaviour, MockWorker, broker, get, matrix, multiply, reference, result};
+
+// the `GET /workers` entry for `url`
+async fn worker_status(broker: &std::sync::Arc<crate::context::BrokerContext>, url: &str) -> Value {
+    let (_, workers) = get(broker, "/workers").await;
+    workers.as_array().unwrap().iter().find(|worker| worker["url"] == url).cloned().unwrap()
+}
+
+#[tokio::test]
+async fn the_majority_outvotes_a_corrupt_worker() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let honest = [MockWorker::start(Behaviour::Correct), MockWorker::start(Behaviour::Correct)];
+    let broker = broker(&[&corrupt.url, &honest[0].url, &honest[1].url], |_| {});
+    let (left, right) = (matrix(4, 3, 41), matrix(3, 4, 42));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 3 })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    // each of the 4 tiles went to every worker
+    assert_eq!(corrupt.requests(), 4);
+    assert_eq!(worker_status(&broker, &corrupt.url).await["disagreements"], 4);
+    for worker in &honest {
+        assert_eq!(worker.requests(), 4);
+        let status = worker_status(&broker, &worker.url).await;
+        assert_eq!((&status["agreements"], &status["disagreements"]), (&json!(4), &json!(0)));
+    }
+}
+
+#[tokio::test]
+async fn repeat_offenders_are_quarantined_until_it_runs_out() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let honest = [MockWorker::start(Behaviour::Correct), MockWorker::start(Behaviour::Correct)];
+    let broker = broker(&[&corrupt.url, &honest[0].url, &honest[1].url], |config| {
+        config.quarantine.after = 2;
+        config.quarantine.duration_ms = 300;
+    });
+    let (left, right) = (matrix(4, 3, 43), matrix(3, 4, 44));
+    let body = json!({ "left": left, "right": right, "replicas": 2 });
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 3 })).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert!(worker_status(&broker, &corrupt.url).await["quarantined_ms"].is_u64());
+
+    // in quarantine the worker is handed nothing, so two replicas are all there's room for
+    let sent = corrupt.requests();
+    let (status, reply) = multiply(&broker, body.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+    assert_eq!(corrupt.requests(), sent);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 3 })).await;
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert!(corrupt.requests() > sent);
+}
+
+#[tokio::test]
+async fn a_split_vote_fails_the_job() {
+    let corrupt = MockWorker::start(Behaviour::Corrupt);
+    let honest = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&corrupt.url, &honest.url], |_| {});
+    let (left, right) = (matrix(2, 2, 45), matrix(2, 2, 46));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 2 })).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{}", reply);
+    assert_eq!(reply["code"], "no_majority");
+    assert_eq!(reply["details"]["answers"], json!([1, 1]));
+    // nobody can be blamed for a tie
+    assert_eq!(worker_status(&broker, &corrupt.url).await["disagreements"], 0);
+}
+
+#[tokio::test]
+async fn replicas_on_a_failing_worker_are_retried_elsewhere() {
+    let failing = MockWorker::start(Behaviour::Correct);
+    failing.script([Behaviour::Fail(StatusCode::INTERNAL_SERVER_ERROR)]);
+    let honest = [MockWorker::start(Behaviour::Correct), MockWorker::start(Behaviour::Correct)];
+    let broker = broker(&[&failing.url, &honest[0].url, &honest[1].url], |_| {});
+    let (left, right) = (matrix(2, 3, 47), matrix(3, 2, 48));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 2 })).await;
+
+    assert_eq!(status, StatusCode::OK, "{}", reply);
+    assert_eq!(result(&reply), reference(&left, &right));
+}
+
+#[tokio::test]
+async fn more_replicas_than_live_workers_are_refused() {
+    let worker = MockWorker::start(Behaviour::Correct);
+    let broker = broker(&[&worker.url], |_| {});
+    let (left, right) = (matrix(2, 2, 49), matrix(2, 2, 50));
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 2 })).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", reply);
+    assert_eq!(reply["code"], "worker_unavailable");
+
+    let (status, reply) = multiply(&broker, json!({ "left": left, "right": right, "replicas": 0 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", reply);
+    assert_eq!(worker.requests(), 0);
+}
@@ -1,43 +1,452 @@
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::sync::Arc;
 
-pub type Matrix = Vec<Vec<i32>>;
+use crate::element::{DType, Element, OverflowPolicy};
+use crate::error::BrokerError;
+use crate::dispatch::Strategy;
+use crate::store::MatrixStore;
+use crate::wire::WireFormat;
 
+/// Nested rows, as sent by clients of the broker's HTTP API. The numbers are
+/// converted to the request's `dtype` before any work is sent out.
+pub type Matrix = Vec<Vec<serde_json::Number>>;
+
+/// A matrix of a request: nested rows, or just its non-zero cells in one of
+/// the `SparseParts` forms.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum MatrixInput {
+    Dense(Matrix),
+    Sparse(SparseParts<serde_json::Number>),
+}
+
+// nested rows are an array and a sparse matrix an object, which tells the two
+// apart up front and keeps serde's own errors about what is wrong inside either
+impl<'de> Deserialize<'de> for MatrixInput {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct InputVisitor;
+
+        impl<'de> Visitor<'de> for InputVisitor {
+            type Value = MatrixInput;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an array of rows, or a sparse matrix in csr or coo format")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, rows: A) -> Result<MatrixInput, A::Error> {
+                Matrix::deserialize(SeqAccessDeserializer::new(rows)).map(MatrixInput::Dense)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, parts: A) -> Result<MatrixInput, A::Error> {
+                SparseParts::deserialize(MapAccessDeserializer::new(parts)).map(MatrixInput::Sparse)
+            }
+        }
+
+        deserializer.deserialize_any(InputVisitor)
+    }
+}
+
+impl MatrixInput {
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, MatrixInput::Sparse(_))
+    }
+
+    /// Values held, which for a sparse matrix is only those it lists.
+    pub fn cells(&self) -> usize {
+        match self {
+            MatrixInput::Dense(nested) => nested.iter().map(Vec::len).sum(),
+            MatrixInput::Sparse(parts) => parts.values().len(),
+        }
+    }
+}
+
+/// A sparse matrix as clients send it, and get a result back in when they ask
+/// for one, tagged by its `format`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "format", rename_all = "lowercase")]
+pub enum SparseParts<V> {
+    /// Compressed sparse rows: the values of row i are `values[row_ptr[i]..row_ptr[i + 1]]`,
+    /// in the columns at the same positions of `col_indices`.
+    Csr { rows: usize, cols: usize, row_ptr: Vec<usize>, col_indices: Vec<usize>, values: Vec<V> },
+    /// Coordinates: cell (`row_indices[n]`, `col_indices[n]`) holds `values[n]`, in any order.
+    Coo { rows: usize, cols: usize, row_indices: Vec<usize>, col_indices: Vec<usize>, values: Vec<V> },
+}
+
+impl<V> SparseParts<V> {
+    pub fn shape(&self) -> (usize, usize) {
+        match self {
+            SparseParts::Csr { rows, cols, .. } | SparseParts::Coo { rows, cols, .. } => (*rows, *cols),
+        }
+    }
+
+    pub fn values(&self) -> &[V] {
+        match self {
+            SparseParts::Csr { values, .. } | SparseParts::Coo { values, .. } => values,
+        }
+    }
+}
+
+/// How the result of a multiplication is sent back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultFormat {
+    /// Nested rows.
+    #[default]
+    Dense,
+    /// The non-zero cells in compressed sparse row form.
+    Csr,
+    /// The non-zero cells as coordinates, row by row.
+    Coo,
+}
+
+/// A result in the format the client asked for.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Product<T> {
+    Dense(Vec<Vec<T>>),
+    Sparse(SparseParts<T>),
+}
+
+impl ResultFormat {
+    pub fn render<T: Element>(self, result: &DenseMatrix<T>) -> Product<T> {
+        let (rows, cols) = result.shape();
+        match self {
+            ResultFormat::Dense => Product::Dense(result.to_rows()),
+            ResultFormat::Csr => {
+                let sparse = CsrMatrix::from_dense(result);
+                Product::Sparse(SparseParts::Csr {
+                    rows,
+                    cols,
+                    row_ptr: sparse.row_ptr().to_vec(),
+                    col_indices: sparse.col_indices().to_vec(),
+                    values: sparse.values().to_vec(),
+                })
+            }
+            ResultFormat::Coo => {
+                let sparse = CsrMatrix::from_dense(result);
+                let (mut row_indices, mut col_indices, mut values) = (Vec::new(), Vec::new(), Vec::new());
+                for (i, j, value) in sparse.triplets() {
+                    row_indices.push(i);
+                    col_indices.push(j);
+                    values.push(value);
+                }
+                Product::Sparse(SparseParts::Coo { rows, cols, row_indices, col_indices, values })
+            }
+        }
+    }
+}
+
+/// A tile of the result: a block of rows of the left matrix and a block of
+/// columns of the right matrix, positioned at (`row_offset`, `col_offset`).
+/// The blocks are shared between every tile that uses them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockPayload<T> {
+    pub row_offset: usize,
+    pub col_offset: usize,
+    pub overflow: OverflowPolicy,
+    pub left: Arc<DenseMatrix<T>>,
+    pub right: Arc<DenseMatrix<T>>,
+}
+
+/// A block payload tagged with its element type, which is what goes over the wire.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedBlockPayload {
+    I32(BlockPayload<i32>),
+    I64(BlockPayload<i64>),
+    F32(BlockPayload<f32>),
+    F64(BlockPayload<f64>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlockResponse<T> {
+    pub result: DenseMatrix<T>,
+}
+
+/// A tile of the product of sparse matrices: a block of rows of the left
+/// matrix, and a block of columns of the right matrix transposed so each of its
+/// rows is a column of the tile. Only the non-zero cells go over the wire.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct DotProductPayload {
-    pub row: Vec<i32>,
-    pub col: Vec<i32>,
+pub struct SparseBlockPayload<T> {
+    pub row_offset: usize,
+    pub col_offset: usize,
+    pub overflow: OverflowPolicy,
+    pub left: Arc<CsrMatrix<T>>,
+    pub right: Arc<CsrMatrix<T>>,
 }
 
+/// A sparse block payload tagged with its element type, which is what goes over the wire.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedSparseBlockPayload {
+    I32(SparseBlockPayload<i32>),
+    I64(SparseBlockPayload<i64>),
+    F32(SparseBlockPayload<f32>),
+    F64(SparseBlockPayload<f64>),
+}
+
+/// The tile of a sparse block payload, holding only the cells which aren't zero.
 #[derive(Serialize, Deserialize)]
-pub struct DotProductResponse {
-    pub result: i32,
+pub struct SparseBlockResponse<T> {
+    pub result: CsrMatrix<T>,
+}
+
+/// One dot product of a batch, which is cell (`row_id`, `col_id`) of the result.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DotProductItem<T> {
+    pub row_id: usize,
+    pub col_id: usize,
+    pub row: Vec<T>,
+    pub col: Vec<T>,
+}
+
+/// The cells of a tile as separate dot products, for the `dot_products` strategy.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DotProductBatch<T> {
+    pub overflow: OverflowPolicy,
+    pub items: Vec<DotProductItem<T>>,
+}
+
+/// A dot product batch tagged with its element type, which is what goes over the wire.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedDotProductBatch {
+    I32(DotProductBatch<i32>),
+    I64(DotProductBatch<i64>),
+    F32(DotProductBatch<f32>),
+    F64(DotProductBatch<f64>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DotProductResult<T> {
+    pub row_id: usize,
+    pub col_id: usize,
+    pub result: T,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DotProductBatchResponse<T> {
+    pub results: Vec<DotProductResult<T>>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct MatMultRequest {
-    pub left: Matrix,
-    pub right: Matrix,
+    // element type of both matrices and the result, i32 when omitted
+    #[serde(default)]
+    pub dtype: DType,
+    // integer overflow behaviour, saturating when omitted
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    // each matrix is given inline, as nested rows or in CSR or COO form, or as the
+    // ID it was stored under with POST /matrices
+    #[serde(default)]
+    pub left: Option<MatrixInput>,
+    #[serde(default)]
+    pub right: Option<MatrixInput>,
+    #[serde(default)]
+    pub left_id: Option<String>,
+    #[serde(default)]
+    pub right_id: Option<String>,
+    // optional tile size, the broker's defaults are used when omitted
+    #[serde(default)]
+    pub tile_rows: Option<usize>,
+    #[serde(default)]
+    pub tile_cols: Option<usize>,
+    // optional encoding for broker to worker traffic, "bincode" or "json"
+    #[serde(default)]
+    pub wire_format: Option<WireFormat>,
+    // optional number of failed tile attempts the job may retry on other workers
+    #[serde(default)]
+    pub retry_budget: Option<usize>,
+    // optional way of handing out the work, "blocks" or "dot_products"
+    #[serde(default)]
+    pub strategy: Option<Strategy>,
+    // optional number of dot products per request under the dot_products strategy
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    // optional rounds of Freivalds' check on the result, 0 to skip it
+    #[serde(default)]
+    pub verify_rounds: Option<usize>,
+    // optional number of distinct workers to compute each tile on, the block a majority agrees on is kept
+    #[serde(default)]
+    pub replicas: Option<usize>,
+    // optional percentile of recent tile round trips after which a tile is also sent to a second worker, 0 never
+    #[serde(default)]
+    pub hedge_percentile: Option<f64>,
+    // optional format of the result, "dense" nested rows by default, or "csr" or "coo"
+    #[serde(default)]
+    pub result_format: Option<ResultFormat>,
+}
+
+impl MatMultRequest {
+    pub fn requested_options(&self) -> RequestedOptions {
+        RequestedOptions {
+            tile_rows: self.tile_rows,
+            tile_cols: self.tile_cols,
+            wire_format: self.wire_format,
+            retry_budget: self.retry_budget,
+            strategy: self.strategy,
+            batch_size: self.batch_size,
+            verify_rounds: self.verify_rounds,
+            replicas: self.replicas,
+            hedge_percentile: self.hedge_percentile,
+            result_format: self.result_format,
+        }
+    }
+
+    /// Takes both matrices out of the request, looking up those given by ID in
+    /// `store`, and checks their shapes before any of their values are
+    /// converted or any tile is sent out: every row of a matrix must be as long
+    /// as its first, neither may be empty, and the left matrix's columns must
+    /// match the right matrix's rows. Nor may the product be bigger than
+    /// `max_result_cells`, see `check_size`.
+    pub fn take_operands(&mut self, store: &MatrixStore, max_result_cells: usize) -> Result<Operands, BrokerError> {
+        let left = operand("left", self.left.take(), self.left_id.as_deref(), store)?;
+        let right = operand("right", self.right.take(), self.right_id.as_deref(), store)?;
+        let left_shape = input_shape("left", &left)?;
+        let right_shape = input_shape("right", &right)?;
+        if left_shape.1 != right_shape.0 {
+            return Err(BrokerError::InvalidShape { left: left_shape, right: right_shape });
+        }
+        check_size(left_shape, right_shape, max_result_cells)?;
+        Ok(Operands { left, right, result_cols: right_shape.1 })
+    }
 }
 
-#[derive(Debug)]
-pub struct AppError(pub String);
+/// The two matrices of a request, shared with the store when they came from it.
+pub struct Operands {
+    pub left: Arc<MatrixInput>,
+    pub right: Arc<MatrixInput>,
+    pub result_cols: usize,
+}
 
-#[derive(Debug)]
-pub struct WorkerError(pub String);
+impl Operands {
+    /// Whether the matrices are multiplied as sparse ones, which they are if
+    /// either was sent sparse.
+    pub fn is_sparse(&self) -> bool {
+        self.left.is_sparse() || self.right.is_sparse()
+    }
+}
 
-impl warp::reject::Reject for AppError {}
-impl warp::reject::Reject for WorkerError {}
+// one matrix of a request, which is given either inline or by ID
+fn operand(
+    matrix: &'static str,
+    inline: Option<MatrixInput>,
+    id: Option<&str>,
+    store: &MatrixStore,
+) -> Result<Arc<MatrixInput>, BrokerError> {
+    match (inline, id) {
+        (Some(inline), None) => Ok(Arc::new(inline)),
+        (None, Some(id)) => Ok(Arc::clone(&store.get(id)?.matrix)),
+        (Some(_), Some(_)) => Err(BrokerError::InvalidRequest(format!("Give either {0} or {0}_id, not both", matrix))),
+        (None, None) => Err(BrokerError::InvalidRequest(format!(
+            "The {0} matrix is missing, give it as {0} or as the ID it was stored under as {0}_id",
+            matrix
+        ))),
+    }
+}
 
-impl From<String> for AppError {
-    fn from(s: String) -> Self {
-        AppError(s)
+/// Rows and columns of a matrix of a request. A sparse matrix's are as it
+/// declares them, its parts are checked once they are converted.
+pub fn input_shape(matrix: &'static str, input: &MatrixInput) -> Result<(usize, usize), BrokerError> {
+    match input {
+        MatrixInput::Dense(nested) => matrix_shape(matrix, nested),
+        MatrixInput::Sparse(parts) => match parts.shape() {
+            (0, _) => Err(BrokerError::EmptyInput { matrix }),
+            (rows, 0) => Err(BrokerError::NoColumns { matrix, rows }),
+            shape => Ok(shape),
+        },
     }
 }
 
-impl From<Box<dyn Error + Send + Sync>> for AppError {
-    fn from(err: Box<dyn Error + Send + Sync>) -> Self {
-        AppError(err.to_string())
+/// Checks the product of a `left` by a `right` matrix is one the broker can
+/// compute: the result is assembled dense so may have at most `max_cells`
+/// cells, and no matrix may have more rows or columns than that either, as a
+/// sparse one is allocated by its declared shape however few values it holds.
+pub fn check_size(left: (usize, usize), right: (usize, usize), max_cells: usize) -> Result<(), BrokerError> {
+    if [left.0, left.1, right.0, right.1].into_iter().any(|len| len > max_cells) {
+        return Err(BrokerError::PayloadTooLarge(format!(
+            "Multiplying a {}x{} by a {}x{} matrix is beyond the broker's limit of {} rows or columns per matrix",
+            left.0, left.1, right.0, right.1, max_cells
+        )));
+    }
+    match left.0.checked_mul(right.1) {
+        Some(cells) if cells <= max_cells => Ok(()),
+        _ => Err(BrokerError::PayloadTooLarge(format!(
+            "The product of a {}x{} by a {}x{} matrix would have more than the {} cells the broker computes",
+            left.0, left.1, right.0, right.1, max_cells
+        ))),
     }
 }
 
+/// Rows and columns of a nested matrix, or the first row that breaks its rectangularity.
+pub fn matrix_shape(matrix: &'static str, nested: &Matrix) -> Result<(usize, usize), BrokerError> {
+    let expected = match nested.first() {
+        None => return Err(BrokerError::EmptyInput { matrix }),
+        Some(first) if first.is_empty() => return Err(BrokerError::NoColumns { matrix, rows: nested.len() }),
+        Some(first) => first.len(),
+    };
+    match nested.iter().position(|row| row.len() != expected) {
+        Some(row) => Err(BrokerError::RaggedMatrix { matrix, row, len: nested[row].len(), expected }),
+        None => Ok((nested.len(), expected)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct MatMultResponse<T> {
+    pub dtype: DType,
+    pub overflow: OverflowPolicy,
+    pub result: Product<T>,
+}
+
+/// Body of the worker registration and heartbeat requests.
+#[derive(Deserialize, Debug)]
+pub struct WorkerAddress {
+    pub url: String,
+    // relative throughput the worker offers (e.g. its compute threads), 1 when omitted
+    #[serde(default)]
+    pub capacity: Option<usize>,
+    // where to send the worker tiles over gRPC, they go over HTTP when omitted
+    #[serde(default)]
+    pub grpc_url: Option<String>,
+}
+
+/// The job settings a request may give, the broker's config fills in the rest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestedOptions {
+    pub tile_rows: Option<usize>,
+    pub tile_cols: Option<usize>,
+    pub wire_format: Option<WireFormat>,
+    pub retry_budget: Option<usize>,
+    pub strategy: Option<Strategy>,
+    pub batch_size: Option<usize>,
+    pub verify_rounds: Option<usize>,
+    pub replicas: Option<usize>,
+    pub hedge_percentile: Option<f64>,
+    pub result_format: Option<ResultFormat>,
+}
+
+/// Per-job settings for how `distribute_mat_mult` splits and ships the work.
+#[derive(Clone, Copy, Debug)]
+pub struct JobOptions {
+    pub tile_rows: usize,
+    pub tile_cols: usize,
+    pub wire_format: WireFormat,
+    pub overflow: OverflowPolicy,
+    pub retry_budget: usize,
+    pub strategy: Strategy,
+    /// Rounds of Freivalds' check the result must pass, 0 for none.
+    pub verify_rounds: usize,
+    /// Distinct workers each tile is computed on, 1 for no redundancy.
+    pub replicas: usize,
+    /// Percentile of recent tile round trips after which an outstanding tile
+    /// is sent to a second worker too, 0 never.
+    pub hedge_percentile: f64,
+    /// How the result is sent back, which doesn't change how it is computed.
+    pub result_format: ResultFormat,
+}
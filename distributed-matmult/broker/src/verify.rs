@@ -0,0 +1,231 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use rand::Rng;
+
+use crate::element::{Element, OverflowPolicy};
+
+/// A factor of a product as the check reads it, a row at a time. A sparse
+/// matrix gives only the cells it holds, which is all that adds to a product.
+pub trait Factor<T> {
+    fn rows(&self) -> usize;
+
+    fn cols(&self) -> usize;
+
+    /// The column and value of the cells of row `i` which may not be zero.
+    fn row_cells(&self, i: usize) -> impl Iterator<Item = (usize, T)> + '_;
+
+    /// Copies the sub-matrix covering `rows` x `cols` into a factor of its own.
+    fn sub_block(&self, rows: Range<usize>, cols: Range<usize>) -> Self;
+}
+
+impl<T: Element> Factor<T> for DenseMatrix<T> {
+    fn rows(&self) -> usize {
+        DenseMatrix::rows(self)
+    }
+
+    fn cols(&self) -> usize {
+        DenseMatrix::cols(self)
+    }
+
+    fn row_cells(&self, i: usize) -> impl Iterator<Item = (usize, T)> + '_ {
+        self.row(i).iter().copied().enumerate()
+    }
+
+    fn sub_block(&self, rows: Range<usize>, cols: Range<usize>) -> Self {
+        self.block(rows, cols).to_matrix()
+    }
+}
+
+impl<T: Element> Factor<T> for CsrMatrix<T> {
+    fn rows(&self) -> usize {
+        CsrMatrix::rows(self)
+    }
+
+    fn cols(&self) -> usize {
+        CsrMatrix::cols(self)
+    }
+
+    fn row_cells(&self, i: usize) -> impl Iterator<Item = (usize, T)> + '_ {
+        let (cols, values) = self.row(i);
+        cols.iter().copied().zip(values.iter().copied())
+    }
+
+    fn sub_block(&self, rows: Range<usize>, cols: Range<usize>) -> Self {
+        self.block(rows, cols)
+    }
+}
+
+/// What Freivalds' check found wrong with a product: the rows and columns
+/// holding cells which don't add up. A wrong cell shows up in both its row and
+/// its column, so the cells to suspect are where the two cross.
+#[derive(Debug, Default, PartialEq)]
+pub struct Verdict {
+    pub rows: Vec<usize>,
+    pub cols: Vec<usize>,
+    /// Rows left out because they can't be checked, see `checkable_rows`.
+    pub unchecked_rows: usize,
+}
+
+impl Verdict {
+    pub fn passed(&self) -> bool {
+        self.rows.is_empty() && self.cols.is_empty()
+    }
+
+    /// Whether the tile covering `rows` x `cols` may hold a wrong cell. When only
+    /// rows (or only columns) were caught, all of their cells are suspect.
+    pub fn suspects(&self, rows: &Range<usize>, cols: &Range<usize>) -> bool {
+        let in_rows = self.rows.is_empty() || self.rows.iter().any(|i| rows.contains(i));
+        let in_cols = self.cols.is_empty() || self.cols.iter().any(|j| cols.contains(j));
+        !self.passed() && in_rows && in_cols
+    }
+}
+
+/// Checks `product` against `left` x `right` with `rounds` rounds of Freivalds'
+/// algorithm. Each round compares A·(B·r) with C·r and (sᵀ·A)·B with sᵀ·C for
+/// random 0/1 vectors r and s, which costs a few matrix-vector products rather
+/// than a multiplication, and misses a wrong cell with probability at most 1/2.
+pub fn check<T: Element, F: Factor<T>>(
+    left: &F,
+    right: &F,
+    product: &DenseMatrix<T>,
+    overflow: OverflowPolicy,
+    rounds: usize,
+) -> Verdict {
+    let checkable = checkable_rows(left, right, overflow);
+    let mut rng = rand::rng();
+    let (mut bad_rows, mut bad_cols) = (BTreeSet::new(), BTreeSet::new());
+    for _ in 0..rounds {
+        let r: Vec<bool> = (0..product.cols()).map(|_| rng.random()).collect();
+        bad_rows.extend(check_rows(left, right, product, &r, &checkable));
+        // only rows which can be checked take part in the columns' sums
+        let s: Vec<bool> = checkable.iter().map(|&checkable| checkable && rng.random()).collect();
+        bad_cols.extend(check_cols(left, right, product, &s));
+    }
+    Verdict {
+        rows: bad_rows.into_iter().collect(),
+        cols: bad_cols.into_iter().collect(),
+        unchecked_rows: checkable.iter().filter(|&&checkable| !checkable).count(),
+    }
+}
+
+/// Checks just the tile of `product` covering `rows` x `cols`, against the rows
+/// of `left` and columns of `right` it is made of. This tells apart the tiles
+/// which hold a wrong cell from the others where a verdict's rows and columns
+/// cross, as with several wrong cells they cross at more tiles than hold one.
+pub fn check_tile<T: Element, F: Factor<T>>(
+    left: &F,
+    right: &F,
+    product: &DenseMatrix<T>,
+    rows: &Range<usize>,
+    cols: &Range<usize>,
+    overflow: OverflowPolicy,
+    rounds: usize,
+) -> bool {
+    let left = left.sub_block(rows.clone(), 0..left.cols());
+    let right = right.sub_block(0..right.rows(), cols.clone());
+    let product = product.block(rows.clone(), cols.clone()).to_matrix();
+    check(&left, &right, &product, overflow, rounds).passed()
+}
+
+// the rows i where (A·(B·r))_i and (C·r)_i differ
+fn check_rows<T: Element, F: Factor<T>>(
+    left: &F,
+    right: &F,
+    product: &DenseMatrix<T>,
+    r: &[bool],
+    checkable: &[bool],
+) -> Vec<usize> {
+    // B·r, with the magnitudes of its terms which bound the rounding error of floats
+    let mut b_r = vec![T::default(); right.rows()];
+    let mut b_r_magnitude = vec![0.0; right.rows()];
+    for k in 0..right.rows() {
+        for (_, b) in right.row_cells(k).filter(|&(j, _)| r[j]) {
+            b_r[k] = b_r[k].wrapping_add(b);
+            b_r_magnitude[k] += b.to_f64().abs();
+        }
+    }
+    let terms = left.cols() + product.cols();
+    (0..left.rows())
+        .filter(|&i| checkable[i])
+        .filter(|&i| {
+            let (mut expected, mut magnitude) = (T::default(), 0.0);
+            for (k, a) in left.row_cells(i) {
+                expected = expected.wrapping_add(a.wrapping_mul(b_r[k]));
+                magnitude += a.to_f64().abs() * b_r_magnitude[k];
+            }
+            let mut actual = T::default();
+            for (&c, _) in product.row(i).iter().zip(r).filter(|(_, picked)| **picked) {
+                actual = actual.wrapping_add(c);
+            }
+            differs(expected, actual, magnitude, terms)
+        })
+        .collect()
+}
+
+// the columns j where ((sᵀ·A)·B)_j and (sᵀ·C)_j differ
+fn check_cols<T: Element, F: Factor<T>>(
+    left: &F,
+    right: &F,
+    product: &DenseMatrix<T>,
+    s: &[bool],
+) -> Vec<usize> {
+    let picked: Vec<usize> = (0..left.rows()).filter(|&i| s[i]).collect();
+    // sᵀ·A and sᵀ·C, with the magnitudes of their terms
+    let mut s_a = vec![T::default(); left.cols()];
+    let mut s_a_magnitude = vec![0.0; left.cols()];
+    let mut s_c = vec![T::default(); product.cols()];
+    for &i in &picked {
+        for (k, a) in left.row_cells(i) {
+            s_a[k] = s_a[k].wrapping_add(a);
+            s_a_magnitude[k] += a.to_f64().abs();
+        }
+        for (j, &c) in product.row(i).iter().enumerate() {
+            s_c[j] = s_c[j].wrapping_add(c);
+        }
+    }
+    let mut expected = vec![T::default(); product.cols()];
+    let mut magnitude = vec![0.0; product.cols()];
+    for k in 0..right.rows() {
+        for (j, b) in right.row_cells(k) {
+            expected[j] = expected[j].wrapping_add(s_a[k].wrapping_mul(b));
+            magnitude[j] += s_a_magnitude[k] * b.to_f64().abs();
+        }
+    }
+    let terms = left.cols() + picked.len();
+    (0..product.cols()).filter(|&j| differs(expected[j], s_c[j], magnitude[j], terms)).collect()
+}
+
+// integers must match exactly, floats to within the rounding error, on both our
+// side and the workers', of sums of `terms` terms whose magnitudes add up to
+// `magnitude`. That is taken from the inputs alone, as a correct cell is never
+// bigger, so an infinite or NaN cell never matches.
+fn differs<T: Element>(expected: T, actual: T, magnitude: f64, terms: usize) -> bool {
+    if T::EPSILON == 0.0 {
+        return expected != actual;
+    }
+    let tolerance = 2.0 * (terms + 1) as f64 * T::EPSILON * magnitude;
+    let difference = (expected.to_f64() - actual.to_f64()).abs();
+    difference.is_nan() || difference > tolerance
+}
+
+// the rows of the product whose cells are linear in the inputs, which is all
+// the check can vouch for: under `saturating` a row whose products or partial
+// sums could leave the type's range may hold clamped cells, and a float row
+// whose magnitudes overflow may hold infinities and NaNs
+fn checkable_rows<T: Element, F: Factor<T>>(left: &F, right: &F, overflow: OverflowPolicy) -> Vec<bool> {
+    if T::EPSILON == 0.0 && overflow != OverflowPolicy::Saturating {
+        return vec![true; left.rows()];
+    }
+    let row_max: Vec<f64> =
+        (0..right.rows()).map(|k| right.row_cells(k).map(|(_, b)| b.to_f64().abs()).fold(0.0, f64::max)).collect();
+    (0..left.rows())
+        .map(|i| {
+            let bound: f64 = left.row_cells(i).map(|(k, a)| a.to_f64().abs() * row_max[k]).sum();
+            // leave a margin for the rounding of the bound itself
+            bound.is_finite() && bound * (1.0 + 1e-9) < T::LIMIT
+        })
+        .collect()
+}
@@ -0,0 +1,57 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+/// Encoding used for the bodies exchanged with workers. The broker announces it
+/// with `Content-Type` and asks for the same in `Accept`; workers fall back to
+/// JSON when no content type is given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::Bincode => "bincode",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Bincode => BINCODE_CONTENT_TYPE,
+        }
+    }
+
+    /// Maps a media type (ignoring parameters such as `charset`) to a format.
+    pub fn from_media_type(media_type: &str) -> Option<WireFormat> {
+        let essence = media_type.split(';').next().unwrap_or("").trim();
+        if essence.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+            Some(WireFormat::Json)
+        } else if essence.eq_ignore_ascii_case(BINCODE_CONTENT_TYPE) {
+            Some(WireFormat::Bincode)
+        } else {
+            None
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            WireFormat::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            WireFormat::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/// A configuration value that could not be read or does not make sense.
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Replaces `target` with the parsed value of the variable, if it is set.
+pub fn env_override<T: FromStr>(name: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = value
+            .trim()
+            .parse()
+            .map_err(|e| ConfigError(format!("{}: invalid value {:?}: {}", name, value, e)))?;
+    }
+    Ok(())
+}
+
+/// Sets `target` from the parsed variable, an empty value unsets it.
+pub fn env_option_override<T: FromStr>(name: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = match value.trim() {
+            "" => None,
+            trimmed => Some(
+                trimmed
+                    .parse()
+                    .map_err(|e| ConfigError(format!("{}: invalid value {:?}: {}", name, value, e)))?,
+            ),
+        };
+    }
+    Ok(())
+}
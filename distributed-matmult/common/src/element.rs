@@ -0,0 +1,24 @@
+pub use matmult_overflow::OverflowPolicy;
+use serde::{Deserialize, Serialize};
+
+/// Element type of the matrices in a multiplication.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DType {
+    #[default]
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl DType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DType::I32 => "i32",
+            DType::I64 => "i64",
+            DType::F32 => "f32",
+            DType::F64 => "f64",
+        }
+    }
+}
@@ -0,0 +1,7 @@
+// the matrix and element types the broker and the worker both use, and the
+// tracing and config setup they share, so neither keeps a copy of its own
+pub mod config;
+pub mod element;
+pub mod matrix;
+pub mod sparse;
+pub mod trace;
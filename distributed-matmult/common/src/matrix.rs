@@ -0,0 +1,271 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Reasons a matrix could not be built with the requested shape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShapeError {
+    /// A row of a nested matrix has a different length to the first row.
+    Ragged { row: usize, len: usize, expected: usize },
+    /// The flat data does not hold exactly `rows * cols` values.
+    DataLength { rows: usize, cols: usize, len: usize },
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShapeError::Ragged { row, len, expected } => write!(
+                f,
+                "row {} has {} columns but row 0 has {}",
+                row, len, expected
+            ),
+            // the shape comes from the client, so its cell count may not even fit in a usize
+            ShapeError::DataLength { rows, cols, len } => match rows.checked_mul(*cols) {
+                Some(cells) => write!(f, "a {}x{} matrix needs {} values but {} were given", rows, cols, cells, len),
+                None => write!(f, "a {}x{} matrix has too many cells for the {} values given", rows, cols, len),
+            },
+        }
+    }
+}
+
+impl Error for ShapeError {}
+
+/// Row-major matrix stored in one contiguous buffer with an explicit shape.
+///
+/// Serializes as `{rows, cols, data}`; deserializing checks that `data` holds
+/// exactly `rows * cols` values, so a `DenseMatrix` is always rectangular.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "DenseMatrixParts<T>")]
+pub struct DenseMatrix<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
+
+// unchecked mirror of `DenseMatrix` which serde deserializes into first
+#[derive(Deserialize)]
+struct DenseMatrixParts<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
+
+impl<T> TryFrom<DenseMatrixParts<T>> for DenseMatrix<T> {
+    type Error = ShapeError;
+
+    fn try_from(parts: DenseMatrixParts<T>) -> Result<Self, Self::Error> {
+        if parts.rows.checked_mul(parts.cols) != Some(parts.data.len()) {
+            return Err(ShapeError::DataLength { rows: parts.rows, cols: parts.cols, len: parts.data.len() });
+        }
+        Ok(DenseMatrix { rows: parts.rows, cols: parts.cols, data: parts.data })
+    }
+}
+
+impl<T: Copy + Default> DenseMatrix<T> {
+    pub fn new(rows: usize, cols: usize, data: Vec<T>) -> Result<Self, ShapeError> {
+        if rows.checked_mul(cols) != Some(data.len()) {
+            return Err(ShapeError::DataLength { rows, cols, len: data.len() });
+        }
+        Ok(DenseMatrix { rows, cols, data })
+    }
+
+    /// Panics if `rows * cols` overflows, callers bound the shape beforehand.
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        let cells = rows.checked_mul(cols).unwrap_or_else(|| panic!("a {}x{} matrix has too many cells", rows, cols));
+        DenseMatrix { rows, cols, data: vec![T::default(); cells] }
+    }
+
+    /// Builds a matrix from nested rows, failing on the first row whose length
+    /// differs from the first row's.
+    pub fn from_rows(nested: &[Vec<T>]) -> Result<Self, ShapeError> {
+        let cols = nested.first().map_or(0, |row| row.len());
+        let mut data = Vec::with_capacity(nested.len() * cols);
+        for (row_ind, row) in nested.iter().enumerate() {
+            if row.len() != cols {
+                return Err(ShapeError::Ragged { row: row_ind, len: row.len(), expected: cols });
+            }
+            data.extend_from_slice(row);
+        }
+        Ok(DenseMatrix { rows: nested.len(), cols, data })
+    }
+
+    /// Copies the matrix back out into nested rows.
+    pub fn to_rows(&self) -> Vec<Vec<T>> {
+        (0..self.rows).map(|i| self.row(i).to_vec()).collect()
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    /// Gives up the matrix for its row-major values.
+    pub fn into_data(self) -> Vec<T> {
+        self.data
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> T {
+        self.data[i * self.cols + j]
+    }
+
+    pub fn row(&self, i: usize) -> &[T] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn row_mut(&mut self, i: usize) -> &mut [T] {
+        &mut self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    /// Iterates down column `j`, striding through the contiguous buffer.
+    pub fn col(&self, j: usize) -> impl Iterator<Item = T> + '_ {
+        assert!(j < self.cols, "column {} out of range for {} columns", j, self.cols);
+        self.data.iter().skip(j).step_by(self.cols).copied()
+    }
+
+    /// Borrows the sub-matrix covering `rows` x `cols` without copying it.
+    pub fn block(&self, rows: Range<usize>, cols: Range<usize>) -> MatrixView<'_, T> {
+        assert!(rows.end <= self.rows && cols.end <= self.cols, "block out of range");
+        MatrixView { matrix: self, rows, cols }
+    }
+
+    /// Copies `block` into this matrix with its top-left corner at (`row_offset`, `col_offset`).
+    pub fn set_block(&mut self, row_offset: usize, col_offset: usize, block: &DenseMatrix<T>) {
+        assert!(
+            row_offset + block.rows <= self.rows && col_offset + block.cols <= self.cols,
+            "block out of range"
+        );
+        for i in 0..block.rows {
+            self.row_mut(row_offset + i)[col_offset..col_offset + block.cols].copy_from_slice(block.row(i));
+        }
+    }
+}
+
+/// A borrowed rectangular region of a `DenseMatrix`.
+#[derive(Clone, Debug)]
+pub struct MatrixView<'a, T> {
+    matrix: &'a DenseMatrix<T>,
+    rows: Range<usize>,
+    cols: Range<usize>,
+}
+
+impl<T: Copy + Default> MatrixView<'_, T> {
+    pub fn rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols.len()
+    }
+
+    pub fn row(&self, i: usize) -> &[T] {
+        &self.matrix.row(self.rows.start + i)[self.cols.clone()]
+    }
+
+    /// Copies the viewed region into its own contiguous matrix.
+    pub fn to_matrix(&self) -> DenseMatrix<T> {
+        let mut data = Vec::with_capacity(self.rows() * self.cols());
+        for i in 0..self.rows() {
+            data.extend_from_slice(self.row(i));
+        }
+        DenseMatrix { rows: self.rows(), cols: self.cols(), data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> DenseMatrix<i32> {
+        DenseMatrix::new(3, 4, (0..12).collect()).unwrap()
+    }
+
+    #[test]
+    fn data_which_does_not_fill_the_shape_is_rejected() {
+        assert_eq!(matrix().shape(), (3, 4));
+        assert_eq!(DenseMatrix::new(2, 3, vec![1; 5]), Err(ShapeError::DataLength { rows: 2, cols: 3, len: 5 }));
+        // a shape whose cell count overflows is rejected rather than wrapping round to the data's length
+        let error = DenseMatrix::<i32>::new(usize::MAX, 2, vec![]).unwrap_err();
+        assert_eq!(error, ShapeError::DataLength { rows: usize::MAX, cols: 2, len: 0 });
+        assert_eq!(
+            DenseMatrix::new(2, 2, vec![1; 3]).unwrap_err().to_string(),
+            "a 2x2 matrix needs 4 values but 3 were given"
+        );
+    }
+
+    #[test]
+    fn nested_rows_must_all_be_as_long_as_the_first() {
+        let rows = vec![vec![1, 2], vec![3, 4], vec![5, 6]];
+        let matrix = DenseMatrix::from_rows(&rows).unwrap();
+        assert_eq!(matrix.shape(), (3, 2));
+        assert_eq!(matrix.as_slice(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(matrix.to_rows(), rows);
+
+        let ragged = DenseMatrix::from_rows(&[vec![1, 2], vec![3], vec![5, 6]]).unwrap_err();
+        assert_eq!(ragged, ShapeError::Ragged { row: 1, len: 1, expected: 2 });
+        assert_eq!(ragged.to_string(), "row 1 has 1 columns but row 0 has 2");
+        assert_eq!(DenseMatrix::<i32>::from_rows(&[]).unwrap().shape(), (0, 0));
+    }
+
+    #[test]
+    fn deserializing_checks_the_data_fills_the_shape() {
+        let json = serde_json::to_string(&matrix()).unwrap();
+        assert_eq!(serde_json::from_str::<DenseMatrix<i32>>(&json).unwrap(), matrix());
+
+        let short = serde_json::from_str::<DenseMatrix<i32>>(r#"{"rows": 2, "cols": 2, "data": [1, 2, 3]}"#);
+        assert!(short.unwrap_err().to_string().contains("a 2x2 matrix needs 4 values but 3 were given"));
+        let huge = format!(r#"{{"rows": {}, "cols": 2, "data": []}}"#, usize::MAX);
+        let error = serde_json::from_str::<DenseMatrix<i32>>(&huge).unwrap_err();
+        assert!(error.to_string().contains("matrix has too many cells for the 0 values given"), "{}", error);
+    }
+
+    #[test]
+    fn rows_columns_and_blocks_view_the_right_cells() {
+        let mut matrix = matrix();
+        assert_eq!(matrix.get(1, 2), 6);
+        assert_eq!(matrix.row(2), [8, 9, 10, 11]);
+        assert_eq!(matrix.col(1).collect::<Vec<_>>(), [1, 5, 9]);
+        assert_eq!(matrix.col(3).collect::<Vec<_>>(), [3, 7, 11]);
+
+        let block = matrix.block(1..3, 1..3);
+        assert_eq!((block.rows(), block.cols()), (2, 2));
+        assert_eq!(block.row(1), [9, 10]);
+        assert_eq!(block.to_matrix().to_rows(), [[5, 6], [9, 10]]);
+        assert_eq!(matrix.block(0..0, 0..4).to_matrix().shape(), (0, 4));
+
+        matrix.row_mut(0)[0] = -1;
+        matrix.set_block(1, 2, &DenseMatrix::from_rows(&[vec![20, 21], vec![22, 23]]).unwrap());
+        assert_eq!(matrix.to_rows(), [[-1, 1, 2, 3], [4, 5, 20, 21], [8, 9, 22, 23]]);
+        assert_eq!(matrix.into_data().len(), 12);
+    }
+
+    #[test]
+    #[should_panic(expected = "column 4 out of range for 4 columns")]
+    fn columns_past_the_last_panic() {
+        matrix().col(4).count();
+    }
+
+    #[test]
+    #[should_panic(expected = "block out of range")]
+    fn blocks_past_the_edge_panic() {
+        matrix().block(2..4, 0..1);
+    }
+
+    #[test]
+    #[should_panic(expected = "too many cells")]
+    fn zeros_panics_when_the_cell_count_overflows() {
+        DenseMatrix::<i32>::zeros(usize::MAX, 2);
+    }
+}
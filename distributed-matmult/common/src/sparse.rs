@@ -0,0 +1,331 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::matrix::DenseMatrix;
+
+/// Reasons the parts of a sparse matrix don't describe one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SparseError {
+    /// `row_ptr` does not have one entry more than the matrix has rows.
+    RowPointerLength { rows: usize, len: usize },
+    /// `row_ptr` does not run from 0 up to the number of values without decreasing.
+    RowPointer { index: usize, value: usize },
+    /// An index array is not as long as the values.
+    IndexLength { indices: &'static str, len: usize, values: usize },
+    /// A value lies outside the matrix.
+    OutOfRange { row: usize, col: usize, rows: usize, cols: usize },
+    /// A cell is given twice, or the columns of a row don't ascend.
+    Unordered { row: usize, col: usize },
+}
+
+impl fmt::Display for SparseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SparseError::RowPointerLength { rows, len } => {
+                write!(f, "row_ptr has {} entries but needs one more than the matrix's {} rows", len, rows)
+            }
+            SparseError::RowPointer { index, value } => write!(
+                f,
+                "row_ptr must run from 0 up to the number of values without decreasing, but row_ptr[{}] is {}",
+                index, value
+            ),
+            SparseError::IndexLength { indices, len, values } => {
+                write!(f, "{} has {} entries but there are {} values", indices, len, values)
+            }
+            SparseError::OutOfRange { row, col, rows, cols } => {
+                write!(f, "cell ({}, {}) is outside a {}x{} matrix", row, col, rows, cols)
+            }
+            SparseError::Unordered { row, col } => {
+                write!(f, "cell ({}, {}) is given more than once or out of order", row, col)
+            }
+        }
+    }
+}
+
+impl Error for SparseError {}
+
+/// Matrix holding only its non-zero cells, in compressed sparse row form: the
+/// values of row `i` are `values[row_ptr[i]..row_ptr[i + 1]]`, in the columns
+/// at the same positions of `col_indices`, which ascend within each row.
+///
+/// Serializes as `{rows, cols, row_ptr, col_indices, values}`; deserializing
+/// checks the parts fit together, so a `CsrMatrix` is always well formed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "CsrMatrixParts<T>")]
+pub struct CsrMatrix<T> {
+    rows: usize,
+    cols: usize,
+    row_ptr: Vec<usize>,
+    col_indices: Vec<usize>,
+    values: Vec<T>,
+}
+
+// unchecked mirror of `CsrMatrix` which serde deserializes into first
+#[derive(Deserialize)]
+struct CsrMatrixParts<T> {
+    rows: usize,
+    cols: usize,
+    row_ptr: Vec<usize>,
+    col_indices: Vec<usize>,
+    values: Vec<T>,
+}
+
+impl<T> TryFrom<CsrMatrixParts<T>> for CsrMatrix<T> {
+    type Error = SparseError;
+
+    fn try_from(parts: CsrMatrixParts<T>) -> Result<Self, Self::Error> {
+        let CsrMatrixParts { rows, cols, row_ptr, col_indices, values } = parts;
+        check_row_ptr(rows, &row_ptr, values.len())?;
+        if col_indices.len() != values.len() {
+            return Err(SparseError::IndexLength { indices: "col_indices", len: col_indices.len(), values: values.len() });
+        }
+        for (row, bounds) in row_ptr.windows(2).enumerate() {
+            let mut previous = None;
+            for &col in &col_indices[bounds[0]..bounds[1]] {
+                if col >= cols {
+                    return Err(SparseError::OutOfRange { row, col, rows, cols });
+                }
+                if previous.is_some_and(|previous| previous >= col) {
+                    return Err(SparseError::Unordered { row, col });
+                }
+                previous = Some(col);
+            }
+        }
+        Ok(CsrMatrix { rows, cols, row_ptr, col_indices, values })
+    }
+}
+
+/// Checks that `row_ptr` has an entry per row and one more, starts at 0, never
+/// decreases and ends at the number of values.
+pub fn check_row_ptr(rows: usize, row_ptr: &[usize], values: usize) -> Result<(), SparseError> {
+    if rows.checked_add(1) != Some(row_ptr.len()) {
+        return Err(SparseError::RowPointerLength { rows, len: row_ptr.len() });
+    }
+    let mut previous = 0;
+    for (index, &value) in row_ptr.iter().enumerate() {
+        let expected_end = index < rows || value == values;
+        if (index == 0 && value != 0) || value < previous || value > values || !expected_end {
+            return Err(SparseError::RowPointer { index, value });
+        }
+        previous = value;
+    }
+    Ok(())
+}
+
+impl<T: Copy + Default + PartialEq> CsrMatrix<T> {
+    pub fn new(
+        rows: usize,
+        cols: usize,
+        row_ptr: Vec<usize>,
+        col_indices: Vec<usize>,
+        values: Vec<T>,
+    ) -> Result<Self, SparseError> {
+        CsrMatrix::try_from(CsrMatrixParts { rows, cols, row_ptr, col_indices, values })
+    }
+
+    /// Builds a matrix from (row, column, value) triplets in any order, failing
+    /// on a cell outside the matrix or given more than once.
+    pub fn from_triplets(rows: usize, cols: usize, mut triplets: Vec<(usize, usize, T)>) -> Result<Self, SparseError> {
+        if let Some(&(row, col, _)) = triplets.iter().find(|&&(row, col, _)| row >= rows || col >= cols) {
+            return Err(SparseError::OutOfRange { row, col, rows, cols });
+        }
+        triplets.sort_by_key(|&(row, col, _)| (row, col));
+        if let Some(pair) = triplets.windows(2).find(|pair| (pair[0].0, pair[0].1) == (pair[1].0, pair[1].1)) {
+            return Err(SparseError::Unordered { row: pair[1].0, col: pair[1].1 });
+        }
+        let mut row_ptr = vec![0; rows + 1];
+        for &(row, _, _) in &triplets {
+            row_ptr[row + 1] += 1;
+        }
+        for row in 0..rows {
+            row_ptr[row + 1] += row_ptr[row];
+        }
+        let (col_indices, values) = triplets.into_iter().map(|(_, col, value)| (col, value)).unzip();
+        Ok(CsrMatrix { rows, cols, row_ptr, col_indices, values })
+    }
+
+    /// Keeps the cells of `dense` which aren't zero.
+    pub fn from_dense(dense: &DenseMatrix<T>) -> Self {
+        let mut row_ptr = Vec::with_capacity(dense.rows() + 1);
+        let (mut col_indices, mut values) = (Vec::new(), Vec::new());
+        row_ptr.push(0);
+        for i in 0..dense.rows() {
+            for (j, &value) in dense.row(i).iter().enumerate() {
+                if value != T::default() {
+                    col_indices.push(j);
+                    values.push(value);
+                }
+            }
+            row_ptr.push(values.len());
+        }
+        CsrMatrix { rows: dense.rows(), cols: dense.cols(), row_ptr, col_indices, values }
+    }
+
+    /// Drops the stored cells which are zero, as a matrix built from parts may have.
+    pub fn without_zeros(self) -> Self {
+        if !self.values.contains(&T::default()) {
+            return self;
+        }
+        let mut row_ptr = Vec::with_capacity(self.rows + 1);
+        let (mut col_indices, mut values) = (Vec::new(), Vec::new());
+        row_ptr.push(0);
+        for i in 0..self.rows {
+            let (cols, row_values) = self.row(i);
+            for (&j, &value) in cols.iter().zip(row_values) {
+                if value != T::default() {
+                    col_indices.push(j);
+                    values.push(value);
+                }
+            }
+            row_ptr.push(values.len());
+        }
+        CsrMatrix { rows: self.rows, cols: self.cols, row_ptr, col_indices, values }
+    }
+
+    /// Fills in the zeros.
+    pub fn to_dense(&self) -> DenseMatrix<T> {
+        let mut dense = DenseMatrix::zeros(self.rows, self.cols);
+        for i in 0..self.rows {
+            let (cols, values) = self.row(i);
+            let row = dense.row_mut(i);
+            for (&j, &value) in cols.iter().zip(values) {
+                row[j] = value;
+            }
+        }
+        dense
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Number of cells stored, which is every cell that isn't zero.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn row_ptr(&self) -> &[usize] {
+        &self.row_ptr
+    }
+
+    pub fn col_indices(&self) -> &[usize] {
+        &self.col_indices
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    /// The ascending columns of row `i` which hold a value, and those values.
+    pub fn row(&self, i: usize) -> (&[usize], &[T]) {
+        let range = self.row_ptr[i]..self.row_ptr[i + 1];
+        (&self.col_indices[range.clone()], &self.values[range])
+    }
+
+    /// Iterates over the stored cells as (row, column, value), row by row.
+    pub fn triplets(&self) -> impl Iterator<Item = (usize, usize, T)> + '_ {
+        (0..self.rows).flat_map(move |i| {
+            let (cols, values) = self.row(i);
+            cols.iter().zip(values).map(move |(&j, &value)| (i, j, value))
+        })
+    }
+
+    /// The matrix with rows and columns swapped, still in compressed sparse row
+    /// form, so its rows are the columns of this one.
+    pub fn transpose(&self) -> CsrMatrix<T> {
+        let mut row_ptr = vec![0; self.cols + 1];
+        for &j in &self.col_indices {
+            row_ptr[j + 1] += 1;
+        }
+        for j in 0..self.cols {
+            row_ptr[j + 1] += row_ptr[j];
+        }
+        // walking the rows in order leaves each column's cells in ascending row order
+        let mut next = row_ptr.clone();
+        let mut col_indices = vec![0; self.nnz()];
+        let mut values = vec![T::default(); self.nnz()];
+        for (i, j, value) in self.triplets() {
+            col_indices[next[j]] = i;
+            values[next[j]] = value;
+            next[j] += 1;
+        }
+        CsrMatrix { rows: self.cols, cols: self.rows, row_ptr, col_indices, values }
+    }
+
+    /// Copies the sub-matrix covering `rows` x `cols` into a matrix of its own.
+    pub fn block(&self, rows: Range<usize>, cols: Range<usize>) -> CsrMatrix<T> {
+        assert!(rows.end <= self.rows && cols.end <= self.cols, "block out of range");
+        let mut row_ptr = Vec::with_capacity(rows.len() + 1);
+        let (mut col_indices, mut values) = (Vec::new(), Vec::new());
+        row_ptr.push(0);
+        for i in rows.clone() {
+            let (row_cols, row_values) = self.row(i);
+            for (&j, &value) in row_cols.iter().zip(row_values) {
+                if cols.contains(&j) {
+                    col_indices.push(j - cols.start);
+                    values.push(value);
+                }
+            }
+            row_ptr.push(values.len());
+        }
+        CsrMatrix { rows: rows.len(), cols: cols.len(), row_ptr, col_indices, values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dense() -> DenseMatrix<i32> {
+        DenseMatrix::from_rows(&[vec![0, 3, 0, 1], vec![0, 0, 0, 0], vec![5, 0, 0, -2]]).unwrap()
+    }
+
+    #[test]
+    fn triplets_in_any_order_build_the_same_matrix() {
+        let triplets = vec![(2, 3, -2), (0, 1, 3), (2, 0, 5), (0, 3, 1)];
+        let sparse = CsrMatrix::from_triplets(3, 4, triplets).unwrap();
+
+        assert_eq!(sparse, CsrMatrix::from_dense(&dense()));
+        assert_eq!(sparse.row_ptr(), [0, 2, 2, 4]);
+        assert_eq!(sparse.to_dense(), dense());
+        assert_eq!(sparse.triplets().collect::<Vec<_>>(), [(0, 1, 3), (0, 3, 1), (2, 0, 5), (2, 3, -2)]);
+    }
+
+    #[test]
+    fn transposes_and_blocks_match_the_dense_ones() {
+        let sparse = CsrMatrix::from_dense(&dense());
+        let transposed: Vec<Vec<i32>> = (0..4).map(|j| dense().col(j).collect()).collect();
+
+        assert_eq!(sparse.transpose().to_dense().to_rows(), transposed);
+        assert_eq!(sparse.transpose().transpose(), sparse);
+        assert_eq!(sparse.block(1..3, 0..2).to_dense(), dense().block(1..3, 0..2).to_matrix());
+        // stored zeros are dropped
+        let with_zero = CsrMatrix::new(1, 2, vec![0, 2], vec![0, 1], vec![0, 7]).unwrap();
+        assert_eq!(with_zero.without_zeros().triplets().collect::<Vec<_>>(), [(0, 1, 7)]);
+    }
+
+    #[test]
+    fn parts_which_do_not_fit_together_are_rejected() {
+        let out_of_range = SparseError::OutOfRange { row: 0, col: 2, rows: 2, cols: 2 };
+        assert_eq!(CsrMatrix::<i32>::from_triplets(2, 2, vec![(0, 2, 1)]), Err(out_of_range));
+        let twice = CsrMatrix::from_triplets(2, 2, vec![(1, 0, 1), (1, 0, 2)]);
+        assert_eq!(twice, Err(SparseError::Unordered { row: 1, col: 0 }));
+        let decreasing = CsrMatrix::new(2, 2, vec![0, 1, 0], vec![0], vec![1]);
+        assert_eq!(decreasing, Err(SparseError::RowPointer { index: 2, value: 0 }));
+        // a declared shape too big to have a row pointer doesn't overflow
+        let error = check_row_ptr(usize::MAX, &[0], 0).unwrap_err();
+        assert_eq!(error, SparseError::RowPointerLength { rows: usize::MAX, len: 1 });
+        assert!(error.to_string().contains(&usize::MAX.to_string()));
+    }
+}
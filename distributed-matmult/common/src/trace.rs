@@ -0,0 +1,378 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+use uuid::Uuid;
+
+// spans sent to the collector in one request, or written as one line of the file
+const EXPORT_BATCH: usize = 512;
+// closed spans waiting to be exported, any more are dropped until the exporter
+// catches up so a slow or unreachable collector can't eat up the memory
+const EXPORT_QUEUE: usize = 16 * EXPORT_BATCH;
+
+// spans dropped for finding the export queue full, since the process started
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+/// How log lines are written to stdout.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines, with the fields of the enclosing spans.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+/// Where finished spans go, on top of the log.
+pub enum SpanExport {
+    /// POSTed as OTLP/JSON to `{endpoint}/v1/traces` of a collector.
+    Collector(String),
+    /// Appended as OTLP/JSON, one export request per line.
+    File(PathBuf),
+}
+
+/// 16 random bytes naming a trace, shared by every span of one request
+/// across the broker and its workers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceId([u8; 16]);
+
+/// 8 random bytes naming one span within a trace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SpanId([u8; 8]);
+
+impl TraceId {
+    pub fn random() -> Self {
+        TraceId(*Uuid::new_v4().as_bytes())
+    }
+}
+
+impl SpanId {
+    pub fn random() -> Self {
+        let bytes = Uuid::new_v4().into_bytes();
+        SpanId(bytes[..8].try_into().expect("a uuid has more than 8 bytes"))
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+// lowercase hex into a fixed number of bytes, None if it is the wrong length or all zero
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    bytes.iter().any(|&b| b != 0).then_some(bytes)
+}
+
+/// Reads a W3C `traceparent` header, `00-{trace id}-{parent span id}-{flags}`.
+pub fn parse_traceparent(header: &str) -> Option<(TraceId, SpanId)> {
+    let mut parts = header.trim().split('-');
+    let (version, trace_id, span_id, _flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" {
+        return None;
+    }
+    Some((TraceId(parse_hex(trace_id)?), SpanId(parse_hex(span_id)?)))
+}
+
+/// The root span of an HTTP request, continuing the caller's trace if it sent
+/// a `traceparent` header and starting a new one otherwise.
+pub fn request_span(method: &str, path: &str, traceparent: Option<&str>) -> tracing::Span {
+    let (trace_id, parent) = match traceparent.and_then(parse_traceparent) {
+        Some((trace_id, parent)) => (trace_id, Some(parent)),
+        None => (TraceId::random(), None),
+    };
+    let parent = parent.map(|parent| parent.to_string()).unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        http.method = %method,
+        http.path = %path,
+        trace_id = %trace_id,
+        parent_span_id = %parent,
+    )
+}
+
+/// The `traceparent` header naming the current span, for the requests made within it.
+pub fn current_traceparent() -> Option<String> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            let record = extensions.get::<SpanRecord>()?;
+            Some(format!("00-{}-{}-01", record.trace_id, record.span_id))
+        })
+        .flatten()
+}
+
+/// Number of closed spans dropped rather than exported for finding the export
+/// queue full.
+pub fn dropped_spans() -> u64 {
+    DROPPED_SPANS.load(Ordering::Relaxed)
+}
+
+/// Installs the global subscriber: the log on stdout, filtered by `filter` (in
+/// `RUST_LOG` syntax), and the layer which gives this crate's spans their trace
+/// and span IDs and hands them to `export` when they close.
+pub fn init(service: &'static str, format: LogFormat, filter: &str, export: Option<SpanExport>) -> Result<(), String> {
+    let filter = EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter {:?}: {}", filter, e))?;
+    let log = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_current_span(true).boxed(),
+    };
+
+    let exporter = export.map(|sink| {
+        let (sender, receiver) = mpsc::channel(EXPORT_QUEUE);
+        tokio::spawn(export_spans(service, sink, receiver));
+        sender
+    });
+    // only this crate's spans are traced, not those of the HTTP libraries underneath
+    let spans = SpanLayer { exporter }.with_filter(Targets::new().with_target(service, Level::INFO));
+
+    tracing_subscriber::registry()
+        .with(log.with_filter(filter))
+        .with(spans)
+        .try_init()
+        .map_err(|e| e.to_string())
+}
+
+// what is known about a span, kept in its extensions while it is open
+struct SpanRecord {
+    trace_id: TraceId,
+    span_id: SpanId,
+    parent_span_id: Option<SpanId>,
+    name: &'static str,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+struct SpanLayer {
+    exporter: Option<mpsc::Sender<(SpanRecord, SystemTime)>>,
+}
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+
+        // a span carries on its parent's trace unless it names its own, as a request span does
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<SpanRecord>().map(|record| (record.trace_id, record.span_id))
+        });
+        let trace_id = fields
+            .trace_id
+            .or(parent.map(|(trace_id, _)| trace_id))
+            .unwrap_or_else(TraceId::random);
+        let record = SpanRecord {
+            trace_id,
+            span_id: SpanId::random(),
+            parent_span_id: fields.parent_span_id.or(parent.map(|(_, span_id)| span_id)),
+            name: attrs.metadata().name(),
+            kind: fields.kind,
+            start: SystemTime::now(),
+            attributes: fields.attributes,
+            error: None,
+        };
+        span.extensions_mut().insert(record);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = FieldVisitor::default();
+        values.record(&mut fields);
+        if let Some(record) = span.extensions_mut().get_mut::<SpanRecord>() {
+            record.attributes.extend(fields.attributes);
+        }
+    }
+
+    // an error logged inside a span marks the span as failed
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let Some(span) = ctx.event_span(event) else { return };
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+        if let Some(record) = span.extensions_mut().get_mut::<SpanRecord>() {
+            record.error = Some(fields.message.unwrap_or_default());
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let (Some(exporter), Some(span)) = (&self.exporter, ctx.span(&id)) else { return };
+        if let Some(record) = span.extensions_mut().remove::<SpanRecord>()
+            && exporter.try_send((record, SystemTime::now())).is_err()
+        {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// picks the fields with a meaning for tracing out of a span's or event's fields
+#[derive(Default)]
+struct FieldVisitor {
+    trace_id: Option<TraceId>,
+    parent_span_id: Option<SpanId>,
+    kind: u8,
+    message: Option<String>,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "trace_id" => self.trace_id = parse_hex(value).map(TraceId),
+            "parent_span_id" => self.parent_span_id = parse_hex(value).map(SpanId),
+            // OTLP span kinds: internal, server, client
+            "otel.kind" => {
+                self.kind = match value {
+                    "server" => 2,
+                    "client" => 3,
+                    _ => 1,
+                }
+            }
+            "message" => self.message = Some(value.to_string()),
+            name => self.attributes.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+// an OTLP/JSON ExportTraceServiceRequest holding `spans`
+fn export_request(service: &str, spans: &[(SpanRecord, SystemTime)]) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|(record, end)| {
+            let attributes: Vec<serde_json::Value> = record
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                .collect();
+            let status = match &record.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({}),
+            };
+            json!({
+                "traceId": record.trace_id.to_string(),
+                "spanId": record.span_id.to_string(),
+                "parentSpanId": record.parent_span_id.map(|id| id.to_string()).unwrap_or_default(),
+                "name": record.name,
+                "kind": record.kind.max(1),
+                "startTimeUnixNano": unix_nanos(record.start),
+                "endTimeUnixNano": unix_nanos(*end),
+                "attributes": attributes,
+                "status": status,
+            })
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": service } }] },
+            "scopeSpans": [{ "scope": { "name": service }, "spans": spans }],
+        }]
+    })
+}
+
+// sends closed spans on in batches for as long as the process runs. Failures and
+// dropped spans are only logged: this module's events are outside the service's
+// target, so they never become spans to export themselves
+async fn export_spans(
+    service: &'static str,
+    sink: SpanExport,
+    mut spans: mpsc::Receiver<(SpanRecord, SystemTime)>,
+) {
+    let client = reqwest::Client::new();
+    let mut reported_drops = 0;
+    while let Some(first) = spans.recv().await {
+        let dropped = dropped_spans();
+        if dropped > reported_drops {
+            tracing::warn!(dropped = dropped - reported_drops, "Dropped spans while the export queue was full");
+            reported_drops = dropped;
+        }
+        let mut batch = vec![first];
+        while batch.len() < EXPORT_BATCH {
+            match spans.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        let body = export_request(service, &batch);
+        let outcome = match &sink {
+            SpanExport::Collector(endpoint) => {
+                let url = format!("{}/v1/traces", endpoint.trim_end_matches('/').trim_end_matches("/v1/traces"));
+                client
+                    .post(url)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+            SpanExport::File(path) => append_line(path, &body).await.map_err(|e| e.to_string()),
+        };
+        if let Err(e) = outcome {
+            tracing::warn!(spans = batch.len(), "Failed to export spans: {}", e);
+        }
+    }
+}
+
+async fn append_line(path: &PathBuf, body: &serde_json::Value) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(body)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+    file.write_all(&line).await
+}
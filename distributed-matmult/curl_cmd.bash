@@ -3,6 +3,7 @@
 curl -X POST http://localhost:8000/multiply_matrices_distributed \
      -H "Content-Type: application/json" \
      -d '{
+           "dtype": "i32",
            "left": [[1, 2, 3],
                         [4, 5, 6]],
            "right": [[ 7,  8],
@@ -0,0 +1,74 @@
+// the integer overflow policies every matmult implementation honours, kept in
+// one small crate so the single process ones need nothing else to share them
+use serde::{Deserialize, Serialize};
+
+/// How integer multiply-adds behave when they leave the element type's range.
+/// The distributed broker forwards the policy with every tile so all workers
+/// agree. Floats always follow IEEE arithmetic; `Widen` accumulates them in `f64`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wrap around in two's complement, matching release-mode `+` and `*`.
+    Wrapping,
+    /// Clamp every product and partial sum to the type's bounds.
+    #[default]
+    Saturating,
+    /// Fail, naming the cell, as soon as any product or partial sum overflows.
+    Checked,
+    /// Accumulate in a type twice as wide and fail only if the final sum does not fit.
+    Widen,
+}
+
+impl OverflowPolicy {
+    /// Every policy, in declaration order, so `policy as usize` indexes it.
+    pub const ALL: [OverflowPolicy; 4] =
+        [OverflowPolicy::Wrapping, OverflowPolicy::Saturating, OverflowPolicy::Checked, OverflowPolicy::Widen];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OverflowPolicy::Wrapping => "wrapping",
+            OverflowPolicy::Saturating => "saturating",
+            OverflowPolicy::Checked => "checked",
+            OverflowPolicy::Widen => "widen",
+        }
+    }
+}
+
+/// Dot product of `row_vec` and `col_vec` under `policy`, accumulating terms in
+/// ascending order. Returns `None` if the `Checked` or `Widen` policy overflowed.
+pub fn dot_product(row_vec: &[i32], col_vec: &[i32], policy: OverflowPolicy) -> Option<i32> {
+    let mut terms = row_vec.iter().zip(col_vec);
+    match policy {
+        OverflowPolicy::Wrapping => Some(terms.fold(0i32, |acc, (l, r)| acc.wrapping_add(l.wrapping_mul(*r)))),
+        OverflowPolicy::Saturating => Some(terms.fold(0i32, |acc, (l, r)| acc.saturating_add(l.saturating_mul(*r)))),
+        OverflowPolicy::Checked => terms.try_fold(0i32, |acc, (l, r)| l.checked_mul(*r).and_then(|p| acc.checked_add(p))),
+        OverflowPolicy::Widen => {
+            // products of two i32s always fit in an i64
+            let wide = terms.try_fold(0i64, |acc, (l, r)| acc.checked_add(i64::from(*l) * i64::from(*r)))?;
+            i32::try_from(wide).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_policy_handles_an_overflowing_dot_product_its_own_way() {
+        // both products overflow an i32, though their sum of 0 does not
+        let (row, col) = ([i32::MAX, i32::MAX], [2, -2]);
+        assert_eq!(dot_product(&row, &col, OverflowPolicy::Wrapping), Some(0));
+        assert_eq!(dot_product(&row, &col, OverflowPolicy::Saturating), Some(-1));
+        assert_eq!(dot_product(&row, &col, OverflowPolicy::Checked), None);
+        assert_eq!(dot_product(&row, &col, OverflowPolicy::Widen), Some(0));
+        assert_eq!(dot_product(&[i32::MAX, 1], &[1, 1], OverflowPolicy::Widen), None);
+    }
+
+    #[test]
+    fn policies_are_indexed_by_their_discriminant() {
+        for policy in OverflowPolicy::ALL {
+            assert_eq!(OverflowPolicy::ALL[policy as usize], policy);
+        }
+    }
+}
@@ -0,0 +1,9 @@
+// generates the gRPC service from the proto file shared with the broker, and
+// the client the service's tests call it through
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use the vendored protoc so building doesn't need one installed
+    // SAFETY: build scripts are single threaded, nothing else reads the environment
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    tonic_prost_build::compile_protos("../proto/matmult.proto")?;
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use matmult_common::config::{ConfigError, env_option_override, env_override};
+use matmult_common::trace::{LogFormat, SpanExport};
+use serde::Deserialize;
+
+// config file read when WORKER_CONFIG is not set, skipped if it does not exist
+const DEFAULT_CONFIG_PATH: &str = "worker.toml";
+
+/// Worker settings, loaded the same way as the broker's: a TOML file
+/// (`WORKER_CONFIG`, or `worker.toml` if present), then `WORKER_*` environment
+/// variables, which may also come from a `.env` file. `BROKER_URL` and
+/// `WORKER_PORT` are still honoured as before.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Address the HTTP server binds to.
+    pub listen_addr: SocketAddr,
+    /// Broker to register with, registration is skipped when unset.
+    pub broker_url: Option<String>,
+    /// Address the broker should use to reach this worker, defaults to
+    /// `http://$HOSTNAME:<port>`.
+    pub advertise_url: Option<String>,
+    pub heartbeat_secs: u64,
+    /// Largest request body accepted, larger ones get a 413.
+    pub max_body_bytes: u64,
+    /// Threads used by the block kernel, 0 means one per core.
+    pub compute_threads: usize,
+    /// Most blocks multiplied at once, further requests wait their turn.
+    pub max_concurrent_blocks: usize,
+    /// Address the gRPC server binds to, gRPC is not served when unset.
+    pub grpc_listen_addr: Option<SocketAddr>,
+    /// Address the broker should use to reach the gRPC server, defaults to
+    /// `http://$HOSTNAME:<grpc port>`.
+    pub grpc_advertise_url: Option<String>,
+    /// Which of the two servers the broker sends this worker's tiles to.
+    pub transport: Transport,
+    pub tracing: TracingConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub log_format: LogFormat,
+    /// Which log lines are written, in `RUST_LOG` syntax.
+    pub log_filter: String,
+    /// Base URL of an OTLP/HTTP collector to send spans to, such as
+    /// `http://localhost:4318`.
+    pub otlp_endpoint: Option<String>,
+    /// File to append spans to as OTLP/JSON, one export request per line.
+    pub otlp_file: Option<PathBuf>,
+}
+
+/// How the broker talks to the worker.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Http,
+    Grpc,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Transport::Http),
+            "grpc" => Ok(Transport::Grpc),
+            _ => Err("expected http or grpc".to_string()),
+        }
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 9001)),
+            broker_url: None,
+            advertise_url: None,
+            heartbeat_secs: 5,
+            max_body_bytes: 256 * 1024 * 1024,
+            compute_threads: 0,
+            max_concurrent_blocks: 4,
+            grpc_listen_addr: None,
+            grpc_advertise_url: None,
+            transport: Transport::default(),
+            tracing: TracingConfig::default(),
+        }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            log_format: LogFormat::Text,
+            log_filter: "info,warp=warn".to_string(),
+            otlp_endpoint: None,
+            otlp_file: None,
+        }
+    }
+}
+
+impl WorkerConfig {
+    /// Loads the file, applies environment overrides and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        // a missing .env file is fine, the real environment still applies
+        dotenvy::dotenv().ok();
+
+        let mut config = match env::var("WORKER_CONFIG") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            Err(_) => WorkerConfig::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError(format!("cannot read config file {}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| ConfigError(format!("invalid config file {}: {}", path.display(), e)))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override("WORKER_LISTEN_ADDR", &mut self.listen_addr)?;
+        // WORKER_PORT only changes the port, keeping the bind address
+        let mut port = self.listen_addr.port();
+        env_override("WORKER_PORT", &mut port)?;
+        self.listen_addr.set_port(port);
+        env_option_override("BROKER_URL", &mut self.broker_url)?;
+        env_option_override("WORKER_ADVERTISE_URL", &mut self.advertise_url)?;
+        env_override("WORKER_HEARTBEAT_SECS", &mut self.heartbeat_secs)?;
+        env_override("WORKER_MAX_BODY_BYTES", &mut self.max_body_bytes)?;
+        env_override("WORKER_COMPUTE_THREADS", &mut self.compute_threads)?;
+        env_override("WORKER_MAX_CONCURRENT_BLOCKS", &mut self.max_concurrent_blocks)?;
+        env_option_override("WORKER_GRPC_LISTEN_ADDR", &mut self.grpc_listen_addr)?;
+        env_option_override("WORKER_GRPC_ADVERTISE_URL", &mut self.grpc_advertise_url)?;
+        env_override("WORKER_TRANSPORT", &mut self.transport)?;
+        env_override("WORKER_LOG_FORMAT", &mut self.tracing.log_format)?;
+        env_override("WORKER_LOG_FILTER", &mut self.tracing.log_filter)?;
+        env_option_override("WORKER_OTLP_ENDPOINT", &mut self.tracing.otlp_endpoint)?;
+        env_option_override("WORKER_OTLP_FILE", &mut self.tracing.otlp_file)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let urls = [
+            ("broker_url", &self.broker_url),
+            ("advertise_url", &self.advertise_url),
+            ("grpc_advertise_url", &self.grpc_advertise_url),
+            ("tracing.otlp_endpoint", &self.tracing.otlp_endpoint),
+        ];
+        for (name, url) in urls {
+            let not_http = |url: &&String| !(url.starts_with("http://") || url.starts_with("https://"));
+            if let Some(url) = url.as_ref().filter(not_http) {
+                return Err(ConfigError(format!("{}: {:?} is not an absolute http(s) URL", name, url)));
+            }
+        }
+        if self.heartbeat_secs == 0 {
+            return Err(ConfigError("heartbeat_secs must be greater than 0".to_string()));
+        }
+        if self.max_body_bytes == 0 {
+            return Err(ConfigError("max_body_bytes must be greater than 0".to_string()));
+        }
+        if self.max_concurrent_blocks == 0 {
+            return Err(ConfigError("max_concurrent_blocks must be greater than 0".to_string()));
+        }
+        if self.transport == Transport::Grpc && self.grpc_listen_addr.is_none() {
+            return Err(ConfigError("transport = \"grpc\" needs grpc_listen_addr to be set".to_string()));
+        }
+        if self.tracing.otlp_endpoint.is_some() && self.tracing.otlp_file.is_some() {
+            return Err(ConfigError("tracing: set otlp_endpoint or otlp_file, not both".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs)
+    }
+
+    /// Where finished spans are exported to, if anywhere.
+    pub fn span_export(&self) -> Option<SpanExport> {
+        match (&self.tracing.otlp_endpoint, &self.tracing.otlp_file) {
+            (Some(endpoint), _) => Some(SpanExport::Collector(endpoint.clone())),
+            (None, Some(path)) => Some(SpanExport::File(path.clone())),
+            (None, None) => None,
+        }
+    }
+
+    /// The URL to register with the broker under.
+    pub fn advertise_url(&self) -> String {
+        self.advertise_url.clone().unwrap_or_else(|| {
+            let host = env::var("HOSTNAME").unwrap_or_else(|_| "127.0.0.1".to_string());
+            format!("http://{}:{}", host, self.listen_addr.port())
+        })
+    }
+
+    /// The gRPC URL to register with the broker, if it should send tiles over gRPC.
+    pub fn grpc_url(&self) -> Option<String> {
+        let grpc_addr = self.grpc_listen_addr.filter(|_| self.transport == Transport::Grpc)?;
+        Some(self.grpc_advertise_url.clone().unwrap_or_else(|| {
+            let host = env::var("HOSTNAME").unwrap_or_else(|_| "127.0.0.1".to_string());
+            format!("http://{}:{}", host, grpc_addr.port())
+        }))
+    }
+}
+
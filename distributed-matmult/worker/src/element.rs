@@ -0,0 +1,128 @@
+use std::fmt::Debug;
+
+pub use matmult_common::element::{DType, OverflowPolicy};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::grpc::pb;
+
+/// A value type the worker can multiply. Implemented for each `DType`.
+pub trait Element:
+    Copy + Default + PartialEq + Debug + Send + Sync + Serialize + DeserializeOwned + 'static
+{
+    const DTYPE: DType;
+
+    /// Accumulator used by `OverflowPolicy::Widen`.
+    type Wide: Copy + Default + Send + Sync;
+
+    fn wrapping_mul_add(acc: Self, left: Self, right: Self) -> Self;
+
+    fn saturating_mul_add(acc: Self, left: Self, right: Self) -> Self;
+
+    /// `None` if the product or the sum overflows.
+    fn checked_mul_add(acc: Self, left: Self, right: Self) -> Option<Self>;
+
+    /// Adds `left * right` to a widened accumulator, `None` if even that overflows.
+    fn wide_mul_add(acc: Self::Wide, left: Self, right: Self) -> Option<Self::Wide>;
+
+    /// Converts a widened accumulator back, `None` if it does not fit.
+    fn narrow(wide: Self::Wide) -> Option<Self>;
+
+    /// Unpacks the values of a gRPC request, `None` if they are of another type.
+    fn from_values(values: pb::values::Kind) -> Option<Vec<Self>>;
+
+    /// Packs values for a gRPC reply.
+    fn into_values(values: Vec<Self>) -> pb::values::Kind;
+
+    fn into_scalar(self) -> pb::scalar::Kind;
+}
+
+// the gRPC conversions, which only differ in the names of the message types
+macro_rules! proto_conversions {
+    ($variant:ident, $array:ident) => {
+        fn from_values(values: pb::values::Kind) -> Option<Vec<Self>> {
+            match values {
+                pb::values::Kind::$variant(array) => Some(array.values),
+                _ => None,
+            }
+        }
+
+        fn into_values(values: Vec<Self>) -> pb::values::Kind {
+            pb::values::Kind::$variant(pb::$array { values })
+        }
+
+        fn into_scalar(self) -> pb::scalar::Kind {
+            pb::scalar::Kind::$variant(self)
+        }
+    };
+}
+
+macro_rules! int_element {
+    ($ty:ty, $dtype:expr, $wide:ty, $variant:ident, $array:ident) => {
+        impl Element for $ty {
+            const DTYPE: DType = $dtype;
+
+            type Wide = $wide;
+
+            fn wrapping_mul_add(acc: Self, left: Self, right: Self) -> Self {
+                acc.wrapping_add(left.wrapping_mul(right))
+            }
+
+            fn saturating_mul_add(acc: Self, left: Self, right: Self) -> Self {
+                acc.saturating_add(left.saturating_mul(right))
+            }
+
+            fn checked_mul_add(acc: Self, left: Self, right: Self) -> Option<Self> {
+                left.checked_mul(right).and_then(|product| acc.checked_add(product))
+            }
+
+            fn wide_mul_add(acc: Self::Wide, left: Self, right: Self) -> Option<Self::Wide> {
+                // the product of two values always fits in the wide type
+                acc.checked_add(<$wide>::from(left) * <$wide>::from(right))
+            }
+
+            fn narrow(wide: Self::Wide) -> Option<Self> {
+                <$ty>::try_from(wide).ok()
+            }
+
+            proto_conversions!($variant, $array);
+        }
+    };
+}
+
+macro_rules! float_element {
+    ($ty:ty, $dtype:expr, $variant:ident, $array:ident) => {
+        impl Element for $ty {
+            const DTYPE: DType = $dtype;
+
+            type Wide = f64;
+
+            fn wrapping_mul_add(acc: Self, left: Self, right: Self) -> Self {
+                acc + left * right
+            }
+
+            fn saturating_mul_add(acc: Self, left: Self, right: Self) -> Self {
+                acc + left * right
+            }
+
+            fn checked_mul_add(acc: Self, left: Self, right: Self) -> Option<Self> {
+                Some(acc + left * right)
+            }
+
+            fn wide_mul_add(acc: Self::Wide, left: Self, right: Self) -> Option<Self::Wide> {
+                Some(acc + f64::from(left) * f64::from(right))
+            }
+
+            fn narrow(wide: Self::Wide) -> Option<Self> {
+                Some(wide as $ty)
+            }
+
+            proto_conversions!($variant, $array);
+        }
+    };
+}
+
+int_element!(i32, DType::I32, i64, I32, Int32Array);
+int_element!(i64, DType::I64, i128, I64, Int64Array);
+float_element!(f32, DType::F32, F32, FloatArray);
+float_element!(f64, DType::F64, F64, DoubleArray);
@@ -0,0 +1,139 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::json;
+use tonic::metadata::MetadataValue;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+
+use crate::element::OverflowPolicy;
+
+/// Everything that can go wrong handling a worker request. The `code` and
+/// `details` are what the broker reads to decide whether another worker is
+/// worth trying, so they must stay stable.
+#[derive(Debug, Clone)]
+pub enum WorkerError {
+    /// The body could not be decoded, or its contents don't make sense.
+    InvalidRequest(String),
+    /// The body's `Content-Type` is not one the worker can decode.
+    UnsupportedContentType(String),
+    /// The left block's columns don't match the right block's rows, or the
+    /// two vectors of a dot product have different lengths.
+    InvalidShape { left: (usize, usize), right: (usize, usize) },
+    /// A result cell overflowed under the `checked` or `widen` policy,
+    /// `cell` locates it in the full result.
+    Overflow { policy: OverflowPolicy, cell: (usize, usize) },
+    PayloadTooLarge,
+    LengthRequired,
+    NotFound,
+    MethodNotAllowed,
+    Internal(String),
+}
+
+impl warp::reject::Reject for WorkerError {}
+
+/// The JSON body of every error response.
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub details: serde_json::Value,
+}
+
+impl WorkerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WorkerError::InvalidRequest(_) => "invalid_request",
+            WorkerError::UnsupportedContentType(_) => "unsupported_content_type",
+            WorkerError::InvalidShape { .. } => "invalid_shape",
+            WorkerError::Overflow { .. } => "overflow",
+            WorkerError::PayloadTooLarge => "payload_too_large",
+            WorkerError::LengthRequired => "length_required",
+            WorkerError::NotFound => "not_found",
+            WorkerError::MethodNotAllowed => "method_not_allowed",
+            WorkerError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WorkerError::InvalidRequest(_) | WorkerError::InvalidShape { .. } => StatusCode::BAD_REQUEST,
+            WorkerError::UnsupportedContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            WorkerError::Overflow { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            WorkerError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            WorkerError::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            WorkerError::NotFound => StatusCode::NOT_FOUND,
+            WorkerError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            WorkerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn details(&self) -> serde_json::Value {
+        match self {
+            WorkerError::UnsupportedContentType(content_type) => json!({ "content_type": content_type }),
+            WorkerError::InvalidShape { left, right } => json!({ "left": left, "right": right }),
+            WorkerError::Overflow { policy, cell } => json!({ "policy": policy, "cell": cell }),
+            _ => json!({}),
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        let body = ErrorBody { code: self.code(), message: self.to_string(), details: self.details() };
+        warp::reply::with_status(warp::reply::json(&body), self.status()).into_response()
+    }
+
+    /// The gRPC equivalent of `into_response`: the status code closest to the
+    /// HTTP one, with `code` and `details` in the `error-code` and
+    /// `error-details` metadata for the broker to read.
+    pub fn into_status(self) -> tonic::Status {
+        let mut status = tonic::Status::new(grpc_code(self.status()), self.to_string());
+        let metadata = status.metadata_mut();
+        metadata.insert("error-code", MetadataValue::from_static(self.code()));
+        if let Ok(details) = MetadataValue::try_from(self.details().to_string()) {
+            metadata.insert("error-details", details);
+        }
+        status
+    }
+}
+
+// the gRPC status code for an HTTP status, as the broker maps them back
+fn grpc_code(status: StatusCode) -> tonic::Code {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::LENGTH_REQUIRED | StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            tonic::Code::InvalidArgument
+        }
+        StatusCode::NOT_FOUND => tonic::Code::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => tonic::Code::Unimplemented,
+        StatusCode::PAYLOAD_TOO_LARGE => tonic::Code::ResourceExhausted,
+        StatusCode::UNPROCESSABLE_ENTITY => tonic::Code::OutOfRange,
+        _ => tonic::Code::Internal,
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::InvalidRequest(reason) => f.write_str(reason),
+            WorkerError::UnsupportedContentType(content_type) => {
+                write!(f, "Unsupported content type {:?}", content_type)
+            }
+            WorkerError::InvalidShape { left, right } => write!(
+                f,
+                "Unable to multiply a {}x{} block by a {}x{} block",
+                left.0, left.1, right.0, right.1
+            ),
+            WorkerError::Overflow { policy, cell: (row, col) } => write!(
+                f,
+                "Arithmetic overflow computing result cell ({}, {}) under the {} overflow policy",
+                row, col, policy.as_str()
+            ),
+            WorkerError::PayloadTooLarge => f.write_str("Request body, or the block it asks for, is over the worker's size limit"),
+            WorkerError::LengthRequired => f.write_str("Request body must have a Content-Length"),
+            WorkerError::NotFound => f.write_str("No such route"),
+            WorkerError::MethodNotAllowed => f.write_str("Method not allowed"),
+            WorkerError::Internal(reason) => write!(f, "Internal error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for WorkerError {}
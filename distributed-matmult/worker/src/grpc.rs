@@ -0,0 +1,551 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::{Stream, stream};
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use matmult_common::trace;
+use prost::Message;
+use tokio::sync::Semaphore;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::Instrument;
+
+use crate::element::{DType, Element, OverflowPolicy};
+use crate::error::WorkerError;
+use crate::metrics::Metrics;
+use crate::types::{BlockPayload, DotProductBatch, DotProductItem, DotProductPayload, SparseBlockPayload};
+
+pub mod pb {
+    tonic::include_proto!("matmult");
+}
+
+// the `format` label of the byte counters for gRPC traffic
+const PROTOBUF: &str = "protobuf";
+
+type ReplyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// The `Worker` gRPC service, computing the same blocks and dot products as
+/// the HTTP routes and sharing their compute slots and metrics.
+pub struct WorkerService {
+    block_slots: Arc<Semaphore>,
+    max_body_bytes: u64,
+    metrics: Arc<Metrics>,
+}
+
+impl WorkerService {
+    pub fn new(block_slots: Arc<Semaphore>, max_body_bytes: u64, metrics: Arc<Metrics>) -> Self {
+        WorkerService { block_slots, max_body_bytes, metrics }
+    }
+
+    // runs one call under a request span joined to the broker's trace, counting
+    // and timing it as the HTTP routes are
+    async fn serve<R, F>(&self, method: &str, traceparent: Option<String>, call: F) -> Result<R, Status>
+    where
+        F: Future<Output = Result<R, WorkerError>>,
+    {
+        let route = format!("/matmult.Worker/{}", method);
+        let span = trace::request_span("POST", &route, traceparent.as_deref());
+        let started = Instant::now();
+        let outcome = call.instrument(span.clone()).await;
+        let _entered = span.enter();
+        match outcome {
+            Ok(reply) => {
+                self.metrics.observe_request(&route, 200, started.elapsed());
+                Ok(reply)
+            }
+            Err(error) => {
+                self.metrics.observe_request(&route, error.status().as_u16(), started.elapsed());
+                self.metrics.observe_error(error.code());
+                if error.status().is_server_error() {
+                    tracing::error!(code = error.code(), "{}", error);
+                }
+                Err(error.into_status())
+            }
+        }
+    }
+
+    // adds a streamed message to the bytes received so far, refusing the stream
+    // once it carries more than a request body may
+    fn count_received(&self, received: u64, len: usize) -> Result<u64, WorkerError> {
+        let received = received.saturating_add(len as u64);
+        if received > self.max_body_bytes {
+            tracing::warn!("stream is over {} bytes, the body limit", self.max_body_bytes);
+            return Err(WorkerError::PayloadTooLarge);
+        }
+        Ok(received)
+    }
+
+    async fn block<T: Element>(&self, request: pb::BlockRequest) -> Result<pb::BlockReply, WorkerError> {
+        let payload = BlockPayload {
+            row_offset: request.row_offset as usize,
+            col_offset: request.col_offset as usize,
+            overflow: overflow_policy(request.overflow()),
+            left: dense::<T>("left", request.left)?,
+            right: dense::<T>("right", request.right)?,
+        };
+        let slots = Arc::clone(&self.block_slots);
+        let response = crate::multiply_block(payload, slots, self.max_body_bytes, Arc::clone(&self.metrics)).await?;
+        Ok(pb::BlockReply { result: Some(to_proto(response.result)) })
+    }
+
+    async fn sparse_block<T: Element>(&self, request: pb::SparseBlockRequest) -> Result<pb::SparseBlockReply, WorkerError> {
+        let payload = SparseBlockPayload {
+            row_offset: request.row_offset as usize,
+            col_offset: request.col_offset as usize,
+            overflow: overflow_policy(request.overflow()),
+            left: sparse::<T>("left", request.left)?,
+            right: sparse::<T>("right", request.right)?,
+        };
+        let slots = Arc::clone(&self.block_slots);
+        let response =
+            crate::multiply_sparse_block(payload, slots, self.max_body_bytes, Arc::clone(&self.metrics)).await?;
+        Ok(pb::SparseBlockReply { result: Some(sparse_to_proto(response.result)) })
+    }
+
+    async fn block_from_parts<T: Element>(
+        &self,
+        header: pb::BlockHeader,
+        left: Vec<pb::Matrix>,
+        right: Vec<pb::Matrix>,
+    ) -> Result<pb::BlockReply, WorkerError> {
+        let payload = BlockPayload {
+            row_offset: header.row_offset as usize,
+            col_offset: header.col_offset as usize,
+            overflow: overflow_policy(header.overflow()),
+            left: concat_rows::<T>("left", left)?,
+            right: concat_rows::<T>("right", right)?,
+        };
+        let slots = Arc::clone(&self.block_slots);
+        let response = crate::multiply_block(payload, slots, self.max_body_bytes, Arc::clone(&self.metrics)).await?;
+        Ok(pb::BlockReply { result: Some(to_proto(response.result)) })
+    }
+
+    async fn dot_products<T: Element>(
+        &self,
+        requests: Vec<pb::DotProductRequest>,
+    ) -> Result<Vec<pb::DotProductReply>, WorkerError> {
+        // the batch kernel takes one policy for every dot product
+        let overflow = requests.first().map_or(pb::OverflowPolicy::Saturating, |request| request.overflow());
+        if requests.iter().any(|request| request.overflow() != overflow) {
+            return Err(WorkerError::InvalidRequest(
+                "Every dot product of a stream must use the same overflow policy".to_string(),
+            ));
+        }
+        let items = requests
+            .into_iter()
+            .map(|request| {
+                Ok(DotProductItem {
+                    row_id: request.row_id as usize,
+                    col_id: request.col_id as usize,
+                    row: values::<T>("row", request.row)?,
+                    col: values::<T>("col", request.col)?,
+                })
+            })
+            .collect::<Result<Vec<_>, WorkerError>>()?;
+        let batch = DotProductBatch { overflow: overflow_policy(overflow), items };
+        let response = crate::dot_products(batch, Arc::clone(&self.block_slots), Arc::clone(&self.metrics)).await?;
+        Ok(response
+            .results
+            .into_iter()
+            .map(|result| pb::DotProductReply {
+                row_id: result.row_id as u64,
+                col_id: result.col_id as u64,
+                result: Some(pb::Scalar { kind: Some(result.result.into_scalar()) }),
+            })
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl pb::worker_server::Worker for WorkerService {
+    async fn compute_block(&self, request: Request<pb::BlockRequest>) -> Result<Response<pb::BlockReply>, Status> {
+        let traceparent = traceparent(&request);
+        let request = request.into_inner();
+        self.metrics.add_bytes_received(PROTOBUF, request.encoded_len());
+        let reply = self
+            .serve("ComputeBlock", traceparent, async {
+                // the left block's values decide the element type of the whole request
+                match dtype(request.left.as_ref().and_then(|left| left.values.as_ref()))? {
+                    DType::I32 => self.block::<i32>(request).await,
+                    DType::I64 => self.block::<i64>(request).await,
+                    DType::F32 => self.block::<f32>(request).await,
+                    DType::F64 => self.block::<f64>(request).await,
+                }
+            })
+            .await?;
+        self.metrics.add_bytes_sent(PROTOBUF, reply.encoded_len());
+        Ok(Response::new(reply))
+    }
+
+    async fn compute_sparse_block(
+        &self,
+        request: Request<pb::SparseBlockRequest>,
+    ) -> Result<Response<pb::SparseBlockReply>, Status> {
+        let traceparent = traceparent(&request);
+        let request = request.into_inner();
+        self.metrics.add_bytes_received(PROTOBUF, request.encoded_len());
+        let reply = self
+            .serve("ComputeSparseBlock", traceparent, async {
+                match dtype(request.left.as_ref().and_then(|left| left.values.as_ref()))? {
+                    DType::I32 => self.sparse_block::<i32>(request).await,
+                    DType::I64 => self.sparse_block::<i64>(request).await,
+                    DType::F32 => self.sparse_block::<f32>(request).await,
+                    DType::F64 => self.sparse_block::<f64>(request).await,
+                }
+            })
+            .await?;
+        self.metrics.add_bytes_sent(PROTOBUF, reply.encoded_len());
+        Ok(Response::new(reply))
+    }
+
+    async fn compute_block_stream(
+        &self,
+        request: Request<Streaming<pb::BlockPart>>,
+    ) -> Result<Response<pb::BlockReply>, Status> {
+        let traceparent = traceparent(&request);
+        let mut parts = request.into_inner();
+        let reply = self
+            .serve("ComputeBlockStream", traceparent, async {
+                let mut header = None;
+                let (mut left, mut right) = (Vec::new(), Vec::new());
+                let mut received = 0;
+                while let Some(part) = parts.message().await.map_err(stream_error)? {
+                    self.metrics.add_bytes_received(PROTOBUF, part.encoded_len());
+                    received = self.count_received(received, part.encoded_len())?;
+                    match part.part {
+                        Some(pb::block_part::Part::Header(part)) if header.is_none() => header = Some(part),
+                        Some(pb::block_part::Part::Header(_)) => {
+                            return Err(WorkerError::InvalidRequest("The stream has more than one header".to_string()));
+                        }
+                        Some(pb::block_part::Part::Left(rows)) => left.push(rows),
+                        Some(pb::block_part::Part::Right(rows)) => right.push(rows),
+                        None => {}
+                    }
+                }
+                let header = header
+                    .ok_or_else(|| WorkerError::InvalidRequest("The stream has no header".to_string()))?;
+                match dtype(left.first().and_then(|rows| rows.values.as_ref()))? {
+                    DType::I32 => self.block_from_parts::<i32>(header, left, right).await,
+                    DType::I64 => self.block_from_parts::<i64>(header, left, right).await,
+                    DType::F32 => self.block_from_parts::<f32>(header, left, right).await,
+                    DType::F64 => self.block_from_parts::<f64>(header, left, right).await,
+                }
+            })
+            .await?;
+        self.metrics.add_bytes_sent(PROTOBUF, reply.encoded_len());
+        Ok(Response::new(reply))
+    }
+
+    async fn dot_product(
+        &self,
+        request: Request<pb::DotProductRequest>,
+    ) -> Result<Response<pb::DotProductReply>, Status> {
+        let traceparent = traceparent(&request);
+        let request = request.into_inner();
+        self.metrics.add_bytes_received(PROTOBUF, request.encoded_len());
+        let reply = self
+            .serve("DotProduct", traceparent, async {
+                let (row_id, col_id) = (request.row_id, request.col_id);
+                let result = match dtype(request.row.as_ref())? {
+                    DType::I32 => single_dot_product::<i32>(request)?,
+                    DType::I64 => single_dot_product::<i64>(request)?,
+                    DType::F32 => single_dot_product::<f32>(request)?,
+                    DType::F64 => single_dot_product::<f64>(request)?,
+                };
+                Ok(pb::DotProductReply { row_id, col_id, result: Some(pb::Scalar { kind: Some(result) }) })
+            })
+            .await?;
+        self.metrics.add_bytes_sent(PROTOBUF, reply.encoded_len());
+        Ok(Response::new(reply))
+    }
+
+    type DotProductStreamStream = ReplyStream<pb::DotProductReply>;
+
+    async fn dot_product_stream(
+        &self,
+        request: Request<Streaming<pb::DotProductRequest>>,
+    ) -> Result<Response<Self::DotProductStreamStream>, Status> {
+        let traceparent = traceparent(&request);
+        let mut requests = request.into_inner();
+        // answered as one batch once the client has sent every dot product,
+        // so there is no point in keeping the computed replies apart
+        let replies = self
+            .serve("DotProductStream", traceparent, async {
+                let mut received = Vec::new();
+                let mut received_bytes = 0;
+                while let Some(request) = requests.message().await.map_err(stream_error)? {
+                    self.metrics.add_bytes_received(PROTOBUF, request.encoded_len());
+                    received_bytes = self.count_received(received_bytes, request.encoded_len())?;
+                    received.push(request);
+                }
+                let replies = match dtype(received.first().and_then(|request| request.row.as_ref()))? {
+                    DType::I32 => self.dot_products::<i32>(received).await?,
+                    DType::I64 => self.dot_products::<i64>(received).await?,
+                    DType::F32 => self.dot_products::<f32>(received).await?,
+                    DType::F64 => self.dot_products::<f64>(received).await?,
+                };
+                Ok(replies)
+            })
+            .await?;
+        let sent = replies.iter().map(Message::encoded_len).sum();
+        self.metrics.add_bytes_sent(PROTOBUF, sent);
+        Ok(Response::new(Box::pin(stream::iter(replies.into_iter().map(Ok)))))
+    }
+}
+
+fn single_dot_product<T: Element>(request: pb::DotProductRequest) -> Result<pb::scalar::Kind, WorkerError> {
+    let overflow = overflow_policy(request.overflow());
+    let payload = DotProductPayload {
+        row_id: request.row_id as usize,
+        col_id: request.col_id as usize,
+        row: values::<T>("row", request.row)?,
+        col: values::<T>("col", request.col)?,
+        overflow,
+    };
+    Ok(crate::dot_product(payload)?.result.into_scalar())
+}
+
+// the caller's trace context, which the broker sends as it does over HTTP
+fn traceparent<M>(request: &Request<M>) -> Option<String> {
+    request.metadata().get("traceparent").and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+// a stream which broke off part way, the client went away or sent something unreadable
+fn stream_error(status: Status) -> WorkerError {
+    WorkerError::InvalidRequest(format!("Request stream failed: {}", status.message()))
+}
+
+fn overflow_policy(policy: pb::OverflowPolicy) -> OverflowPolicy {
+    match policy {
+        pb::OverflowPolicy::Saturating => OverflowPolicy::Saturating,
+        pb::OverflowPolicy::Wrapping => OverflowPolicy::Wrapping,
+        pb::OverflowPolicy::Checked => OverflowPolicy::Checked,
+        pb::OverflowPolicy::Widen => OverflowPolicy::Widen,
+    }
+}
+
+// the element type of a request, from the values which decide it
+fn dtype(values: Option<&pb::Values>) -> Result<DType, WorkerError> {
+    match values.and_then(|values| values.kind.as_ref()) {
+        Some(pb::values::Kind::I32(_)) => Ok(DType::I32),
+        Some(pb::values::Kind::I64(_)) => Ok(DType::I64),
+        Some(pb::values::Kind::F32(_)) => Ok(DType::F32),
+        Some(pb::values::Kind::F64(_)) => Ok(DType::F64),
+        None => Err(WorkerError::InvalidRequest("The request has no values to tell its element type by".to_string())),
+    }
+}
+
+// the values of a request as `T`, which every one of them must be
+fn values<T: Element>(name: &str, values: Option<pb::Values>) -> Result<Vec<T>, WorkerError> {
+    match values.and_then(|values| values.kind) {
+        // proto3 leaves an empty array out altogether
+        None => Ok(Vec::new()),
+        Some(kind) => T::from_values(kind).ok_or_else(|| {
+            WorkerError::InvalidRequest(format!("{} does not hold {} values like the rest of the request", name, T::DTYPE.as_str()))
+        }),
+    }
+}
+
+fn dense<T: Element>(name: &str, matrix: Option<pb::Matrix>) -> Result<DenseMatrix<T>, WorkerError> {
+    let matrix = matrix.ok_or_else(|| WorkerError::InvalidRequest(format!("The {} block is missing", name)))?;
+    let data = values::<T>(name, matrix.values)?;
+    DenseMatrix::new(matrix.rows as usize, matrix.cols as usize, data)
+        .map_err(|e| WorkerError::InvalidRequest(format!("The {} block is malformed: {}", name, e)))
+}
+
+fn sparse<T: Element>(name: &str, matrix: Option<pb::SparseMatrix>) -> Result<CsrMatrix<T>, WorkerError> {
+    let matrix = matrix.ok_or_else(|| WorkerError::InvalidRequest(format!("The {} block is missing", name)))?;
+    let values = values::<T>(name, matrix.values)?;
+    CsrMatrix::new(
+        matrix.rows as usize,
+        matrix.cols as usize,
+        matrix.row_ptr.into_iter().map(|ptr| ptr as usize).collect(),
+        matrix.col_indices.into_iter().map(|col| col as usize).collect(),
+        values,
+    )
+    .map_err(|e| WorkerError::InvalidRequest(format!("The {} block is malformed: {}", name, e)))
+}
+
+// stacks the runs of rows a block was streamed in
+fn concat_rows<T: Element>(name: &str, parts: Vec<pb::Matrix>) -> Result<DenseMatrix<T>, WorkerError> {
+    let cols = parts.first().map_or(0, |part| part.cols);
+    let mut rows = 0;
+    let mut data = Vec::new();
+    for part in parts {
+        if part.cols != cols {
+            return Err(WorkerError::InvalidRequest(format!(
+                "The {} block was streamed in parts of {} and {} columns",
+                name, cols, part.cols
+            )));
+        }
+        rows += part.rows;
+        let part = dense::<T>(name, Some(part))?;
+        data.extend(part.into_data());
+    }
+    DenseMatrix::new(rows as usize, cols as usize, data)
+        .map_err(|e| WorkerError::InvalidRequest(format!("The {} block is malformed: {}", name, e)))
+}
+
+fn to_proto<T: Element>(matrix: DenseMatrix<T>) -> pb::Matrix {
+    let (rows, cols) = matrix.shape();
+    pb::Matrix {
+        rows: rows as u64,
+        cols: cols as u64,
+        values: Some(pb::Values { kind: Some(T::into_values(matrix.into_data())) }),
+    }
+}
+
+fn sparse_to_proto<T: Element>(matrix: CsrMatrix<T>) -> pb::SparseMatrix {
+    pb::SparseMatrix {
+        rows: matrix.rows() as u64,
+        cols: matrix.cols() as u64,
+        row_ptr: matrix.row_ptr().iter().map(|&ptr| ptr as u64).collect(),
+        col_indices: matrix.col_indices().iter().map(|&col| col as u64).collect(),
+        values: Some(pb::Values { kind: Some(T::into_values(matrix.values().to_vec())) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+    use tonic::transport::server::TcpIncoming;
+
+    use super::*;
+    use crate::grpc::pb::worker_client::WorkerClient;
+
+    // the service on an ephemeral localhost port with a body limit of `max_body_bytes`,
+    // and a client of it; the server shuts down when the sender is dropped
+    async fn serve(max_body_bytes: u64) -> (WorkerClient<tonic::transport::Channel>, oneshot::Sender<()>) {
+        let incoming = TcpIncoming::bind(([127, 0, 0, 1], 0).into()).unwrap();
+        let url = format!("http://{}", incoming.local_addr().unwrap());
+        let service = WorkerService::new(Arc::new(Semaphore::new(1)), max_body_bytes, Arc::new(Metrics::new().unwrap()));
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let server = tonic::transport::Server::builder()
+            .add_service(pb::worker_server::WorkerServer::new(service))
+            .serve_with_incoming_shutdown(incoming, async {
+                stopped.await.ok();
+            });
+        tokio::spawn(server);
+        (WorkerClient::connect(url).await.unwrap(), shutdown)
+    }
+
+    fn matrix(rows: usize, cols: usize, data: Vec<i64>) -> pb::Matrix {
+        to_proto(DenseMatrix::new(rows, cols, data).unwrap())
+    }
+
+    fn part(part: pb::block_part::Part) -> pb::BlockPart {
+        pb::BlockPart { part: Some(part) }
+    }
+
+    fn header() -> pb::block_part::Part {
+        pb::block_part::Part::Header(pb::BlockHeader { row_offset: 0, col_offset: 0, overflow: 0 })
+    }
+
+    #[tokio::test]
+    async fn blocks_are_multiplied_whether_sent_whole_or_streamed() {
+        let (mut client, _shutdown) = serve(1024 * 1024).await;
+        // [[1, 2], [3, 4], [5, 6]] by [[1, 0, 2], [0, 1, 3]]
+        let left = [1, 2, 3, 4, 5, 6];
+        let right = matrix(2, 3, vec![1, 0, 2, 0, 1, 3]);
+        let expected = DenseMatrix::new(3, 3, vec![1, 2, 8, 3, 4, 18, 5, 6, 28]).unwrap();
+
+        let request = pb::BlockRequest {
+            left: Some(matrix(3, 2, left.to_vec())),
+            right: Some(right.clone()),
+            ..Default::default()
+        };
+        let reply = client.compute_block(request).await.unwrap().into_inner();
+        assert_eq!(dense::<i64>("result", reply.result).unwrap(), expected);
+
+        let parts = vec![
+            part(header()),
+            part(pb::block_part::Part::Left(matrix(1, 2, left[..2].to_vec()))),
+            part(pb::block_part::Part::Left(matrix(2, 2, left[2..].to_vec()))),
+            part(pb::block_part::Part::Right(right)),
+        ];
+        let reply = client.compute_block_stream(stream::iter(parts)).await.unwrap().into_inner();
+        assert_eq!(dense::<i64>("result", reply.result).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn streams_are_checked_and_limited() {
+        let (mut client, _shutdown) = serve(1024).await;
+
+        let parts = vec![part(header()), part(header())];
+        let status = client.compute_block_stream(stream::iter(parts)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", status.message());
+
+        // 64 values of up to 10 bytes each a part, the 1 KiB limit is passed by the third
+        let mut parts = vec![part(header())];
+        parts.extend((0..8).map(|_| part(pb::block_part::Part::Left(matrix(1, 64, vec![i64::MAX; 64])))));
+        let status = client.compute_block_stream(stream::iter(parts)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted, "{}", status.message());
+        assert_eq!(status.metadata().get("error-code").unwrap(), "payload_too_large");
+
+        let requests = (0..8).map(|cell| pb::DotProductRequest {
+            row_id: cell,
+            row: Some(pb::Values { kind: Some(i64::into_values(vec![i64::MAX; 64])) }),
+            col: Some(pb::Values { kind: Some(i64::into_values(vec![1; 64])) }),
+            ..Default::default()
+        });
+        let status = client.dot_product_stream(stream::iter(requests)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted, "{}", status.message());
+    }
+
+    #[tokio::test]
+    async fn sparse_blocks_are_multiplied() {
+        let (mut client, _shutdown) = serve(1024 * 1024).await;
+        // [[0, 2], [3, 0]] by the transposed columns [[1, 1], [0, 4]]
+        let left = CsrMatrix::from_dense(&DenseMatrix::new(2, 2, vec![0i64, 2, 3, 0]).unwrap());
+        let right = CsrMatrix::from_dense(&DenseMatrix::new(2, 2, vec![1i64, 1, 0, 4]).unwrap());
+
+        let request = pb::SparseBlockRequest {
+            left: Some(sparse_to_proto(left)),
+            right: Some(sparse_to_proto(right)),
+            ..Default::default()
+        };
+        let reply = client.compute_sparse_block(request).await.unwrap().into_inner();
+
+        let result = sparse::<i64>("result", reply.result).unwrap().to_dense();
+        assert_eq!(result, DenseMatrix::new(2, 2, vec![2, 8, 3, 0]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn dot_products_are_answered_per_cell() {
+        let (mut client, _shutdown) = serve(1024 * 1024).await;
+        let values = |values: Vec<i32>| Some(pb::Values { kind: Some(i32::into_values(values)) });
+
+        let requests: Vec<_> = (0..3u64)
+            .map(|cell| pb::DotProductRequest {
+                row_id: cell,
+                col_id: cell + 1,
+                row: values(vec![cell as i32, 2]),
+                col: values(vec![3, 4]),
+                ..Default::default()
+            })
+            .collect();
+        let mut replies = client.dot_product_stream(stream::iter(requests)).await.unwrap().into_inner();
+        let mut results = Vec::new();
+        while let Some(reply) = replies.message().await.unwrap() {
+            results.push((reply.row_id, reply.col_id, reply.result.and_then(|result| result.kind)));
+        }
+        let expected = (0..3u64).map(|cell| (cell, cell + 1, Some(pb::scalar::Kind::I32(cell as i32 * 3 + 8))));
+        assert_eq!(results, expected.collect::<Vec<_>>());
+
+        // the cell a checked dot product overflowed in comes back with the error
+        let request = pb::DotProductRequest {
+            row_id: 4,
+            col_id: 7,
+            overflow: pb::OverflowPolicy::Checked as i32,
+            row: values(vec![i32::MAX]),
+            col: values(vec![2]),
+        };
+        let status = client.dot_product(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange, "{}", status.message());
+        assert_eq!(status.metadata().get("error-code").unwrap(), "overflow");
+        let details: serde_json::Value =
+            serde_json::from_str(status.metadata().get("error-details").unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(details["cell"], serde_json::json!([4, 7]));
+    }
+}
@@ -0,0 +1,332 @@
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use rayon::prelude::*;
+
+use crate::element::{Element, OverflowPolicy};
+
+// number of inner-dimension values processed per pass, chosen so a panel of the
+// right block stays resident in cache while every output row in a chunk uses it
+const INNER_BLOCK: usize = 256;
+
+// number of output rows handed to a single rayon task
+const ROWS_PER_TASK: usize = 8;
+
+/// Position of the first overflowing cell within a block, row-major.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Overflowed {
+    pub row: usize,
+    pub col: usize,
+}
+
+/// Multiplies a `rows x inner` block by an `inner x cols` block under `policy`.
+///
+/// The product is computed in i-k-j order so the innermost loop streams through
+/// a row of the right block and a row of the output, both contiguous. Chunks of
+/// output rows are spread over the worker's cores with rayon. Every cell still
+/// accumulates its terms in ascending `k`, so each policy gives exactly the same
+/// answer as a plain dot product would.
+pub fn multiply_blocks<T: Element>(
+    left: &DenseMatrix<T>,
+    right: &DenseMatrix<T>,
+    policy: OverflowPolicy,
+) -> Result<DenseMatrix<T>, Overflowed> {
+    match policy {
+        OverflowPolicy::Wrapping => multiply_with(left, right, |acc, l, r| Some(T::wrapping_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Saturating => multiply_with(left, right, |acc, l, r| Some(T::saturating_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Checked => multiply_with(left, right, T::checked_mul_add, Some),
+        OverflowPolicy::Widen => multiply_with(left, right, T::wide_mul_add, T::narrow),
+    }
+}
+
+/// Dot product of `row` and `col` under `policy`, `None` if it overflows.
+pub fn dot_product<T: Element>(row: &[T], col: &[T], policy: OverflowPolicy) -> Option<T> {
+    fn fold<T: Element, A: Default>(
+        row: &[T],
+        col: &[T],
+        mul_add: impl Fn(A, T, T) -> Option<A>,
+        finish: impl Fn(A) -> Option<T>,
+    ) -> Option<T> {
+        let total = row.iter().zip(col).try_fold(A::default(), |acc, (&l, &r)| mul_add(acc, l, r))?;
+        finish(total)
+    }
+
+    match policy {
+        OverflowPolicy::Wrapping => fold(row, col, |acc, l, r| Some(T::wrapping_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Saturating => fold(row, col, |acc, l, r| Some(T::saturating_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Checked => fold(row, col, T::checked_mul_add, Some),
+        OverflowPolicy::Widen => fold(row, col, T::wide_mul_add, T::narrow),
+    }
+}
+
+/// Dot product under `policy` of two sparse vectors, each given as its
+/// ascending indices and the values at them, `None` if it overflows. Only the
+/// indices both hold take part, in ascending order, so each policy gives the
+/// same answer as `dot_product` on the dense vectors as long as the values the
+/// other vector meets zeros with are finite.
+pub fn sparse_dot_product<T: Element>(row: (&[usize], &[T]), col: (&[usize], &[T]), policy: OverflowPolicy) -> Option<T> {
+    match policy {
+        OverflowPolicy::Wrapping => sparse_fold(row, col, |acc, l, r| Some(T::wrapping_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Saturating => sparse_fold(row, col, |acc, l, r| Some(T::saturating_mul_add(acc, l, r)), Some),
+        OverflowPolicy::Checked => sparse_fold(row, col, T::checked_mul_add, Some),
+        OverflowPolicy::Widen => sparse_fold(row, col, T::wide_mul_add, T::narrow),
+    }
+}
+
+// walks the two index lists together, multiplying and adding where they meet
+fn sparse_fold<T: Element, A: Default>(
+    (row_indices, row_values): (&[usize], &[T]),
+    (col_indices, col_values): (&[usize], &[T]),
+    mul_add: impl Fn(A, T, T) -> Option<A>,
+    finish: impl Fn(A) -> Option<T>,
+) -> Option<T> {
+    let (mut a, mut b) = (0, 0);
+    let mut total = A::default();
+    while a < row_indices.len() && b < col_indices.len() {
+        match row_indices[a].cmp(&col_indices[b]) {
+            std::cmp::Ordering::Less => a += 1,
+            std::cmp::Ordering::Greater => b += 1,
+            std::cmp::Ordering::Equal => {
+                total = mul_add(total, row_values[a], col_values[b])?;
+                a += 1;
+                b += 1;
+            }
+        }
+    }
+    finish(total)
+}
+
+/// Multiplies a sparse `rows x inner` block by a sparse `inner x cols` block
+/// under `policy`, the right block given transposed so each of its rows is a
+/// column of the product. Every cell is the sparse dot product of a row of
+/// `left` and a row of `right_t`; rows of the product are spread over the
+/// worker's cores with rayon, and only the cells which aren't zero are kept.
+pub fn multiply_sparse_blocks<T: Element>(
+    left: &CsrMatrix<T>,
+    right_t: &CsrMatrix<T>,
+    policy: OverflowPolicy,
+) -> Result<CsrMatrix<T>, Overflowed> {
+    let rows: Vec<Vec<(usize, T)>> = (0..left.rows())
+        .into_par_iter()
+        .map(|i| {
+            let mut cells = Vec::new();
+            for j in 0..right_t.rows() {
+                match sparse_dot_product(left.row(i), right_t.row(j), policy) {
+                    Some(value) if value != T::default() => cells.push((j, value)),
+                    Some(_) => {}
+                    None => return Err(Overflowed { row: i, col: j }),
+                }
+            }
+            Ok(cells)
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        // the first overflow in row order is the earliest cell, as the dense kernel reports
+        .collect::<Result<_, Overflowed>>()?;
+
+    let triplets = rows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, cells)| cells.into_iter().map(move |(j, value)| (i, j, value)))
+        .collect();
+    Ok(CsrMatrix::from_triplets(left.rows(), right_t.rows(), triplets).expect("cells are within the product"))
+}
+
+// the blocked kernel, parameterised by the accumulator type `A`, the multiply-add
+// step for the policy and the conversion from the accumulator back to `T`
+fn multiply_with<T, A>(
+    left: &DenseMatrix<T>,
+    right: &DenseMatrix<T>,
+    mul_add: impl Fn(A, T, T) -> Option<A> + Sync,
+    finish: impl Fn(A) -> Option<T> + Sync,
+) -> Result<DenseMatrix<T>, Overflowed>
+where
+    T: Element,
+    A: Copy + Default + Send + Sync,
+{
+    let (rows, inner) = left.shape();
+    let cols = right.cols();
+
+    // a block with no columns has nothing to compute
+    if cols == 0 {
+        return Ok(DenseMatrix::zeros(rows, 0));
+    }
+
+    let mut acc = vec![A::default(); rows * cols];
+
+    // each chunk reports the first cell it saw overflow, the earliest one wins
+    let first_overflow = acc
+        .par_chunks_mut(ROWS_PER_TASK * cols)
+        .enumerate()
+        .filter_map(|(chunk_ind, acc_chunk)| {
+            let first_row = chunk_ind * ROWS_PER_TASK;
+            let mut overflowed: Option<Overflowed> = None;
+            for k_start in (0..inner).step_by(INNER_BLOCK) {
+                let k_end = (k_start + INNER_BLOCK).min(inner);
+                for (local_row, acc_row) in acc_chunk.chunks_mut(cols).enumerate() {
+                    let left_row = left.row(first_row + local_row);
+                    for (k, &left_value) in left_row.iter().enumerate().take(k_end).skip(k_start) {
+                        for (j, (cell, &right_value)) in acc_row.iter_mut().zip(right.row(k)).enumerate() {
+                            match mul_add(*cell, left_value, right_value) {
+                                Some(next) => *cell = next,
+                                None => {
+                                    let here = Overflowed { row: first_row + local_row, col: j };
+                                    overflowed = Some(overflowed.map_or(here, |seen| seen.min(here)));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            overflowed
+        })
+        .min();
+
+    if let Some(overflowed) = first_overflow {
+        return Err(overflowed);
+    }
+
+    let mut data = Vec::with_capacity(rows * cols);
+    for (ind, &cell) in acc.iter().enumerate() {
+        data.push(finish(cell).ok_or(Overflowed { row: ind / cols, col: ind % cols })?);
+    }
+    Ok(DenseMatrix::new(rows, cols, data).expect("accumulator holds rows * cols values"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a rows x cols matrix of small pseudo random values, about half of them zero
+    fn matrix(rows: usize, cols: usize, seed: u64) -> DenseMatrix<i64> {
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let data = (0..rows * cols)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let value = (state >> 33) as i64 % 19 - 9;
+                if value.abs() < 5 { 0 } else { value }
+            })
+            .collect();
+        DenseMatrix::new(rows, cols, data).unwrap()
+    }
+
+    // the textbook triple loop
+    fn reference(left: &DenseMatrix<i64>, right: &DenseMatrix<i64>) -> DenseMatrix<i64> {
+        let mut product = DenseMatrix::zeros(left.rows(), right.cols());
+        for i in 0..left.rows() {
+            for j in 0..right.cols() {
+                product.row_mut(i)[j] = (0..left.cols()).map(|k| left.get(i, k) * right.get(k, j)).sum();
+            }
+        }
+        product
+    }
+
+    const POLICIES: [OverflowPolicy; 4] =
+        [OverflowPolicy::Wrapping, OverflowPolicy::Saturating, OverflowPolicy::Checked, OverflowPolicy::Widen];
+
+    #[test]
+    fn dense_blocks_match_the_reference() {
+        // more rows than a rayon task takes and an inner dimension over a cache block
+        for (rows, inner, cols) in [(1, 1, 1), (19, 300, 13), (8, 256, 5), (3, 7, 0)] {
+            let (left, right) = (matrix(rows, inner, 1), matrix(inner, cols, 2));
+            let expected = reference(&left, &right);
+            for policy in POLICIES {
+                let product = multiply_blocks(&left, &right, policy);
+                assert_eq!(product, Ok(expected.clone()), "{:?} {:?}", (rows, inner, cols), policy);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_blocks_match_the_reference() {
+        for (rows, inner, cols) in [(1, 1, 1), (17, 40, 11), (6, 3, 9)] {
+            let (left, right) = (matrix(rows, inner, 3), matrix(inner, cols, 4));
+            let expected = reference(&left, &right);
+            let right_t = CsrMatrix::from_dense(&right).transpose();
+            for policy in POLICIES {
+                let product = multiply_sparse_blocks(&CsrMatrix::from_dense(&left), &right_t, policy).unwrap();
+                assert_eq!(product.to_dense(), expected, "{:?} {:?}", (rows, inner, cols), policy);
+                // zero cells of the product are left out
+                assert_eq!(product.nnz(), expected.as_slice().iter().filter(|value| **value != 0).count());
+            }
+        }
+    }
+
+    #[test]
+    fn dot_products_match_the_reference() {
+        let (row, col) = (matrix(1, 50, 5), matrix(50, 1, 6));
+        let expected = reference(&row, &col).get(0, 0);
+        let col: Vec<i64> = col.col(0).collect();
+        let sparse_row = CsrMatrix::from_dense(&row);
+        let sparse_col = CsrMatrix::from_dense(&DenseMatrix::new(1, 50, col.clone()).unwrap());
+        for policy in POLICIES {
+            assert_eq!(dot_product(row.row(0), &col, policy), Some(expected), "{:?}", policy);
+            let sparse = sparse_dot_product(sparse_row.row(0), sparse_col.row(0), policy);
+            assert_eq!(sparse, Some(expected), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn wrapping_wraps_around() {
+        assert_eq!(dot_product(&[i32::MAX, 1], &[1, 1], OverflowPolicy::Wrapping), Some(i32::MIN));
+        assert_eq!(dot_product(&[i32::MIN], &[-1], OverflowPolicy::Wrapping), Some(i32::MIN));
+        assert_eq!(dot_product(&[i64::MAX, i64::MAX], &[2, 1], OverflowPolicy::Wrapping), Some(i64::MAX - 2));
+    }
+
+    #[test]
+    fn saturating_clamps_products_and_sums() {
+        assert_eq!(dot_product(&[i32::MAX, 1], &[1, 1], OverflowPolicy::Saturating), Some(i32::MAX));
+        assert_eq!(dot_product(&[i32::MIN], &[-1], OverflowPolicy::Saturating), Some(i32::MAX));
+        // clamped partial sums stay clamped, the final sum isn't the exact one
+        assert_eq!(dot_product(&[i32::MAX, 1, -1], &[1, 1, 1], OverflowPolicy::Saturating), Some(i32::MAX - 1));
+        assert_eq!(dot_product(&[i64::MIN, -1], &[1, 1], OverflowPolicy::Saturating), Some(i64::MIN));
+    }
+
+    #[test]
+    fn checked_fails_on_any_overflowing_step() {
+        assert_eq!(dot_product(&[i32::MAX, 0], &[1, 1], OverflowPolicy::Checked), Some(i32::MAX));
+        assert_eq!(dot_product(&[i32::MAX, 1], &[1, 1], OverflowPolicy::Checked), None);
+        assert_eq!(dot_product(&[i32::MIN], &[-1], OverflowPolicy::Checked), None);
+        // even if the final sum would fit
+        assert_eq!(dot_product(&[i32::MAX, 1, -1], &[1, 1, 1], OverflowPolicy::Checked), None);
+    }
+
+    #[test]
+    fn widen_fails_only_if_the_final_sum_does_not_fit() {
+        assert_eq!(dot_product(&[i32::MAX, 1, -1], &[1, 1, 1], OverflowPolicy::Widen), Some(i32::MAX));
+        assert_eq!(dot_product(&[i32::MIN], &[-1], OverflowPolicy::Widen), None);
+        assert_eq!(dot_product(&[i32::MIN, i32::MIN], &[i32::MIN, -1], OverflowPolicy::Widen), None);
+        assert_eq!(dot_product(&[i64::MAX, i64::MAX, i64::MIN], &[2, -1, 1], OverflowPolicy::Widen), Some(-1));
+        assert_eq!(dot_product(&[i64::MIN, -1], &[1, 1], OverflowPolicy::Widen), None);
+    }
+
+    #[test]
+    fn the_first_overflowed_cell_is_reported() {
+        // cells (9, 2), (12, 1) and (17, 0) overflow, in three different rayon tasks
+        let mut left = DenseMatrix::<i32>::zeros(20, 2);
+        let mut right = DenseMatrix::<i32>::zeros(2, 3);
+        right.row_mut(0).copy_from_slice(&[1, 2, 4]);
+        right.row_mut(1).copy_from_slice(&[1, 1, 1]);
+        left.row_mut(9).copy_from_slice(&[i32::MAX / 4 + 1, 0]);
+        left.row_mut(12).copy_from_slice(&[i32::MAX / 2 + 1, 0]);
+        left.row_mut(17).copy_from_slice(&[i32::MAX, 1]);
+
+        let first = Overflowed { row: 9, col: 2 };
+        for policy in [OverflowPolicy::Checked, OverflowPolicy::Widen] {
+            assert_eq!(multiply_blocks(&left, &right, policy), Err(first), "{:?}", policy);
+            let right_t = CsrMatrix::from_dense(&right).transpose();
+            let sparse = multiply_sparse_blocks(&CsrMatrix::from_dense(&left), &right_t, policy);
+            assert_eq!(sparse.err(), Some(first), "{:?}", policy);
+        }
+        // the others don't fail
+        assert!(multiply_blocks(&left, &right, OverflowPolicy::Wrapping).is_ok());
+        assert_eq!(multiply_blocks(&left, &right, OverflowPolicy::Saturating).unwrap().get(17, 0), i32::MAX);
+    }
+
+    #[test]
+    fn floats_follow_ieee_arithmetic() {
+        for policy in POLICIES {
+            assert_eq!(dot_product(&[f32::MAX, f32::MAX], &[1.0, 1.0], policy), Some(f32::INFINITY), "{:?}", policy);
+            assert_eq!(dot_product(&[0.5, -2.0], &[4.0, 1.5], policy), Some(-1.0), "{:?}", policy);
+        }
+    }
+}
@@ -1,91 +1,564 @@
-use std::convert::Infallible; // For infallible handlers
+use std::sync::Arc;
+use std::time::Instant;
+use matmult_common::trace;
 use warp::{Filter, Rejection, Reply};
-use warp::http::StatusCode;
 
+mod config;
+mod element;
+mod error;
+mod grpc;
+mod kernel;
+mod metrics;
+mod registration;
 mod types;
-use types::{DotProductPayload, DotProductResponse, WorkerError};
-use std::env;
+mod wire;
+use config::WorkerConfig;
+use element::Element;
+use error::WorkerError;
+use metrics::Metrics;
+use types::{
+    BlockPayload, BlockResponse, DotProductBatch, DotProductBatchResponse, DotProductPayload, DotProductResponse,
+    DotProductResult, SparseBlockPayload, SparseBlockResponse, TypedBlockPayload, TypedDotProductBatch,
+    TypedDotProductPayload, TypedSparseBlockPayload,
+};
+use wire::WireFormat;
+use tokio::sync::Semaphore;
+use rayon::prelude::*;
+use tokio::task;
 
-async fn calculate_dot_product_handler(
-    payload: DotProductPayload,
-) -> Result<impl Reply, Rejection> {
+fn dot_product<T: Element>(payload: DotProductPayload<T>) -> Result<DotProductResponse<T>, WorkerError> {
     if payload.row.len() != payload.col.len() {
-        eprintln!(
-            "Worker error: Row length ({}) does not match column length ({}).",
+        tracing::warn!(
+            "Row length ({}) does not match column length ({})",
             payload.row.len(),
             payload.col.len()
         );
-        return Err(warp::reject::custom(WorkerError(
-            "Row and column vectors must have the same length.".to_string(),
-        )));
+        return Err(WorkerError::InvalidShape {
+            left: (1, payload.row.len()),
+            right: (payload.col.len(), 1),
+        });
+    }
+
+    let total = kernel::dot_product(&payload.row, &payload.col, payload.overflow)
+        .ok_or(WorkerError::Overflow { policy: payload.overflow, cell: (payload.row_id, payload.col_id) })?;
+
+    Ok(DotProductResponse { result: total })
+}
+
+async fn calculate_dot_product_handler(
+    payload: TypedDotProductPayload,
+    reply_format: WireFormat,
+    metrics: Arc<Metrics>,
+) -> Result<impl Reply, Rejection> {
+    // compute in whichever element type the payload was tagged with
+    match payload {
+        TypedDotProductPayload::I32(payload) => wire::reply(reply_format, &dot_product(payload)?, &metrics),
+        TypedDotProductPayload::I64(payload) => wire::reply(reply_format, &dot_product(payload)?, &metrics),
+        TypedDotProductPayload::F32(payload) => wire::reply(reply_format, &dot_product(payload)?, &metrics),
+        TypedDotProductPayload::F64(payload) => wire::reply(reply_format, &dot_product(payload)?, &metrics),
     }
+}
 
-    let mut total: i32 = 0;
-    for i in 0..payload.row.len() {
-        total = total.saturating_add(payload.row[i].saturating_mul(payload.col[i]));
+async fn dot_products<T: Element>(
+    batch: DotProductBatch<T>,
+    block_slots: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+) -> Result<DotProductBatchResponse<T>, WorkerError> {
+    // every item must pair a row and a column of the same length
+    if let Some(item) = batch.items.iter().find(|item| item.row.len() != item.col.len()) {
+        tracing::warn!(
+            "Dot product for cell ({}, {}) has a row of length {} and a column of length {}",
+            item.row_id, item.col_id, item.row.len(), item.col.len()
+        );
+        return Err(WorkerError::InvalidShape {
+            left: (1, item.row.len()),
+            right: (item.col.len(), 1),
+        });
     }
 
-    let response = DotProductResponse { result: total };
+    // a batch shares the compute slots with blocks, it is as much work as a small one
+    let waiting = metrics.block_waiting();
+    let _slot = block_slots
+        .acquire_owned()
+        .await
+        .map_err(|e| WorkerError::Internal(format!("Block limiter closed: {}", e)))?;
+    drop(waiting);
+    let _in_flight = metrics.block_in_flight();
+
+    let (policy, cells) = (batch.overflow, batch.items.len());
+    let started = Instant::now();
+    let kernel_span = tracing::info_span!("kernel", dtype = T::DTYPE.as_str(), cells);
+    let outcome = task::spawn_blocking(move || {
+        kernel_span.in_scope(|| {
+            batch
+                .items
+                .par_iter()
+                .map(|item| match kernel::dot_product(&item.row, &item.col, policy) {
+                    Some(result) => Ok(DotProductResult { row_id: item.row_id, col_id: item.col_id, result }),
+                    None => Err((item.row_id, item.col_id)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+    })
+    .await
+    .map_err(|e| WorkerError::Internal(format!("Dot product task failed: {}", e)))?;
+    metrics.observe_block(T::DTYPE.as_str(), cells, started.elapsed());
 
-    Ok(warp::reply::json(&response))
+    match outcome {
+        Ok(results) => Ok(DotProductBatchResponse { results }),
+        Err(cell) => {
+            tracing::warn!(
+                "{} overflow in result cell ({}, {}) under the {} policy",
+                T::DTYPE.as_str(), cell.0, cell.1, policy.as_str()
+            );
+            Err(WorkerError::Overflow { policy, cell })
+        }
+    }
 }
 
-async fn handle_worker_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code;
-    let message;
+async fn calculate_dot_products_handler(
+    batch: TypedDotProductBatch,
+    reply_format: WireFormat,
+    block_slots: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+) -> Result<impl Reply, Rejection> {
+    // compute in whichever element type the batch was tagged with
+    let batch_metrics = Arc::clone(&metrics);
+    match batch {
+        TypedDotProductBatch::I32(batch) => {
+            wire::reply(reply_format, &dot_products(batch, block_slots, batch_metrics).await?, &metrics)
+        }
+        TypedDotProductBatch::I64(batch) => {
+            wire::reply(reply_format, &dot_products(batch, block_slots, batch_metrics).await?, &metrics)
+        }
+        TypedDotProductBatch::F32(batch) => {
+            wire::reply(reply_format, &dot_products(batch, block_slots, batch_metrics).await?, &metrics)
+        }
+        TypedDotProductBatch::F64(batch) => {
+            wire::reply(reply_format, &dot_products(batch, block_slots, batch_metrics).await?, &metrics)
+        }
+    }
+}
 
-    if err.is_not_found() {
-        code = StatusCode::NOT_FOUND;
-        message = "NOT_FOUND".to_string();
-    } else if let Some(worker_err) = err.find::<WorkerError>() {
-        code = StatusCode::BAD_REQUEST; // Or INTERNAL_SERVER_ERROR depending on error type
-        message = worker_err.0.clone();
-    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
-        code = StatusCode::METHOD_NOT_ALLOWED;
-        message = "METHOD_NOT_ALLOWED".to_string();
-    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
-        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
-        message = "UNSUPPORTED_MEDIA_TYPE".to_string();
+// refuses a product of more cells than a body of `max_body_bytes` could carry,
+// before a compute slot is taken or the kernel allocates them
+fn check_cells<T: Element>(rows: usize, cols: usize, max_body_bytes: u64) -> Result<usize, WorkerError> {
+    let max_cells = usize::try_from(max_body_bytes).unwrap_or(usize::MAX) / size_of::<T>();
+    match rows.checked_mul(cols) {
+        Some(cells) if cells <= max_cells => Ok(cells),
+        _ => {
+            tracing::warn!(
+                "{} block product would be {}x{}, over the {} cell limit",
+                T::DTYPE.as_str(), rows, cols, max_cells
+            );
+            Err(WorkerError::PayloadTooLarge)
+        }
     }
-     else if err.find::<warp::body::BodyDeserializeError>().is_some() {
-        code = StatusCode::BAD_REQUEST;
-        message = "INVALID_REQUEST_BODY".to_string();
+}
+
+async fn multiply_block<T: Element>(
+    payload: BlockPayload<T>,
+    block_slots: Arc<Semaphore>,
+    max_body_bytes: u64,
+    metrics: Arc<Metrics>,
+) -> Result<BlockResponse<T>, WorkerError> {
+    // the blocks are rectangular by construction, but the left block must have as
+    // many columns as the right block has rows:
+    if payload.left.cols() != payload.right.rows() {
+        tracing::warn!(
+            "{} blocks for tile at ({}, {}) are {}x{} and {}x{}",
+            T::DTYPE.as_str(), payload.row_offset, payload.col_offset,
+            payload.left.rows(), payload.left.cols(), payload.right.rows(), payload.right.cols()
+        );
+        return Err(WorkerError::InvalidShape {
+            left: payload.left.shape(),
+            right: payload.right.shape(),
+        });
+    }
+    // the blocks may be tiny and still declare a huge product, with no columns between them
+    let cells = check_cells::<T>(payload.left.rows(), payload.right.cols(), max_body_bytes)?;
+
+    // wait for a free slot so only max_concurrent_blocks kernels share the compute threads
+    let waiting = metrics.block_waiting();
+    let _slot = block_slots
+        .acquire_owned()
+        .await
+        .map_err(|e| WorkerError::Internal(format!("Block limiter closed: {}", e)))?;
+    drop(waiting);
+    let _in_flight = metrics.block_in_flight();
+
+    // the kernel is CPU bound, so run it on the blocking pool rather than an async worker thread
+    let (row_offset, col_offset, policy) = (payload.row_offset, payload.col_offset, payload.overflow);
+    let started = Instant::now();
+    // the blocking pool doesn't inherit the request's span, so carry it across
+    let kernel_span = tracing::info_span!("kernel", dtype = T::DTYPE.as_str(), cells);
+    let outcome = task::spawn_blocking(move || {
+        kernel_span.in_scope(|| kernel::multiply_blocks(&payload.left, &payload.right, policy))
+    })
+        .await
+        .map_err(|e| WorkerError::Internal(format!("Block multiplication task failed: {}", e)))?;
+    metrics.observe_block(T::DTYPE.as_str(), cells, started.elapsed());
+
+    match outcome {
+        Ok(result) => Ok(BlockResponse { result }),
+        Err(overflowed) => {
+            // report the cell's position in the full result, not just within this block
+            let cell = (row_offset + overflowed.row, col_offset + overflowed.col);
+            tracing::warn!(
+                "{} overflow in result cell ({}, {}) under the {} policy",
+                T::DTYPE.as_str(), cell.0, cell.1, policy.as_str()
+            );
+            Err(WorkerError::Overflow { policy, cell })
+        }
+    }
+}
+
+async fn multiply_block_handler(
+    payload: TypedBlockPayload,
+    reply_format: WireFormat,
+    block_slots: Arc<Semaphore>,
+    max_body_bytes: u64,
+    metrics: Arc<Metrics>,
+) -> Result<impl Reply, Rejection> {
+    // compute in whichever element type the payload was tagged with
+    let block_metrics = Arc::clone(&metrics);
+    match payload {
+        TypedBlockPayload::I32(payload) => {
+            let response = multiply_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedBlockPayload::I64(payload) => {
+            let response = multiply_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedBlockPayload::F32(payload) => {
+            let response = multiply_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedBlockPayload::F64(payload) => {
+            let response = multiply_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+    }
+}
+
+async fn multiply_sparse_block<T: Element>(
+    payload: SparseBlockPayload<T>,
+    block_slots: Arc<Semaphore>,
+    max_body_bytes: u64,
+    metrics: Arc<Metrics>,
+) -> Result<SparseBlockResponse<T>, WorkerError> {
+    // the right block comes transposed, so both blocks must span the inner dimension
+    if payload.left.cols() != payload.right.cols() {
+        tracing::warn!(
+            "{} sparse blocks for tile at ({}, {}) are {}x{} and {}x{}",
+            T::DTYPE.as_str(), payload.row_offset, payload.col_offset,
+            payload.left.rows(), payload.left.cols(), payload.right.cols(), payload.right.rows()
+        );
+        return Err(WorkerError::InvalidShape {
+            left: payload.left.shape(),
+            right: (payload.right.cols(), payload.right.rows()),
+        });
+    }
+    // the kernel visits every cell of the product, zero or not
+    let cells = check_cells::<T>(payload.left.rows(), payload.right.rows(), max_body_bytes)?;
+
+    // sparse blocks queue for the same compute slots as dense ones
+    let waiting = metrics.block_waiting();
+    let _slot = block_slots
+        .acquire_owned()
+        .await
+        .map_err(|e| WorkerError::Internal(format!("Block limiter closed: {}", e)))?;
+    drop(waiting);
+    let _in_flight = metrics.block_in_flight();
+
+    let (row_offset, col_offset, policy) = (payload.row_offset, payload.col_offset, payload.overflow);
+    let started = Instant::now();
+    let kernel_span = tracing::info_span!("kernel", dtype = T::DTYPE.as_str(), cells, sparse = true);
+    let outcome = task::spawn_blocking(move || {
+        kernel_span.in_scope(|| kernel::multiply_sparse_blocks(&payload.left, &payload.right, policy))
+    })
+        .await
+        .map_err(|e| WorkerError::Internal(format!("Sparse block multiplication task failed: {}", e)))?;
+    metrics.observe_block(T::DTYPE.as_str(), cells, started.elapsed());
+
+    match outcome {
+        Ok(result) => Ok(SparseBlockResponse { result }),
+        Err(overflowed) => {
+            let cell = (row_offset + overflowed.row, col_offset + overflowed.col);
+            tracing::warn!(
+                "{} overflow in result cell ({}, {}) under the {} policy",
+                T::DTYPE.as_str(), cell.0, cell.1, policy.as_str()
+            );
+            Err(WorkerError::Overflow { policy, cell })
+        }
     }
-    else {
-        eprintln!("Unhandled worker rejection: {:?}", err);
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "UNHANDLED_REJECTION".to_string();
+}
+
+async fn multiply_sparse_block_handler(
+    payload: TypedSparseBlockPayload,
+    reply_format: WireFormat,
+    block_slots: Arc<Semaphore>,
+    max_body_bytes: u64,
+    metrics: Arc<Metrics>,
+) -> Result<impl Reply, Rejection> {
+    // compute in whichever element type the payload was tagged with
+    let block_metrics = Arc::clone(&metrics);
+    match payload {
+        TypedSparseBlockPayload::I32(payload) => {
+            let response = multiply_sparse_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedSparseBlockPayload::I64(payload) => {
+            let response = multiply_sparse_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedSparseBlockPayload::F32(payload) => {
+            let response = multiply_sparse_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
+        TypedSparseBlockPayload::F64(payload) => {
+            let response = multiply_sparse_block(payload, block_slots, max_body_bytes, block_metrics).await?;
+            wire::reply(reply_format, &response, &metrics)
+        }
     }
+}
 
-    let json = warp::reply::json(&serde_json::json!({
-        "error": message,
-    }));
+/// Warp handler for GET /metrics, in the Prometheus text format.
+async fn metrics_handler(metrics: Arc<Metrics>) -> Result<impl Reply, Rejection> {
+    let body = metrics.render().map_err(|e| warp::reject::custom(WorkerError::Internal(e)))?;
+    Ok(warp::reply::with_header(body, "Content-Type", "text/plain; version=0.0.4"))
+}
 
-    Ok(warp::reply::with_status(json, code))
+// every error goes back as a `{code, message, details}` body, which the broker
+// reads to tell a bad tile from a failing worker
+async fn handle_worker_rejection(err: Rejection, metrics: Arc<Metrics>) -> Result<warp::reply::Response, Rejection> {
+    let error = if let Some(worker_err) = err.find::<WorkerError>() {
+        worker_err.clone()
+    } else if err.is_not_found() {
+        WorkerError::NotFound
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        WorkerError::MethodNotAllowed
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        WorkerError::PayloadTooLarge
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        WorkerError::LengthRequired
+    } else if let Some(unsupported) = err.find::<warp::reject::UnsupportedMediaType>() {
+        WorkerError::UnsupportedContentType(unsupported.to_string())
+    } else if let Some(bad_body) = err.find::<warp::body::BodyDeserializeError>() {
+        WorkerError::InvalidRequest(bad_body.to_string())
+    } else {
+        tracing::error!("Unhandled worker rejection: {:?}", err);
+        WorkerError::Internal(format!("Unhandled rejection: {:?}", err))
+    };
+    metrics.observe_error(error.code());
+    if error.status().is_server_error() {
+        tracing::error!(code = error.code(), "{}", error);
+    }
+    Ok(error.into_response())
 }
 
 #[tokio::main]
 async fn main() {
+    // settings come from the config file and WORKER_* environment variables,
+    // refuse to start on anything that doesn't make sense
+    let config = match WorkerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid worker configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tracing_config = &config.tracing;
+    if let Err(e) = trace::init("worker", tracing_config.log_format, &tracing_config.log_filter, config.span_export()) {
+        eprintln!("Invalid worker configuration: {}", e);
+        std::process::exit(1);
+    }
+
+    // size the pool the block kernel runs on, rayon uses one thread per core by default
+    if config.compute_threads > 0 {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.compute_threads)
+            .build_global()
+            .expect("the rayon thread pool is only configured once");
+    }
+    let block_slots = Arc::new(Semaphore::new(config.max_concurrent_blocks));
+    let metrics = match Metrics::new() {
+        Ok(metrics) => Arc::new(metrics),
+        Err(e) => {
+            tracing::error!("Cannot register metrics: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let metrics_filter = {
+        let metrics = Arc::clone(&metrics);
+        warp::any().map(move || Arc::clone(&metrics))
+    };
+
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["Content-Type"])
-        .allow_methods(vec!["POST", "OPTIONS"]);
+        .allow_headers(vec!["Content-Type", "Accept", "traceparent"])
+        .allow_methods(vec!["GET", "POST", "OPTIONS"]);
 
     let dot_product_route = warp::post()
         .and(warp::path("calculate_dot_product"))
-        .and(warp::body::json())
+        .and(wire::body(config.max_body_bytes, Arc::clone(&metrics))) // JSON or bincode, depending on the Content-Type
+        .and(metrics_filter.clone())
         .and_then(calculate_dot_product_handler);
 
+    // the gRPC service shares the compute slots, so blocks from both servers queue together
+    let grpc_service = grpc::WorkerService::new(Arc::clone(&block_slots), config.max_body_bytes, Arc::clone(&metrics));
+    let block_slots_filter = warp::any().map(move || Arc::clone(&block_slots));
+    let max_body_bytes = config.max_body_bytes;
+    let max_body_filter = warp::any().map(move || max_body_bytes);
+
+    // many dot products per request, each tagged with the result cell it is for
+    let dot_products_route = warp::post()
+        .and(warp::path("calculate_dot_products"))
+        .and(wire::body(config.max_body_bytes, Arc::clone(&metrics)))
+        .and(block_slots_filter.clone())
+        .and(metrics_filter.clone())
+        .and_then(calculate_dot_products_handler);
+
+    let multiply_block_route = warp::post()
+        .and(warp::path("multiply_block"))
+        .and(wire::body(config.max_body_bytes, Arc::clone(&metrics)))
+        .and(block_slots_filter.clone())
+        .and(max_body_filter)
+        .and(metrics_filter.clone())
+        .and_then(multiply_block_handler);
+
+    // tiles of sparse matrices, which carry only their non-zero cells both ways
+    let multiply_sparse_block_route = warp::post()
+        .and(warp::path("multiply_sparse_block"))
+        .and(wire::body(config.max_body_bytes, Arc::clone(&metrics)))
+        .and(block_slots_filter)
+        .and(max_body_filter)
+        .and(metrics_filter.clone())
+        .and_then(multiply_sparse_block_handler);
+
+    // GET /metrics exports request, kernel and error metrics for Prometheus
+    let metrics_route = warp::get()
+        .and(warp::path!("metrics"))
+        .and(metrics_filter)
+        .and_then(metrics_handler);
+
+    // every response, errors included, is counted and timed by route and status
+    let log_metrics = Arc::clone(&metrics);
+    let request_metrics = warp::log::custom(move |info| {
+        let route = metrics::route_label(info.path());
+        log_metrics.observe_request(route, info.status().as_u16(), info.elapsed());
+    });
+
     let routes = dot_product_route
+        .or(dot_products_route)
+        .or(multiply_block_route)
+        .or(multiply_sparse_block_route)
+        .or(metrics_route)
         .with(cors)
-        .recover(handle_worker_rejection);
+        .recover(move |err| handle_worker_rejection(err, Arc::clone(&metrics)))
+        // each request is traced as part of the broker's trace for the tile, via its traceparent
+        .with(warp::trace(|info| {
+            let traceparent = info.request_headers().get("traceparent").and_then(|value| value.to_str().ok());
+            trace::request_span(info.method().as_str(), info.path(), traceparent)
+        }))
+        .with(request_metrics);
 
-    let port_str = env::var("WORKER_PORT").unwrap_or_else(|_| "9001".to_string());
-    let port: u16 = port_str.parse().expect("WORKER_PORT must be a valid port number");
+    // join the broker's worker registry if we were told where the broker is
+    match &config.broker_url {
+        Some(broker_url) => {
+            task::spawn(registration::register_and_heartbeat(
+                broker_url.clone(),
+                config.advertise_url(),
+                config.grpc_url(),
+                rayon::current_num_threads(),
+                config.heartbeat_interval(),
+            ));
+        }
+        None => tracing::info!("BROKER_URL is not set, this worker will not register with a broker"),
+    }
+
+    // serve the same computations over gRPC too, if an address was given for it
+    if let Some(grpc_addr) = config.grpc_listen_addr {
+        let server = tonic::transport::Server::builder().add_service(
+            grpc::pb::worker_server::WorkerServer::new(grpc_service)
+                .max_decoding_message_size(config.max_body_bytes as usize)
+                .max_encoding_message_size(usize::MAX),
+        );
+        task::spawn(async move {
+            if let Err(e) = server.serve(grpc_addr).await {
+                tracing::error!("gRPC server on {} failed: {}", grpc_addr, e);
+            }
+        });
+        tracing::info!(
+            transport = ?config.transport,
+            "Worker gRPC server running on http://{} (matmult.Worker)",
+            grpc_addr
+        );
+    }
 
-    println!(
-        "Worker node server running on http://0.0.0.0:{}/calculate_dot_product", // Listen on all interfaces
-        port
+    tracing::info!(
+        "Worker node server running on http://{} (/calculate_dot_product, /calculate_dot_products, /multiply_block, /multiply_sparse_block, /metrics)",
+        config.listen_addr
     );
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    warp::serve(routes).run(config.listen_addr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use matmult_common::matrix::DenseMatrix;
+    use matmult_common::sparse::CsrMatrix;
+
+    use super::*;
+    use crate::element::OverflowPolicy;
+
+    fn slots() -> Arc<Semaphore> {
+        Arc::new(Semaphore::new(1))
+    }
+
+    fn metrics() -> Arc<Metrics> {
+        Arc::new(Metrics::new().unwrap())
+    }
+
+    #[tokio::test]
+    async fn products_too_big_for_the_limit_are_refused_before_they_are_computed() {
+        // a few bytes of empty blocks with a product of a million squared cells, or more than fit in usize
+        for (rows, cols) in [(1_000_000, 1_000_000), (usize::MAX, 2)] {
+            let payload = BlockPayload {
+                row_offset: 0,
+                col_offset: 0,
+                overflow: OverflowPolicy::Wrapping,
+                left: DenseMatrix::<i32>::new(rows, 0, vec![]).unwrap(),
+                right: DenseMatrix::new(0, cols, vec![]).unwrap(),
+            };
+            let outcome = multiply_block(payload, slots(), 1024 * 1024, metrics()).await;
+            assert!(matches!(outcome, Err(WorkerError::PayloadTooLarge)), "{}x{}", rows, cols);
+        }
+
+        let payload = SparseBlockPayload {
+            row_offset: 0,
+            col_offset: 0,
+            overflow: OverflowPolicy::Wrapping,
+            left: CsrMatrix::<i64>::from_triplets(100_000, 1, vec![]).unwrap(),
+            right: CsrMatrix::from_triplets(100_000, 1, vec![]).unwrap(),
+        };
+        let outcome = multiply_sparse_block(payload, slots(), 1024 * 1024, metrics()).await;
+        assert!(matches!(outcome, Err(WorkerError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn products_within_the_limit_are_computed() {
+        // 16 i64 cells is 128 bytes
+        let payload = BlockPayload {
+            row_offset: 0,
+            col_offset: 0,
+            overflow: OverflowPolicy::Checked,
+            left: DenseMatrix::<i64>::new(4, 1, vec![1, 2, 3, 4]).unwrap(),
+            right: DenseMatrix::new(1, 4, vec![1, 1, 1, 1]).unwrap(),
+        };
+        let response = multiply_block(payload.clone(), slots(), 128, metrics()).await.unwrap();
+        assert_eq!(response.result.row(3), [4, 4, 4, 4]);
+        let outcome = multiply_block(payload, slots(), 127, metrics()).await;
+        assert!(matches!(outcome, Err(WorkerError::PayloadTooLarge)));
+    }
 }
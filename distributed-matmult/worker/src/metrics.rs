@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use matmult_common::trace;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+    exponential_buckets,
+};
+
+/// Everything the worker exports on `GET /metrics`, in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_seconds: HistogramVec,
+    errors: IntCounterVec,
+    bytes_received: IntCounterVec,
+    bytes_sent: IntCounterVec,
+    kernel_seconds: HistogramVec,
+    block_cells: HistogramVec,
+    blocks_in_flight: IntGauge,
+    blocks_waiting: IntGauge,
+    // counted by the span exporter, copied over when scraped
+    dropped_spans: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("worker".to_string()), None)?;
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered, by route and status"),
+                &["route", "status"],
+            )?,
+            http_request_seconds: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time to answer an HTTP request, by route")
+                    .buckets(exponential_buckets(0.001, 4.0, 10)?),
+                &["route"],
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new("errors_total", "Errors returned to callers, by error code"),
+                &["code"],
+            )?,
+            bytes_received: IntCounterVec::new(
+                Opts::new("bytes_received_total", "Encoded request body bytes received, by wire format"),
+                &["format"],
+            )?,
+            bytes_sent: IntCounterVec::new(
+                Opts::new("bytes_sent_total", "Encoded response body bytes sent, by wire format"),
+                &["format"],
+            )?,
+            kernel_seconds: HistogramVec::new(
+                HistogramOpts::new("kernel_duration_seconds", "Time spent computing one block, by element type")
+                    .buckets(exponential_buckets(0.0001, 4.0, 10)?),
+                &["dtype"],
+            )?,
+            block_cells: HistogramVec::new(
+                HistogramOpts::new("block_cells", "Cells in each block computed, by element type")
+                    .buckets(exponential_buckets(1.0, 4.0, 12)?),
+                &["dtype"],
+            )?,
+            blocks_in_flight: IntGauge::new("blocks_in_flight", "Blocks being computed right now")?,
+            blocks_waiting: IntGauge::new("blocks_waiting", "Blocks waiting for a free compute slot")?,
+            dropped_spans: IntCounter::new(
+                "dropped_spans_total",
+                "Closed spans dropped rather than exported, for finding the export queue full",
+            )?,
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.http_requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.http_request_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.bytes_received.clone()))?;
+        metrics.registry.register(Box::new(metrics.bytes_sent.clone()))?;
+        metrics.registry.register(Box::new(metrics.kernel_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.block_cells.clone()))?;
+        metrics.registry.register(Box::new(metrics.blocks_in_flight.clone()))?;
+        metrics.registry.register(Box::new(metrics.blocks_waiting.clone()))?;
+        metrics.registry.register(Box::new(metrics.dropped_spans.clone()))?;
+        Ok(metrics)
+    }
+
+    pub fn observe_request(&self, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests.with_label_values(&[route, &status.to_string()]).inc();
+        self.http_request_seconds.with_label_values(&[route]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_error(&self, code: &str) {
+        self.errors.with_label_values(&[code]).inc();
+    }
+
+    pub fn add_bytes_received(&self, format: &str, bytes: usize) {
+        self.bytes_received.with_label_values(&[format]).inc_by(bytes as u64);
+    }
+
+    pub fn add_bytes_sent(&self, format: &str, bytes: usize) {
+        self.bytes_sent.with_label_values(&[format]).inc_by(bytes as u64);
+    }
+
+    pub fn observe_block(&self, dtype: &str, cells: usize, elapsed: Duration) {
+        self.kernel_seconds.with_label_values(&[dtype]).observe(elapsed.as_secs_f64());
+        self.block_cells.with_label_values(&[dtype]).observe(cells as f64);
+    }
+
+    /// Counts a block, or a batch of dot products, as waiting for a compute slot until the guard is dropped.
+    pub fn block_waiting(&self) -> GaugeGuard {
+        GaugeGuard::new(&self.blocks_waiting)
+    }
+
+    /// Counts a block, or a batch of dot products, as being computed until the guard is dropped.
+    pub fn block_in_flight(&self) -> GaugeGuard {
+        GaugeGuard::new(&self.blocks_in_flight)
+    }
+
+    pub fn render(&self) -> Result<String, String> {
+        self.dropped_spans.inc_by(trace::dropped_spans().saturating_sub(self.dropped_spans.get()));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+/// Holds a gauge one higher for as long as it lives.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// The route a request path was served by, so unknown paths share one label value.
+pub fn route_label(path: &str) -> &'static str {
+    match path.trim_matches('/') {
+        "calculate_dot_product" => "/calculate_dot_product",
+        "calculate_dot_products" => "/calculate_dot_products",
+        "multiply_block" => "/multiply_block",
+        "multiply_sparse_block" => "/multiply_sparse_block",
+        "metrics" => "/metrics",
+        _ => "other",
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+use serde_json::json;
+
+/// Registers this worker with the broker, offering `capacity` (its compute
+/// threads, which the broker's weighted scheduler shares tiles out by) and,
+/// if tiles should come over gRPC, the `grpc_url` to send them to, and
+/// then heartbeats every `interval` for as long as the worker runs. If the broker has forgotten the worker (it
+/// restarted, or the worker missed too many heartbeats) it answers a heartbeat
+/// with 404 and the worker registers again. Failures are logged and retried on
+/// the next tick, so the broker may start before or after the worker.
+pub async fn register_and_heartbeat(
+    broker_url: String,
+    advertise_url: String,
+    grpc_url: Option<String>,
+    capacity: usize,
+    interval: Duration,
+) {
+    let client = Client::new();
+    let register_url = format!("{}/workers/register", broker_url.trim_end_matches('/'));
+    let heartbeat_url = format!("{}/workers/heartbeat", broker_url.trim_end_matches('/'));
+    let body = json!({ "url": advertise_url, "capacity": capacity, "grpc_url": grpc_url });
+
+    let mut registered = false;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        let target = if registered { &heartbeat_url } else { &register_url };
+        match client.post(target).json(&body).timeout(interval).send().await {
+            Ok(response) if response.status().is_success() => {
+                if !registered {
+                    tracing::info!("Registered with broker {} as {}", broker_url, advertise_url);
+                }
+                registered = true;
+            }
+            Ok(response) if response.status() == StatusCode::NOT_FOUND && registered => {
+                tracing::warn!("Broker {} no longer knows this worker, registering again", broker_url);
+                registered = false;
+            }
+            Ok(response) => {
+                tracing::warn!("Broker {} rejected {} with status {}", broker_url, target, response.status());
+            }
+            Err(e) => {
+                tracing::warn!("Could not reach broker at {}: {}", target, e);
+            }
+        }
+    }
+}
@@ -1,42 +1,239 @@
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::fmt;
 
-pub type Matrix = Vec<Vec<i32>>;
+use matmult_common::matrix::DenseMatrix;
+use matmult_common::sparse::CsrMatrix;
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::element::{DType, OverflowPolicy};
+
+/// A single dot product. It is cell (`row_id`, `col_id`) of some result, which
+/// an overflow is reported against, or the only cell (0, 0) of a row times a
+/// column when the client leaves them out.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct DotProductPayload {
-    pub row: Vec<i32>,
-    pub col: Vec<i32>,
+pub struct DotProductPayload<T> {
+    #[serde(default)]
+    pub row_id: usize,
+    #[serde(default)]
+    pub col_id: usize,
+    pub row: Vec<T>,
+    pub col: Vec<T>,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct DotProductResponse {
-    pub result: i32,
+/// A dot product payload tagged with its element type. In JSON the type is an
+/// optional `dtype` field next to `row` and `col`, i32 when it is left out as
+/// in bodies from before payloads were typed. Bincode can't skip a missing
+/// field, so there the type is sent ahead of the payload.
+pub enum TypedDotProductPayload {
+    I32(DotProductPayload<i32>),
+    I64(DotProductPayload<i64>),
+    F32(DotProductPayload<f32>),
+    F64(DotProductPayload<f64>),
 }
 
-#[derive(Deserialize, Debug)]
-pub struct MatMultRequest {
-    pub left: Matrix,
-    pub right: Matrix,
+impl Serialize for TypedDotProductPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TypedDotProductPayload::I32(payload) => serialize_typed(serializer, DType::I32, payload),
+            TypedDotProductPayload::I64(payload) => serialize_typed(serializer, DType::I64, payload),
+            TypedDotProductPayload::F32(payload) => serialize_typed(serializer, DType::F32, payload),
+            TypedDotProductPayload::F64(payload) => serialize_typed(serializer, DType::F64, payload),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct AppError(pub String);
-#[derive(Debug)]
-pub struct WorkerError(pub String);
+fn serialize_typed<S: Serializer, T: Serialize>(
+    serializer: S,
+    dtype: DType,
+    payload: &DotProductPayload<T>,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Flat<'a, T> {
+        dtype: DType,
+        #[serde(flatten)]
+        payload: &'a DotProductPayload<T>,
+    }
 
-impl warp::reject::Reject for AppError {}
-impl warp::reject::Reject for WorkerError {}
+    if serializer.is_human_readable() {
+        Flat { dtype, payload }.serialize(serializer)
+    } else {
+        (dtype, payload).serialize(serializer)
+    }
+}
 
-impl From<String> for AppError {
-    fn from(s: String) -> Self {
-        AppError(s)
+impl<'de> Deserialize<'de> for TypedDotProductPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return deserializer.deserialize_tuple(2, TypedDotProductVisitor);
+        }
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        let dtype = match value.as_object_mut().and_then(|fields| fields.remove("dtype")) {
+            Some(dtype) => DType::deserialize(dtype).map_err(D::Error::custom)?,
+            None => DType::I32,
+        };
+        let typed = match dtype {
+            DType::I32 => serde_json::from_value(value).map(TypedDotProductPayload::I32),
+            DType::I64 => serde_json::from_value(value).map(TypedDotProductPayload::I64),
+            DType::F32 => serde_json::from_value(value).map(TypedDotProductPayload::F32),
+            DType::F64 => serde_json::from_value(value).map(TypedDotProductPayload::F64),
+        };
+        typed.map_err(D::Error::custom)
     }
 }
 
-impl From<Box<dyn Error + Send + Sync>> for AppError {
-    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
-        AppError(e.to_string())
+// reads the element type bincode sends ahead of a dot product payload, then the
+// payload in that type
+struct TypedDotProductVisitor;
+
+impl<'de> Visitor<'de> for TypedDotProductVisitor {
+    type Value = TypedDotProductPayload;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an element type followed by a dot product payload")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let dtype: DType = seq.next_element()?.ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        let typed = match dtype {
+            DType::I32 => seq.next_element()?.map(TypedDotProductPayload::I32),
+            DType::I64 => seq.next_element()?.map(TypedDotProductPayload::I64),
+            DType::F32 => seq.next_element()?.map(TypedDotProductPayload::F32),
+            DType::F64 => seq.next_element()?.map(TypedDotProductPayload::F64),
+        };
+        typed.ok_or_else(|| A::Error::invalid_length(1, &self))
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct DotProductResponse<T> {
+    pub result: T,
+}
+
+/// One dot product of a batch, which is cell (`row_id`, `col_id`) of the result.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DotProductItem<T> {
+    pub row_id: usize,
+    pub col_id: usize,
+    pub row: Vec<T>,
+    pub col: Vec<T>,
+}
+
+/// Many dot products in one request, so a worker is sent a batch of cells at a
+/// time rather than one round trip per cell.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DotProductBatch<T> {
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    pub items: Vec<DotProductItem<T>>,
+}
+
+/// A dot product batch tagged with its element type.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedDotProductBatch {
+    I32(DotProductBatch<i32>),
+    I64(DotProductBatch<i64>),
+    F32(DotProductBatch<f32>),
+    F64(DotProductBatch<f64>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DotProductResult<T> {
+    pub row_id: usize,
+    pub col_id: usize,
+    pub result: T,
+}
+
+/// The results of a batch, in the order of its items.
+#[derive(Serialize, Deserialize)]
+pub struct DotProductBatchResponse<T> {
+    pub results: Vec<DotProductResult<T>>,
+}
+
+/// A tile of the result: a block of rows of the left matrix and a block of
+/// columns of the right matrix, positioned at (`row_offset`, `col_offset`).
+/// Each block carries its own shape.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockPayload<T> {
+    pub row_offset: usize,
+    pub col_offset: usize,
+    pub overflow: OverflowPolicy,
+    pub left: DenseMatrix<T>,
+    pub right: DenseMatrix<T>,
+}
+
+/// A block payload tagged with its element type.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedBlockPayload {
+    I32(BlockPayload<i32>),
+    I64(BlockPayload<i64>),
+    F32(BlockPayload<f32>),
+    F64(BlockPayload<f64>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BlockResponse<T> {
+    pub result: DenseMatrix<T>,
+}
+
+/// A tile of the product of sparse matrices, positioned at (`row_offset`,
+/// `col_offset`). `right` is the block of columns of the right matrix
+/// transposed, one row per column of the tile, so each cell of the tile is the
+/// dot product of a row of `left` and a row of `right`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SparseBlockPayload<T> {
+    pub row_offset: usize,
+    pub col_offset: usize,
+    pub overflow: OverflowPolicy,
+    pub left: CsrMatrix<T>,
+    pub right: CsrMatrix<T>,
+}
+
+/// A sparse block payload tagged with its element type.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypedSparseBlockPayload {
+    I32(SparseBlockPayload<i32>),
+    I64(SparseBlockPayload<i64>),
+    F32(SparseBlockPayload<f32>),
+    F64(SparseBlockPayload<f64>),
+}
+
+/// The tile of a sparse block payload, holding only the cells which aren't zero.
+#[derive(Serialize, Deserialize)]
+pub struct SparseBlockResponse<T> {
+    pub result: CsrMatrix<T>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::WireFormat;
+
+    #[test]
+    fn dot_products_without_a_dtype_are_i32() {
+        let payload: TypedDotProductPayload = serde_json::from_str(r#"{"row": [1, 2], "col": [3, 4]}"#).unwrap();
+        let TypedDotProductPayload::I32(payload) = payload else { panic!("not an i32 payload") };
+        assert_eq!((payload.row_id, payload.col_id, payload.row, payload.col), (0, 0, vec![1, 2], vec![3, 4]));
+
+        let payload: TypedDotProductPayload =
+            serde_json::from_str(r#"{"dtype": "i64", "row": [5000000000], "col": [2], "overflow": "checked"}"#).unwrap();
+        let TypedDotProductPayload::I64(payload) = payload else { panic!("not an i64 payload") };
+        assert_eq!((payload.row, payload.overflow), (vec![5_000_000_000], OverflowPolicy::Checked));
+    }
+
+    #[test]
+    fn typed_dot_products_survive_both_wire_formats() {
+        for format in [WireFormat::Json, WireFormat::Bincode] {
+            let (row, col) = (vec![0.5, -2.0], vec![4.0, 1.5]);
+            let payload = DotProductPayload { row_id: 3, col_id: 1, row, col, overflow: OverflowPolicy::Wrapping };
+            let bytes = format.encode(&TypedDotProductPayload::F64(payload)).unwrap();
+            let decoded = format.decode::<TypedDotProductPayload>(&bytes, 1024).unwrap();
+            let TypedDotProductPayload::F64(payload) = decoded else { panic!("not an f64 payload over {:?}", format) };
+            assert_eq!((payload.row_id, payload.col_id, payload.col), (3, 1, vec![4.0, 1.5]), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use bincode::Options;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use warp::http::header::CONTENT_TYPE;
+use warp::hyper::body::Buf;
+use warp::{Filter, Rejection};
+
+use crate::error::WorkerError;
+use crate::metrics::Metrics;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINCODE_CONTENT_TYPE: &str = "application/x-bincode";
+
+/// Encoding used for a request or response body, negotiated via `Content-Type`
+/// and `Accept`. JSON is the fallback when no content type is given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::Bincode => "bincode",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Bincode => BINCODE_CONTENT_TYPE,
+        }
+    }
+
+    /// Maps a media type (ignoring parameters such as `charset`) to a format.
+    pub fn from_media_type(media_type: &str) -> Option<WireFormat> {
+        let essence = media_type.split(';').next().unwrap_or("").trim();
+        if essence.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+            Some(WireFormat::Json)
+        } else if essence.eq_ignore_ascii_case(BINCODE_CONTENT_TYPE) {
+            Some(WireFormat::Bincode)
+        } else {
+            None
+        }
+    }
+
+    /// Picks the first supported format listed in an `Accept` header.
+    pub fn from_accept(accept: &str) -> Option<WireFormat> {
+        accept.split(',').find_map(WireFormat::from_media_type)
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            WireFormat::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Decodes `bytes`, with bincode refusing to read or allocate more than
+    /// `max_bytes` whatever the lengths written in the body claim.
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8], max_bytes: u64) -> Result<T, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            // the same encoding as `bincode::deserialize`, with a limit. Bincode ignores
+            // the limit when decoding a slice, so the bytes are read as a stream
+            WireFormat::Bincode => bincode::options()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(max_bytes)
+                .deserialize_from(bytes)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Warp filter which decodes the request body according to its `Content-Type`
+/// and extracts the format the reply should be encoded with: the first
+/// supported type in `Accept`, otherwise the same format as the request.
+/// Bodies over `max_bytes` are rejected before they are read if their
+/// `Content-Length` says so, and chunked ones as soon as that much is read.
+pub fn body<T: DeserializeOwned + Send>(
+    max_bytes: u64,
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (T, WireFormat), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and(warp::any().map(move || Arc::clone(&metrics)))
+        .and_then(
+            move |content_type: Option<String>,
+                  accept: Option<String>,
+                  length: Option<u64>,
+                  body,
+                  metrics: Arc<Metrics>| async move {
+                let format = match content_type.as_deref() {
+                    None => WireFormat::Json,
+                    Some(media_type) => WireFormat::from_media_type(media_type)
+                        .ok_or_else(|| warp::reject::custom(WorkerError::UnsupportedContentType(media_type.to_string())))?,
+                };
+                if length.is_some_and(|length| length > max_bytes) {
+                    return Err(warp::reject::custom(WorkerError::PayloadTooLarge));
+                }
+                let bytes = read_body(body, max_bytes).await?;
+                metrics.add_bytes_received(format.as_str(), bytes.len());
+                let value = format.decode::<T>(&bytes, max_bytes).map_err(|e| {
+                    warp::reject::custom(WorkerError::InvalidRequest(format!(
+                        "Invalid {} request body: {}",
+                        format.content_type(),
+                        e
+                    )))
+                })?;
+                let reply_format = accept.as_deref().and_then(WireFormat::from_accept).unwrap_or(format);
+                Ok::<_, Rejection>((value, reply_format))
+            },
+        )
+        .untuple_one()
+}
+
+// collects a body, which may be chunked, failing as soon as it runs over `max_bytes`
+async fn read_body(
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, Rejection> {
+    let mut bytes = Vec::new();
+    let mut body = std::pin::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| {
+            warp::reject::custom(WorkerError::InvalidRequest(format!("Failed to read the request body: {}", e)))
+        })?;
+        if (bytes.len() + chunk.remaining()) as u64 > max_bytes {
+            return Err(warp::reject::custom(WorkerError::PayloadTooLarge));
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            bytes.extend_from_slice(part);
+            let read = part.len();
+            chunk.advance(read);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Encodes `value` as the body of a 200 response in the given format.
+pub fn reply<T: Serialize>(format: WireFormat, value: &T, metrics: &Metrics) -> Result<warp::reply::Response, Rejection> {
+    let bytes = format
+        .encode(value)
+        .map_err(|e| warp::reject::custom(WorkerError::Internal(format!("Failed to encode response: {}", e))))?;
+    metrics.add_bytes_sent(format.as_str(), bytes.len());
+    let mut response = warp::reply::Response::new(bytes.into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, warp::http::HeaderValue::from_static(format.content_type()));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::hyper::body::Bytes;
+
+    use super::*;
+
+    // runs `bytes` through the body filter with the given headers
+    async fn decode(
+        content_type: Option<&str>,
+        accept: Option<&str>,
+        bytes: Vec<u8>,
+    ) -> Result<(Vec<i64>, WireFormat), Rejection> {
+        let mut request = warp::test::request().method("POST").body(bytes);
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+        if let Some(accept) = accept {
+            request = request.header("accept", accept);
+        }
+        request.filter(&body::<Vec<i64>>(1024, Arc::new(Metrics::new().unwrap()))).await
+    }
+
+    #[test]
+    fn media_types_are_matched_without_their_parameters() {
+        assert_eq!(WireFormat::from_media_type("application/json"), Some(WireFormat::Json));
+        assert_eq!(WireFormat::from_media_type("Application/JSON; charset=utf-8"), Some(WireFormat::Json));
+        assert_eq!(WireFormat::from_media_type(" application/x-bincode "), Some(WireFormat::Bincode));
+        assert_eq!(WireFormat::from_media_type("text/plain"), None);
+        // the first supported type in an Accept list wins
+        let accept = "text/html, application/x-bincode, application/json";
+        assert_eq!(WireFormat::from_accept(accept), Some(WireFormat::Bincode));
+        assert_eq!(WireFormat::from_accept("*/*"), None);
+    }
+
+    #[tokio::test]
+    async fn replies_use_the_accepted_format_or_the_request_format() {
+        let values = vec![1, -2, i64::MAX];
+        let json = serde_json::to_vec(&values).unwrap();
+        let bincode = bincode::serialize(&values).unwrap();
+
+        for (content_type, accept, bytes, reply_format) in [
+            // JSON is assumed without a content type
+            (None, None, json.clone(), WireFormat::Json),
+            (Some(JSON_CONTENT_TYPE), None, json.clone(), WireFormat::Json),
+            (Some(BINCODE_CONTENT_TYPE), None, bincode.clone(), WireFormat::Bincode),
+            (Some(BINCODE_CONTENT_TYPE), Some("text/html, application/json"), bincode.clone(), WireFormat::Json),
+            (Some("application/json; charset=utf-8"), Some(BINCODE_CONTENT_TYPE), json.clone(), WireFormat::Bincode),
+            // an Accept with nothing supported falls back to the request's format
+            (Some(BINCODE_CONTENT_TYPE), Some("*/*"), bincode.clone(), WireFormat::Bincode),
+        ] {
+            let decoded = decode(content_type, accept, bytes).await.unwrap();
+            assert_eq!(decoded, (values.clone(), reply_format), "{:?} {:?}", content_type, accept);
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_rejected() {
+        let rejection = decode(None, None, serde_json::to_vec(&vec![1i64; 600]).unwrap()).await.unwrap_err();
+        assert!(matches!(rejection.find(), Some(WorkerError::PayloadTooLarge)));
+
+        // a chunked body has no length up front, it is cut off once it has run over
+        let chunks = || futures::stream::iter([&b"[1, "[..], b"2]"].map(|chunk| Ok::<_, warp::Error>(Bytes::from(chunk))));
+        assert_eq!(read_body(chunks(), 6).await.unwrap(), b"[1, 2]");
+        let rejection = read_body(chunks(), 5).await.unwrap_err();
+        assert!(matches!(rejection.find(), Some(WorkerError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn bincode_reads_no_more_than_the_limit() {
+        let bytes = bincode::serialize(&vec![7u8; 100]).unwrap();
+        assert_eq!(WireFormat::Bincode.decode::<Vec<u8>>(&bytes, 1024), Ok(vec![7; 100]));
+        assert!(WireFormat::Bincode.decode::<Vec<u8>>(&bytes, 64).unwrap_err().contains("limit"));
+        // nor believes a length it has no bytes for
+        let huge = u64::MAX.to_le_bytes();
+        assert!(WireFormat::Bincode.decode::<Vec<u8>>(&huge, 1024).is_err());
+    }
+
+    #[tokio::test]
+    async fn unreadable_bodies_are_rejected() {
+        let rejection = decode(Some("text/plain"), None, b"[1]".to_vec()).await.unwrap_err();
+        let unsupported = rejection.find::<WorkerError>();
+        assert!(matches!(unsupported, Some(WorkerError::UnsupportedContentType(media_type)) if media_type == "text/plain"));
+
+        // JSON sent as bincode, and the other way round
+        for (content_type, bytes) in
+            [(BINCODE_CONTENT_TYPE, b"[1, 2]".to_vec()), (JSON_CONTENT_TYPE, bincode::serialize(&vec![1i64]).unwrap())]
+        {
+            let rejection = decode(Some(content_type), None, bytes).await.unwrap_err();
+            assert!(matches!(rejection.find(), Some(WorkerError::InvalidRequest(_))), "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn replies_carry_their_content_type() {
+        let metrics = Metrics::new().unwrap();
+        for format in [WireFormat::Json, WireFormat::Bincode] {
+            let response = reply(format, &vec![4i64, 5], &metrics).unwrap();
+            assert_eq!(response.headers()[CONTENT_TYPE], format.content_type());
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use hydro_deploy::Deployment; // imports Deployment struct from hydro_deploy crate
+use matmult_overflow::OverflowPolicy; // imports the shared integer overflow policies
 
 #[tokio::main] // marks the `main` function as the entry point for a Tokio runtime, enabling async ops
 async fn main() {
@@ -7,9 +8,10 @@ async fn main() {
     let flow = hydro_lang::FlowBuilder::new(); // new FlowBuilder instance, used to define the structure of hydro flow
     let leader = flow.process(); // defines single leader process within the flow
     let workers = flow.cluster(); // defines a clusdter of worker processes within flow
-    hydro_template::cluster_matmult::cluster_matmult(&leader, &workers); 
+    hydro_template::cluster_matmult::cluster_matmult(&leader, &workers, OverflowPolicy::Saturating);
     // calls cluster_matmult function from the `hydro_template` crate, which is just src,
-    // passing leader and worker cluster to set up the distributed matrix multiplication
+    // passing leader and worker cluster to set up the distributed matrix multiplication,
+    // and the overflow policy its dot products follow, the broker's default
 
     let _nodes = flow // starts defining how flow's processes and clusters will be deployed
         .with_process(&leader, deployment.Localhost()) // deploys leader psrocess to localhost
@@ -1,19 +1,23 @@
 use hydro_lang::*; // imports Hydro language crate for using the framework
 use serde::{Serialize, Deserialize}; // imports Serde traits for serialization & deserialization
 
+use matmult_overflow::OverflowPolicy; // imports the shared integer overflow policies
+
 #[derive(Serialize, Deserialize, Clone, Debug)] // procedural macros derive traits
 struct MatrixCellTask { // defines a struct, represents task for calculating single cell in result matrix
     target_row: i32, // he target row index for cell being calculated
     target_col: i32, // target column index for the cell being calculated
     row_vec: Vec<i32>, // row vector from the left matrix needed for dot product
     col_vec: Vec<i32>, // column vector from right matrix needed for the dot product
+    policy: OverflowPolicy, // how the dot product handles i32 overflow, chosen with the input matrices
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)] // derives traits
 struct MatrixCellValue { // defines struct to represent dot product value for a single result matrix cell
     row_ind: i32, // row index of the computed cell
     col_ind: i32, // column index of computed cell
-    value: i32, // computed value of fcell
+    value: Option<i32>, // computed value of fcell, None if it overflowed under the policy
+    policy: OverflowPolicy, // the overflow policy the cell was computed under
 }
 
 // create a tag for the leader and worker processes:
@@ -25,16 +29,19 @@ pub type Matrix = Vec<Vec<i32>>; // defines type alias for a matrix as a vector
 pub fn cluster_matmult<'a>(
     leader: &Process<'a, Leader>, // reference to a Hydro `Process` representing the leader
     workers: &Cluster<'a, Worker>, // reference to sa Hydro `Cluster` representing the worker processes
+    policy: OverflowPolicy, // how the dot products handle i32 overflow
 ) {
     // NOTE: because the hydro implmentation required a hard coded set of matrices, 
     // the result matrix dimensions are also set
     let result_rows: i32 = 2; // dsets the number of rows for resulting matrix
     let result_cols: i32 = 2; // sets  number of columns for resulting matrix
+    let policy_index: usize = policy as usize; // q! closures capture plain numbers, so the policy goes in as its index into OverflowPolicy::ALL
 
     leader // starts a Hydro dataflow from leader process
         .source_iter(q!({ // creates source that iterates over items, specified by a 'quoted' (i.e. the q! macro) Hydro closure
             let left_matrix_base: Matrix = vec![vec![1, 2, 3], vec![4, 5, 6]]; // hardcoded left matrix
             let right_matrix_base: Matrix = vec![vec![7, 8], vec![9, 10], vec![11, 12]]; // hardcoded right matrix
+            let policy = matmult_overflow::OverflowPolicy::ALL[policy_index]; // the policy the caller chose, back from its index
 
             (0..result_rows).flat_map(move |row_ind| { // fterates over each row index of the result matrix and flattens resulting iterators
                 let left_matrix_for_r = left_matrix_base.clone(); // clones left matrix for use within row iteration
@@ -66,6 +73,7 @@ pub fn cluster_matmult<'a>(
                         target_col: col_ind as i32, // sets target column for task
                         row_vec, // sssigns extracted row vector
                         col_vec, // Assigns extracted column vector
+                        policy, // carries the overflow policy to the worker
                     }
                 })
             })
@@ -75,11 +83,9 @@ pub fn cluster_matmult<'a>(
             let row_vec = task.row_vec; // extracts row vector from task
             let col_vec = task.col_vec; // extracts column vector from task
 
-            let mut sum = 0; // inits sum for dot product
+            let mut sum = Some(0); // inits sum for dot product
             if row_vec.len() == col_vec.len() { // dhecks if lengths of the row and column vectors equal
-                for k in 0..row_vec.len() { // iterates through elements of the vectors
-                    sum += row_vec[k] * col_vec[k]; // calculates dot product
-                }
+                sum = matmult_overflow::dot_product(&row_vec, &col_vec, task.policy); // calculates dot product under the task's overflow policy
             } else { // if the vector lengths dont match
                 eprintln!( // prints error message
                     "Error: Vector lengths mismatch for cell C[{}, {}] (row: {}, col: {})", // formats error message
@@ -91,13 +97,20 @@ pub fn cluster_matmult<'a>(
                 row_ind: task.target_row, // sets row index of result
                 col_ind: task.target_col, // sets column index of result
                 value: sum, // seys computed val
+                policy: task.policy, // passes the policy back for error reporting
             }
         }))
         .send_bincode_anonymous(leader) // seends the computed MatrixCellValues back to leader procss using bincode serialization
         .for_each(q!(|cell_value: MatrixCellValue| { // fpr each received MatrixCellValue on leader process
-            println!( // prints result
-                "Matrix Cell Result: C[{}, {}] = {}", // formats output string
-                cell_value.row_ind, cell_value.col_ind, cell_value.value // unserts row, column, & value of the result
-            );
+            match cell_value.value { // checks whether the cell was computed or overflowed
+                Some(value) => println!( // prints result
+                    "Matrix Cell Result: C[{}, {}] = {}", // formats output string
+                    cell_value.row_ind, cell_value.col_ind, value // unserts row, column, & value of the result
+                ),
+                None => eprintln!( // prints error naming the offending cell
+                    "Error: arithmetic overflow computing C[{}, {}] under the {:?} overflow policy", // formats error message
+                    cell_value.row_ind, cell_value.col_ind, cell_value.policy // provides the cell and policy
+                ),
+            }
         }));
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
 use std::error::Error;
 
+// the overflow policies and dot product kernel are shared with the other matmults
+use matmult_overflow::{OverflowPolicy, dot_product};
+
 type Matrix = Vec<Vec<i32>>; // type alias
 
-pub fn matmult(left: &Matrix, right: &Matrix) -> Result<Matrix, Box<dyn Error>> {
+pub fn matmult(left: &Matrix, right: &Matrix, policy: OverflowPolicy) -> Result<Matrix, Box<dyn Error>> {
     // ensure matrices are populated:
     if left.is_empty() || right.is_empty() {
         return Err("At least one of the given matrices was empty".into());
@@ -27,12 +30,14 @@ pub fn matmult(left: &Matrix, right: &Matrix) -> Result<Matrix, Box<dyn Error>>
     // init result matrix and populate w all 0s:
     let mut result = vec![vec![0; num_cols_right]; num_rows_left];
 
-    // multiply the matrices synchronously, one dot product at a time:
+    // multiply the matrices synchronously, one dot product at a time, handling
+    // overflow the way the policy says:
     for i in 0..num_rows_left {
         for j in 0..num_cols_right {
-            for k in 0..num_cols_left {
-                result[i][j] += left[i][k] * right[k][j];
-            }
+            let col: Vec<i32> = right.iter().map(|row| row[j]).collect();
+            result[i][j] = dot_product(&left[i], &col, policy).ok_or_else(|| {
+                format!("Arithmetic overflow computing result[{}][{}] under the {:?} overflow policy", i, j, policy)
+            })?;
         }
     }
 
@@ -45,7 +50,7 @@ fn main() {
 
     // unwrap returns the value of a Result if it is a value, 
     // otherwise panics with the error
-    let result: Matrix = matmult(&left, &right).unwrap();
+    let result: Matrix = matmult(&left, &right, OverflowPolicy::Saturating).unwrap();
     let expected: Matrix = vec![
         vec![19, 22], // [1*5 + 2*7, 1*6 + 2*8]
         vec![43, 50], // [3*5 + 4*7, 3*6 + 4*8]